@@ -0,0 +1,240 @@
+use clap::{App, Arg, ArgMatches, SubCommand};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::exit;
+use train::dataset::{DataSet, Instance};
+use util::{open_input, open_output, Id, Result, Value};
+
+/// Writes the data set as CSV, with a header row "label,qid,f1,f2,..."
+/// and a row of the dense feature values of each instance.
+pub fn write_csv<W: Write>(dataset: &DataSet, writer: &mut W) -> Result<()> {
+    let mut header = vec!["label".to_string(), "qid".to_string()];
+    header.extend(dataset.fid_iter().map(|fid| format!("f{}", fid)));
+    writeln!(writer, "{}", header.join(","))?;
+
+    for instance in dataset.iter() {
+        let mut row = vec![
+            instance.label().to_string(),
+            dataset.qid_label(instance.qid()).into_owned(),
+        ];
+        let values = dataset.fid_iter().map(|fid| instance.value(fid));
+        row.extend(values.map(|value| value.to_string()));
+        writeln!(writer, "{}", row.join(","))?;
+    }
+    Ok(())
+}
+
+/// The meaning of a CSV column.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Column {
+    Label,
+    Qid,
+    Feature(Id),
+}
+
+impl Column {
+    /// Parses a header field: "label", "qid", or "f" followed by the
+    /// feature id.
+    fn parse(field: &str) -> Result<Column> {
+        match field.trim() {
+            "label" => Ok(Column::Label),
+            "qid" => Ok(Column::Qid),
+            field if field.starts_with('f') => match field[1..].parse() {
+                Ok(0) | Err(_) => {
+                    Err(From::from(format!("Invalid column: {}", field)))
+                }
+                Ok(id) => Ok(Column::Feature(id)),
+            },
+            field => Err(From::from(format!("Invalid column: {}", field))),
+        }
+    }
+}
+
+/// Reads CSV written by `write_csv`, or any CSV whose header names
+/// the columns "label", "qid", and "f<id>". The qid column is
+/// optional and defaults to 0, and a non-numeric qid is kept as the
+/// qid label. Zero feature values are not stored.
+pub fn read_csv<R: Read>(reader: R) -> Result<Vec<Instance>> {
+    let mut lines = BufReader::new(reader).lines();
+    let header = match lines.next() {
+        Some(line) => line?,
+        None => Err("Missing header row")?,
+    };
+    let columns = header
+        .split(',')
+        .map(Column::parse)
+        .collect::<Result<Vec<Column>>>()?;
+    if !columns.contains(&Column::Label) {
+        Err("Missing label column")?;
+    }
+
+    let mut instances = Vec::new();
+    for (index, line) in lines.enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split(',').collect();
+        if fields.len() != columns.len() {
+            Err(format!("Wrong number of fields on row {}", index + 1))?;
+        }
+
+        let mut label = 0.0;
+        let mut qid = None;
+        let mut pairs = Vec::new();
+        for (&column, field) in columns.iter().zip(fields) {
+            let field = field.trim();
+            match column {
+                Column::Label => label = field.parse::<Value>()?,
+                Column::Qid => qid = Some(field),
+                Column::Feature(id) => {
+                    let value = field.parse::<Value>()?;
+                    if value != 0.0 {
+                        pairs.push((id, value));
+                    }
+                }
+            }
+        }
+        let mut instance = Instance::new_sparse(label, 0, pairs);
+        match qid.map(|qid| qid.parse::<Id>()) {
+            Some(Ok(id)) => instance.set_qid(id),
+            Some(Err(_)) => instance.set_qid_label(qid.map(str::to_string)),
+            None => (),
+        }
+        instances.push(instance);
+    }
+    Ok(instances)
+}
+
+/// Converts the input in the format `from` to the format `to`.
+fn convert(input: &str, output: &str, from: &str, to: &str) -> Result<()> {
+    let mut writer = open_output(output)?;
+    match (from, to) {
+        ("svmlight", "csv") => {
+            let dataset = DataSet::load(open_input(input)?)?;
+            write_csv(&dataset, &mut writer)?;
+        }
+        ("csv", "svmlight") => {
+            for instance in read_csv(open_input(input)?)? {
+                writeln!(writer, "{}", instance)?;
+            }
+        }
+        _ => Err(format!("Cannot convert from {} to {}", from, to))?,
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn main<'a>(matches: &ArgMatches<'a>) {
+    let input = matches.value_of("input").unwrap();
+    let output = matches.value_of("output").unwrap();
+    let from = matches.value_of("from").unwrap();
+    let to = matches.value_of("to").unwrap();
+
+    convert(input, output, from, to).unwrap_or_else(|e| {
+        error!("Failed to convert {}: {}", input, e);
+        exit(1)
+    });
+}
+
+/// Returns the convert command.
+pub fn clap_command<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("convert")
+        .about("Convert data between svmlight and CSV")
+        .arg(
+            Arg::with_name("input")
+                .short("i")
+                .long("input")
+                .value_name("FILE")
+                .takes_value(true)
+                .required(true)
+                .display_order(1)
+                .help("Input file, or - for the standard input"),
+        )
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .value_name("FILE")
+                .takes_value(true)
+                .default_value("-")
+                .display_order(2)
+                .help("Output file, or - for the standard output"),
+        )
+        .arg(
+            Arg::with_name("from")
+                .long("from")
+                .takes_value(true)
+                .possible_values(&["svmlight", "csv"])
+                .default_value("svmlight")
+                .display_order(3)
+                .help("Format of the input"),
+        )
+        .arg(
+            Arg::with_name("to")
+                .long("to")
+                .takes_value(true)
+                .possible_values(&["svmlight", "csv"])
+                .default_value("csv")
+                .display_order(4)
+                .help("Format of the output"),
+        )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_csv_round_trip() {
+        let s = "3 qid:1 1:5 3:1
+2 qid:1 2:7
+0 qid:2 1:1";
+        let dataset = DataSet::load(Cursor::new(s)).unwrap();
+
+        let mut csv = Vec::new();
+        write_csv(&dataset, &mut csv).unwrap();
+        assert_eq!(
+            ::std::str::from_utf8(&csv).unwrap(),
+            "label,qid,f1,f2,f3
+3,1,5,0,1
+2,1,0,7,0
+0,2,1,0,0
+"
+        );
+
+        let instances = read_csv(&csv[..]).unwrap();
+        let lines: Vec<String> =
+            instances.iter().map(|instance| instance.to_string()).collect();
+        assert_eq!(
+            lines,
+            vec!["3 qid:1 1:5 2:0 3:1", "2 qid:1 1:0 2:7", "0 qid:2 1:1"]
+        );
+    }
+
+    #[test]
+    fn test_csv_string_qid() {
+        let s = "3 qid:GX008 1:5
+2 qid:GX010 1:7";
+        let dataset = DataSet::load(Cursor::new(s)).unwrap();
+
+        let mut csv = Vec::new();
+        write_csv(&dataset, &mut csv).unwrap();
+        assert_eq!(
+            ::std::str::from_utf8(&csv).unwrap(),
+            "label,qid,f1\n3,GX008,5\n2,GX010,7\n"
+        );
+
+        let instances = read_csv(&csv[..]).unwrap();
+        assert_eq!(instances[0].to_string(), "3 qid:GX008 1:5");
+    }
+
+    #[test]
+    fn test_read_csv_errors() {
+        assert!(read_csv(Cursor::new("")).is_err());
+        assert!(read_csv(Cursor::new("qid,f1\n1,2")).is_err());
+        assert!(read_csv(Cursor::new("label,x1\n1,2")).is_err());
+        assert!(read_csv(Cursor::new("label,f1\n1,2,3")).is_err());
+    }
+}
@@ -0,0 +1,358 @@
+use clap::{App, Arg, ArgMatches, SubCommand};
+use std::process::exit;
+use metric;
+use metric::{Measure, TieBreak, ZeroQueries};
+use train::Evaluate;
+use train::dataset::DataSet;
+use train::lambdamart::regression_tree::Ensemble;
+use util::{open_input, Id, Result};
+
+/// Parses a metric "NAME@K", e.g. "NDCG@10". "NAME" alone is cut off
+/// at 10.
+///
+/// # Examples
+///
+/// ```
+/// use rforests::eval;
+///
+/// let metric = eval::parse_metric("DCG@5").unwrap();
+/// assert_eq!(metric.name(), "DCG@5");
+/// assert!(eval::parse_metric("NDCG@0").is_err());
+/// assert!(eval::parse_metric("MAP@10").is_err());
+/// ```
+pub fn parse_metric(s: &str) -> Result<Box<Measure>> {
+    let (name, k) = split_metric(s)?;
+    metric::new(name, k)
+        .ok_or_else(|| From::from(format!("Unknown metric: {}", s)))
+}
+
+/// Splits a metric "NAME@K" into the name and the cut-off, 10 if not
+/// given.
+///
+/// # Examples
+///
+/// ```
+/// use rforests::eval;
+///
+/// assert_eq!(eval::split_metric("DCG@5").unwrap(), ("DCG", 5));
+/// assert_eq!(eval::split_metric("NDCG").unwrap(), ("NDCG", 10));
+/// assert!(eval::split_metric("NDCG@0").is_err());
+/// ```
+pub fn split_metric(s: &str) -> Result<(&str, usize)> {
+    let (name, k) = match s.find('@') {
+        Some(pos) => (&s[..pos], s[pos + 1..].parse::<usize>().ok()),
+        None => (s, Some(10)),
+    };
+    match k {
+        Some(k) if k > 0 => Ok((name, k)),
+        _ => Err(From::from(format!("Invalid metric: {}", s))),
+    }
+}
+
+/// Returns the score of each query of the data set ranked by `e`, in
+/// the order of `DataSet::query_iter`.
+pub fn query_scores<E: Evaluate>(
+    dataset: &DataSet,
+    e: &E,
+    metric: &Box<Measure>,
+    tie_break: TieBreak,
+) -> Vec<(Id, f64)> {
+    let scores = e.evaluate_all(dataset);
+    dataset
+        .query_iter()
+        .map(|(qid, mut query)| {
+            tie_break.rank(
+                &mut query,
+                |id| scores[id],
+                |id| dataset[id].label(),
+            );
+            let labels: Vec<f64> =
+                query.iter().map(|&id| dataset[id].label()).collect();
+            (qid, metric.measure(&labels))
+        })
+        .collect()
+}
+
+/// Returns the metric `s` if given, or else the metric the model was
+/// trained with, or else NDCG@10. The metric takes the gains, the
+/// discount base and the gain cap the model was trained with, so that
+/// it scores the same as during the training.
+fn eval_metric(ensemble: &Ensemble, s: Option<&str>) -> Result<Box<Measure>> {
+    let metadata = ensemble.metadata();
+    let s = match (s, &metadata.metric, metadata.metric_k) {
+        (Some(s), _, _) => s.to_string(),
+        (None, &Some(ref name), Some(k)) => format!("{}@{}", name, k),
+        (None, &Some(ref name), None) => name.clone(),
+        (None, &None, _) => "NDCG@10".to_string(),
+    };
+    let (name, k) = split_metric(&s)?;
+    metric::new_with_max_gain(
+        name,
+        k,
+        metadata.gain_table.clone(),
+        metadata.discount_base.unwrap_or(2.0),
+        metadata.max_gain,
+    ).ok_or_else(|| From::from(format!("Unknown metric: {}", s)))
+}
+
+/// Maps the features of the data set to the ids the model was trained
+/// on, if the model saved a feature map.
+fn map_features(ensemble: &Ensemble, dataset: &mut DataSet) {
+    if !ensemble.feature_map().is_empty() {
+        dataset.remap_features(ensemble.feature_map());
+    }
+}
+
+fn eval(
+    model_path: &str,
+    data_path: &str,
+    metric: Option<&str>,
+    per_query: bool,
+    zero_queries: ZeroQueries,
+    tie_break: TieBreak,
+) -> Result<()> {
+    let ensemble = Ensemble::load(open_input(model_path)?)
+        .map_err(|e| format!("Failed to load {}: {}", model_path, e))?;
+    let mut dataset = DataSet::load(open_input(data_path)?)
+        .map_err(|e| format!("Failed to load {}: {}", data_path, e))?;
+    map_features(&ensemble, &mut dataset);
+    let metric = &eval_metric(&ensemble, metric)?;
+
+    if per_query {
+        let scores = query_scores(&dataset, &ensemble, metric, tie_break);
+        for (qid, score) in scores {
+            println!(
+                "{}\t{}\t{:.4}",
+                metric.name(),
+                dataset.qid_label(qid),
+                score
+            );
+        }
+    }
+    let score = dataset.evaluate(&ensemble, metric, zero_queries, tie_break);
+    println!("{} on {}: {:.4}", metric.name(), data_path, score);
+    Ok(())
+}
+
+pub fn main<'a>(matches: &ArgMatches<'a>) {
+    let model_path = matches.value_of("model").unwrap();
+    let data_path = matches.value_of("data").unwrap();
+    let metric = matches.value_of("metric");
+    let per_query = matches.is_present("per-query");
+    let zero_queries = if matches.is_present("exclude-zero-queries") {
+        ZeroQueries::Exclude
+    } else {
+        ZeroQueries::Include
+    };
+    let tie_break = value_t!(matches, "eval-compat", TieBreak)
+        .unwrap_or_else(|e| e.exit());
+
+    let result = eval(
+        model_path,
+        data_path,
+        metric,
+        per_query,
+        zero_queries,
+        tie_break,
+    );
+    result.unwrap_or_else(|e| {
+        error!("{}", e);
+        exit(1)
+    });
+}
+
+/// Returns the eval command.
+pub fn clap_command<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("eval")
+        .about("Evaluate a saved model on a data set")
+        .arg(
+            Arg::with_name("model")
+                .long("model")
+                .value_name("FILE")
+                .takes_value(true)
+                .required(true)
+                .display_order(1)
+                .help("Model saved by train lambdamart --save-model"),
+        )
+        .arg(
+            Arg::with_name("data")
+                .long("data")
+                .value_name("FILE")
+                .takes_value(true)
+                .required(true)
+                .display_order(2)
+                .help("Data file, or - for the standard input"),
+        )
+        .arg(
+            Arg::with_name("metric")
+                .short("m")
+                .long("metric")
+                .value_name("NAME@K")
+                .takes_value(true)
+                .display_order(3)
+                .help("Metric to report, NDCG or DCG cut off at K; defaults to the metric the model was trained with, or NDCG@10"),
+        )
+        .arg(
+            Arg::with_name("per-query")
+                .long("per-query")
+                .display_order(4)
+                .help("Also print the score of each query"),
+        )
+        .arg(
+            Arg::with_name("exclude-zero-queries")
+                .long("exclude-zero-queries")
+                .display_order(5)
+                .help("Leave the queries whose labels are all zeros out of the mean, as train lambdamart --exclude-zero-queries"),
+        )
+        .arg(
+            Arg::with_name("eval-compat")
+                .long("eval-compat")
+                .takes_value(true)
+                .value_name("MODE")
+                .possible_values(&["default", "trec"])
+                .default_value("default")
+                .display_order(6)
+                .help("How tied scores are ranked, as train lambdamart --eval-compat; trec ranks them by descending label, the best case of the ties"),
+        )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_query_scores() {
+        // Scores 1.0 if feature 1 is greater than 2.0, otherwise 0.0.
+        let model = "rforests-ensemble
+mode sum
+features 0
+calibration none
+trees 1
+tree 1 3
+split 1 2 1 2 0.5
+leaf 0
+leaf 1
+";
+        let ensemble = Ensemble::load(model.as_bytes()).unwrap();
+        let data = "1 qid:1 1:3
+0 qid:1 1:1
+0 qid:2 1:3
+1 qid:2 1:1";
+        let dataset = DataSet::load(Cursor::new(data)).unwrap();
+        let metric = parse_metric("NDCG@10").unwrap();
+
+        let scores =
+            query_scores(&dataset, &ensemble, &metric, TieBreak::Index);
+        let worst = 1.0 / 3.0_f64.log2();
+        assert_eq!(scores, vec![(1, 1.0), (2, worst)]);
+
+        let mean = dataset.evaluate(
+            &ensemble,
+            &metric,
+            ZeroQueries::Include,
+            TieBreak::Index,
+        );
+        assert!((mean - (1.0 + worst) / 2.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_eval_ties_and_zero_queries() {
+        // Every instance scores 0.0, so the instances of a query tie.
+        let model = "rforests-ensemble
+mode sum
+features 0
+calibration none
+trees 0
+";
+        let ensemble = Ensemble::load(model.as_bytes()).unwrap();
+        let data = "0 qid:1 1:1
+1 qid:1 1:2
+0 qid:2 1:1";
+        let dataset = DataSet::load(Cursor::new(data)).unwrap();
+        let metric = parse_metric("NDCG@10").unwrap();
+        let worst = 1.0 / 3.0_f64.log2();
+
+        let scores =
+            query_scores(&dataset, &ensemble, &metric, TieBreak::Trec);
+        assert_eq!(scores, vec![(1, 1.0), (2, 0.0)]);
+
+        let mean = |zero_queries, tie_break| {
+            dataset.evaluate(&ensemble, &metric, zero_queries, tie_break)
+        };
+        let index = mean(ZeroQueries::Include, TieBreak::Index);
+        assert!((index - worst / 2.0).abs() < 1e-9);
+        assert_eq!(mean(ZeroQueries::Include, TieBreak::Trec), 0.5);
+        assert_eq!(mean(ZeroQueries::Exclude, TieBreak::Trec), 1.0);
+    }
+
+    #[test]
+    fn test_eval_feature_map() {
+        // The model was trained on features 3 and 5 of the data, as its
+        // features 1 and 2.
+        let model = "rforests-ensemble
+mode sum
+features 2 3 5
+calibration none
+trees 1
+tree 1 3
+split 1 2 1 2 0.5
+leaf 0
+leaf 1
+";
+        let ensemble = Ensemble::load(model.as_bytes()).unwrap();
+        let data = "1 qid:1 1:1 3:3
+0 qid:1 1:3 3:1
+0 qid:2 1:1 3:3 5:1
+1 qid:2 1:3 3:1";
+        let mut dataset = DataSet::load(Cursor::new(data)).unwrap();
+        map_features(&ensemble, &mut dataset);
+        let metric = eval_metric(&ensemble, None).unwrap();
+        assert_eq!(metric.name(), "NDCG@10");
+
+        let scores =
+            query_scores(&dataset, &ensemble, &metric, TieBreak::Index);
+        let worst = 1.0 / 3.0_f64.log2();
+        assert_eq!(scores, vec![(1, 1.0), (2, worst)]);
+    }
+
+    #[test]
+    fn test_eval_metric() {
+        let model = "rforests-ensemble
+meta metric DCG
+meta metric-k 5
+mode sum
+features 0
+calibration none
+trees 0
+";
+        let ensemble = Ensemble::load(model.as_bytes()).unwrap();
+        let name = |s| eval_metric(&ensemble, s).unwrap().name();
+        assert_eq!(name(None), "DCG@5");
+        assert_eq!(name(Some("NDCG@3")), "NDCG@3");
+        assert!(eval_metric(&ensemble, Some("MAP@3")).is_err());
+    }
+
+    #[test]
+    fn test_eval_metric_gains() {
+        // Label 2 gains 10.0 by the table, capped to 4.0, discounted by
+        // log10(2) at the first position.
+        let model = "rforests-ensemble
+meta metric DCG
+meta metric-k 1
+meta gain-table 0,1,10
+meta discount-base 10
+meta cap-gain 4
+mode sum
+features 0
+calibration none
+trees 0
+";
+        let ensemble = Ensemble::load(model.as_bytes()).unwrap();
+        let expected = 4.0 / 2.0_f64.log10();
+        for s in vec![None, Some("DCG@1")] {
+            let metric = eval_metric(&ensemble, s).unwrap();
+            assert!((metric.measure(&[2.0]) - expected).abs() < 1e-9);
+        }
+    }
+}
@@ -1 +1,2 @@
 pub mod svmlight;
+pub mod prediction;
@@ -0,0 +1,197 @@
+use std::cmp::Reverse;
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+use metric::ScoreNorm;
+use util::{OrderedScore, Result};
+
+/// The layout of the predicted scores.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PredictionFormat {
+    /// "qid Q0 docid rank score tag", the run format of trec_eval. The
+    /// documents of a query are listed by descending score.
+    Trec,
+    /// "qid\tdocid\tscore", in the order of the input.
+    Tsv,
+    /// The score alone, in the order of the input.
+    Scores,
+}
+
+impl FromStr for PredictionFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> ::std::result::Result<PredictionFormat, String> {
+        match s {
+            "trec" => Ok(PredictionFormat::Trec),
+            "tsv" => Ok(PredictionFormat::Tsv),
+            "scores" => Ok(PredictionFormat::Scores),
+            _ => Err(format!("Invalid prediction format: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for PredictionFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PredictionFormat::Trec => write!(f, "trec"),
+            PredictionFormat::Tsv => write!(f, "tsv"),
+            PredictionFormat::Scores => write!(f, "scores"),
+        }
+    }
+}
+
+/// Writes the predicted scores one query at a time in a
+/// `PredictionFormat`.
+///
+/// The data has no document ids, so the docid of an instance is its
+/// position in its query, as in the score files of RankLib.
+///
+/// # Examples
+///
+/// ```
+/// use rforests::format::prediction::*;
+///
+/// let mut output = Vec::new();
+/// {
+///     let mut writer =
+///         PredictionWriter::new(&mut output, PredictionFormat::Trec);
+///     writer.write_query("7", &[0.5, 2.0]).unwrap();
+/// }
+/// assert_eq!(
+///     String::from_utf8(output).unwrap(),
+///     "7 Q0 1 1 2 rforests\n7 Q0 0 2 0.5 rforests\n"
+/// );
+/// ```
+pub struct PredictionWriter<W: Write> {
+    writer: W,
+    format: PredictionFormat,
+    /// The run tag of the TREC format.
+    tag: String,
+    /// How the scores of each query are rescaled before written.
+    score_normalize: Option<ScoreNorm>,
+}
+
+impl<W: Write> PredictionWriter<W> {
+    /// Creates a writer with the run tag "rforests".
+    pub fn new(writer: W, format: PredictionFormat) -> PredictionWriter<W> {
+        PredictionWriter {
+            writer: writer,
+            format: format,
+            tag: "rforests".to_string(),
+            score_normalize: None,
+        }
+    }
+
+    /// Sets the run tag of the TREC format.
+    pub fn set_tag(&mut self, tag: &str) {
+        self.tag = tag.to_string();
+    }
+
+    /// Sets how the scores of each query are rescaled before they are
+    /// written. `None`, the default, writes them as they are.
+    pub fn set_score_normalize(&mut self, norm: Option<ScoreNorm>) {
+        self.score_normalize = norm;
+    }
+
+    /// Writes the scores of the instances of a query, in the order of
+    /// the input.
+    pub fn write_query(&mut self, qid: &str, scores: &[f64]) -> Result<()> {
+        let mut normalized;
+        let scores = match self.score_normalize {
+            Some(norm) => {
+                normalized = scores.to_vec();
+                norm.normalize(&mut normalized);
+                &normalized[..]
+            }
+            None => scores,
+        };
+        match self.format {
+            PredictionFormat::Trec => {
+                let mut indices: Vec<usize> = (0..scores.len()).collect();
+                indices.sort_by_key(|&index| {
+                    (Reverse(OrderedScore(scores[index])), index)
+                });
+                for (rank, &index) in indices.iter().enumerate() {
+                    writeln!(
+                        self.writer,
+                        "{} Q0 {} {} {} {}",
+                        qid,
+                        index,
+                        rank + 1,
+                        scores[index],
+                        self.tag
+                    )?;
+                }
+            }
+            PredictionFormat::Tsv => for (index, score) in
+                scores.iter().enumerate()
+            {
+                writeln!(self.writer, "{}\t{}\t{}", qid, index, score)?;
+            },
+            PredictionFormat::Scores => for score in scores {
+                writeln!(self.writer, "{}", score)?;
+            },
+        }
+        Ok(())
+    }
+
+    /// Flushes the underlying writer.
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write(format: PredictionFormat) -> String {
+        let mut output = Vec::new();
+        {
+            let mut writer = PredictionWriter::new(&mut output, format);
+            writer.write_query("1", &[1.0, 3.0, 1.0]).unwrap();
+            writer.write_query("A", &[-0.5]).unwrap();
+        }
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_prediction_writer() {
+        assert_eq!(
+            write(PredictionFormat::Trec),
+            "1 Q0 1 1 3 rforests
+1 Q0 0 2 1 rforests
+1 Q0 2 3 1 rforests
+A Q0 0 1 -0.5 rforests
+"
+        );
+        assert_eq!(
+            write(PredictionFormat::Tsv),
+            "1\t0\t1\n1\t1\t3\n1\t2\t1\nA\t0\t-0.5\n"
+        );
+        assert_eq!(write(PredictionFormat::Scores), "1\n3\n1\n-0.5\n");
+    }
+
+    #[test]
+    fn test_prediction_writer_normalize() {
+        let mut output = Vec::new();
+        {
+            let mut writer =
+                PredictionWriter::new(&mut output, PredictionFormat::Scores);
+            writer.set_score_normalize(Some(ScoreNorm::PerQueryZScore));
+            writer.write_query("1", &[1.0, 3.0]).unwrap();
+            writer.write_query("2", &[5.0]).unwrap();
+        }
+        assert_eq!(String::from_utf8(output).unwrap(), "-1\n1\n0\n");
+    }
+
+    #[test]
+    fn test_prediction_format() {
+        for s in &["trec", "tsv", "scores"] {
+            let format: PredictionFormat = s.parse().unwrap();
+            assert_eq!(format.to_string(), *s);
+        }
+        assert!("csv".parse::<PredictionFormat>().is_err());
+    }
+}
@@ -1,7 +1,9 @@
 use std;
-use std::fs::File;
 use std::io::BufReader;
 use std::io::prelude::*;
+use std::cell::Cell;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use util::*;
 use train::dataset::*;
 
@@ -12,47 +14,29 @@ use train::dataset::*;
 // <value> .=. <float>
 // <info> .=. <string>
 
-// #[derive(Copy, Clone, Default, Debug, PartialEq)]
-// pub struct Feature {
-//     pub id: usize,
-//     pub value: f64,
-// }
-
-// impl Feature {
-//     pub fn new(id: usize, value: f64) -> Feature {
-//         Feature {
-//             id: id,
-//             value: value,
-//         }
-//     }
-// }
-
-// impl FromStr for Feature {
-//     type Err = Box<std::error::Error>;
-
-//     fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
-//         let v: Vec<&str> = s.split(':').collect();
-//         if v.len() != 2 {
-//             Err(format!("Invalid string: {}", s))?;
-//         }
-
-//         let id = v[0].parse::<usize>()?;
-//         let value = v[1].parse::<f64>()?;
-
-//         Ok(Feature {
-//             id: id,
-//             value: value,
-//         })
-//     }
-// }
+const MAX_SCALE_VALUE: f64 = ::std::i16::MAX as f64 - 1.0;
 
-// impl ToString for Feature {
-//     fn to_string(&self) -> String {
-//         format!("{}:{}", self.id, self.value as u32)
-//     }
-// }
+/// The default of the largest feature id accepted by the parser.
+pub const DEFAULT_MAX_FEATURE_ID: Id = 10_000_000;
+
+/// The largest feature id accepted by the parser. See
+/// `set_max_feature_id`.
+static MAX_FEATURE_ID: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_FEATURE_ID);
+
+/// Sets the largest feature id accepted by the parser. A line of a
+/// larger id is rejected, since the dense values of the instance would
+/// take memory in proportion to the id, e.g. 800MB for a corrupt
+/// "99999999:1". It is meant to be called once at startup, before any
+/// data is loaded.
+pub fn set_max_feature_id(id: Id) {
+    assert!(id > 0);
+    MAX_FEATURE_ID.store(id, Ordering::Relaxed);
+}
 
-const MAX_SCALE_VALUE: f64 = ::std::i16::MAX as f64 - 1.0;
+/// Returns the largest feature id accepted by the parser.
+pub fn max_feature_id() -> Id {
+    MAX_FEATURE_ID.load(Ordering::Relaxed)
+}
 
 pub struct FeatureScale {
     logarithm: bool,
@@ -90,11 +74,70 @@ impl<'a> From<&'a FeatureStat> for FeatureScale {
     }
 }
 
-#[derive(Default, Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy)]
 pub struct FeatureStat {
     pub id: usize,
     pub min: f64,
     pub max: f64,
+    /// Sum of the values.
+    pub sum: f64,
+    /// Sum of the squared values.
+    pub sum_sq: f64,
+    /// Number of the instances, including those without the feature.
+    pub count: usize,
+}
+
+impl Default for FeatureStat {
+    /// A stat of no values. The min and max are infinities, so that
+    /// the first value observed replaces both.
+    fn default() -> FeatureStat {
+        FeatureStat {
+            id: 0,
+            min: std::f64::INFINITY,
+            max: std::f64::NEG_INFINITY,
+            sum: 0.0,
+            sum_sq: 0.0,
+            count: 0,
+        }
+    }
+}
+
+impl FeatureStat {
+    /// Returns whether any value of the feature is observed. The min
+    /// and max of a feature of no values are meaningless.
+    pub fn is_observed(&self) -> bool {
+        self.min <= self.max
+    }
+
+    /// Returns the mean of the values. Absent values count as 0.0.
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        self.sum / self.count as f64
+    }
+
+    /// Returns the population variance of the values. Absent values
+    /// count as 0.0.
+    pub fn variance(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        let mean = self.mean();
+        (self.sum_sq / self.count as f64 - mean * mean).max(0.0)
+    }
+}
+
+impl std::fmt::Display for FeatureStat {
+    /// Displays the range "min - max" of the values, or "absent" if no
+    /// value is observed.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.is_observed() {
+            write!(f, "{} - {}", self.min, self.max)
+        } else {
+            write!(f, "absent")
+        }
+    }
 }
 
 #[derive(Default, Debug)]
@@ -102,6 +145,8 @@ pub struct FilesStats {
     pub max_feature_id: usize,
     feature_stats: Vec<FeatureStat>,
     instances_count: Vec<(String, usize)>,
+    /// Number of the instances of all the files.
+    total_instances: usize,
 }
 
 impl FilesStats {
@@ -142,10 +187,12 @@ impl FilesStats {
     fn update(&mut self, feature_id: Id, value: Value) {
         // feature_id-1 is used as vec index
         if feature_id > self.feature_stats.len() {
-            self.feature_stats.resize(
-                feature_id,
-                FeatureStat::default(),
-            );
+            for id in (self.feature_stats.len() + 1)..(feature_id + 1) {
+                self.feature_stats.push(FeatureStat {
+                    id: id,
+                    ..FeatureStat::default()
+                });
+            }
         }
 
         let stat = &mut self.feature_stats[feature_id - 1];
@@ -153,16 +200,50 @@ impl FilesStats {
         stat.id = feature_id;
         stat.max = stat.max.max(value);
         stat.min = stat.min.min(value);
+        stat.sum += value;
+        stat.sum_sq += value * value;
 
         self.max_feature_id = self.max_feature_id.max(feature_id);
     }
 
+    /// Adds the instances to the total count. Absent feature values
+    /// are not visited, so the count of every feature is the total
+    /// count, and the absent values contribute 0.0 to the sums.
+    fn add_instances(&mut self, count: usize) {
+        self.total_instances += count;
+        for stat in self.feature_stats.iter_mut() {
+            stat.count = self.total_instances;
+        }
+    }
+
     fn update_stats_from_file(&mut self, filename: &str) -> Result<()> {
-        let file = File::open(filename)?;
+        let file = open_input(filename)?;
+        // The size of the standard input is unknown.
+        let size = if filename == STDIN_PATH {
+            None
+        } else {
+            Some(::std::fs::metadata(filename)?.len())
+        };
+        self.update_stats_from_reader(filename, file, size)
+    }
 
+    /// Updates the stats with the instances of the reader. The
+    /// progress is logged as a percentage of `size`, the length of the
+    /// input in bytes, if known.
+    fn update_stats_from_reader<R: Read>(
+        &mut self,
+        name: &str,
+        reader: R,
+        size: Option<u64>,
+    ) -> Result<()> {
+        let bytes_read = Rc::new(Cell::new(0));
+        let reader = CountingReader {
+            reader: reader,
+            count: bytes_read.clone(),
+        };
         let mut instance_count = 0;
         for (line_index, instance) in
-            SvmLightFile::instances(file).enumerate()
+            SvmLightFile::instances(reader).enumerate()
         {
             let instance = instance?;
             instance_count += 1;
@@ -173,26 +254,68 @@ impl FilesStats {
 
             // Notify the user every 5000 lines.
             if (line_index + 1) % 5000 == 0 {
-                info!("Processed {} lines", line_index + 1);
+                info!(
+                    "{}: {}",
+                    name,
+                    progress(line_index + 1, bytes_read.get(), size)
+                );
             }
         }
 
-        self.instances_count.push(
-            (filename.to_string(), instance_count),
-        );
+        self.instances_count.push((name.to_string(), instance_count));
+        self.add_instances(instance_count);
 
         Ok(())
     }
 }
 
+/// A reader which counts the bytes read through it.
+struct CountingReader<R> {
+    reader: R,
+    count: Rc<Cell<u64>>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.count.set(self.count.get() + n as u64);
+        Ok(n)
+    }
+}
+
+/// Describes the progress of reading a file of `size` bytes, if known.
+/// The bytes are read ahead of the parsed lines in blocks, so the
+/// percentage is approximate.
+fn progress(lines: usize, bytes_read: u64, size: Option<u64>) -> String {
+    match size {
+        Some(size) if size > 0 => {
+            let percent = (bytes_read.min(size) * 100) / size;
+            format!("{}% ({} lines)", percent, lines)
+        }
+        _ => format!("Processed {} lines", lines),
+    }
+}
+
+impl<'a> From<&'a DataSet> for FilesStats {
+    fn from(dataset: &'a DataSet) -> FilesStats {
+        let mut stats = FilesStats::default();
+        for fid in dataset.fid_iter() {
+            for value in dataset.feature_value_iter(fid) {
+                stats.update(fid, value);
+            }
+        }
+        stats.add_instances(dataset.len());
+        stats
+    }
+}
+
 pub struct SvmLightFile;
 
 impl SvmLightFile {
-    /// Read from reader and create (label, qid, values) tuple for
-    /// each line.
-    pub fn parse_reader<R>(
-        reader: R,
-    ) -> impl Iterator<Item = Result<(Value, Id, Vec<Value>)>>
+    /// Read from reader and yield each line that is neither empty, a
+    /// comment, nor a meta line starting with "@", such as the feature
+    /// name headers of LETOR, e.g. "@feature 1 BM25".
+    pub fn lines<R>(reader: R) -> impl Iterator<Item = Result<String>>
     where
         R: std::io::Read,
     {
@@ -203,22 +326,30 @@ impl SvmLightFile {
 
         buf_reader
             .lines()
-            // Filter empty line and comment line
+            // Filter empty line, comment line and meta line
             .filter(|result| match result {
                 &Ok(ref line) => {
                     let trimed = line.trim();
                     !trimed.is_empty() && !trimed.starts_with("#")
+                        && !trimed.starts_with("@")
                 }
                 &Err(_) => true,
             })
-            .map(|result| {
-                result
-                // Change the error type to match the function signature
-                .map_err(|e| e.description().into())
-                .and_then(|line| {
-                    SvmLightFile::parse_str(line.as_str())
-                })
-            })
+            // Change the error type to match the function signature
+            .map(|result| result.map_err(|e| e.description().into()))
+    }
+
+    /// Read from reader and create (label, qid, values) tuple for
+    /// each line.
+    pub fn parse_reader<R>(
+        reader: R,
+    ) -> impl Iterator<Item = Result<(Value, Id, Vec<Value>)>>
+    where
+        R: std::io::Read,
+    {
+        SvmLightFile::lines(reader).map(|result| {
+            result.and_then(|line| SvmLightFile::parse_str(line.as_str()))
+        })
     }
 
     /// Read from reader and create Instance struct for each line.
@@ -226,9 +357,22 @@ impl SvmLightFile {
     where
         R: std::io::Read,
     {
-        SvmLightFile::parse_reader(reader).map(|parse_result| {
-            parse_result.map(|(label, qid, values)| {
-                Instance::new(label, qid, values)
+        SvmLightFile::lines(reader).map(|result| {
+            result.and_then(|line| SvmLightFile::parse_instance(line.as_str()))
+        })
+    }
+
+    /// Read from reader and create Instance struct for each line,
+    /// storing only the present features. See `Instance::new_sparse`.
+    pub fn sparse_instances<R>(
+        reader: R,
+    ) -> impl Iterator<Item = Result<Instance>>
+    where
+        R: std::io::Read,
+    {
+        SvmLightFile::lines(reader).map(|result| {
+            result.and_then(|line| {
+                SvmLightFile::parse_instance_sparse(line.as_str())
             })
         })
     }
@@ -239,10 +383,10 @@ impl SvmLightFile {
         Ok(label)
     }
 
-    /// Parse "qid:3333".
-    fn parse_qid(qid: &str) -> Result<Id> {
+    /// Parse "qid:3333" or "qid:GX008" into the text of the qid.
+    fn parse_qid(qid: &str) -> Result<&str> {
         let v: Vec<&str> = qid.split(':').collect();
-        if v.len() != 2 {
+        if v.len() != 2 || v[1].is_empty() {
             Err(format!("Invalid qid field: {}", qid))?;
         }
 
@@ -250,30 +394,65 @@ impl SvmLightFile {
             Err(format!("Invalid qid field: {}", v[0]))?;
         }
 
-        let qid = v[1].parse::<Id>()?;
+        Ok(v[1])
+    }
 
-        Ok(qid)
+    /// Parse the text of a numeric qid. A missing qid is 0.
+    fn numeric_qid(qid: Option<&str>) -> Result<Id> {
+        match qid {
+            Some(qid) => Ok(qid.parse::<Id>()?),
+            None => Ok(0),
+        }
     }
 
-    /// Parse &["1:3.0" "3:4.0"] into Vec of values. Absent indices
-    /// are filled with 0.0. The example above would result vec![0.0,
-    /// 3.0, 0.0, 4.0].
-    fn parse_values(fields: &[&str]) -> Result<Vec<f64>> {
-        fn parse(s: &str) -> Result<(Id, Value)> {
+    /// Sets the qid of the instance from its text. A non-numeric qid
+    /// is kept as the qid label, to be mapped to a numeric id by
+    /// `DataSet`.
+    fn set_qid(instance: &mut Instance, qid: Option<&str>) {
+        match SvmLightFile::numeric_qid(qid) {
+            Ok(id) => instance.set_qid(id),
+            Err(_) => instance.set_qid_label(qid.map(str::to_string)),
+        }
+    }
+
+    /// Parse &["1:3.0" "3:4.0"] into Vec of (id, value) pairs. The
+    /// example above would result vec![(1, 3.0), (3, 4.0)]. NaN and
+    /// infinite values are rejected, since they would poison the sums
+    /// of the training, and so are the ids larger than
+    /// `max_feature_id()`.
+    fn parse_pairs(fields: &[&str]) -> Result<Vec<(Id, Value)>> {
+        let max_id = max_feature_id();
+        let parse = |s: &str| -> Result<(Id, Value)> {
             let v: Vec<&str> = s.split(':').collect();
             if v.len() != 2 {
                 Err(format!("Invalid string: {}", s))?;
             }
 
             let id = v[0].parse::<Id>()?;
+            if id > max_id {
+                Err(format!(
+                    "Feature id {} exceeds the maximum {}",
+                    id,
+                    max_id
+                ))?;
+            }
             let value = v[1].parse::<Value>()?;
+            if !value.is_finite() {
+                Err(format!("Non-finite value of feature {}: {}", id, v[1]))?;
+            }
 
             Ok((id, value))
-        }
+        };
+
+        fields.iter().map(|&s| parse(s)).collect()
+    }
 
+    /// Parse &["1:3.0" "3:4.0"] into Vec of values. Absent indices
+    /// are filled with 0.0. The example above would result vec![0.0,
+    /// 3.0, 0.0, 4.0].
+    fn parse_values(fields: &[&str]) -> Result<Vec<f64>> {
         // (id, value) pairs
-        let v: Vec<(Id, Value)> =
-            fields.iter().map(|&s| parse(s)).collect::<Result<_>>()?;
+        let v: Vec<(Id, Value)> = SvmLightFile::parse_pairs(fields)?;
         let max_id = v.iter().max_by_key(|e| e.0).unwrap().0;
         let mut ret: Vec<f64> = vec![0.0; max_id];
         for &(id, value) in v.iter() {
@@ -283,21 +462,134 @@ impl SvmLightFile {
         Ok(ret)
     }
 
-    /// Parse "3.0 qid:3864 1:3.000000 2:9.000000 4:3.0 # 3:10.0".
-    pub fn parse_str(s: &str) -> Result<(Value, Id, Vec<Value>)> {
+    /// Parse "pos:3", the 1-based position at which the instance was
+    /// shown, e.g. in a click log.
+    fn parse_position(position: &str) -> Result<usize> {
+        match position["pos:".len()..].parse::<usize>() {
+            Ok(position) if position > 0 => Ok(position),
+            _ => Err(From::from(format!("Invalid position: {}", position))),
+        }
+    }
+
+    /// Parse "cost:0.5".
+    fn parse_cost(cost: &str) -> Result<Value> {
+        let weight = cost["cost:".len()..].parse::<Value>()?;
+        if !(weight >= 0.0) {
+            Err(format!("Invalid cost field: {}", cost))?;
+        }
+        Ok(weight)
+    }
+
+    /// Split the line into fields, and parse the label and the qid
+    /// from the leading fields. Lines without a qid, as in plain
+    /// libsvm files, all belong to the query 0. An optional
+    /// "cost:W" field gives the weight of the instance, which
+    /// defaults to 1.0, and an optional "pos:P" field the position of
+    /// the instance. Returns the label, the qid, the weight, the
+    /// position, and the remaining feature fields.
+    fn parse_fields(
+        s: &str,
+    ) -> Result<(Value, Option<&str>, Value, Option<usize>, Vec<&str>)> {
+        SvmLightFile::parse_fields_at(s, 0)
+    }
+
+    /// Like `parse_fields`, but the label is the field at
+    /// `label_column`, and the fields before it, e.g. line ids, are
+    /// ignored.
+    fn parse_fields_at(
+        s: &str,
+        label_column: usize,
+    ) -> Result<(Value, Option<&str>, Value, Option<usize>, Vec<&str>)> {
         let line: &str = s.trim().split('#').next().unwrap().trim();
-        let fields: Vec<&str> = line.split_whitespace().collect();
-        if fields.len() < 2 {
+        let mut fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < label_column + 2 {
             Err(format!("Invalid line"))?;
         }
+        fields.drain(..label_column);
 
         let label = SvmLightFile::parse_label(fields[0])?;
-        let qid = SvmLightFile::parse_qid(fields[1])?;
-        let values: Vec<Value> = SvmLightFile::parse_values(&fields[2..])?;
+        let (qid, skip) = if fields[1].starts_with("qid:") {
+            (Some(SvmLightFile::parse_qid(fields[1])?), 2)
+        } else {
+            (None, 1)
+        };
+        fields.drain(..skip);
+
+        let weight = match fields.iter().position(|f| f.starts_with("cost:")) {
+            Some(pos) => SvmLightFile::parse_cost(fields.remove(pos))?,
+            None => 1.0,
+        };
+        let position = match fields.iter().position(|f| f.starts_with("pos:"))
+        {
+            Some(pos) => {
+                let position = fields.remove(pos);
+                Some(SvmLightFile::parse_position(position)?)
+            }
+            None => None,
+        };
+
+        Ok((label, qid, weight, position, fields))
+    }
+
+    /// Parse "3.0 qid:3864 1:3.000000 2:9.000000 4:3.0 # 3:10.0". The
+    /// qid is optional and defaults to 0.
+    pub fn parse_str(s: &str) -> Result<(Value, Id, Vec<Value>)> {
+        let (label, qid, _weight, _position, fields) =
+            SvmLightFile::parse_fields(s)?;
+        let qid = SvmLightFile::numeric_qid(qid)?;
+        let values: Vec<Value> = SvmLightFile::parse_values(&fields)?;
 
         Ok((label, qid, values))
     }
 
+    /// Parse "3.0 qid:3864 1:3.000000 2:9.000000 4:3.0 # 3:10.0" into
+    /// (label, qid, (id, value) pairs). The qid is optional and
+    /// defaults to 0.
+    pub fn parse_str_sparse(s: &str) -> Result<(Value, Id, Vec<(Id, Value)>)> {
+        let (label, qid, _weight, _position, fields) =
+            SvmLightFile::parse_fields(s)?;
+        let qid = SvmLightFile::numeric_qid(qid)?;
+        let pairs = SvmLightFile::parse_pairs(&fields)?;
+
+        Ok((label, qid, pairs))
+    }
+
+    /// Parse a line into an Instance, including the weight given by
+    /// an optional "cost:W" field and the position given by an
+    /// optional "pos:P" field. A non-numeric qid, such as
+    /// "qid:GX008", is kept as the qid label of the instance.
+    pub fn parse_instance(s: &str) -> Result<Instance> {
+        SvmLightFile::parse_instance_at(s, 0)
+    }
+
+    /// Parse a line whose label is the field at `label_column` into
+    /// an Instance. See `parse_instance`.
+    pub fn parse_instance_at(s: &str, label_column: usize) -> Result<Instance> {
+        let (label, qid, weight, position, fields) =
+            SvmLightFile::parse_fields_at(s, label_column)?;
+        let values = SvmLightFile::parse_values(&fields)?;
+
+        let mut instance = Instance::new(label, 0, values);
+        SvmLightFile::set_qid(&mut instance, qid);
+        instance.set_weight(weight);
+        instance.set_position(position);
+        Ok(instance)
+    }
+
+    /// Parse a line into an Instance storing only the present
+    /// features. See `parse_instance`.
+    pub fn parse_instance_sparse(s: &str) -> Result<Instance> {
+        let (label, qid, weight, position, fields) =
+            SvmLightFile::parse_fields(s)?;
+        let pairs = SvmLightFile::parse_pairs(&fields)?;
+
+        let mut instance = Instance::new_sparse(label, 0, pairs);
+        SvmLightFile::set_qid(&mut instance, qid);
+        instance.set_weight(weight);
+        instance.set_position(position);
+        Ok(instance)
+    }
+
     // pub fn write_compact_format(
     //     input: File,
     //     mut output: File,
@@ -343,6 +635,224 @@ mod tests {
         assert_eq!(label, 3.0);
         assert_eq!(qid, 3864);
         assert_eq!(values, vec![3.0, 9.0, 0.0, 3.0]);
+
+        // Graded labels need not be integers, e.g. averaged judgments.
+        let s = "2.5 qid:3864 1:3.0";
+        let (label, _qid, _values) = SvmLightFile::parse_str(s).unwrap();
+        assert_eq!(label, 2.5);
+        let mut instances = SvmLightFile::instances(s.as_bytes());
+        assert_eq!(instances.next().unwrap().unwrap().label(), 2.5);
+    }
+
+    #[test]
+    fn test_line_parse_non_finite() {
+        for s in &["3.0 qid:1 1:NaN", "3.0 qid:1 1:1.0 2:inf"] {
+            assert!(SvmLightFile::parse_str(s).is_err());
+            assert!(SvmLightFile::parse_instance_sparse(s).is_err());
+        }
+
+        let e = SvmLightFile::parse_instance("3.0 qid:1 1:1.0 2:inf");
+        assert_eq!(
+            e.unwrap_err().to_string(),
+            "Non-finite value of feature 2: inf"
+        );
+    }
+
+    #[test]
+    fn test_line_parse_max_feature_id() {
+        let s = "3.0 qid:1 1:1.0 99999999:1";
+        let e = SvmLightFile::parse_instance(s).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Feature id 99999999 exceeds the maximum 10000000"
+        );
+        assert!(SvmLightFile::parse_instance_sparse(s).is_err());
+        assert!(DataSet::load(s.as_bytes()).is_err());
+
+        let s = "3.0 qid:1 10000000:1";
+        let instance = SvmLightFile::parse_instance_sparse(s).unwrap();
+        assert_eq!(instance.value(10000000), 1.0);
+    }
+
+    #[test]
+    fn test_line_parse_string_qid() {
+        let s = "3.0 qid:GX008 1:3.0";
+        let instance = SvmLightFile::parse_instance(s).unwrap();
+        assert_eq!(instance.qid_label(), Some("GX008"));
+        assert_eq!(instance.to_string(), "3 qid:GX008 1:3");
+
+        let s = "3.0 qid:38 1:3.0";
+        let instance = SvmLightFile::parse_instance(s).unwrap();
+        assert_eq!(instance.qid(), 38);
+        assert_eq!(instance.qid_label(), None);
+
+        assert!(SvmLightFile::parse_str("3.0 qid:GX008 1:3.0").is_err());
+        assert!(SvmLightFile::parse_instance("3.0 qid: 1:3.0").is_err());
+    }
+
+    #[test]
+    fn test_line_parse_cost() {
+        let s = "3.0 qid:3864 cost:0.5 1:3.0 4:3.0";
+        let instance = SvmLightFile::parse_instance(s).unwrap();
+        assert_eq!(instance.weight(), 0.5);
+        assert_eq!(instance.value(1), 3.0);
+        assert_eq!(instance.value(4), 3.0);
+
+        let instance = SvmLightFile::parse_instance_sparse(s).unwrap();
+        assert_eq!(instance.weight(), 0.5);
+        assert_eq!(instance.to_string(), "3 qid:3864 cost:0.5 1:3 2:0 3:0 4:3");
+
+        let s = "3.0 qid:3864 1:3.0";
+        assert_eq!(SvmLightFile::parse_instance(s).unwrap().weight(), 1.0);
+        let s = "3.0 qid:3864 cost:-1 1:3.0";
+        assert!(SvmLightFile::parse_instance(s).is_err());
+    }
+
+    #[test]
+    fn test_line_parse_position() {
+        let s = "1 qid:3864 pos:2 1:3.0";
+        let instance = SvmLightFile::parse_instance(s).unwrap();
+        assert_eq!(instance.position(), Some(2));
+        assert_eq!(instance.value(1), 3.0);
+
+        let instance = SvmLightFile::parse_instance_sparse(s).unwrap();
+        assert_eq!(instance.position(), Some(2));
+        assert_eq!(instance.to_string(), "1 qid:3864 pos:2 1:3");
+
+        let s = "1 qid:3864 1:3.0";
+        assert_eq!(SvmLightFile::parse_instance(s).unwrap().position(), None);
+        let s = "1 qid:3864 pos:0 1:3.0";
+        assert!(SvmLightFile::parse_instance(s).is_err());
+        let s = "1 qid:3864 pos:x 1:3.0";
+        assert!(SvmLightFile::parse_instance(s).is_err());
+    }
+
+    #[test]
+    fn test_line_parse_label_column() {
+        let s = "17 3.0 qid:3864 1:3.0 3:9.0";
+        let instance = SvmLightFile::parse_instance_at(s, 1).unwrap();
+        assert_eq!(instance.label(), 3.0);
+        assert_eq!(instance.qid(), 3864);
+        assert_eq!(instance.value(3), 9.0);
+
+        let s = "3.0 qid:3864 1:3.0 3:9.0";
+        let instance = SvmLightFile::parse_instance_at(s, 0).unwrap();
+        assert_eq!(instance.label(), 3.0);
+        assert_eq!(instance.qid(), 3864);
+        assert!(SvmLightFile::parse_instance_at(s, 3).is_err());
+    }
+
+    #[test]
+    fn test_line_parse_sparse() {
+        let s = "3.0 qid:3864 1:3.000000 2:9.000000 4:3.0 # 3:10.0";
+        let (label, qid, pairs) = SvmLightFile::parse_str_sparse(s).unwrap();
+        assert_eq!(label, 3.0);
+        assert_eq!(qid, 3864);
+        assert_eq!(pairs, vec![(1, 3.0), (2, 9.0), (4, 3.0)]);
+    }
+
+    #[test]
+    fn test_line_parse_without_qid() {
+        let s = "1 1:3.000000 2:9.000000 4:3.0 # 3:10.0";
+        let (label, qid, values) = SvmLightFile::parse_str(s).unwrap();
+        assert_eq!(label, 1.0);
+        assert_eq!(qid, 0);
+        assert_eq!(values, vec![3.0, 9.0, 0.0, 3.0]);
+
+        let (label, qid, pairs) = SvmLightFile::parse_str_sparse(s).unwrap();
+        assert_eq!(label, 1.0);
+        assert_eq!(qid, 0);
+        assert_eq!(pairs, vec![(1, 3.0), (2, 9.0), (4, 3.0)]);
+    }
+
+    #[test]
+    fn test_feature_stat_mean_variance() {
+        // The feature 3 is absent from the first line.
+        let s = "1 qid:1 1:2.0 2:4.0
+0 qid:1 1:4.0 2:4.0 3:6.0";
+        let mut stats = FilesStats::default();
+        stats
+            .update_stats_from_reader("test", ::std::io::Cursor::new(s), None)
+            .unwrap();
+
+        let stats: Vec<FeatureStat> = stats.feature_stats().cloned().collect();
+        assert_eq!(stats[0].count, 2);
+        assert_eq!(stats[0].mean(), 3.0);
+        assert_eq!(stats[0].variance(), 1.0);
+        assert_eq!(stats[1].variance(), 0.0);
+        assert_eq!(stats[2].count, 2);
+        assert_eq!(stats[2].mean(), 3.0);
+        assert_eq!(stats[2].variance(), 9.0);
+    }
+
+    #[test]
+    fn test_feature_stat_min_max() {
+        let s = "1 qid:1 1:3.0 2:-1.0
+0 qid:1 1:9.0 2:-5.0
+0 qid:1 1:5.0 2:-2.0";
+        let mut stats = FilesStats::default();
+        stats
+            .update_stats_from_reader("test", ::std::io::Cursor::new(s), None)
+            .unwrap();
+
+        let stats: Vec<FeatureStat> = stats.feature_stats().cloned().collect();
+        assert_eq!((stats[0].min, stats[0].max), (3.0, 9.0));
+        assert_eq!((stats[1].min, stats[1].max), (-5.0, -1.0));
+    }
+
+    #[test]
+    fn test_feature_stat_unobserved() {
+        // No value of the feature 2 is observed.
+        let mut stats = FilesStats::default();
+        stats.update(1, 3.0);
+        stats.update(1, 9.0);
+        stats.update(3, 1.0);
+
+        let stats: Vec<FeatureStat> = stats.feature_stats().cloned().collect();
+        let ids: Vec<Id> = stats.iter().map(|stat| stat.id).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+        assert!(!stats[1].is_observed());
+        assert_eq!(stats[0].to_string(), "3 - 9");
+        assert_eq!(stats[1].to_string(), "absent");
+    }
+
+    #[test]
+    fn test_progress() {
+        assert_eq!(progress(5000, 250, Some(1000)), "25% (5000 lines)");
+        assert_eq!(progress(5000, 1200, Some(1000)), "100% (5000 lines)");
+        assert_eq!(progress(5000, 250, None), "Processed 5000 lines");
+
+        let count = Rc::new(Cell::new(0));
+        let mut reader = CountingReader {
+            reader: "1 qid:1 1:2.0".as_bytes(),
+            count: count.clone(),
+        };
+        let mut s = String::new();
+        reader.read_to_string(&mut s).unwrap();
+        assert_eq!(count.get(), 13);
+    }
+
+    #[test]
+    fn test_line_parse_invalid_qid() {
+        assert!(SvmLightFile::parse_str("1 qid:x 1:3.0").is_err());
+        assert!(SvmLightFile::parse_str("1 id:3 1:3.0").is_err());
+    }
+
+    #[test]
+    fn test_lines_skip_meta_and_blank() {
+        let s = "@feature 1 BM25\r\n# comment\r\n\r\n3 qid:1 1:5.0\r\n  \r
+@feature 2 PageRank
+0 qid:1 1:2.0 # docid = 7\r\n";
+        let lines: Vec<String> = SvmLightFile::lines(s.as_bytes())
+            .map(|line| line.unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+
+        let parsed: Vec<(Value, Id, Vec<Value>)> =
+            SvmLightFile::parse_reader(s.as_bytes())
+                .map(|result| result.unwrap())
+                .collect();
+        assert_eq!(parsed, vec![(3.0, 1, vec![5.0]), (0.0, 1, vec![2.0])]);
     }
 }
 // @Feature id:2 name:abc
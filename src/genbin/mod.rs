@@ -1,11 +1,12 @@
 use std::fs::File;
+use std::io::{BufWriter, Write};
 use std::path::{Path, PathBuf};
 // use std::io::prelude::*;
 // use std::io::BufReader;
 // use std::error::Error;
 use std::collections::HashMap;
 
-use util::Result;
+use util::{open_input, Result, STDIN_PATH};
 use format::svmlight;
 use format::svmlight::SvmLightFile;
 
@@ -29,6 +30,8 @@ Usage:
 Options:
     -r, --ranking               Support ranking
     -h, --help                  Display this message
+
+A <file> of \"-\" reads the standard input, which is copied to stdin.txt.
 ";
 
 pub fn append_to_file_name(origin: &str, s: &str) -> String {
@@ -51,9 +54,78 @@ pub fn change_extension(origin: &str, new_ext: &str) -> String {
         .to_string()
 }
 
+/// Returns the storage width of the indices of a feature of `len`
+/// distinct values. An index of `len` values runs from 0 to `len - 1`,
+/// so 256 values fit in u8 and 65536 in u16.
+fn storage_width(len: usize) -> &'static str {
+    if len <= 2 {
+        "bit"
+    } else if len <= 1 << 8 {
+        "u8"
+    } else if len <= 1 << 16 {
+        "u16"
+    } else {
+        "u32"
+    }
+}
+
+/// Writes the number of distinct values and the storage width of each
+/// feature, one "fid values width" line per feature, so that the
+/// binary files can be interpreted later.
+fn write_quantization(path: &str, value_table: &[Vec<u32>]) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "# fid values width")?;
+    for (index, values) in value_table.iter().enumerate() {
+        let len = values.len();
+        writeln!(writer, "{} {} {}", index + 1, len, storage_width(len))?;
+    }
+    Ok(())
+}
+
+/// Turns the counts of the values of each feature into the sorted
+/// distinct values. An instance without a feature has the value 0, so
+/// 0 is added to a feature which fewer than `instances` instances
+/// have. Dense features which never take 0 get no spurious 0 bin.
+fn build_value_table(
+    feature_value_hash: Vec<HashMap<u32, u32>>,
+    instances: usize,
+) -> Vec<Vec<u32>> {
+    feature_value_hash
+        .into_iter()
+        .map(|hash| {
+            let present: u32 = hash.values().sum();
+            let mut values = hash.keys().cloned().collect::<Vec<_>>();
+            if (present as usize) < instances && !hash.contains_key(&0) {
+                values.push(0);
+            }
+            values.sort();
+            values
+        })
+        .collect()
+}
+
+/// Name of the file the standard input is copied to.
+const STDIN_COPY: &str = "stdin.txt";
+
+/// Returns the path to read an input file from. The input files are
+/// read more than once, but the standard input can be read only once,
+/// so a dash is copied to `STDIN_COPY` and that file read instead.
+fn spool_input(input: &str) -> Result<String> {
+    if input == STDIN_PATH {
+        let mut output = File::create(STDIN_COPY)?;
+        ::std::io::copy(&mut open_input(input)?, &mut output)?;
+        Ok(STDIN_COPY.to_string())
+    } else {
+        Ok(input.to_string())
+    }
+}
+
 pub fn execute(args: Args) -> Result<()> {
     debug!("rforests genbin args: {:?}", args);
-    let input_files = args.arg_file.clone();
+    let input_files = args.arg_file
+        .iter()
+        .map(|input| spool_input(input))
+        .collect::<Result<Vec<_>>>()?;
 
     // Generate statistics from the files
     let stats = svmlight::FilesStats::parse(&input_files)?;
@@ -69,7 +141,7 @@ pub fn execute(args: Args) -> Result<()> {
     {
         info!("Converting {} to {}", input_name, output_name);
 
-        let input = File::open(input_name.as_str())?;
+        let input = open_input(input_name)?;
         let output = File::create(output_name)?;
         // SvmLightFile::write_compact_format(input, output, &feature_scales)?;
     }
@@ -77,10 +149,12 @@ pub fn execute(args: Args) -> Result<()> {
     // Load value maps from output files
     let mut feature_value_hash: Vec<HashMap<u32, u32>> = Vec::new();
     feature_value_hash.resize(stats.max_feature_id, HashMap::default());
+    let mut instances = 0;
     for output_name in &output_files {
         let output = File::open(&output_name)?;
         for instance in SvmLightFile::instances(output) {
             let instance = instance?;
+            instances += 1;
 
             for (id, value) in instance.value_iter() {
                 let hash = &mut feature_value_hash[id - 1];
@@ -90,17 +164,7 @@ pub fn execute(args: Args) -> Result<()> {
     }
 
     // Turn hash table into vector
-    let value_table: Vec<_> = feature_value_hash
-        .into_iter()
-        .map(|hash| {
-            // The hash does not contains 0 as its key. Add it.
-            let mut values =
-                (0..1).chain(hash.keys().cloned()).collect::<Vec<_>>();
-            values.sort();
-            // println!("Sorted values: {:?}", values);
-            values
-        })
-        .collect();
+    let value_table = build_value_table(feature_value_hash, instances);
 
     // Find indices for each value
     let mut feature_indices: Vec<Vec<u32>> = Vec::new();
@@ -115,7 +179,7 @@ pub fn execute(args: Args) -> Result<()> {
             // does not comiple // TODO some features are skipped
             for (id, value) in instance.value_iter() {
                 let values = &value_table[id - 1];
-                let index = values.binary_search(&&(value as u32));
+                let index = values.binary_search(&(value.round() as u32));
                 feature_indices[id - 1].push(index.unwrap() as u32);
                 // assert_eq!(
                 //     feature_indices[feature.id - 1].len(),
@@ -130,15 +194,13 @@ pub fn execute(args: Args) -> Result<()> {
 
     for dist in &value_table {
         let len = dist.len();
-        if len <= 2 {
-            // std::collections::BitVec;
-        } else if len <= ::std::u8::MAX as usize {
-        } else if len <= ::std::u16::MAX as usize {
-        } else if len <= ::std::u32::MAX as usize {
-        }
-
-        println!("len {}", len);
+        println!("len {} {}", len, storage_width(len));
     }
+
+    // The value table is shared by all the input files.
+    let quant_name = change_extension(&input_files[0], "quant.txt");
+    info!("Writing quantization report to {}", quant_name);
+    write_quantization(&quant_name, &value_table)?;
     // Generate bin names
     let bin_files: Vec<_> = input_files
         .iter()
@@ -209,3 +271,33 @@ fn convert(
 
 //     unimplemented!()
 // }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_build_value_table() {
+        // Feature 1 is always 5.0. Feature 2 is omitted by one of the
+        // three instances.
+        let mut dense = HashMap::new();
+        dense.insert(5, 3);
+        let mut sparse = HashMap::new();
+        sparse.insert(1, 1);
+        sparse.insert(7, 1);
+
+        let value_table = build_value_table(vec![dense, sparse], 3);
+        assert_eq!(value_table, vec![vec![5], vec![0, 1, 7]]);
+        assert_eq!(value_table[0].binary_search(&5), Ok(0));
+    }
+
+    #[test]
+    fn test_storage_width() {
+        assert_eq!(storage_width(2), "bit");
+        assert_eq!(storage_width(3), "u8");
+        assert_eq!(storage_width(256), "u8");
+        assert_eq!(storage_width(257), "u16");
+        assert_eq!(storage_width(65536), "u16");
+        assert_eq!(storage_width(65537), "u32");
+    }
+}
@@ -13,30 +13,118 @@ extern crate scoped_threadpool;
 #[macro_use]
 extern crate lazy_static;
 extern crate num_cpus;
+extern crate toml;
 
-use clap::App;
+use clap::{App, Arg};
 
 pub mod util;
 pub mod format;
 pub mod metric;
 pub mod train;
+pub mod convert;
+pub mod eval;
 
-pub fn main() {
-    env_logger::init().unwrap();
+/// Initializes the logger. The level given by the verbosity flags
+/// takes precedence over the `RUST_LOG` environment variable.
+fn init_logger(verbose: u64, quiet: bool) {
+    let mut builder = env_logger::LogBuilder::new();
+    let level = match (quiet, verbose) {
+        (true, _) => Some(log::LogLevelFilter::Error),
+        (false, 0) => None,
+        (false, 1) => Some(log::LogLevelFilter::Info),
+        (false, _) => Some(log::LogLevelFilter::Debug),
+    };
+
+    match level {
+        Some(level) => {
+            builder.filter(None, level);
+        }
+        None => {
+            if let Ok(s) = std::env::var("RUST_LOG") {
+                builder.parse(&s);
+            }
+        }
+    }
+    builder.init().unwrap();
+}
 
+pub fn main() {
     let train_command = train::clap_command();
 
     let matches = App::new("rforests")
         .version(crate_version!())
         .author(crate_authors!())
         .about("A Rust library of tree-based learning algorithms")
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .multiple(true)
+                .help("Log more messages: -v for info, -vv for debug"),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .short("q")
+                .conflicts_with("verbose")
+                .help("Log errors only"),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .long("threads")
+                .takes_value(true)
+                .value_name("NUM")
+                .help("Number of worker threads [default: number of CPUs]"),
+        )
+        .arg(
+            Arg::with_name("max-feature-id")
+                .long("max-feature-id")
+                .takes_value(true)
+                .value_name("NUM")
+                .help("Reject input lines of larger feature ids [default: 10000000]"),
+        )
         .subcommand(train_command)
+        .subcommand(convert::clap_command())
+        .subcommand(eval::clap_command())
         .get_matches();
 
+    init_logger(
+        matches.occurrences_of("verbose"),
+        matches.is_present("quiet"),
+    );
+
+    if matches.is_present("threads") {
+        let threads = value_t!(matches.value_of("threads"), usize)
+            .unwrap_or_else(|e| e.exit());
+        if threads == 0 {
+            clap::Error::with_description(
+                "The number of threads must be positive",
+                clap::ErrorKind::InvalidValue,
+            ).exit();
+        }
+        util::set_threads(threads);
+    }
+
+    if matches.is_present("max-feature-id") {
+        let id = value_t!(matches.value_of("max-feature-id"), usize)
+            .unwrap_or_else(|e| e.exit());
+        if id == 0 {
+            clap::Error::with_description(
+                "The maximum feature id must be positive",
+                clap::ErrorKind::InvalidValue,
+            ).exit();
+        }
+        format::svmlight::set_max_feature_id(id);
+    }
+
     match matches.subcommand_name() {
         Some("train") => train::main(
             matches.subcommand_matches("train").unwrap(),
         ),
+        Some("convert") => convert::main(
+            matches.subcommand_matches("convert").unwrap(),
+        ),
+        Some("eval") => eval::main(
+            matches.subcommand_matches("eval").unwrap(),
+        ),
         _ => (),
     }
 }
@@ -1,21 +1,80 @@
-use super::Measure;
+use super::{kahan_sum, Measure};
 
 pub struct DCGScorer {
     truncation_level: usize,
+    /// Gain of each integer label. See `DCGScorer::with_gains`.
+    gains: Option<Vec<f64>>,
+    /// Base of the logarithm of the discount. See
+    /// `DCGScorer::set_discount_base`.
+    discount_base: f64,
+    /// The gains are capped to this if given. See
+    /// `DCGScorer::set_max_gain`.
+    max_gain: Option<f64>,
 }
 
 impl DCGScorer {
     pub fn new(truncation_level: usize) -> DCGScorer {
-        DCGScorer { truncation_level: truncation_level }
+        DCGScorer {
+            truncation_level: truncation_level,
+            gains: None,
+            discount_base: 2.0,
+            max_gain: None,
+        }
+    }
+
+    /// Creates a scorer that looks up the gain of label `l` in
+    /// `gains[l]`. Labels that are not integers within the table use
+    /// the default gain `2^l - 1`.
+    pub fn with_gains(truncation_level: usize, gains: Vec<f64>) -> DCGScorer {
+        DCGScorer {
+            truncation_level: truncation_level,
+            gains: Some(gains),
+            discount_base: 2.0,
+            max_gain: None,
+        }
+    }
+
+    /// Sets the base of the logarithm in the discount `1 / log(i + 2)`
+    /// of position `i`, 2.0 by default. Some toolkits use the natural
+    /// logarithm or base 10. The base must be greater than 1.
+    pub fn set_discount_base(&mut self, base: f64) {
+        self.discount_base = base;
     }
 
-    // Maybe cache the values. But I haven't come up with a method to
-    // share the cached values.
-    fn discount(&self, i: usize) -> f64 {
-        1.0 / (i as f64 + 2.0).log2()
+    /// Returns the discount of position `i`, counted from 0.
+    pub fn discount(&self, i: usize) -> f64 {
+        if self.discount_base == 2.0 {
+            1.0 / (i as f64 + 2.0).log2()
+        } else {
+            1.0 / (i as f64 + 2.0).log(self.discount_base)
+        }
     }
 
-    fn gain(&self, score: f64) -> f64 {
+    /// Caps the gain of each label to `max_gain`, so that the
+    /// exponential gain `2^l - 1` of an absurdly high grade does not
+    /// dominate the metric or overflow. `None`, the default, does not
+    /// cap the gains.
+    pub fn set_max_gain(&mut self, max_gain: Option<f64>) {
+        self.max_gain = max_gain;
+    }
+
+    /// Returns the gain of a label.
+    pub fn gain(&self, score: f64) -> f64 {
+        let gain = self.uncapped_gain(score);
+        match self.max_gain {
+            Some(max_gain) => gain.min(max_gain),
+            None => gain,
+        }
+    }
+
+    fn uncapped_gain(&self, score: f64) -> f64 {
+        if let Some(ref gains) = self.gains {
+            if score >= 0.0 && score.fract() == 0.0 {
+                if let Some(&gain) = gains.get(score as usize) {
+                    return gain;
+                }
+            }
+        }
         score.exp2() - 1.0
     }
 }
@@ -29,14 +88,39 @@ impl Measure for DCGScorer {
         self.truncation_level
     }
 
+    fn gains(&self) -> Option<&[f64]> {
+        self.gains.as_ref().map(|gains| &gains[..])
+    }
+
+    fn discount_base(&self) -> f64 {
+        self.discount_base
+    }
+
+    fn max_gain(&self) -> Option<f64> {
+        self.max_gain
+    }
+
+    /// The terms are summed with `kahan_sum`, so that long lists of
+    /// high grades do not lose precision.
     fn measure(&self, labels: &[f64]) -> f64 {
         let n = usize::min(labels.len(), self.truncation_level);
-        (0..n)
-            .map(|i| self.gain(labels[i]) * self.discount(i))
-            .sum()
+        kahan_sum((0..n).map(|i| self.gain(labels[i]) * self.discount(i)))
     }
 
-    fn swap_changes(&self, labels: &[f64]) -> Vec<Vec<f64>> {
+    fn ideal_dcg(&self, labels: &[f64]) -> f64 {
+        use std::cmp::Ordering;
+
+        let mut clone: Vec<f64> = labels.iter().cloned().collect();
+        clone.sort_by(|a, b| b.partial_cmp(a).unwrap_or(Ordering::Equal));
+        self.measure(&clone)
+    }
+
+    /// DCG is not normalized, so the ideal DCG is not used.
+    fn swap_changes_with_ideal(
+        &self,
+        labels: &[f64],
+        _ideal_dcg: f64,
+    ) -> Vec<Vec<f64>> {
         let nlabels = labels.len();
 
         let mut changes = vec![vec![0.0; nlabels]; nlabels];
@@ -75,6 +159,37 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_dcg_score_gains() {
+        let dcg = DCGScorer::with_gains(10, vec![0.0, 1.0, 5.0]);
+        assert_eq!(
+            dcg.measure(&vec![2.0, 1.0, 4.0]),
+            5.0 / 2.0_f64.log2() + 1.0 / 3.0_f64.log2() + 15.0 / 4.0_f64.log2()
+        );
+    }
+
+    #[test]
+    fn test_dcg_score_discount_base() {
+        let mut dcg = DCGScorer::new(10);
+        dcg.set_discount_base(10.0);
+        let expected = 7.0 / 2.0_f64.log10() + 3.0 / 3.0_f64.log10() +
+            15.0 / 4.0_f64.log10();
+        let score = dcg.measure(&vec![3.0, 2.0, 4.0]);
+        assert!((score - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_dcg_score_max_gain() {
+        let mut dcg = DCGScorer::new(10);
+        dcg.set_max_gain(Some(10.0));
+        assert_eq!(dcg.gain(31.0), 10.0);
+        assert_eq!(dcg.gain(2.0), 3.0);
+        assert_eq!(
+            dcg.measure(&vec![3.0, 2.0, 4.0]),
+            7.0 / 2.0_f64.log2() + 3.0 / 3.0_f64.log2() + 10.0 / 4.0_f64.log2()
+        );
+    }
+
     #[test]
     fn test_dcg_swap_changes() {
         let dcg = DCGScorer::new(10);
@@ -2,6 +2,10 @@ pub mod dcg;
 pub mod ndcg;
 pub use self::dcg::DCGScorer;
 pub use self::ndcg::NDCGScorer;
+use std::cmp::Reverse;
+use std::fmt;
+use std::str::FromStr;
+use util::{Id, OrderedScore, Result};
 
 pub trait Measure: Sync {
     fn get_k(&self) -> usize;
@@ -9,16 +13,324 @@ pub trait Measure: Sync {
     fn measure(&self, labels: &[f64]) -> f64;
 
     /// The changes in score values by swaping any two of the labels.
-    fn swap_changes(&self, labels: &[f64]) -> Vec<Vec<f64>>;
+    fn swap_changes(&self, labels: &[f64]) -> Vec<Vec<f64>> {
+        self.swap_changes_with_ideal(labels, self.ideal_dcg(labels))
+    }
+
+    /// The DCG of the labels sorted in descending order.
+    fn ideal_dcg(&self, labels: &[f64]) -> f64;
+
+    /// Same as `swap_changes`, with the ideal DCG of the labels
+    /// computed beforehand. The labels of a query do not change
+    /// during training, so the ideal DCG can be computed only once.
+    fn swap_changes_with_ideal(
+        &self,
+        labels: &[f64],
+        ideal_dcg: f64,
+    ) -> Vec<Vec<f64>>;
 
     /// Name of the scorer. For display.
     fn name(&self) -> String;
+
+    /// The gain of each integer label, if the metric has a table of
+    /// gains. See `DCGScorer::with_gains`.
+    fn gains(&self) -> Option<&[f64]>;
+
+    /// The base of the logarithm of the discount. See
+    /// `DCGScorer::set_discount_base`.
+    fn discount_base(&self) -> f64;
+
+    /// The cap of the gains, if any. See `DCGScorer::set_max_gain`.
+    fn max_gain(&self) -> Option<f64>;
+}
+
+/// Whether the queries without relevant documents, i.e. whose labels
+/// are all zeros, count in the average of a metric over the queries.
+/// NDCG of such a query is 0.0, so counting them lowers the average.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ZeroQueries {
+    /// Count them with their scores.
+    Include,
+    /// Leave them out of the average.
+    Exclude,
+}
+
+impl ZeroQueries {
+    /// Returns whether a query of the labels counts in the average.
+    pub fn counts(&self, labels: &[f64]) -> bool {
+        match *self {
+            ZeroQueries::Include => true,
+            ZeroQueries::Exclude => labels.iter().any(|&label| label != 0.0),
+        }
+    }
+}
+
+/// How the instances of equal scores are ordered when a query is
+/// ranked for evaluation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TieBreak {
+    /// By the order of the instances in the data.
+    Index,
+    /// By descending label, then by the order in the data, so that
+    /// the metric does not depend on the order of the tied instances.
+    /// This is the optimistic ranking of the ties, an upper bound of
+    /// the metric. It does not reproduce trec_eval, which breaks ties
+    /// by the docno in reverse lexical order, since the data has no
+    /// docnos.
+    Trec,
+}
+
+impl TieBreak {
+    /// Sorts the indices by descending score, breaking the ties as
+    /// configured.
+    pub fn rank<S, L>(&self, indices: &mut [Id], score: S, label: L)
+    where
+        S: Fn(Id) -> f64,
+        L: Fn(Id) -> f64,
+    {
+        match *self {
+            TieBreak::Index => indices.sort_by_key(|&index| {
+                (Reverse(OrderedScore(score(index))), index)
+            }),
+            TieBreak::Trec => indices.sort_by_key(|&index| {
+                (
+                    Reverse(OrderedScore(score(index))),
+                    Reverse(OrderedScore(label(index))),
+                    index,
+                )
+            }),
+        }
+    }
+}
+
+impl FromStr for TieBreak {
+    type Err = String;
+
+    fn from_str(s: &str) -> ::std::result::Result<TieBreak, String> {
+        match s {
+            "default" => Ok(TieBreak::Index),
+            "trec" => Ok(TieBreak::Trec),
+            _ => Err(format!("Invalid evaluation compatibility: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for TieBreak {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TieBreak::Index => write!(f, "default"),
+            TieBreak::Trec => write!(f, "trec"),
+        }
+    }
+}
+
+/// How the model scores of a query are rescaled before they are
+/// ranked or written. The ranking within a query does not change, but
+/// the scores become comparable across the queries.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScoreNorm {
+    /// Subtracts the mean of the query and divides by its standard
+    /// deviation. The scores of a query of equal scores become 0.0.
+    PerQueryZScore,
+}
+
+impl ScoreNorm {
+    /// Rescales the scores of a query in place.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rforests::metric::ScoreNorm;
+    ///
+    /// let mut scores = vec![1.0, 3.0];
+    /// ScoreNorm::PerQueryZScore.normalize(&mut scores);
+    /// assert_eq!(scores, vec![-1.0, 1.0]);
+    /// ```
+    pub fn normalize(&self, scores: &mut [f64]) {
+        match *self {
+            ScoreNorm::PerQueryZScore => {
+                if scores.is_empty() {
+                    return;
+                }
+                let n = scores.len() as f64;
+                let mean = scores.iter().sum::<f64>() / n;
+                let variance = scores
+                    .iter()
+                    .map(|score| (score - mean) * (score - mean))
+                    .sum::<f64>() / n;
+                let std = variance.sqrt();
+                for score in scores.iter_mut() {
+                    *score = if std > 0.0 {
+                        (*score - mean) / std
+                    } else {
+                        0.0
+                    };
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for ScoreNorm {
+    type Err = String;
+
+    fn from_str(s: &str) -> ::std::result::Result<ScoreNorm, String> {
+        match s {
+            "zscore" => Ok(ScoreNorm::PerQueryZScore),
+            _ => Err(format!("Invalid score normalization: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for ScoreNorm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ScoreNorm::PerQueryZScore => write!(f, "zscore"),
+        }
+    }
 }
 
 pub fn new(name: &str, k: usize) -> Option<Box<Measure>> {
+    new_with_gains(name, k, None)
+}
+
+/// Creates a metric with an optional table of the gains of the
+/// labels. See `DCGScorer::with_gains`.
+pub fn new_with_gains(
+    name: &str,
+    k: usize,
+    gains: Option<Vec<f64>>,
+) -> Option<Box<Measure>> {
+    new_with_discount(name, k, gains, 2.0)
+}
+
+/// Creates a metric with an optional table of the gains of the labels
+/// and the base of the logarithm of the discount. See
+/// `DCGScorer::set_discount_base`.
+pub fn new_with_discount(
+    name: &str,
+    k: usize,
+    gains: Option<Vec<f64>>,
+    discount_base: f64,
+) -> Option<Box<Measure>> {
+    new_with_max_gain(name, k, gains, discount_base, None)
+}
+
+/// Same as `new_with_discount`, with the gains optionally capped to
+/// `max_gain`. See `DCGScorer::set_max_gain`.
+pub fn new_with_max_gain(
+    name: &str,
+    k: usize,
+    gains: Option<Vec<f64>>,
+    discount_base: f64,
+    max_gain: Option<f64>,
+) -> Option<Box<Measure>> {
     match name {
-        "NDCG" => Some(Box::new(NDCGScorer::new(k))),
-        "DCG" => Some(Box::new(DCGScorer::new(k))),
+        "NDCG" => {
+            let mut ndcg = match gains {
+                Some(g) => NDCGScorer::with_gains(k, g),
+                None => NDCGScorer::new(k),
+            };
+            ndcg.set_discount_base(discount_base);
+            ndcg.set_max_gain(max_gain);
+            Some(Box::new(ndcg))
+        }
+        "DCG" => {
+            let mut dcg = match gains {
+                Some(g) => DCGScorer::with_gains(k, g),
+                None => DCGScorer::new(k),
+            };
+            dcg.set_discount_base(discount_base);
+            dcg.set_max_gain(max_gain);
+            Some(Box::new(dcg))
+        }
         _ => None,
     }
 }
+
+/// Sums the values with compensated (Kahan-Babuska) summation, which
+/// keeps the low-order bits that a plain sum loses when adding small
+/// values to a large running total, e.g. the gains `2^31 - 1` of high
+/// grades over a long list.
+///
+/// # Examples
+///
+/// ```
+/// use rforests::metric;
+///
+/// let values = vec![1e16, 1.0, -1e16];
+/// assert_eq!(values.iter().sum::<f64>(), 0.0);
+/// assert_eq!(metric::kahan_sum(values), 1.0);
+/// ```
+pub fn kahan_sum<I: IntoIterator<Item = f64>>(values: I) -> f64 {
+    let mut sum = 0.0;
+    // The low-order bits lost so far.
+    let mut compensation = 0.0;
+    for value in values {
+        let total = sum + value;
+        if f64::abs(sum) >= f64::abs(value) {
+            compensation += (sum - total) + value;
+        } else {
+            compensation += (value - total) + sum;
+        }
+        sum = total;
+    }
+    sum + compensation
+}
+
+/// Parses a gain table "0,1,3,7,15", the gains of the labels from 0.
+///
+/// # Examples
+///
+/// ```
+/// use rforests::metric;
+///
+/// let gains = metric::parse_gains("0,1,3,7,15").unwrap();
+/// assert_eq!(gains, vec![0.0, 1.0, 3.0, 7.0, 15.0]);
+/// assert!(metric::parse_gains("0,a").is_err());
+/// ```
+pub fn parse_gains(s: &str) -> Result<Vec<f64>> {
+    s.split(',')
+        .map(|gain| {
+            gain.trim()
+                .parse::<f64>()
+                .map_err(|_| From::from(format!("Invalid gain table: {}", s)))
+        })
+        .collect()
+}
+
+/// Parses the base of the logarithm of the discount, which must be
+/// greater than 1.
+///
+/// # Examples
+///
+/// ```
+/// use rforests::metric;
+///
+/// assert_eq!(metric::parse_discount_base("10").unwrap(), 10.0);
+/// assert!(metric::parse_discount_base("1").is_err());
+/// ```
+pub fn parse_discount_base(s: &str) -> Result<f64> {
+    match s.trim().parse::<f64>() {
+        Ok(base) if base > 1.0 && base.is_finite() => Ok(base),
+        _ => Err(From::from(format!("Invalid discount base: {}", s))),
+    }
+}
+
+/// Parses the cap of the gains, which must be positive and finite.
+///
+/// # Examples
+///
+/// ```
+/// use rforests::metric;
+///
+/// assert_eq!(metric::parse_max_gain("1e6").unwrap(), 1e6);
+/// assert!(metric::parse_max_gain("0").is_err());
+/// assert!(metric::parse_max_gain("inf").is_err());
+/// ```
+pub fn parse_max_gain(s: &str) -> Result<f64> {
+    match s.trim().parse::<f64>() {
+        Ok(gain) if gain > 0.0 && gain.is_finite() => Ok(gain),
+        _ => Err(From::from(format!("Invalid gain cap: {}", s))),
+    }
+}
@@ -18,27 +18,37 @@ impl NDCGScorer {
         }
     }
 
-    // Maybe cache the values. But I haven't come up with a method to
-    // share the cached values.
-    fn discount(&self, i: usize) -> f64 {
-        let len = DISCOUNT.len();
-        if i >= len {
-            1.0 / (i as f64 + 2.0).log2()
-        } else {
-            DISCOUNT[i]
+    /// Creates a scorer with a table of gains. See
+    /// `DCGScorer::with_gains`.
+    pub fn with_gains(truncation_level: usize, gains: Vec<f64>) -> NDCGScorer {
+        NDCGScorer {
+            truncation_level: truncation_level,
+            dcg: DCGScorer::with_gains(truncation_level, gains),
         }
     }
 
-    fn gain(&self, score: f64) -> f64 {
-        score.exp2() - 1.0
+    /// Sets the base of the logarithm of the discount. See
+    /// `DCGScorer::set_discount_base`.
+    pub fn set_discount_base(&mut self, base: f64) {
+        self.dcg.set_discount_base(base);
+    }
+
+    /// Caps the gains. See `DCGScorer::set_max_gain`.
+    pub fn set_max_gain(&mut self, max_gain: Option<f64>) {
+        self.dcg.set_max_gain(max_gain);
     }
 
-    fn max_dcg(&self, labels: &[f64]) -> f64 {
-        use std::cmp::Ordering;
+    /// The cached values are of base 2, the default.
+    fn discount(&self, i: usize) -> f64 {
+        if self.dcg.discount_base() == 2.0 && i < DISCOUNT.len() {
+            DISCOUNT[i]
+        } else {
+            self.dcg.discount(i)
+        }
+    }
 
-        let mut clone: Vec<f64> = labels.iter().cloned().collect();
-        clone.sort_by(|a, b| b.partial_cmp(a).unwrap_or(Ordering::Equal));
-        self.dcg.measure(&clone)
+    fn gain(&self, score: f64) -> f64 {
+        self.dcg.gain(score)
     }
 }
 
@@ -51,22 +61,40 @@ impl Measure for NDCGScorer {
         self.truncation_level
     }
 
+    fn gains(&self) -> Option<&[f64]> {
+        self.dcg.gains()
+    }
+
+    fn discount_base(&self) -> f64 {
+        self.dcg.discount_base()
+    }
+
+    fn max_gain(&self) -> Option<f64> {
+        self.dcg.max_gain()
+    }
+
     fn measure(&self, labels: &[f64]) -> f64 {
-        let max = self.max_dcg(labels);
+        let max = self.ideal_dcg(labels);
         if max.abs() == 0.0 {
             0.0
         } else {
-            self.dcg.measure(labels) / self.max_dcg(labels)
+            self.dcg.measure(labels) / max
         }
     }
 
-    fn swap_changes(&self, labels: &[f64]) -> Vec<Vec<f64>> {
+    fn ideal_dcg(&self, labels: &[f64]) -> f64 {
+        self.dcg.ideal_dcg(labels)
+    }
+
+    fn swap_changes_with_ideal(
+        &self,
+        labels: &[f64],
+        ideal_dcg: f64,
+    ) -> Vec<Vec<f64>> {
         let nlabels = labels.len();
 
         let mut changes = vec![vec![0.0; nlabels]; nlabels];
 
-        let ideal_dcg = self.max_dcg(labels);
-
         let size = usize::min(self.truncation_level, nlabels);
         for i in 0..size {
             for j in i + 1..nlabels {
@@ -84,6 +112,7 @@ impl Measure for NDCGScorer {
 #[cfg(test)]
 mod test {
     use super::*;
+    use test::Bencher;
 
     #[test]
     fn test_ndcg_score() {
@@ -101,6 +130,35 @@ mod test {
         assert_eq!(ndcg.measure(&vec![0.0, 0.0, 0.0]), 0.0);
     }
 
+    #[test]
+    fn test_ndcg_score_gains() {
+        let ndcg = NDCGScorer::with_gains(10, vec![0.0, 1.0, 3.0, 4.0]);
+        let dcg = 4.0 / 2.0_f64.log2() + 1.0 / 3.0_f64.log2();
+        let max_dcg = 4.0 / 2.0_f64.log2() + 1.0 / 3.0_f64.log2();
+        assert_eq!(ndcg.measure(&vec![3.0, 1.0]), dcg / max_dcg);
+
+        let changes = ndcg.swap_changes(&vec![1.0, 3.0]);
+        let origin = 1.0 / 2.0_f64.log2() + 4.0 / 3.0_f64.log2();
+        assert!((changes[0][1] - (origin - dcg) / max_dcg).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_ndcg_discount_base() {
+        // The base scales the DCG and the ideal DCG alike, so it cancels
+        // out in NDCG. The DCGs themselves are checked instead.
+        let mut ndcg = NDCGScorer::new(10);
+        ndcg.set_discount_base(::std::f64::consts::E);
+        let dcg = 3.0 / 2.0_f64.ln() + 7.0 / 3.0_f64.ln();
+        let max_dcg = 7.0 / 2.0_f64.ln() + 3.0 / 3.0_f64.ln();
+        assert!((ndcg.dcg.measure(&vec![2.0, 3.0]) - dcg).abs() < 1e-9);
+        assert!((ndcg.ideal_dcg(&vec![2.0, 3.0]) - max_dcg).abs() < 1e-9);
+        assert!((ndcg.discount(1) - 1.0 / 3.0_f64.ln()).abs() < 1e-9);
+
+        // The swap changes use the same discount as the ideal DCG.
+        let changes = ndcg.swap_changes(&vec![2.0, 3.0]);
+        assert!((changes[0][1] - (dcg - max_dcg) / max_dcg).abs() < 1e-9);
+    }
+
     #[test]
     fn test_ndcg_score_k_is_2() {
         let ndcg = NDCGScorer::new(2);
@@ -161,4 +219,11 @@ mod test {
             });
         assert!(check);
     }
+
+    #[bench]
+    fn bench_swap_changes(b: &mut Bencher) {
+        let ndcg = NDCGScorer::new(10);
+        let labels: Vec<f64> = (0..100).map(|i| (i * 7 % 5) as f64).collect();
+        b.iter(|| ndcg.swap_changes(&labels));
+    }
 }
@@ -0,0 +1,327 @@
+use clap::{App, Arg, ArgMatches, SubCommand};
+use std;
+use metric;
+use metric::{Measure, TieBreak, ZeroQueries};
+use train::dataset::*;
+use train::Evaluate;
+use util::{open_input, Result};
+
+/// A linear combination of the feature values.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinearModel {
+    /// The weight of each feature. The weight of feature id `i` is at
+    /// index `i - 1`.
+    pub weights: Vec<f64>,
+}
+
+impl LinearModel {
+    /// Creates a model of `nfeatures` equal weights which sum to 1.
+    pub fn uniform(nfeatures: usize) -> LinearModel {
+        LinearModel { weights: vec![1.0 / nfeatures as f64; nfeatures] }
+    }
+
+    /// Rescales the weights so that their absolute values sum to 1.
+    /// The ranking of the instances is not changed.
+    fn normalize(&mut self) {
+        let sum: f64 = self.weights.iter().map(|w| w.abs()).sum();
+        if sum > 0.0 {
+            for weight in self.weights.iter_mut() {
+                *weight /= sum;
+            }
+        }
+    }
+}
+
+impl Evaluate for LinearModel {
+    fn evaluate(&self, instance: &Instance) -> f64 {
+        instance
+            .value_iter()
+            .filter_map(|(id, value)| {
+                self.weights.get(id - 1).map(|weight| weight * value)
+            })
+            .sum()
+    }
+}
+
+/// Configurable options for coordinate ascent.
+pub struct Config {
+    pub train: DataSet,
+    pub validate: Option<DataSet>,
+
+    pub metric: Box<Measure>,
+    /// Maximum number of passes over all the features.
+    pub passes: usize,
+    /// The step sizes tried on each feature, in both directions.
+    pub steps: Vec<f64>,
+    /// Training stops when a pass improves the metric by less than
+    /// this.
+    pub tolerance: f64,
+    pub print_metric: bool,
+}
+
+/// Coordinate ascent on the ranking metric, as in RankLib. Each pass
+/// changes the weight of one feature at a time by the step size which
+/// maximizes the metric on the training data.
+pub struct CoordinateAscent {
+    config: Config,
+    model: LinearModel,
+}
+
+impl CoordinateAscent {
+    /// Create a new CoordinateAscent instance.
+    pub fn new(config: Config) -> CoordinateAscent {
+        let nfeatures = config.train.fid_iter().count();
+        CoordinateAscent {
+            config: config,
+            model: LinearModel::uniform(nfeatures),
+        }
+    }
+
+    /// Returns the learned model.
+    pub fn model(&self) -> &LinearModel {
+        &self.model
+    }
+
+    /// Learns the weights. Returns the score on the training data
+    /// after each pass.
+    pub fn learn(&mut self) -> Result<Vec<f64>> {
+        if self.config.steps.is_empty() {
+            Err("No step sizes")?;
+        }
+
+        let mut best = self.evaluate(&self.config.train);
+        let mut history = Vec::with_capacity(self.config.passes);
+
+        self.print_metric_header();
+        for i in 0..self.config.passes {
+            let start = best;
+            for index in 0..self.model.weights.len() {
+                best = self.ascend(index, best);
+            }
+
+            let validate_score =
+                self.config.validate.as_ref().map(|v| self.evaluate(v));
+            history.push(best);
+            self.print_metric(i, best, validate_score);
+
+            if best - start < self.config.tolerance {
+                break;
+            }
+        }
+
+        Ok(history)
+    }
+
+    /// Tries each step on the weight at `index`, and keeps the one
+    /// which scores higher than `score`. Returns the new score.
+    fn ascend(&mut self, index: usize, score: f64) -> f64 {
+        let origin = self.model.weights[index];
+        let mut best = (score, origin);
+        for &step in self.config.steps.iter() {
+            for &delta in &[step, -step] {
+                self.model.weights[index] = origin + delta;
+                let score = self.evaluate(&self.config.train);
+                if score > best.0 {
+                    best = (score, origin + delta);
+                }
+            }
+        }
+
+        self.model.weights[index] = best.1;
+        self.model.normalize();
+        best.0
+    }
+
+    pub fn evaluate(&self, dataset: &DataSet) -> f64 {
+        dataset.evaluate(
+            &self.model,
+            &self.config.metric,
+            ZeroQueries::Include,
+            TieBreak::Index,
+        )
+    }
+
+    fn print(&self, msg: &str) {
+        if self.config.print_metric {
+            println!("{}", msg);
+        }
+    }
+
+    /// Print metric header.
+    fn print_metric_header(&self) {
+        self.print(&format!(
+            "{:<7} | {:>9} | {:>9}",
+            "#pass",
+            self.config.metric.name() + "-T",
+            self.config.metric.name() + "-V"
+        ));
+    }
+
+    /// Print metric after each pass.
+    fn print_metric(
+        &self,
+        iteration: usize,
+        train_score: f64,
+        validate_score: Option<f64>,
+    ) {
+        let s = format!(
+            "{:<7} | {:>9.4} | {}",
+            iteration + 1,
+            train_score,
+            validate_score
+                .map(|score| format!("{:>9.4}", score))
+                .unwrap_or("".to_string())
+        );
+        self.print(&s);
+    }
+}
+
+fn load_dataset(path: &str, label_column: usize) -> Result<DataSet> {
+    let file = open_input(path)?;
+    DataSet::load_with_label_column(file, label_column)
+}
+
+/// Parses comma separated step sizes, e.g. "0.01,0.1,1".
+fn parse_steps(s: &str) -> Result<Vec<f64>> {
+    let mut steps = Vec::new();
+    for field in s.split(',') {
+        let step = field.trim().parse::<f64>()?;
+        if !(step > 0.0) {
+            Err(format!("Invalid step size: {}", field))?;
+        }
+        steps.push(step);
+    }
+    Ok(steps)
+}
+
+pub fn main<'a>(matches: &ArgMatches<'a>) {
+    let validate_file_path = matches.value_of("validate-file");
+    let metric_name = matches.value_of("metric").unwrap();
+    let metric_k = value_t!(matches.value_of("metric-k"), usize)
+        .unwrap_or_else(|e| e.exit());
+    let passes = value_t!(matches.value_of("passes"), usize)
+        .unwrap_or_else(|e| e.exit());
+    let tolerance = value_t!(matches.value_of("tolerance"), f64)
+        .unwrap_or_else(|e| e.exit());
+    let steps = parse_steps(matches.value_of("steps").unwrap())
+        .unwrap_or_else(|e| {
+            error!("{}", e);
+            std::process::exit(1)
+        });
+    let label_column = value_t!(matches.value_of("label-column"), usize)
+        .unwrap_or_else(|e| e.exit());
+
+    let load = |path: &str| {
+        load_dataset(path, label_column).unwrap_or_else(|e| {
+            error!("Failed to load {}: {}", path, e);
+            std::process::exit(1)
+        })
+    };
+
+    let config = Config {
+        train: super::load_train_files(matches),
+        validate: validate_file_path.map(&load),
+        // The param is valid.
+        metric: metric::new_with_max_gain(
+            metric_name,
+            metric_k,
+            super::gain_table(matches),
+            super::discount_base(matches),
+            super::max_gain(matches),
+        ).unwrap(),
+        passes: passes,
+        steps: steps,
+        tolerance: tolerance,
+        print_metric: true,
+    };
+
+    let mut ca = CoordinateAscent::new(config);
+    ca.learn().unwrap();
+}
+
+pub fn clap_command<'a, 'b>() -> App<'a, 'b> {
+    let train_common_args = super::common_args();
+    // Coordinate ascent args
+    let coordinate_ascent_command = SubCommand::with_name("coordinate-ascent")
+        .about("Train a linear model by coordinate ascent")
+        .args(&train_common_args)
+        .arg(
+            Arg::with_name("passes")
+                .long("passes")
+                .takes_value(true)
+                .value_name("NUM")
+                .default_value("25")
+                .display_order(101)
+                .help("Maximum number of passes over the features"),
+        )
+        .arg(
+            Arg::with_name("steps")
+                .long("steps")
+                .takes_value(true)
+                .value_name("STEP,...")
+                .default_value("0.001,0.01,0.1,1")
+                .display_order(102)
+                .help("Step sizes tried on the weight of each feature"),
+        )
+        .arg(
+            Arg::with_name("tolerance")
+                .long("tolerance")
+                .takes_value(true)
+                .value_name("DELTA")
+                .default_value("0.001")
+                .display_order(103)
+                .help("Stop when a pass improves the metric by less than this"),
+        );
+    coordinate_ascent_command
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::File;
+
+    #[test]
+    fn test_linear_model() {
+        let model = LinearModel { weights: vec![0.5, -1.0] };
+        let instance = Instance::new(0.0, 1, vec![2.0, 3.0, 4.0]);
+        assert_eq!(model.evaluate(&instance), -2.0);
+    }
+
+    #[test]
+    fn test_parse_steps() {
+        assert_eq!(parse_steps("0.1, 1").unwrap(), vec![0.1, 1.0]);
+        assert!(parse_steps("0.1,0").is_err());
+        assert!(parse_steps("x").is_err());
+    }
+
+    #[test]
+    fn test_coordinate_ascent() {
+        let path = "./data/train-lite.txt";
+        let f = File::open(path).unwrap();
+        let dataset = DataSet::load(f).unwrap();
+        let initial = dataset.evaluate(
+            &LinearModel::uniform(dataset.fid_iter().count()),
+            &metric::new("NDCG", 10).unwrap(),
+            ZeroQueries::Include,
+            TieBreak::Index,
+        );
+
+        let config = Config {
+            train: dataset,
+            validate: None,
+            metric: metric::new("NDCG", 10).unwrap(),
+            passes: 2,
+            steps: vec![0.01, 0.1],
+            tolerance: 0.0,
+            print_metric: false,
+        };
+        let mut ca = CoordinateAscent::new(config);
+        let history = ca.learn().unwrap();
+
+        assert!(history.len() <= 2);
+        assert!(history.windows(2).all(|w| w[1] >= w[0]));
+        assert!(history[0] >= initial);
+        let sum: f64 = ca.model().weights.iter().map(|w| w.abs()).sum();
+        assert!((sum - 1.0).abs() < 1e-9);
+    }
+}
@@ -1,16 +1,36 @@
-use format::svmlight::SvmLightFile;
-use util::{Id, Result, Value};
+use format::svmlight::{FeatureStat, FilesStats, SvmLightFile};
+use util::{Id, OrderedScore, Result, Rng, Value};
 use std;
-use std::cmp::Ordering::*;
+use std::cmp::Reverse;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use train::Evaluate;
 use metric::*;
 
+/// Storage of the feature values of an Instance.
+#[derive(Clone, Debug, PartialEq)]
+enum FeatureValues {
+    /// Values of all the features. Index from 0.
+    Dense(Vec<Value>),
+    /// Parallel vectors of feature ids in ascending order and their
+    /// values. Absent features are 0.0.
+    Sparse { ids: Vec<Id>, values: Vec<Value> },
+}
+
 /// An instance of a label, a qid, and a group of feature values.
 #[derive(Clone, Debug, PartialEq)]
 pub struct Instance {
     qid: Id,
+    /// The original text of a non-numeric qid, until the instance is
+    /// added to a `DataSet`.
+    qid_label: Option<String>,
     label: Value, // or label
-    values: Vec<Value>, // index from 0
+    /// Importance of the instance in training and evaluation.
+    weight: Value,
+    /// The 1-based position at which the instance was shown, for
+    /// click data. See `DataSet::debias_positions`.
+    position: Option<usize>,
+    values: FeatureValues,
 }
 
 impl Instance {
@@ -19,7 +39,47 @@ impl Instance {
         Instance {
             label: label,
             qid: qid,
-            values: values,
+            qid_label: None,
+            weight: 1.0,
+            position: None,
+            values: FeatureValues::Dense(values),
+        }
+    }
+
+    /// Creates a new instance which stores only the given (feature
+    /// id, value) pairs. Suitable for data with few non-zero
+    /// features.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rforests::train::dataset::Instance;
+    ///
+    /// let dense = Instance::new(3.0, 1, vec![0.0, 2.0, 0.0, 4.0]);
+    /// let sparse = Instance::new_sparse(3.0, 1, vec![(4, 4.0), (2, 2.0)]);
+    ///
+    /// assert_eq!(sparse.value(2), 2.0);
+    /// assert_eq!(sparse.value(3), 0.0);
+    /// assert_eq!(sparse.max_feature_id(), dense.max_feature_id());
+    /// assert_eq!(sparse.to_string(), dense.to_string());
+    /// ```
+    pub fn new_sparse(
+        label: Value,
+        qid: Id,
+        mut pairs: Vec<(Id, Value)>,
+    ) -> Instance {
+        pairs.sort_by_key(|&(id, _)| id);
+        let (ids, values) = pairs.into_iter().unzip();
+        Instance {
+            label: label,
+            qid: qid,
+            qid_label: None,
+            weight: 1.0,
+            position: None,
+            values: FeatureValues::Sparse {
+                ids: ids,
+                values: values,
+            },
         }
     }
 
@@ -28,28 +88,190 @@ impl Instance {
         self.qid
     }
 
+    /// Sets the qid of the instance.
+    pub fn set_qid(&mut self, qid: Id) {
+        self.qid = qid;
+    }
+
+    /// Returns the text of a non-numeric qid, if it is not yet mapped
+    /// to a numeric id. See `DataSet::qid_label`.
+    pub fn qid_label(&self) -> Option<&str> {
+        self.qid_label.as_ref().map(|label| &label[..])
+    }
+
+    /// Sets the text of a non-numeric qid.
+    pub fn set_qid_label(&mut self, label: Option<String>) {
+        self.qid_label = label;
+    }
+
     /// Returns the label of the instance.
     pub fn label(&self) -> Value {
         self.label
     }
 
+    /// Returns the weight of the instance, 1.0 unless set otherwise.
+    pub fn weight(&self) -> Value {
+        self.weight
+    }
+
+    /// Sets the weight of the instance. The gradients of the instance
+    /// are scaled by the weight in training.
+    pub fn set_weight(&mut self, weight: Value) {
+        self.weight = weight;
+    }
+
+    /// Returns the position at which the instance was shown, if
+    /// known.
+    pub fn position(&self) -> Option<usize> {
+        self.position
+    }
+
+    /// Sets the position at which the instance was shown.
+    pub fn set_position(&mut self, position: Option<usize>) {
+        self.position = position;
+    }
+
+    /// Returns whether the feature values are stored sparsely.
+    pub fn is_sparse(&self) -> bool {
+        match self.values {
+            FeatureValues::Dense(_) => false,
+            FeatureValues::Sparse { .. } => true,
+        }
+    }
+
     /// Returns the value of the given feature id.
     pub fn value(&self, id: Id) -> Value {
-        self.values.get(id - 1).map_or(0.0, |v| *v)
+        match self.values {
+            FeatureValues::Dense(ref values) => {
+                values.get(id - 1).map_or(0.0, |v| *v)
+            }
+            FeatureValues::Sparse {
+                ref ids,
+                ref values,
+            } => ids.binary_search(&id).map(|pos| values[pos]).unwrap_or(
+                0.0,
+            ),
+        }
+    }
+
+    /// Returns the value of the given feature id, or `None` if the id
+    /// is 0 or beyond the max feature id of the instance. Unlike
+    /// `value`, which reads such ids as 0.0, this catches a model
+    /// referring to features that the data never has.
+    ///
+    /// A feature absent from the line but within the max feature id
+    /// is `Some(0.0)`, since the dense storage does not tell it apart
+    /// from an explicit zero.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rforests::train::dataset::Instance;
+    ///
+    /// let instance = Instance::new(1.0, 1, vec![5.0, 0.0, 3.0]);
+    /// assert_eq!(instance.try_value(1), Some(5.0));
+    /// assert_eq!(instance.try_value(2), Some(0.0));
+    /// assert_eq!(instance.try_value(4), None);
+    /// assert_eq!(instance.try_value(0), None);
+    /// ```
+    pub fn try_value(&self, id: Id) -> Option<Value> {
+        if id == 0 || id > self.max_feature_id() {
+            None
+        } else {
+            Some(self.value(id))
+        }
     }
 
     // See https://github.com/rust-lang/rust/issues/38615 for the
     // reason that 'a is required.
-    /// Returns an iterator over the (feature id, value) pairs.
+    /// Returns an iterator over the (feature id, value) pairs. Absent
+    /// features up to the max feature id are yielded as 0.0.
     pub fn value_iter<'a>(&'a self) -> impl Iterator<Item = (Id, Value)> + 'a {
-        self.values.iter().enumerate().map(|(index, &value)| {
-            (index + 1, value)
-        })
+        (1..(self.max_feature_id() + 1)).map(move |id| (id, self.value(id)))
     }
 
     /// Returns the max feature id.
     pub fn max_feature_id(&self) -> Id {
-        self.values.len() as Id
+        match self.values {
+            FeatureValues::Dense(ref values) => values.len() as Id,
+            FeatureValues::Sparse { ref ids, .. } => {
+                ids.last().cloned().unwrap_or(0)
+            }
+        }
+    }
+
+    /// Returns the ids of the features with non-zero values.
+    fn nonzero_ids(&self) -> Vec<Id> {
+        match self.values {
+            FeatureValues::Dense(ref values) => values
+                .iter()
+                .enumerate()
+                .filter(|&(_, &value)| value != 0.0)
+                .map(|(index, _)| index + 1)
+                .collect(),
+            FeatureValues::Sparse {
+                ref ids,
+                ref values,
+            } => ids.iter()
+                .zip(values.iter())
+                .filter(|&(_, &value)| value != 0.0)
+                .map(|(&id, _)| id)
+                .collect(),
+        }
+    }
+
+    /// Changes the feature ids. `new_ids[id - 1]` is the new id of
+    /// the feature `id`, or None if the feature is dropped. The new ids
+    /// must keep the order of the old ones.
+    fn remap_features(&mut self, new_ids: &[Option<Id>], nfeatures: usize) {
+        let new_id = |id: Id| new_ids.get(id - 1).cloned().unwrap_or(None);
+        match self.values {
+            FeatureValues::Dense(ref mut values) => {
+                let mut remapped = vec![0.0; nfeatures];
+                for (index, &value) in values.iter().enumerate() {
+                    if let Some(id) = new_id(index + 1) {
+                        remapped[id - 1] = value;
+                    }
+                }
+                *values = remapped;
+            }
+            FeatureValues::Sparse {
+                ref mut ids,
+                ref mut values,
+            } => {
+                let (new_ids, new_values) = ids.iter()
+                    .zip(values.iter())
+                    .filter_map(|(&id, &value)| {
+                        new_id(id).map(|id| (id, value))
+                    })
+                    .unzip();
+                *ids = new_ids;
+                *values = new_values;
+            }
+        }
+    }
+
+    /// Sets the value of the given feature id. Absent values before
+    /// `id` are filled with 0.0.
+    fn set_value(&mut self, id: Id, value: Value) {
+        match self.values {
+            FeatureValues::Dense(ref mut values) => {
+                if id > values.len() {
+                    values.resize(id, 0.0);
+                }
+                values[id - 1] = value;
+            }
+            FeatureValues::Sparse {
+                ref mut ids,
+                ref mut values,
+            } => match ids.binary_search(&id) {
+                Ok(pos) => values[pos] = value,
+                Err(pos) => {
+                    ids.insert(pos, id);
+                    values.insert(pos, value);
+                }
+            },
+        }
     }
 }
 
@@ -61,24 +283,63 @@ impl From<(Value, Id, Vec<Value>)> for Instance {
 
 impl std::fmt::Display for Instance {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        let mut values = self.values
-            .iter()
-            .enumerate()
-            .map(|(index, value)| format!("{}:{}", index + 1, value))
+        let mut values = self.value_iter()
+            .map(|(id, value)| format!("{}:{}", id, value))
             .collect::<Vec<_>>();
 
-        let mut v = vec![self.label.to_string(), format!("qid:{}", self.qid)];
+        let qid = match self.qid_label {
+            Some(ref label) => label.clone(),
+            None => self.qid.to_string(),
+        };
+        let mut v = vec![self.label.to_string(), format!("qid:{}", qid)];
+        if self.weight != 1.0 {
+            v.push(format!("cost:{}", self.weight));
+        }
+        if let Some(position) = self.position {
+            v.push(format!("pos:{}", position));
+        }
         v.append(&mut values);
         write!(f, "{}", v.join(" "))
     }
 }
 
-impl std::ops::Deref for Instance {
-    type Target = Vec<Value>;
+/// Methods of rescaling the feature values of a DataSet. See
+/// `DataSet::normalize`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NormalizeMethod {
+    /// Rescales each feature into [0, 1] by the min and max values
+    /// over all the instances.
+    MinMax,
+    /// Rescales each feature to zero mean and unit standard
+    /// deviation over all the instances.
+    ZScore,
+    /// Rescales each feature into [0, 1] by the min and max values
+    /// within each query.
+    MinMaxPerQuery,
+}
 
-    fn deref(&self) -> &Vec<Value> {
-        &self.values
-    }
+/// Which features `DataSet::select_features` keeps.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FeatureCriterion {
+    /// Keeps the features whose variance over all the instances is
+    /// greater than this. `MinVariance(0.0)` drops the constant
+    /// features.
+    MinVariance(f64),
+    /// Keeps the features of the largest absolute Pearson correlation
+    /// with the label, at most this many.
+    TopKByCorrelation(usize),
+}
+
+/// What `DataSet::merge` does when both data sets have a qid.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CollisionPolicy {
+    /// Fails the merge.
+    Error,
+    /// Offsets the qids of the second data set past those of the
+    /// first. See `DataSet::concat`.
+    Offset,
+    /// Keeps the qids, assuming that they are disjoint.
+    Keep,
 }
 
 pub struct QueryIter<'a> {
@@ -105,6 +366,11 @@ impl<'a> Iterator for QueryIter<'a> {
     }
 }
 
+/// The fewest queries measured by a thread of `DataSet::evaluate`.
+/// Fewer queries are measured on the current thread, where the threads
+/// cost more than they save.
+const MIN_CHUNK_QUERIES: usize = 64;
+
 /// A collection type containing a data set. The DataSet is a static
 /// data structure. See also TrainingDataSet which is a mutable data
 /// structure that its label values get updated after each training.
@@ -114,6 +380,12 @@ pub struct DataSet {
     instances: Vec<Instance>,
     // Group by queries. (Start index, Query Length).
     queries: Vec<(usize, usize)>,
+    /// The original text of each qid, indexed by the numeric qid. It
+    /// is empty when the qids in the data are numeric.
+    qid_labels: Vec<String>,
+    /// The feature values by columns, the values of feature id `i` at
+    /// index `i - 1`. See `DataSet::columnarize`.
+    columns: Option<Vec<Vec<Value>>>,
 }
 
 impl std::iter::FromIterator<(Value, Id, Vec<Value>)> for DataSet {
@@ -168,12 +440,16 @@ impl std::iter::FromIterator<(Value, Id, Vec<Value>)> for DataSet {
             instances: instances,
             nfeatures: nfeatures,
             queries: queries,
+            qid_labels: Vec::new(),
+            columns: None,
         }
     }
 }
 
 impl DataSet {
-    /// Load data set from a reader.
+    /// Load data set from a reader. The lines are parsed across the
+    /// threads of `util::POOL`, so this must not be called from one of
+    /// them.
     ///
     /// # Examples
     ///
@@ -196,22 +472,153 @@ impl DataSet {
     where
         R: ::std::io::Read,
     {
-        let mut instances = Vec::new();
+        debug!("Loading data...");
+        DataSet::parse_parallel(reader, SvmLightFile::parse_instance)
+    }
+
+    /// Load data set from a reader whose label is the field at
+    /// `label_column` of each line, e.g. 1 when the lines start with
+    /// a line id. The fields before the label are ignored.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rforests::train::dataset::DataSet;
+    ///
+    /// let s = "1 3.0 qid:1 1:5.0
+    /// 2 2.0 qid:1 1:7.0";
+    ///
+    /// let cursor = ::std::io::Cursor::new(s);
+    /// let dataset = DataSet::load_with_label_column(cursor, 1).unwrap();
+    ///
+    /// assert_eq!(dataset[0].label(), 3.0);
+    /// assert_eq!(dataset[1].qid(), 1);
+    /// assert_eq!(dataset[1].value(1), 7.0);
+    /// ```
+    pub fn load_with_label_column<R>(
+        reader: R,
+        label_column: usize,
+    ) -> Result<DataSet>
+    where
+        R: ::std::io::Read,
+    {
+        debug!("Loading data...");
+        DataSet::parse_parallel(reader, |s| {
+            SvmLightFile::parse_instance_at(s, label_column)
+        })
+    }
+
+    /// Load data set from a reader, storing the feature values of
+    /// each instance sparsely. This saves memory when most of the
+    /// feature values are zeros.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rforests::train::dataset::DataSet;
+    ///
+    /// let s = "3.0 qid:1 1:5.0 700:1.0
+    /// 2.0 qid:2 3:7.0";
+    ///
+    /// let dataset = DataSet::load_sparse(::std::io::Cursor::new(s)).unwrap();
+    ///
+    /// assert!(dataset[0].is_sparse());
+    /// assert_eq!(dataset[0].value(1), 5.0);
+    /// assert_eq!(dataset[0].value(2), 0.0);
+    /// assert_eq!(dataset[0].value(700), 1.0);
+    /// assert_eq!(dataset[1].value(3), 7.0);
+    /// assert_eq!(dataset.fid_iter().count(), 700);
+    /// ```
+    pub fn load_sparse<R>(reader: R) -> Result<DataSet>
+    where
+        R: ::std::io::Read,
+    {
+        debug!("Loading sparse data...");
+        DataSet::parse_parallel(reader, SvmLightFile::parse_instance_sparse)
+    }
+
+    /// Read lines from the reader in chunks and parse the chunks
+    /// across the threads of `util::POOL`. The parsed instances are
+    /// kept in the order of the lines. The pool is locked only while a
+    /// batch of chunks is parsed, not while the lines are read.
+    ///
+    /// This must not be called from a thread of `util::POOL`: the pool
+    /// is locked by the caller of that thread, so it would deadlock.
+    fn parse_parallel<R, F>(reader: R, parse: F) -> Result<DataSet>
+    where
+        R: ::std::io::Read,
+        F: Fn(&str) -> Result<Instance> + Sync,
+    {
+        // Number of lines parsed by a thread at a time.
+        const CHUNK_LINES: usize = 10000;
+
+        let nchunks = ::util::POOL.lock().unwrap().thread_count() as usize;
+        let mut lines = SvmLightFile::lines(reader);
+        let mut instances: Vec<Instance> = Vec::new();
+        loop {
+            let mut chunks: Vec<Vec<String>> = Vec::with_capacity(nchunks);
+            for _ in 0..nchunks {
+                let chunk = lines
+                    .by_ref()
+                    .take(CHUNK_LINES)
+                    .collect::<Result<Vec<String>>>()?;
+                if chunk.is_empty() {
+                    break;
+                }
+                chunks.push(chunk);
+            }
+            if chunks.is_empty() {
+                break;
+            }
+
+            // The errors are turned into String since Box<Error> can
+            // not be sent across threads.
+            type ChunkResult = ::std::result::Result<Vec<Instance>, String>;
+            let mut results: Vec<ChunkResult> =
+                vec![Ok(Vec::new()); chunks.len()];
+            {
+                let parse = &parse;
+                let mut pool = ::util::POOL.lock().unwrap();
+                pool.scoped(|scoped| for (chunk, result) in
+                    chunks.iter().zip(results.iter_mut())
+                {
+                    scoped.execute(move || {
+                        *result = chunk
+                            .iter()
+                            .map(|line| parse(line).map_err(|e| e.to_string()))
+                            .collect();
+                    });
+                });
+            }
+
+            for result in results {
+                instances.append(&mut result?);
+            }
+        }
+
+        DataSet::from_instances(instances.into_iter().map(Ok))
+    }
+
+    /// Build data set from parsed instances, which are grouped by
+    /// their qids.
+    fn from_instances<I>(iter: I) -> Result<DataSet>
+    where
+        I: Iterator<Item = Result<Instance>>,
+    {
+        let mut instances = iter.collect::<Result<Vec<Instance>>>()?;
+        let qid_labels = DataSet::map_qid_labels(&mut instances);
+
         let mut nfeatures = 0;
         let mut queries = Vec::new();
         let mut query_start = 0;
         let mut query_len = 0;
-        debug!("Loading data...");
-        for instance_result in SvmLightFile::instances(reader) {
-            let instance = instance_result?;
+        for (index, instance) in instances.iter().enumerate() {
             nfeatures =
                 usize::max(nfeatures, instance.max_feature_id() as usize);
-            let qid = instance.qid();
-            instances.push(instance);
 
-            if qid != instances[query_start].qid() {
+            if instance.qid() != instances[query_start].qid() {
                 queries.push((query_start, query_len));
-                query_start = instances.len() - 1;
+                query_start = index;
                 query_len = 1;
             } else {
                 query_len += 1;
@@ -228,9 +635,61 @@ impl DataSet {
             instances: instances,
             nfeatures: nfeatures,
             queries: queries,
+            qid_labels: qid_labels,
+            columns: None,
         })
     }
 
+    /// Maps the qids to numeric ids in the order of their first
+    /// appearance, if any of the instances has a non-numeric qid.
+    /// Numeric qids are then mapped by their text as well. Returns the
+    /// text of each mapped id, or an empty Vec if no qid is mapped.
+    fn map_qid_labels(instances: &mut [Instance]) -> Vec<String> {
+        if instances.iter().all(|instance| instance.qid_label.is_none()) {
+            return Vec::new();
+        }
+
+        let mut ids: HashMap<String, Id> = HashMap::new();
+        let mut labels = Vec::new();
+        for instance in instances.iter_mut() {
+            let label = instance
+                .qid_label
+                .take()
+                .unwrap_or_else(|| instance.qid.to_string());
+            instance.qid = *ids.entry(label.clone()).or_insert_with(|| {
+                labels.push(label);
+                labels.len() - 1
+            });
+        }
+        labels
+    }
+
+    /// Returns the original text of the qid. It is the qid itself
+    /// unless the qids in the data are non-numeric.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rforests::train::dataset::DataSet;
+    ///
+    /// let s = "3.0 qid:GX008 1:5.0
+    /// 2.0 qid:GX008 1:7.0
+    /// 3.0 qid:GX010 1:3.0";
+    ///
+    /// let dataset = DataSet::load(::std::io::Cursor::new(s)).unwrap();
+    ///
+    /// assert_eq!(dataset[0].qid(), 0);
+    /// assert_eq!(dataset[2].qid(), 1);
+    /// assert_eq!(dataset.qid_label(0), "GX008");
+    /// assert_eq!(dataset.qid_label(1), "GX010");
+    /// ```
+    pub fn qid_label(&self, qid: Id) -> Cow<str> {
+        match self.qid_labels.get(qid) {
+            Some(label) => Cow::Borrowed(label),
+            None => Cow::Owned(qid.to_string()),
+        }
+    }
+
     /// Returns an iterator over the feature ids in the data set.
     ///
     /// # Examples
@@ -278,13 +737,70 @@ impl DataSet {
         self.instances.iter().map(|instance| instance.label)
     }
 
+    /// Returns an iterator over the values of a feature, in the order
+    /// of the instances. Absent values are 0.0.
     pub fn feature_value_iter<'a>(
         &'a self,
         fid: Id,
     ) -> impl Iterator<Item = Value> + 'a {
-        self.instances.iter().map(
-            move |instance| instance.value(fid),
-        )
+        let column = self.column(fid);
+        self.instances.iter().enumerate().map(move |(index, instance)| {
+            match column {
+                Some(column) => column[index],
+                None => instance.value(fid),
+            }
+        })
+    }
+
+    /// Copies the feature values into columns, one contiguous vector
+    /// per feature, so that reading a feature over all the instances,
+    /// as the binning of the features for training does, no longer
+    /// strides across the instances. The instances are kept for
+    /// everything else. This takes another copy of the feature values
+    /// in memory.
+    ///
+    /// The columns are dropped by the methods which change the
+    /// feature values or the order of the instances. Call this again
+    /// after them.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rforests::train::dataset::DataSet;
+    ///
+    /// let s = "3 qid:1 1:5.0 3:2.0
+    /// 2 qid:1 2:7.0";
+    ///
+    /// let cursor = ::std::io::Cursor::new(s);
+    /// let mut dataset = DataSet::load_sparse(cursor).unwrap();
+    /// dataset.columnarize();
+    ///
+    /// assert!(dataset.is_columnar());
+    /// let values: Vec<f64> = dataset.feature_value_iter(2).collect();
+    /// assert_eq!(values, vec![0.0, 7.0]);
+    /// ```
+    pub fn columnarize(&mut self) {
+        let mut columns = vec![Vec::with_capacity(self.len()); self.nfeatures];
+        for instance in self.instances.iter() {
+            for (index, column) in columns.iter_mut().enumerate() {
+                column.push(instance.value(index + 1));
+            }
+        }
+        self.columns = Some(columns);
+    }
+
+    /// Returns whether the feature values are also stored by columns.
+    pub fn is_columnar(&self) -> bool {
+        self.columns.is_some()
+    }
+
+    /// Returns the column of a feature, if the data set is columnar.
+    fn column(&self, fid: Id) -> Option<&[Value]> {
+        self.columns.as_ref().and_then(|columns| {
+            fid.checked_sub(1)
+                .and_then(|index| columns.get(index))
+                .map(|column| &column[..])
+        })
     }
 
     /// Returns an iterator over the queries' indices.
@@ -320,57 +836,937 @@ impl DataSet {
         })
     }
 
+    /// Returns the ideal DCG of each query, in the order of
+    /// `query_iter`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rforests::metric;
+    /// use rforests::train::dataset::DataSet;
+    ///
+    /// let data = vec![
+    ///     // label, qid, values
+    ///     (1.0, 1, vec![5.0]), // 0
+    ///     (3.0, 1, vec![7.0]), // 1
+    ///     (0.0, 2, vec![3.0]), // 2
+    /// ];
+    ///
+    /// let dataset: DataSet = data.into_iter().collect();
+    /// let metric = metric::new("DCG", 10).unwrap();
+    /// let ideal = 7.0 / 2.0_f64.log2() + 1.0 / 3.0_f64.log2();
+    /// assert_eq!(dataset.ideal_dcgs(&metric), vec![ideal, 0.0]);
+    /// ```
+    pub fn ideal_dcgs(&self, metric: &Box<Measure>) -> Vec<f64> {
+        self.queries
+            .iter()
+            .map(|&(start, len)| {
+                let labels: Vec<Value> = self.instances[start..start + len]
+                    .iter()
+                    .map(|instance| instance.label())
+                    .collect();
+                metric.ideal_dcg(&labels)
+            })
+            .collect()
+    }
+
+    /// Returns the average of the metric over the queries ranked by
+    /// the scores of `e`. See `ZeroQueries` for which queries count,
+    /// and `TieBreak` for how the ties are ranked. The queries are
+    /// measured across the threads of `util::POOL`, so this must not
+    /// be called from one of them.
     pub fn evaluate<E: Evaluate>(
         &self,
         e: &E,
         metric: &Box<Measure>,
+        zero_queries: ZeroQueries,
+        tie_break: TieBreak,
     ) -> f64 {
-        let mut score = 0.0;
-        let mut count: usize = 0;
-        for (qid, query) in self.query_iter() {
-            let mut model_scores: Vec<(Id, Value)> = query
-                .iter()
-                .map(|&id| (id, e.evaluate(&self.instances[id])))
-                .collect();
-            model_scores.sort_by(|&(_index1, score1), &(_index2, score2)| {
-                score2.partial_cmp(&score1).unwrap_or(Equal)
-            });
+        self.evaluate_queries(e, metric, false, zero_queries, tie_break, None)
+    }
 
-            let labels: Vec<f64> = model_scores
-                .iter()
-                .map(|&(id, _)| self.instances[id].label())
-                .collect();
-            let query_score = metric.measure(&labels);
-            debug!("Model score for qid {}: {}", qid, score);
+    /// Like `evaluate`, but the scores of each query are rescaled by
+    /// the normalization, if any, before they are ranked.
+    pub fn evaluate_normalized<E: Evaluate>(
+        &self,
+        e: &E,
+        metric: &Box<Measure>,
+        zero_queries: ZeroQueries,
+        tie_break: TieBreak,
+        score_normalize: Option<ScoreNorm>,
+    ) -> f64 {
+        self.evaluate_queries(
+            e,
+            metric,
+            false,
+            zero_queries,
+            tie_break,
+            score_normalize,
+        )
+    }
+
+    /// Rescales the scores, in the order of the instances, within each
+    /// query by the normalization.
+    pub fn normalize_scores(&self, scores: &mut [f64], norm: ScoreNorm) {
+        for &(start, len) in self.queries.iter() {
+            norm.normalize(&mut scores[start..start + len]);
+        }
+    }
+
+    /// Like `evaluate`, but the average over the queries is weighted
+    /// by the weight of each query, which is the mean weight of its
+    /// instances.
+    pub fn evaluate_weighted<E: Evaluate>(
+        &self,
+        e: &E,
+        metric: &Box<Measure>,
+        zero_queries: ZeroQueries,
+        tie_break: TieBreak,
+    ) -> f64 {
+        self.evaluate_queries(e, metric, true, zero_queries, tie_break, None)
+    }
+
+    /// Measures the queries in chunks across the threads of
+    /// `util::POOL`, since they are independent. The scores of the
+    /// model are computed beforehand by `Evaluate::evaluate_all`, and
+    /// the score of each query is kept apart, so that the average is
+    /// summed in the order of the queries and does not depend on the
+    /// number of threads. The pool is locked only while the chunks are
+    /// measured.
+    fn evaluate_queries<E: Evaluate>(
+        &self,
+        e: &E,
+        metric: &Box<Measure>,
+        weighted: bool,
+        zero_queries: ZeroQueries,
+        tie_break: TieBreak,
+        score_normalize: Option<ScoreNorm>,
+    ) -> f64 {
+        let mut scores = e.evaluate_all(self);
+        if let Some(norm) = score_normalize {
+            self.normalize_scores(&mut scores, norm);
+        }
+
+        // The (score, weight) of each query, None if it does not count.
+        let mut results: Vec<Option<(f64, f64)>> =
+            vec![None; self.queries.len()];
+        {
+            let scores = &scores;
+            let measure = |queries: &[(usize, usize)],
+                           results: &mut [Option<(f64, f64)>]| {
+                for (&(start, len), result) in
+                    queries.iter().zip(results.iter_mut())
+                {
+                    *result = self.measure_query(
+                        scores,
+                        start,
+                        len,
+                        metric,
+                        weighted,
+                        zero_queries,
+                        tie_break,
+                    );
+                }
+            };
+
+            let threads = ::util::POOL.lock().unwrap().thread_count() as usize;
+            let chunk_size = ((self.queries.len() + threads - 1) / threads)
+                .max(MIN_CHUNK_QUERIES);
+            if chunk_size >= self.queries.len() {
+                measure(&self.queries, &mut results);
+            } else {
+                let measure = &measure;
+                let chunks = self.queries
+                    .chunks(chunk_size)
+                    .zip(results.chunks_mut(chunk_size));
+                let mut pool = ::util::POOL.lock().unwrap();
+                pool.scoped(|scoped| for (queries, results) in chunks {
+                    scoped.execute(move || measure(queries, results));
+                });
+            }
+        }
 
-            count += 1;
-            score += query_score;
+        let mut score = 0.0;
+        let mut count = 0.0;
+        for &(query_score, weight) in results.iter().filter_map(|r| r.as_ref())
+        {
+            count += weight;
+            score += query_score * weight;
         }
 
-        let result = score / count as f64;
+        let result = if count == 0.0 { 0.0 } else { score / count };
         debug!("Model score for validation data: {}", result);
         result
     }
-}
 
-impl std::ops::Deref for DataSet {
-    type Target = Vec<Instance>;
+    /// Ranks the query of the instances `start..start + len` by the
+    /// scores and returns its (score, weight), or None if the query
+    /// does not count. See `evaluate_queries`.
+    fn measure_query(
+        &self,
+        scores: &[f64],
+        start: usize,
+        len: usize,
+        metric: &Box<Measure>,
+        weighted: bool,
+        zero_queries: ZeroQueries,
+        tie_break: TieBreak,
+    ) -> Option<(f64, f64)> {
+        let query: Vec<Id> = (start..start + len).collect();
+        let mut ranked = query.clone();
+        tie_break.rank(
+            &mut ranked,
+            |id| scores[id],
+            |id| self.instances[id].label(),
+        );
 
-    fn deref(&self) -> &Vec<Instance> {
-        &self.instances
+        let labels: Vec<f64> = ranked
+            .iter()
+            .map(|&id| self.instances[id].label())
+            .collect();
+        if !zero_queries.counts(&labels) {
+            return None;
+        }
+        let query_score = metric.measure(&labels);
+        debug!(
+            "Model score for qid {}: {}",
+            self.instances[start].qid(),
+            query_score
+        );
+
+        let weight = if weighted {
+            let sum: f64 = query
+                .iter()
+                .map(|&id| self.instances[id].weight())
+                .sum();
+            sum / query.len() as f64
+        } else {
+            1.0
+        };
+        Some((query_score, weight))
     }
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
+impl DataSet {
+    /// Rescales the feature values of all the instances. Features
+    /// whose values are constant over the rescaled instances are left
+    /// unchanged.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rforests::train::dataset::{DataSet, NormalizeMethod};
+    ///
+    /// let data = vec![
+    ///     // label, qid, values
+    ///     (3.0, 1, vec![5.0, 1.0]),
+    ///     (2.0, 1, vec![7.0, 1.0]),
+    ///     (3.0, 2, vec![3.0, 1.0]),
+    /// ];
+    ///
+    /// let mut dataset: DataSet = data.into_iter().collect();
+    /// dataset.normalize(NormalizeMethod::MinMax);
+    ///
+    /// assert_eq!(dataset[0].value(1), 0.5);
+    /// assert_eq!(dataset[1].value(1), 1.0);
+    /// assert_eq!(dataset[2].value(1), 0.0);
+    /// assert_eq!(dataset[0].value(2), 1.0);
+    /// ```
+    pub fn normalize(&mut self, method: NormalizeMethod) {
+        self.columns = None;
+        match method {
+            NormalizeMethod::MinMax => {
+                let indices = (0..self.len()).collect::<Vec<usize>>();
+                self.normalize_min_max(&indices);
+            }
+            NormalizeMethod::ZScore => self.normalize_zscore(),
+            NormalizeMethod::MinMaxPerQuery => {
+                let queries = self.queries.clone();
+                for (start, len) in queries {
+                    let indices = (start..(start + len)).collect::<Vec<usize>>();
+                    self.normalize_min_max(&indices);
+                }
+            }
+        }
+    }
 
-    #[test]
-    fn test_generate_queries() {
-        let s = "0 qid:3864 1:1.0 2:0.0 3:0.0 4:0.0 5:0.0
-2 qid:3864 1:1.0 2:0.007042 3:0.0 4:0.0 5:0.221591
-0 qid:3865 1:0.289474 2:0.014085 3:0.4 4:0.0 5:0.085227";
-        let dataset = DataSet::load(::std::io::Cursor::new(s)).unwrap();
+    /// Min-max normalization of the instances at the given indices.
+    fn normalize_min_max(&mut self, indices: &[usize]) {
+        for fid in 1..(self.nfeatures + 1) {
+            let (min, max) = indices
+                .iter()
+                .map(|&index| self.instances[index].value(fid))
+                .fold((std::f64::MAX, std::f64::MIN), |(min, max), value| {
+                    (min.min(value), max.max(value))
+                });
+
+            let range = max - min;
+            if range <= 0.0 {
+                continue;
+            }
+
+            for &index in indices {
+                let instance = &mut self.instances[index];
+                let value = instance.value(fid);
+                instance.set_value(fid, (value - min) / range);
+            }
+        }
+    }
+
+    /// Z-score normalization over all the instances.
+    fn normalize_zscore(&mut self) {
+        if self.instances.is_empty() {
+            return;
+        }
+
+        let count = self.len() as f64;
+        for fid in 1..(self.nfeatures + 1) {
+            let mean = self.feature_value_iter(fid).sum::<f64>() / count;
+            let variance = self.feature_value_iter(fid)
+                .map(|value| (value - mean) * (value - mean))
+                .sum::<f64>() / count;
+            let std = variance.sqrt();
+            if std <= 0.0 {
+                continue;
+            }
+
+            for instance in self.instances.iter_mut() {
+                let value = instance.value(fid);
+                instance.set_value(fid, (value - mean) / std);
+            }
+        }
+    }
+}
+
+impl DataSet {
+    /// Remaps the ids of the features that have non-zero values to
+    /// the contiguous range 1..=n, dropping the absent features. Data
+    /// with sparse, high feature ids then takes less space.
+    ///
+    /// Returns the original id of each compacted id, i.e. the
+    /// compacted id `i` was `mapping[i - 1]`. Apply the mapping to
+    /// other data sets with `remap_features`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rforests::train::dataset::DataSet;
+    ///
+    /// let s = "3.0 qid:1 1:5.0 700:1.0
+    /// 2.0 qid:2 5:7.0";
+    ///
+    /// let cursor = ::std::io::Cursor::new(s);
+    /// let mut dataset = DataSet::load_sparse(cursor).unwrap();
+    /// let mapping = dataset.compact_features();
+    ///
+    /// assert_eq!(mapping, vec![1, 5, 700]);
+    /// assert_eq!(dataset.fid_iter().count(), 3);
+    /// assert_eq!(dataset[0].value(3), 1.0);
+    /// assert_eq!(dataset[1].value(2), 7.0);
+    /// ```
+    pub fn compact_features(&mut self) -> Vec<Id> {
+        let mut present = vec![false; self.nfeatures];
+        for instance in self.instances.iter() {
+            for id in instance.nonzero_ids() {
+                present[id - 1] = true;
+            }
+        }
+
+        let mapping: Vec<Id> = present
+            .iter()
+            .enumerate()
+            .filter(|&(_, &present)| present)
+            .map(|(index, _)| index + 1)
+            .collect();
+        self.remap_features(&mapping);
+        mapping
+    }
+
+    /// Remaps the feature ids by a mapping returned by
+    /// `compact_features`. Features not in the mapping are dropped.
+    pub fn remap_features(&mut self, mapping: &[Id]) {
+        let max_id = mapping.iter().cloned().max().unwrap_or(0);
+        let mut new_ids = vec![None; max_id];
+        for (index, &id) in mapping.iter().enumerate() {
+            new_ids[id - 1] = Some(index + 1);
+        }
+
+        for instance in self.instances.iter_mut() {
+            instance.remap_features(&new_ids, mapping.len());
+        }
+        self.columns = None;
+        self.nfeatures = mapping.len();
+    }
+
+    /// Drops the features which do not meet the criterion, and remaps
+    /// the ids of the kept ones to the contiguous range 1..=n as
+    /// `compact_features` does. Returns the original ids of the
+    /// removed features.
+    ///
+    /// The kept ids are the others in ascending order. Apply them to
+    /// other data sets with `remap_features`, and save them with the
+    /// model by `Ensemble::set_feature_map` so that the model maps
+    /// unseen data back to the original ids.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rforests::train::dataset::{DataSet, FeatureCriterion};
+    ///
+    /// let s = "2 qid:1 1:5.0 2:1.0 3:3.0
+    /// 0 qid:1 1:5.0 2:0.0 3:1.0";
+    ///
+    /// let mut dataset = DataSet::load(::std::io::Cursor::new(s)).unwrap();
+    /// let removed =
+    ///     dataset.select_features(FeatureCriterion::MinVariance(0.0));
+    ///
+    /// assert_eq!(removed, vec![1]);
+    /// assert_eq!(dataset.fid_iter().count(), 2);
+    /// assert_eq!(dataset[0].value(2), 3.0);
+    /// ```
+    pub fn select_features(&mut self, criterion: FeatureCriterion) -> Vec<Id> {
+        let stats = FilesStats::from(&*self);
+        let stats: Vec<FeatureStat> = stats.feature_stats().cloned().collect();
+        let mut keep = vec![false; self.nfeatures];
+        match criterion {
+            FeatureCriterion::MinVariance(min) => for stat in stats.iter() {
+                keep[stat.id - 1] = stat.variance() > min;
+            },
+            FeatureCriterion::TopKByCorrelation(k) => {
+                let correlations = self.label_correlations(&stats);
+                let mut ranked: Vec<usize> = (0..stats.len()).collect();
+                ranked.sort_by_key(|&index| {
+                    (Reverse(OrderedScore(correlations[index].abs())), index)
+                });
+                for &index in ranked.iter().take(k) {
+                    keep[stats[index].id - 1] = true;
+                }
+            }
+        }
+
+        let (mapping, removed): (Vec<Id>, Vec<Id>) =
+            self.fid_iter().partition(|&id| keep[id - 1]);
+        self.remap_features(&mapping);
+        removed
+    }
+
+    /// Returns the Pearson correlation of each feature of the stats
+    /// with the label. A feature or label of zero variance has a
+    /// correlation of 0.0.
+    fn label_correlations(&self, stats: &[FeatureStat]) -> Vec<f64> {
+        let count = self.len() as f64;
+        let label_mean = self.label_iter().sum::<f64>() / count;
+        let label_variance = self.label_iter()
+            .map(|label| (label - label_mean) * (label - label_mean))
+            .sum::<f64>() / count;
+
+        stats
+            .iter()
+            .map(|stat| {
+                let product = self.feature_value_iter(stat.id)
+                    .zip(self.label_iter())
+                    .map(|(value, label)| value * label)
+                    .sum::<f64>() / count;
+                let covariance = product - stat.mean() * label_mean;
+                let scale = (stat.variance() * label_variance).sqrt();
+                if scale > 0.0 {
+                    covariance / scale
+                } else {
+                    0.0
+                }
+            })
+            .collect()
+    }
+
+    /// Collapses the instances of a query with identical feature values
+    /// and labels into the first of them, whose weight becomes the sum
+    /// of their weights. Instances of different queries are never
+    /// collapsed. Returns the number of instances removed.
+    ///
+    /// Note that a ranking metric of the deduplicated data differs,
+    /// since the removed duplicates no longer take positions.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rforests::train::dataset::DataSet;
+    ///
+    /// let s = "1 qid:1 1:5.0 2:1.0
+    /// 1 qid:1 1:5.0 2:1.0
+    /// 0 qid:1 1:5.0 2:1.0
+    /// 1 qid:2 1:5.0 2:1.0";
+    ///
+    /// let cursor = ::std::io::Cursor::new(s);
+    /// let mut dataset = DataSet::load(cursor).unwrap();
+    ///
+    /// assert_eq!(dataset.dedup_within_query(), 1);
+    /// assert_eq!(dataset.len(), 3);
+    /// assert_eq!(dataset[0].weight(), 2.0);
+    /// assert_eq!(dataset.query_iter().count(), 2);
+    /// ```
+    pub fn dedup_within_query(&mut self) -> usize {
+        let mut instances: Vec<Instance> = Vec::with_capacity(self.len());
+        let mut queries = Vec::with_capacity(self.queries.len());
+        for &(start, len) in self.queries.iter() {
+            let query_start = instances.len();
+            // (label, non-zero values) as bits -> index in instances
+            let mut seen: HashMap<(u64, Vec<(Id, u64)>), usize> =
+                HashMap::new();
+            for instance in self.instances[start..start + len].iter() {
+                let values = instance
+                    .value_iter()
+                    .filter(|&(_, value)| value != 0.0)
+                    .map(|(id, value)| (id, value.to_bits()))
+                    .collect();
+                let key = (instance.label().to_bits(), values);
+                if let Some(&index) = seen.get(&key) {
+                    let weight = instances[index].weight() + instance.weight();
+                    instances[index].set_weight(weight);
+                    continue;
+                }
+                seen.insert(key, instances.len());
+                instances.push(instance.clone());
+            }
+            queries.push((query_start, instances.len() - query_start));
+        }
+
+        let removed = self.instances.len() - instances.len();
+        info!("Collapsed {} duplicate instances", removed);
+        self.instances = instances;
+        self.queries = queries;
+        self.columns = None;
+        removed
+    }
+
+    /// Removes the queries with fewer than `min_docs` or more than
+    /// `max_docs` instances. The remaining instances keep their order.
+    /// Returns the number of queries removed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rforests::train::dataset::DataSet;
+    ///
+    /// let s = "1 qid:1 1:5.0
+    /// 1 qid:2 1:5.0
+    /// 0 qid:2 1:3.0
+    /// 1 qid:3 1:2.0";
+    ///
+    /// let cursor = ::std::io::Cursor::new(s);
+    /// let mut dataset = DataSet::load(cursor).unwrap();
+    ///
+    /// assert_eq!(dataset.filter_queries(2, usize::max_value()), 2);
+    /// assert_eq!(dataset.len(), 2);
+    /// assert_eq!(dataset.query_iter().count(), 1);
+    /// ```
+    pub fn filter_queries(
+        &mut self,
+        min_docs: usize,
+        max_docs: usize,
+    ) -> usize {
+        let mut instances: Vec<Instance> = Vec::with_capacity(self.len());
+        let mut queries = Vec::with_capacity(self.queries.len());
+        for &(start, len) in self.queries.iter() {
+            if len < min_docs || len > max_docs {
+                continue;
+            }
+            queries.push((instances.len(), len));
+            instances.extend_from_slice(&self.instances[start..start + len]);
+        }
+
+        let removed = self.queries.len() - queries.len();
+        info!("Removed {} queries out of the length range", removed);
+        self.instances = instances;
+        self.queries = queries;
+        self.columns = None;
+        removed
+    }
+
+    /// Concatenates the data sets in order into one, e.g. the data
+    /// split into several files. The qids are assumed to be disjoint
+    /// across the data sets. Otherwise, with `rename_qids`, the qids of
+    /// each data set are offset past those of the previous ones, and
+    /// a non-numeric qid is prefixed by the index of its data set, as
+    /// in "1:GX008".
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rforests::train::dataset::DataSet;
+    ///
+    /// let load = |s: &str| DataSet::load(::std::io::Cursor::new(s)).unwrap();
+    /// let a = load("1 qid:1 1:5.0\n0 qid:2 1:3.0");
+    /// let b = load("1 qid:1 1:2.0 2:1.0");
+    ///
+    /// let dataset = DataSet::concat(vec![a.clone(), b.clone()], false);
+    /// assert_eq!(dataset.len(), 3);
+    /// assert_eq!(dataset.fid_iter().count(), 2);
+    /// assert_eq!(dataset[2].qid(), 1);
+    ///
+    /// let dataset = DataSet::concat(vec![a, b], true);
+    /// assert_eq!(dataset[2].qid(), 4);
+    /// ```
+    pub fn concat(datasets: Vec<DataSet>, rename_qids: bool) -> DataSet {
+        let mut instances = Vec::new();
+        // The qids of the next data set start from this.
+        let mut offset = 0;
+        for (index, dataset) in datasets.into_iter().enumerate() {
+            let next = dataset
+                .instances
+                .iter()
+                .map(|instance| offset + instance.qid() + 1)
+                .max()
+                .unwrap_or(offset);
+            let qid_labels = dataset.qid_labels;
+            for mut instance in dataset.instances {
+                // Restores the text of the qid to map it again.
+                if let Some(label) = qid_labels.get(instance.qid()) {
+                    let label = if rename_qids {
+                        format!("{}:{}", index, label)
+                    } else {
+                        label.clone()
+                    };
+                    instance.set_qid_label(Some(label));
+                }
+                if rename_qids {
+                    let qid = instance.qid() + offset;
+                    instance.set_qid(qid);
+                }
+                instances.push(instance);
+            }
+            offset = next;
+        }
+
+        // All the instances are Ok.
+        DataSet::from_instances(instances.into_iter().map(Ok)).unwrap()
+    }
+
+    /// Clamps the labels greater than `max_label` to it, e.g. the
+    /// absurd grades of mislabeled instances, whose gains `2^label -
+    /// 1` would dominate the training. Returns the number of labels
+    /// clamped.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rforests::train::dataset::DataSet;
+    ///
+    /// let s = "50 qid:1 1:5.0
+    /// 2 qid:1 1:3.0";
+    ///
+    /// let cursor = ::std::io::Cursor::new(s);
+    /// let mut dataset = DataSet::load(cursor).unwrap();
+    ///
+    /// assert_eq!(dataset.clamp_labels(4.0), 1);
+    /// assert_eq!(dataset[0].label(), 4.0);
+    /// assert_eq!(dataset[1].label(), 2.0);
+    /// ```
+    pub fn clamp_labels(&mut self, max_label: Value) -> usize {
+        let mut clamped = 0;
+        for instance in self.instances.iter_mut() {
+            if instance.label > max_label {
+                instance.label = max_label;
+                clamped += 1;
+            }
+        }
+        if clamped > 0 {
+            warn!("Clamped {} labels greater than {}", clamped, max_label);
+        }
+        clamped
+    }
+
+    /// Divides the label of each instance by the propensity of its
+    /// position, `propensities[position - 1]`, i.e. the probability
+    /// that a document shown there is examined. This inverse
+    /// propensity weighting turns the clicks of a biased click log
+    /// into unbiased relevance labels for training. The instances
+    /// without positions are left unchanged.
+    ///
+    /// Fails, without changing any label, if a position has no
+    /// propensity or its propensity is not positive.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rforests::train::dataset::DataSet;
+    ///
+    /// let s = "1 qid:1 pos:1 1:5.0
+    /// 1 qid:1 pos:2 1:3.0
+    /// 0 qid:1 pos:3 1:1.0";
+    ///
+    /// let cursor = ::std::io::Cursor::new(s);
+    /// let mut dataset = DataSet::load(cursor).unwrap();
+    ///
+    /// dataset.debias_positions(&[1.0, 0.5, 0.25]).unwrap();
+    /// assert_eq!(dataset[0].label(), 1.0);
+    /// assert_eq!(dataset[1].label(), 2.0);
+    /// assert_eq!(dataset[2].label(), 0.0);
+    /// assert!(dataset.debias_positions(&[1.0, 0.5]).is_err());
+    /// ```
+    pub fn debias_positions(&mut self, propensities: &[f64]) -> Result<()> {
+        for instance in self.instances.iter() {
+            if let Some(position) = instance.position {
+                match propensities.get(position - 1) {
+                    Some(&propensity) if propensity > 0.0 => {}
+                    Some(&propensity) => Err(format!(
+                        "Invalid propensity of position {}: {}",
+                        position,
+                        propensity
+                    ))?,
+                    None => {
+                        Err(format!("No propensity of position {}", position))?
+                    }
+                }
+            }
+        }
+
+        for instance in self.instances.iter_mut() {
+            if let Some(position) = instance.position {
+                instance.label /= propensities[position - 1];
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends the other data set to this one, handling the qids in
+    /// both of them by the policy. The queries and the number of
+    /// features are those of the combined instances.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rforests::train::dataset::{CollisionPolicy, DataSet};
+    ///
+    /// let a: DataSet = vec![(1.0, 1, vec![5.0])].into_iter().collect();
+    /// let b: DataSet = vec![(0.0, 1, vec![3.0])].into_iter().collect();
+    ///
+    /// assert!(a.clone().merge(b.clone(), CollisionPolicy::Error).is_err());
+    /// let dataset = a.merge(b, CollisionPolicy::Offset).unwrap();
+    /// assert_eq!(dataset[1].qid(), 3);
+    /// ```
+    pub fn merge(
+        self,
+        other: DataSet,
+        on_qid_collision: CollisionPolicy,
+    ) -> Result<DataSet> {
+        let rename_qids = match on_qid_collision {
+            CollisionPolicy::Error => {
+                let qids: HashSet<Cow<str>> = self.query_iter()
+                    .map(|(qid, _)| self.qid_label(qid))
+                    .collect();
+                let collision = other
+                    .query_iter()
+                    .map(|(qid, _)| other.qid_label(qid))
+                    .find(|qid| qids.contains(qid));
+                if let Some(qid) = collision {
+                    Err(format!("Duplicate qid {}", qid))?;
+                }
+                false
+            }
+            CollisionPolicy::Offset => true,
+            CollisionPolicy::Keep => false,
+        };
+        Ok(DataSet::concat(vec![self, other], rename_qids))
+    }
+
+    /// Shuffles the instances of each query with the seed. Each query
+    /// keeps its place and its instances, only their order within the
+    /// query changes. The same seed always gives the same order.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rforests::train::dataset::DataSet;
+    ///
+    /// let s = "3 qid:1 1:5.0
+    /// 2 qid:1 1:3.0
+    /// 1 qid:1 1:2.0
+    /// 0 qid:2 1:1.0";
+    ///
+    /// let cursor = ::std::io::Cursor::new(s);
+    /// let mut dataset = DataSet::load(cursor).unwrap();
+    /// dataset.shuffle_within_queries(7);
+    ///
+    /// let mut labels: Vec<f64> =
+    ///     dataset.iter().take(3).map(|i| i.label()).collect();
+    /// labels.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    /// assert_eq!(labels, vec![1.0, 2.0, 3.0]);
+    /// assert_eq!(dataset[3].qid(), 2);
+    /// ```
+    pub fn shuffle_within_queries(&mut self, seed: u64) {
+        let mut rng = Rng::new(seed);
+        for &(start, len) in self.queries.iter() {
+            rng.shuffle(&mut self.instances[start..start + len]);
+        }
+        self.columns = None;
+    }
+}
+
+/// Counts of the instances, queries and labels of a data set. See
+/// `DataSet::stats`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DataSetStats {
+    pub num_instances: usize,
+    pub num_queries: usize,
+    pub num_features: usize,
+    /// 0.0 if there are no queries.
+    pub mean_query_len: f64,
+    pub min_query_len: usize,
+    pub max_query_len: usize,
+    /// The number of instances of each label, rounded to the nearest
+    /// integer.
+    pub label_histogram: BTreeMap<i64, usize>,
+}
+
+/// Statistics of a data set, for checking that a file is parsed as
+/// expected: the counts of `DataSet::stats` and the statistics of each
+/// feature.
+pub struct Summary {
+    pub counts: DataSetStats,
+    pub features: FilesStats,
+}
+
+impl DataSet {
+    /// Returns the counts of the instances, queries and labels.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rforests::train::dataset::DataSet;
+    ///
+    /// let s = "2 qid:1 1:5.0
+    /// 0 qid:1 1:3.0
+    /// 2 qid:2 2:1.0";
+    ///
+    /// let dataset = DataSet::load(::std::io::Cursor::new(s)).unwrap();
+    /// let stats = dataset.stats();
+    ///
+    /// assert_eq!(stats.num_queries, 2);
+    /// assert_eq!(stats.mean_query_len, 1.5);
+    /// assert_eq!(stats.label_histogram[&2], 2);
+    /// ```
+    pub fn stats(&self) -> DataSetStats {
+        let query_lens = self.queries.iter().map(|&(_start, len)| len);
+        let mut label_histogram = BTreeMap::new();
+        for label in self.label_iter() {
+            *label_histogram.entry(label.round() as i64).or_insert(0) += 1;
+        }
+
+        let num_queries = self.queries.len();
+        DataSetStats {
+            num_instances: self.len(),
+            num_queries: num_queries,
+            num_features: self.fid_iter().count(),
+            mean_query_len: if num_queries == 0 {
+                0.0
+            } else {
+                self.len() as f64 / num_queries as f64
+            },
+            min_query_len: query_lens.clone().min().unwrap_or(0),
+            max_query_len: query_lens.max().unwrap_or(0),
+            label_histogram: label_histogram,
+        }
+    }
+
+    /// Returns the statistics of the data set.
+    pub fn summary(&self) -> Summary {
+        Summary {
+            counts: self.stats(),
+            features: FilesStats::from(self),
+        }
+    }
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let counts = &self.counts;
+        writeln!(f, "{:<20}: {}", "Instances", counts.num_instances)?;
+        writeln!(f, "{:<20}: {}", "Queries", counts.num_queries)?;
+        writeln!(f, "{:<20}: {}", "Features", counts.num_features)?;
+        writeln!(
+            f,
+            "{:<20}: {} - {}",
+            "Query length",
+            counts.min_query_len,
+            counts.max_query_len
+        )?;
+        writeln!(
+            f,
+            "{:<20}: {:.2}",
+            "Mean query length",
+            counts.mean_query_len
+        )?;
+        for (label, count) in counts.label_histogram.iter() {
+            writeln!(f, "{:<20}: {}", format!("Label {}", label), count)?;
+        }
+        for stat in self.features.feature_stats() {
+            writeln!(f, "{:<20}: {}", format!("Feature {}", stat.id), stat)?;
+        }
+        Ok(())
+    }
+}
+
+/// Column-wise read access to the feature values and the labels of a
+/// data set, which is what the binning of the features for training
+/// reads. A storage other than `Vec<Instance>`, such as columns in a
+/// memory-mapped file, can implement it to be trained on.
+pub trait FeatureColumns {
+    /// Returns the number of instances.
+    fn instance_count(&self) -> usize;
+
+    /// Returns the ids of the features in ascending order.
+    fn feature_ids(&self) -> Vec<Id>;
+
+    /// Returns the values of the feature in the order of the
+    /// instances. Absent values are 0.0.
+    fn feature_column(&self, fid: Id) -> Cow<[Value]>;
+
+    /// Returns the labels in the order of the instances.
+    fn label_column(&self) -> Cow<[Value]>;
+}
+
+impl FeatureColumns for DataSet {
+    fn instance_count(&self) -> usize {
+        self.len()
+    }
+
+    fn feature_ids(&self) -> Vec<Id> {
+        self.fid_iter().collect()
+    }
+
+    /// Borrows the column if the data set is columnar. Otherwise the
+    /// instances are stored by rows, so the column is collected.
+    fn feature_column(&self, fid: Id) -> Cow<[Value]> {
+        match self.column(fid) {
+            Some(column) => Cow::Borrowed(column),
+            None => Cow::Owned(self.feature_value_iter(fid).collect()),
+        }
+    }
+
+    fn label_column(&self) -> Cow<[Value]> {
+        Cow::Owned(self.label_iter().collect())
+    }
+}
+
+impl std::ops::Deref for DataSet {
+    type Target = Vec<Instance>;
+
+    fn deref(&self) -> &Vec<Instance> {
+        &self.instances
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test::Bencher;
+
+    #[test]
+    fn test_generate_queries() {
+        let s = "0 qid:3864 1:1.0 2:0.0 3:0.0 4:0.0 5:0.0
+2 qid:3864 1:1.0 2:0.007042 3:0.0 4:0.0 5:0.221591
+0 qid:3865 1:0.289474 2:0.014085 3:0.4 4:0.0 5:0.085227";
+        let dataset = DataSet::load(::std::io::Cursor::new(s)).unwrap();
 
         assert_eq!(dataset.nfeatures, 5);
         assert_eq!(
@@ -388,4 +1784,522 @@ mod test {
         assert_eq!(dataset.queries[0], (0, 2));
         assert_eq!(dataset.queries[1], (2, 1));
     }
+
+    #[test]
+    fn test_try_value_sparse() {
+        let instance = Instance::new_sparse(1.0, 1, vec![(2, 5.0), (7, 1.0)]);
+        assert_eq!(instance.try_value(2), Some(5.0));
+        assert_eq!(instance.try_value(3), Some(0.0));
+        assert_eq!(instance.try_value(7), Some(1.0));
+        assert_eq!(instance.try_value(8), None);
+    }
+
+    #[test]
+    fn test_evaluate_weighted() {
+        struct FirstFeature;
+        impl Evaluate for FirstFeature {
+            fn evaluate(&self, instance: &Instance) -> f64 {
+                instance.value(1)
+            }
+        }
+
+        // The model ranks the first query perfectly and the second
+        // query wrongly.
+        let s = "1 qid:1 cost:3.0 1:2.0
+0 qid:1 cost:3.0 1:1.0
+1 qid:2 1:1.0
+0 qid:2 1:2.0";
+        let dataset = DataSet::load(::std::io::Cursor::new(s)).unwrap();
+        let metric = new("NDCG", 10).unwrap();
+
+        let second = 1.0 / 3.0_f64.log2();
+        let include = ZeroQueries::Include;
+        let index = TieBreak::Index;
+        let unweighted =
+            dataset.evaluate(&FirstFeature, &metric, include, index);
+        assert_eq!(unweighted, (1.0 + second) / 2.0);
+        let weighted =
+            dataset.evaluate_weighted(&FirstFeature, &metric, include, index);
+        assert_eq!(weighted, (3.0 + second) / 4.0);
+    }
+
+    #[test]
+    fn test_evaluate_many_queries() {
+        struct FirstFeature;
+        impl Evaluate for FirstFeature {
+            fn evaluate(&self, instance: &Instance) -> f64 {
+                instance.value(1)
+            }
+        }
+
+        // Enough queries to be measured across the threads.
+        let mut rng = Rng::new(7);
+        let data: Vec<(Value, Id, Vec<Value>)> = (0..3000)
+            .map(|i| {
+                let label = rng.gen_index(5) as Value;
+                (label, i / 3, vec![rng.next_f64()])
+            })
+            .collect();
+        let dataset: DataSet = data.into_iter().collect();
+        let metric = new("NDCG", 10).unwrap();
+
+        // Summed serially in the order of the queries.
+        let mut sum = 0.0;
+        for (_qid, mut query) in dataset.query_iter() {
+            query.sort_by_key(|&id| {
+                (Reverse(OrderedScore(dataset[id].value(1))), id)
+            });
+            let labels: Vec<f64> =
+                query.iter().map(|&id| dataset[id].label()).collect();
+            sum += metric.measure(&labels);
+        }
+        let expected = sum / 1000.0;
+
+        let score = dataset.evaluate(
+            &FirstFeature,
+            &metric,
+            ZeroQueries::Include,
+            TieBreak::Index,
+        );
+        assert_eq!(score, expected);
+    }
+
+    #[test]
+    fn test_evaluate_zero_queries() {
+        struct FirstFeature;
+        impl Evaluate for FirstFeature {
+            fn evaluate(&self, instance: &Instance) -> f64 {
+                instance.value(1)
+            }
+        }
+
+        let s = "1 qid:1 1:2.0
+0 qid:1 1:1.0
+0 qid:2 1:1.0
+0 qid:2 1:2.0";
+        let dataset = DataSet::load(::std::io::Cursor::new(s)).unwrap();
+        let metric = new("NDCG", 10).unwrap();
+
+        let index = TieBreak::Index;
+        let include = ZeroQueries::Include;
+        let score = dataset.evaluate(&FirstFeature, &metric, include, index);
+        assert_eq!(score, 0.5);
+        let exclude = ZeroQueries::Exclude;
+        let score = dataset.evaluate(&FirstFeature, &metric, exclude, index);
+        assert_eq!(score, 1.0);
+    }
+
+    #[test]
+    fn test_evaluate_tie_break() {
+        struct Constant;
+        impl Evaluate for Constant {
+            fn evaluate(&self, _instance: &Instance) -> f64 {
+                1.0
+            }
+        }
+
+        let s = "0 qid:1 1:1.0
+1 qid:1 1:1.0";
+        let dataset = DataSet::load(::std::io::Cursor::new(s)).unwrap();
+        let metric = new("NDCG", 10).unwrap();
+        let include = ZeroQueries::Include;
+
+        // The tied instances are ranked in the order of the data.
+        let index = TieBreak::Index;
+        let score = dataset.evaluate(&Constant, &metric, include, index);
+        assert_eq!(score, 1.0 / 3.0_f64.log2());
+
+        // The tied instances are ranked by descending label.
+        let trec = TieBreak::Trec;
+        let score = dataset.evaluate(&Constant, &metric, include, trec);
+        assert_eq!(score, 1.0);
+    }
+
+    #[test]
+    fn test_evaluate_normalized() {
+        struct FirstFeature;
+        impl Evaluate for FirstFeature {
+            fn evaluate(&self, instance: &Instance) -> f64 {
+                instance.value(1)
+            }
+        }
+
+        let s = "1 qid:1 1:2.0
+0 qid:1 1:1.0
+1 qid:2 1:10.0
+0 qid:2 1:30.0
+2 qid:2 1:20.0";
+        let dataset = DataSet::load(::std::io::Cursor::new(s)).unwrap();
+
+        let mut scores = FirstFeature.evaluate_all(&dataset);
+        dataset.normalize_scores(&mut scores, ScoreNorm::PerQueryZScore);
+        let z = 1.5_f64.sqrt();
+        assert_eq!(scores, vec![1.0, -1.0, -z, z, 0.0]);
+
+        // The normalization keeps the ranking within each query.
+        let metric = new("NDCG", 10).unwrap();
+        let include = ZeroQueries::Include;
+        let index = TieBreak::Index;
+        let norm = Some(ScoreNorm::PerQueryZScore);
+        assert_eq!(
+            dataset.evaluate_normalized(
+                &FirstFeature,
+                &metric,
+                include,
+                index,
+                norm
+            ),
+            dataset.evaluate(&FirstFeature, &metric, include, index)
+        );
+    }
+
+    #[test]
+    fn test_columnarize() {
+        let s = "3 qid:1 1:5.0 3:2.0
+2 qid:1 2:7.0
+1 qid:2 1:1.0 2:4.0";
+        let cursor = ::std::io::Cursor::new(s);
+        let mut dataset = DataSet::load_sparse(cursor).unwrap();
+        let rows: Vec<Vec<Value>> = dataset
+            .fid_iter()
+            .map(|fid| dataset.feature_value_iter(fid).collect())
+            .collect();
+
+        dataset.columnarize();
+        assert!(dataset.is_columnar());
+        for fid in dataset.fid_iter() {
+            assert_eq!(dataset.feature_column(fid), &rows[fid - 1][..]);
+            assert_eq!(
+                dataset.feature_value_iter(fid).collect::<Vec<_>>(),
+                rows[fid - 1]
+            );
+        }
+        assert!(dataset.feature_value_iter(9).all(|value| value == 0.0));
+
+        // Stale columns are dropped.
+        dataset.normalize(NormalizeMethod::MinMax);
+        assert!(!dataset.is_columnar());
+        assert_eq!(
+            dataset.feature_value_iter(1).collect::<Vec<_>>(),
+            vec![1.0, 0.0, 0.2]
+        );
+    }
+
+    #[test]
+    fn test_normalize() {
+        let data = vec![
+            // label, qid, values
+            (3.0, 1, vec![0.0, 2.0]),
+            (2.0, 1, vec![2.0, 2.0]),
+            (1.0, 2, vec![0.0, 2.0]),
+            (0.0, 2, vec![2.0, 2.0]),
+        ];
+        let dataset: DataSet = data.into_iter().collect();
+
+        let mut zscore = dataset.clone();
+        zscore.normalize(NormalizeMethod::ZScore);
+        // mean 1.0, std 1.0
+        assert_eq!(
+            zscore.feature_value_iter(1).collect::<Vec<_>>(),
+            vec![-1.0, 1.0, -1.0, 1.0]
+        );
+        // Zero variance feature is unchanged.
+        assert_eq!(
+            zscore.feature_value_iter(2).collect::<Vec<_>>(),
+            vec![2.0; 4]
+        );
+
+        let mut per_query = dataset.clone();
+        per_query.normalize(NormalizeMethod::MinMaxPerQuery);
+        assert_eq!(
+            per_query.feature_value_iter(1).collect::<Vec<_>>(),
+            vec![0.0, 1.0, 0.0, 1.0]
+        );
+        assert_eq!(
+            per_query.feature_value_iter(2).collect::<Vec<_>>(),
+            vec![2.0; 4]
+        );
+    }
+
+    #[test]
+    fn test_load_sparse() {
+        let s = "0 qid:3864 1:1.0 5:0.5
+2 qid:3864 2:0.007042 4:0.1
+0 qid:3865 3:0.4";
+        let dense = DataSet::load(::std::io::Cursor::new(s)).unwrap();
+        let sparse = DataSet::load_sparse(::std::io::Cursor::new(s)).unwrap();
+
+        assert_eq!(sparse.nfeatures, dense.nfeatures);
+        assert_eq!(sparse.queries, dense.queries);
+        for (d, s) in dense.iter().zip(sparse.iter()) {
+            assert!(!d.is_sparse());
+            assert!(s.is_sparse());
+            assert_eq!(s.max_feature_id(), d.max_feature_id());
+            assert_eq!(
+                s.value_iter().collect::<Vec<_>>(),
+                d.value_iter().collect::<Vec<_>>()
+            );
+            assert_eq!(s.to_string(), d.to_string());
+        }
+    }
+
+    #[test]
+    fn test_load_across_chunks() {
+        // More lines than a single chunk, with queries spanning the
+        // chunk boundaries.
+        let s = (0..25000)
+            .map(|i| format!("{} qid:{} 1:{}", i % 3, i / 7, i))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let dataset = DataSet::load(::std::io::Cursor::new(s)).unwrap();
+
+        assert_eq!(dataset.len(), 25000);
+        assert!(
+            dataset
+                .iter()
+                .enumerate()
+                .all(|(i, instance)| instance.value(1) == i as f64)
+        );
+        assert_eq!(dataset.queries.len(), (25000 + 6) / 7);
+        assert_eq!(dataset.queries[1], (7, 7));
+    }
+
+    #[test]
+    fn test_load_string_qids() {
+        let s = "0 qid:GX008 1:1.0
+2 qid:GX008 1:2.0
+1 qid:7 1:3.0
+0 qid:GX010 1:4.0";
+        let dataset = DataSet::load_sparse(::std::io::Cursor::new(s)).unwrap();
+
+        let qids: Vec<Id> = dataset.iter().map(|i| i.qid()).collect();
+        assert_eq!(qids, vec![0, 0, 1, 2]);
+        assert_eq!(dataset.queries, vec![(0, 2), (2, 1), (3, 1)]);
+        assert_eq!(dataset.qid_label(0), "GX008");
+        assert_eq!(dataset.qid_label(1), "7");
+        assert_eq!(dataset.qid_label(2), "GX010");
+        assert!(dataset.iter().all(|i| i.qid_label().is_none()));
+
+        // Numeric qids are kept.
+        let dataset = DataSet::load(::std::io::Cursor::new("0 qid:7 1:1.0"))
+            .unwrap();
+        assert_eq!(dataset[0].qid(), 7);
+        assert_eq!(dataset.qid_label(7), "7");
+    }
+
+    #[test]
+    fn test_dedup_within_query() {
+        let s = "1 qid:1 cost:0.5 1:2.0 3:0.0
+0 qid:1 1:1.0
+1 qid:1 1:2.0
+0 qid:2 1:1.0
+0 qid:2 1:1.0
+0 qid:2 1:1.0";
+        let mut dataset = DataSet::load(::std::io::Cursor::new(s)).unwrap();
+
+        assert_eq!(dataset.dedup_within_query(), 3);
+        assert_eq!(dataset.queries, vec![(0, 2), (2, 1)]);
+        let weights: Vec<Value> = dataset.iter().map(|i| i.weight()).collect();
+        assert_eq!(weights, vec![1.5, 1.0, 3.0]);
+        let labels: Vec<Value> = dataset.label_iter().collect();
+        assert_eq!(labels, vec![1.0, 0.0, 0.0]);
+
+        assert_eq!(dataset.dedup_within_query(), 0);
+    }
+
+    #[test]
+    fn test_filter_queries() {
+        let s = "1 qid:1 1:1.0
+0 qid:2 1:2.0
+1 qid:2 1:3.0
+0 qid:3 1:4.0
+1 qid:3 1:5.0
+2 qid:3 1:6.0
+0 qid:4 1:7.0";
+        let mut dataset = DataSet::load(::std::io::Cursor::new(s)).unwrap();
+
+        assert_eq!(dataset.filter_queries(2, 2), 3);
+        assert_eq!(dataset.queries, vec![(0, 2)]);
+        let values: Vec<Value> = dataset.iter().map(|i| i.value(1)).collect();
+        assert_eq!(values, vec![2.0, 3.0]);
+        let qids: Vec<Id> =
+            dataset.query_iter().map(|(qid, _indices)| qid).collect();
+        assert_eq!(qids, vec![2]);
+    }
+
+    #[test]
+    fn test_concat_string_qids() {
+        let load = |s: &str| DataSet::load(::std::io::Cursor::new(s)).unwrap();
+        let a = load("1 qid:GX008 1:5.0\n0 qid:GX010 1:3.0");
+        let b = load("1 qid:GX008 1:2.0");
+
+        let dataset = DataSet::concat(vec![a.clone(), b.clone()], false);
+        assert_eq!(dataset.len(), 3);
+        assert_eq!(dataset[2].qid(), dataset[0].qid());
+        assert_eq!(dataset.qid_label(dataset[1].qid()), "GX010");
+
+        let dataset = DataSet::concat(vec![a, b], true);
+        assert_eq!(dataset.qid_label(dataset[0].qid()), "0:GX008");
+        assert_eq!(dataset.qid_label(dataset[2].qid()), "1:GX008");
+        assert!(dataset[2].qid() != dataset[0].qid());
+    }
+
+    #[test]
+    fn test_merge() {
+        let a: DataSet = vec![
+            (3.0, 1, vec![5.0]),
+            (2.0, 1, vec![7.0]),
+            (1.0, 4, vec![2.0]),
+        ].into_iter()
+            .collect();
+        let b: DataSet = vec![(0.0, 4, vec![1.0, 6.0]), (1.0, 2, vec![3.0])]
+            .into_iter()
+            .collect();
+
+        assert!(a.clone().merge(b.clone(), CollisionPolicy::Error).is_err());
+
+        let merged = a.clone().merge(b.clone(), CollisionPolicy::Keep).unwrap();
+        assert_eq!(
+            merged.query_iter().collect::<Vec<_>>(),
+            vec![(1, vec![0, 1]), (4, vec![2, 3]), (2, vec![4])]
+        );
+        assert_eq!(merged.fid_iter().count(), 2);
+
+        let merged = a.clone().merge(b, CollisionPolicy::Offset).unwrap();
+        assert_eq!(
+            merged.query_iter().collect::<Vec<_>>(),
+            vec![(1, vec![0, 1]), (4, vec![2]), (9, vec![3]), (7, vec![4])]
+        );
+
+        let c: DataSet = vec![(0.0, 3, vec![1.0])].into_iter().collect();
+        let merged = a.merge(c, CollisionPolicy::Error).unwrap();
+        assert_eq!(merged.queries, vec![(0, 2), (2, 1), (3, 1)]);
+    }
+
+    #[test]
+    fn test_shuffle_within_queries() {
+        let path = "./data/train-lite.txt";
+        let f = std::fs::File::open(path).unwrap();
+        let dataset = DataSet::load(f).unwrap();
+        let shuffled = |seed: u64| {
+            let mut dataset = dataset.clone();
+            dataset.shuffle_within_queries(seed);
+            dataset
+        };
+        let values = |dataset: &DataSet| -> Vec<Vec<Value>> {
+            dataset
+                .iter()
+                .map(|i| i.value_iter().map(|(_, v)| v).collect())
+                .collect()
+        };
+
+        let a = shuffled(1);
+        assert_eq!(values(&a), values(&shuffled(1)));
+        assert!(values(&a) != values(&shuffled(2)));
+        assert!(values(&a) != values(&dataset));
+        assert_eq!(a.queries, dataset.queries);
+        for &(start, len) in dataset.queries.iter() {
+            let qid = dataset[start].qid();
+            assert!(a.instances[start..start + len]
+                .iter()
+                .all(|i| i.qid() == qid));
+            let labels = |dataset: &DataSet| {
+                let mut labels: Vec<Value> = dataset.instances
+                    [start..start + len]
+                    .iter()
+                    .map(|i| i.label())
+                    .collect();
+                labels.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                labels
+            };
+            assert_eq!(labels(&a), labels(&dataset));
+        }
+    }
+
+    #[test]
+    fn test_select_features() {
+        // Feature 1 follows the label, 2 is the opposite but weaker,
+        // 3 is constant, and 4 is noise.
+        let s = "2 qid:1 1:2.0 2:0.0 3:1.0 4:1.0
+1 qid:1 1:1.0 2:1.0 3:1.0 4:0.0
+0 qid:1 1:0.0 2:1.0 3:1.0 4:0.0
+0 qid:2 1:0.0 2:1.0 3:1.0 4:1.0";
+        let load = || DataSet::load(::std::io::Cursor::new(s)).unwrap();
+
+        let mut dataset = load();
+        let criterion = FeatureCriterion::TopKByCorrelation(2);
+        assert_eq!(dataset.select_features(criterion), vec![3, 4]);
+        assert_eq!(dataset.fid_iter().count(), 2);
+        assert_eq!(dataset[0].value(1), 2.0);
+        assert_eq!(dataset[1].value(2), 1.0);
+
+        let mut dataset = load();
+        let criterion = FeatureCriterion::MinVariance(0.2);
+        assert_eq!(dataset.select_features(criterion), vec![2, 3]);
+        assert_eq!(dataset[0].value(2), 1.0);
+
+        // The kept ids map the original data for a model.
+        let mut other = load();
+        other.remap_features(&[1, 4]);
+        assert_eq!(*other, *dataset);
+
+        let mut dataset = load();
+        let criterion = FeatureCriterion::TopKByCorrelation(10);
+        assert!(dataset.select_features(criterion).is_empty());
+        assert_eq!(*dataset, *load());
+    }
+
+    #[test]
+    fn test_load_error() {
+        let s = "0 qid:1 1:1.0\n0 qid:1 1:x";
+        assert!(DataSet::load(::std::io::Cursor::new(s)).is_err());
+    }
+
+    #[test]
+    fn test_summary() {
+        let data = vec![
+            (2.0, 1, vec![1.0, -1.0]),
+            (0.0, 1, vec![3.0, 0.0]),
+            (2.0, 2, vec![2.0, 4.0]),
+            (1.0, 3, vec![5.0, 0.0]),
+            (0.0, 3, vec![0.0, 2.0]),
+            (2.0, 3, vec![4.0, 1.0]),
+        ];
+        let dataset: DataSet = data.into_iter().collect();
+        let summary = dataset.summary();
+
+        let counts = &summary.counts;
+        assert_eq!(counts, &dataset.stats());
+        assert_eq!(counts.num_instances, 6);
+        assert_eq!(counts.num_queries, 3);
+        assert_eq!(counts.num_features, 2);
+        assert_eq!(counts.min_query_len, 1);
+        assert_eq!(counts.max_query_len, 3);
+        assert_eq!(counts.mean_query_len, 2.0);
+        let histogram: Vec<(i64, usize)> = counts
+            .label_histogram
+            .iter()
+            .map(|(&label, &count)| (label, count))
+            .collect();
+        assert_eq!(histogram, vec![(0, 2), (1, 1), (2, 3)]);
+
+        let ranges: Vec<(Id, Value, Value)> = summary
+            .features
+            .feature_stats()
+            .map(|stat| (stat.id, stat.min, stat.max))
+            .collect();
+        assert_eq!(ranges, vec![(1, 0.0, 5.0), (2, -1.0, 4.0)]);
+    }
+
+    #[bench]
+    fn bench_load(b: &mut Bencher) {
+        let s = (0..1000000)
+            .map(|i| {
+                format!("{} qid:{} 1:{} 2:0.5 5:{}", i % 5, i / 100, i, i % 7)
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        b.iter(|| DataSet::load(::std::io::Cursor::new(&s)).unwrap());
+    }
 }
@@ -0,0 +1,237 @@
+use clap::{App, Arg, ArgMatches, SubCommand};
+use std;
+use train::dataset::*;
+use train::lambdamart::loss::SquaredLoss;
+use train::lambdamart::regression_tree::*;
+use train::lambdamart::training_set::*;
+use train::validate_set::*;
+use util::{open_input, Result};
+
+/// Configurable options for GBRT.
+pub struct Config {
+    pub train: DataSet,
+    pub validate: Option<DataSet>,
+
+    pub trees: usize,
+    pub max_leaves: usize,
+    pub learning_rate: f64,
+    pub thresholds: usize,
+    pub min_leaf_samples: usize,
+    pub print_metric: bool,
+}
+
+/// Gradient boosted regression trees with squared error loss. The
+/// labels are fitted pointwise, and the qids are ignored.
+pub struct GBRT {
+    config: Config,
+    ensemble: Ensemble,
+}
+
+impl GBRT {
+    /// Create a new GBRT instance.
+    pub fn new(config: Config) -> GBRT {
+        GBRT {
+            config: config,
+            ensemble: Ensemble::new(),
+        }
+    }
+
+    /// Learns from the given training data. Returns the RMSE on the
+    /// training data after each iteration.
+    pub fn learn(&mut self) -> Result<Vec<f64>> {
+        let mut training =
+            TrainSet::new(&self.config.train, self.config.thresholds);
+        let mut validate =
+            self.config.validate.as_ref().map(|v| ValidateSet::from(v));
+        let mut history = Vec::with_capacity(self.config.trees);
+
+        self.print_metric_header();
+        for i in 0..self.config.trees {
+            training.update_gradients(&SquaredLoss);
+
+            let mut tree = RegressionTree::new(
+                self.config.learning_rate,
+                self.config.max_leaves,
+                self.config.min_leaf_samples,
+            );
+            let leaf_output = tree.fit(&training)?;
+            training.update_result(&leaf_output);
+
+            let train_rmse = training.rmse();
+            validate.as_mut().map(|v| v.update(&tree));
+            let validate_rmse = validate.as_ref().map(|v| v.rmse());
+
+            self.ensemble.push(tree);
+            history.push(train_rmse);
+
+            self.print_metric(i, train_rmse, validate_rmse);
+        }
+
+        Ok(history)
+    }
+
+    fn print(&self, msg: &str) {
+        if self.config.print_metric {
+            println!("{}", msg);
+        }
+    }
+
+    /// Print metric header.
+    fn print_metric_header(&self) {
+        self.print(
+            &format!("{:<7} | {:>9} | {:>9}", "#iter", "RMSE-T", "RMSE-V"),
+        );
+    }
+
+    /// Print metric of each iteration.
+    fn print_metric(
+        &self,
+        iteration: usize,
+        train_rmse: f64,
+        validate_rmse: Option<f64>,
+    ) {
+        let s = format!(
+            "{:<7} | {:>9.4} | {}",
+            iteration,
+            train_rmse,
+            validate_rmse
+                .map(|rmse| format!("{:>9.4}", rmse))
+                .unwrap_or("".to_string())
+        );
+        self.print(&s);
+    }
+}
+
+impl ::train::Evaluate for GBRT {
+    fn evaluate(&self, instance: &Instance) -> f64 {
+        self.ensemble.evaluate(instance)
+    }
+}
+
+fn load_dataset(path: &str, label_column: usize) -> Result<DataSet> {
+    let file = open_input(path)?;
+    DataSet::load_with_label_column(file, label_column)
+}
+
+pub fn main<'a>(matches: &ArgMatches<'a>) {
+    let validate_file_path = matches.value_of("validate-file");
+    let trees = value_t!(matches.value_of("trees"), usize).unwrap_or_else(
+        |e| e.exit(),
+    );
+    let leaves = value_t!(matches.value_of("leaves"), usize)
+        .unwrap_or_else(|e| e.exit());
+    let shrinkage = value_t!(matches.value_of("shrinkage"), f64)
+        .unwrap_or_else(|e| e.exit());
+    let thresholds = value_t!(matches.value_of("thresholds"), usize)
+        .unwrap_or_else(|e| e.exit());
+    let min_leaf_samples =
+        value_t!(matches.value_of("min-leaf-support"), usize)
+            .unwrap_or_else(|e| e.exit());
+    let label_column = value_t!(matches.value_of("label-column"), usize)
+        .unwrap_or_else(|e| e.exit());
+
+    let load = |path: &str| {
+        load_dataset(path, label_column).unwrap_or_else(|e| {
+            error!("Failed to load {}: {}", path, e);
+            std::process::exit(1)
+        })
+    };
+
+    let config = Config {
+        train: super::load_train_files(matches),
+        validate: validate_file_path.map(&load),
+        trees: trees,
+        max_leaves: leaves,
+        learning_rate: shrinkage,
+        thresholds: thresholds,
+        min_leaf_samples: min_leaf_samples,
+        print_metric: true,
+    };
+
+    let mut gbrt = GBRT::new(config);
+    gbrt.learn().unwrap();
+}
+
+pub fn clap_command<'a, 'b>() -> App<'a, 'b> {
+    let train_common_args = super::common_args();
+    // GBRT args
+    let gbrt_command = SubCommand::with_name("gbrt")
+        .about("Train gradient boosted regression trees")
+        .args(&train_common_args)
+        .arg(
+            Arg::with_name("trees")
+                .long("trees")
+                .takes_value(true)
+                .value_name("NUM")
+                .default_value("1000")
+                .display_order(101)
+                .help("Number of trees"),
+        )
+        .arg(
+            Arg::with_name("leaves")
+                .long("leaves")
+                .takes_value(true)
+                .value_name("NUM")
+                .default_value("10")
+                .display_order(102)
+                .help("Number of leaves for each tree"),
+        )
+        .arg(
+            Arg::with_name("shrinkage")
+                .long("shrinkage")
+                .value_name("FACTOR")
+                .takes_value(true)
+                .default_value("0.1")
+                .display_order(103)
+                .help("Shrinkage, or learning rate"),
+        )
+        .arg(
+            Arg::with_name("thresholds")
+                .long("thresholds")
+                .takes_value(true)
+                .value_name("NUM")
+                .default_value("256")
+                .display_order(104)
+                .help("Number of threshold candidates for tree spliting"),
+        )
+        .arg(
+            Arg::with_name("min-leaf-support")
+                .long("min-leaf-support")
+                .takes_value(true)
+                .value_name("NUM")
+                .default_value("1")
+                .display_order(105)
+                .help("Min leaf support -- minimum #samples each leaf has to contain"),
+        );
+    gbrt_command
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::File;
+
+    #[test]
+    fn test_gbrt_rmse_decreases() {
+        let path = "./data/train-lite.txt";
+        let f = File::open(path).unwrap();
+        let dataset = DataSet::load(f).unwrap();
+
+        let config = Config {
+            train: dataset,
+            validate: None,
+            trees: 20,
+            max_leaves: 10,
+            learning_rate: 0.1,
+            thresholds: 256,
+            min_leaf_samples: 1,
+            print_metric: false,
+        };
+        let mut gbrt = GBRT::new(config);
+        let history = gbrt.learn().unwrap();
+
+        assert_eq!(history.len(), 20);
+        assert!(history.windows(2).all(|w| w[1] <= w[0]));
+        assert!(history.last().unwrap() < history.first().unwrap());
+    }
+}
@@ -11,14 +11,24 @@ struct HistogramBin {
 
     // Accumulated sum of all the labels of this and preceding bins.
     acc_sum: f64,
+
+    // Accumulated sum of the hessians, i.e. the Newton step weights,
+    // of this and preceding bins.
+    acc_weight: f64,
 }
 
 impl HistogramBin {
-    pub fn new(threshold: f64, acc_count: usize, acc_sum: f64) -> HistogramBin {
+    pub fn new(
+        threshold: f64,
+        acc_count: usize,
+        acc_sum: f64,
+        acc_weight: f64,
+    ) -> HistogramBin {
         HistogramBin {
             threshold: threshold,
             acc_count: acc_count,
             acc_sum: acc_sum,
+            acc_weight: acc_weight,
         }
     }
 }
@@ -27,18 +37,155 @@ impl std::fmt::Debug for HistogramBin {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
             f,
-            "HistogramBin {{ threshold: {}, acc_count: {}, acc_sum: {} }}",
+            "HistogramBin {{ threshold: {}, acc_count: {}, acc_sum: {}, \
+             acc_weight: {} }}",
             if self.threshold == std::f64::MAX {
                 "f64::MAX".to_string()
             } else {
                 self.threshold.to_string()
             },
             self.acc_count,
-            self.acc_sum.to_string()
+            self.acc_sum.to_string(),
+            self.acc_weight.to_string()
         )
     }
 }
 
+/// How the threshold of a feature is chosen when splitting a node.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SplitStrategy {
+    /// The threshold that reduces the variance the most.
+    Best,
+    /// A random threshold, as in Extremely Randomized Trees.
+    Random,
+}
+
+impl std::str::FromStr for SplitStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<SplitStrategy, String> {
+        match s {
+            "best" => Ok(SplitStrategy::Best),
+            "random" => Ok(SplitStrategy::Random),
+            _ => Err(format!("Invalid split strategy: {}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for SplitStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            SplitStrategy::Best => write!(f, "best"),
+            SplitStrategy::Random => write!(f, "random"),
+        }
+    }
+}
+
+/// Measures how much a split of the labels of a sample improves the
+/// fit, given the sum and the count of the labels on each side. The
+/// histograms keep only these sums, so a criterion does not touch
+/// their accumulation.
+pub trait SplitCriterion: Sync {
+    fn gain(
+        &self,
+        sum_left: f64,
+        count_left: usize,
+        sum_right: f64,
+        count_right: usize,
+    ) -> f64;
+}
+
+/// The reduction of the sum of the squared errors around the means,
+/// sum_left ^ 2 / count_left + sum_right ^ 2 / count_right - sum ^ 2
+/// / count. See `Histogram::best_split`.
+pub struct VarianceReduction;
+
+impl SplitCriterion for VarianceReduction {
+    fn gain(
+        &self,
+        sum_left: f64,
+        count_left: usize,
+        sum_right: f64,
+        count_right: usize,
+    ) -> f64 {
+        let sum = sum_left + sum_right;
+        let count = (count_left + count_right) as f64;
+        sum_left * sum_left / count_left as f64 +
+            sum_right * sum_right / count_right as f64 -
+            sum * sum / count
+    }
+}
+
+/// Friedman's improvement, count_left * count_right / count *
+/// (mean_left - mean_right) ^ 2. Without instance weights it equals
+/// the variance reduction, but it is computed from the difference of
+/// the means rather than of large squared sums, so it loses less
+/// precision when the labels are large.
+pub struct FriedmanMse;
+
+impl SplitCriterion for FriedmanMse {
+    fn gain(
+        &self,
+        sum_left: f64,
+        count_left: usize,
+        sum_right: f64,
+        count_right: usize,
+    ) -> f64 {
+        let count_left = count_left as f64;
+        let count_right = count_right as f64;
+        let diff = sum_left / count_left - sum_right / count_right;
+        count_left * count_right / (count_left + count_right) * diff * diff
+    }
+}
+
+/// Which `SplitCriterion` chooses the splits of the trees.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Criterion {
+    /// See `VarianceReduction`.
+    Variance,
+    /// See `FriedmanMse`.
+    Friedman,
+}
+
+impl Criterion {
+    /// Returns the criterion.
+    pub fn split_criterion(&self) -> &'static SplitCriterion {
+        match *self {
+            Criterion::Variance => &VarianceReduction,
+            Criterion::Friedman => &FriedmanMse,
+        }
+    }
+}
+
+impl std::str::FromStr for Criterion {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Criterion, String> {
+        match s {
+            "variance" => Ok(Criterion::Variance),
+            "friedman" => Ok(Criterion::Friedman),
+            _ => Err(format!("Invalid split criterion: {}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Criterion {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Criterion::Variance => write!(f, "variance"),
+            Criterion::Friedman => write!(f, "friedman"),
+        }
+    }
+}
+
+/// Returns whether either side of a split has less than `min_hessian`
+/// of the sum of the hessians. A limit of 0.0 never applies, so that
+/// the rounding of the sums cannot drop a split.
+fn too_light(weight_left: f64, weight_right: f64, min_hessian: f64) -> bool {
+    min_hessian > 0.0 &&
+        (weight_left < min_hessian || weight_right < min_hessian)
+}
+
 #[derive(Debug)]
 pub struct Histogram {
     // [from, to]
@@ -50,11 +197,12 @@ impl Histogram {
         Histogram { bins: bins }
     }
 
-    /// Return the best splitting point. The returned value is of the
-    /// form (threshold, s value).
+    /// Return the best splitting point by the gain of `criterion`.
+    /// The returned value is of the form (threshold, gain).
     ///
-    /// The best split minimizes the sum of the variance of the left
-    /// part and right part.
+    /// With `VarianceReduction`, the default criterion, the best split
+    /// minimizes the sum of the variance of the left part and right
+    /// part, as follows.
     ///
     /// Minimize sum_of_variance = sum((left_labels - left_label_avg)
     /// ^ 2) + sum((right_labels - right_label_avg) ^ 2)
@@ -73,9 +221,26 @@ impl Histogram {
     ///
     /// To minimize the result, we just need to find a point that
     /// maximizes sum(left_label) ^ 2 + sum(right_labels) ^ 2
-    pub fn best_split(&self, min_leaf: usize) -> Option<(Value, f64)> {
-        let sum = self.bins.last().unwrap().acc_sum;
-        let count = self.bins.last().unwrap().acc_count;
+    ///
+    /// The gain of a split, i.e. the reduction of the variance, is
+    /// that sum minus sum(all_labels) ^ 2 / count. Splits whose gain
+    /// is less than `min_gain` are ignored, as are those leaving fewer
+    /// than `min_leaf` values or less than `min_hessian` of the sum of
+    /// the hessians on either side.
+    ///
+    /// Of the splits of equal gains, the one of the lowest threshold
+    /// is returned.
+    pub fn best_split(
+        &self,
+        min_leaf: usize,
+        min_hessian: f64,
+        min_gain: f64,
+        criterion: &SplitCriterion,
+    ) -> Option<(Value, f64)> {
+        let (sum, count, weight) = match self.bins.last() {
+            Some(bin) => (bin.acc_sum, bin.acc_count, bin.acc_weight),
+            None => return None,
+        };
         let mut split: Option<(f64, f64)> = None;
         for bin in self.bins.iter() {
             let count_left = bin.acc_count;
@@ -83,36 +248,178 @@ impl Histogram {
             if count_left < min_leaf || count_right < min_leaf {
                 continue;
             }
+            let weight_left = bin.acc_weight;
+            if too_light(weight_left, weight - weight_left, min_hessian) {
+                continue;
+            }
 
             let sum_left = bin.acc_sum;
             let sum_right = sum - sum_left;
 
-            let s_value = sum_left * sum_left / count_left as f64 +
-                sum_right * sum_right / count_right as f64;
+            let gain =
+                criterion.gain(sum_left, count_left, sum_right, count_right);
+            if gain < min_gain {
+                continue;
+            }
 
-            split = split.map_or(
-                Some((bin.threshold, s_value)),
-                |(old_threshold, old_s)| if s_value > old_s {
-                    Some((bin.threshold, s_value))
-                } else {
-                    Some((old_threshold, old_s))
-                },
-            );
+            // The bins are in ascending order of the thresholds, so
+            // only a strictly greater gain replaces the split.
+            let better = match split {
+                Some((_, old_gain)) => gain > old_gain,
+                None => true,
+            };
+            if better {
+                split = Some((bin.threshold, gain));
+            }
         }
 
         split
     }
+
+    /// Returns a split at a random threshold, of the form (threshold,
+    /// gain). The threshold is chosen uniformly among those that
+    /// leave at least `min_leaf` values and `min_hessian` of the sum
+    /// of the hessians on each side. Returns None if there is no such
+    /// threshold, or the gain of the chosen one is less than
+    /// `min_gain`. See `best_split`.
+    pub fn random_split(
+        &self,
+        min_leaf: usize,
+        min_hessian: f64,
+        min_gain: f64,
+        criterion: &SplitCriterion,
+        rng: &mut Rng,
+    ) -> Option<(Value, f64)> {
+        let (sum, count, weight) = match self.bins.last() {
+            Some(bin) => (bin.acc_sum, bin.acc_count, bin.acc_weight),
+            None => return None,
+        };
+        let candidates: Vec<&HistogramBin> = self.bins
+            .iter()
+            .filter(|bin| {
+                let weight_left = bin.acc_weight;
+                bin.acc_count >= min_leaf &&
+                    count - bin.acc_count >= min_leaf &&
+                    !too_light(weight_left, weight - weight_left, min_hessian)
+            })
+            .collect();
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let bin = candidates[rng.gen_index(candidates.len())];
+        let count_left = bin.acc_count;
+        let count_right = count - count_left;
+        let sum_left = bin.acc_sum;
+        let sum_right = sum - sum_left;
+        let gain = criterion.gain(sum_left, count_left, sum_right, count_right);
+        if gain < min_gain {
+            None
+        } else {
+            Some((bin.threshold, gain))
+        }
+    }
+}
+
+/// The count and the sum of the labels of each value of a categorical
+/// feature, which is split one category against the rest rather than
+/// at a threshold.
+#[derive(Debug)]
+pub struct CategoryHistogram {
+    /// (category, count, sum, weight) in ascending order of the
+    /// categories, where weight is the sum of the hessians.
+    bins: Vec<(Value, usize, f64, f64)>,
+}
+
+impl CategoryHistogram {
+    /// Returns the best split of one category against the rest, of
+    /// the form (category, gain). The gain and the limits are as in
+    /// `Histogram::best_split`. Of the splits of equal gains, the one
+    /// of the lowest category is returned.
+    pub fn best_split(
+        &self,
+        min_leaf: usize,
+        min_hessian: f64,
+        min_gain: f64,
+        criterion: &SplitCriterion,
+    ) -> Option<(Value, f64)> {
+        let (sum, count, weight) = self.bins.iter().fold(
+            (0.0, 0, 0.0),
+            |(sum, count, weight), &(_, bin_count, bin_sum, bin_weight)| {
+                (sum + bin_sum, count + bin_count, weight + bin_weight)
+            },
+        );
+        if count == 0 {
+            return None;
+        }
+
+        let mut split: Option<(Value, f64)> = None;
+        for &(category, count_left, sum_left, weight_left) in self.bins.iter()
+        {
+            let count_right = count - count_left;
+            if count_left < min_leaf || count_right < min_leaf {
+                continue;
+            }
+            if too_light(weight_left, weight - weight_left, min_hessian) {
+                continue;
+            }
+
+            let sum_right = sum - sum_left;
+            let gain =
+                criterion.gain(sum_left, count_left, sum_right, count_right);
+            if gain < min_gain {
+                continue;
+            }
+
+            let better = match split {
+                Some((_, old_gain)) => gain > old_gain,
+                None => true,
+            };
+            if better {
+                split = Some((category, gain));
+            }
+        }
+
+        split
+    }
+}
+
+impl FromIterator<(Value, Value, Value)> for CategoryHistogram {
+    /// Collects the (value, label, weight) of the instances.
+    fn from_iter<T>(iter: T) -> CategoryHistogram
+    where
+        T: IntoIterator<Item = (Value, Value, Value)>,
+    {
+        let mut bins = std::collections::BTreeMap::new();
+        for (value, label, weight) in iter {
+            let bin = bins.entry(OrderedScore(value)).or_insert((0, 0.0, 0.0));
+            bin.0 += 1;
+            bin.1 += label;
+            bin.2 += weight;
+        }
+
+        CategoryHistogram {
+            bins: bins
+                .into_iter()
+                .map(|(category, (count, sum, weight))| {
+                    (category.0, count, sum, weight)
+                })
+                .collect(),
+        }
+    }
 }
 
 use std::iter::FromIterator;
-impl FromIterator<(Value, usize, Value)> for Histogram {
+impl FromIterator<(Value, usize, Value, Value)> for Histogram {
+    /// Collects the (threshold, acc_count, acc_sum, acc_weight) of the
+    /// bins.
     fn from_iter<T>(iter: T) -> Histogram
     where
-        T: IntoIterator<Item = (Value, usize, Value)>,
+        T: IntoIterator<Item = (Value, usize, Value, Value)>,
     {
         let bins: Vec<HistogramBin> = iter.into_iter()
-            .map(|(threshold, acc_count, acc_sum)| {
-                HistogramBin::new(threshold, acc_count, acc_sum)
+            .map(|(threshold, acc_count, acc_sum, acc_weight)| {
+                HistogramBin::new(threshold, acc_count, acc_sum, acc_weight)
             })
             .collect();
 
@@ -123,7 +430,125 @@ impl FromIterator<(Value, usize, Value)> for Histogram {
 #[cfg(test)]
 mod test {
     // use train::dataset::*;
-    // use super::*;
+    use super::*;
+
+    #[test]
+    fn test_best_split() {
+        // Labels [0.0, 0.0, 3.0, 3.0] of values [1.0, 2.0, 3.0, 4.0].
+        // Splitting at 2.0 separates the labels, with s value
+        // 0.0 ^ 2 / 2 + 6.0 ^ 2 / 2.
+        let histogram: Histogram = vec![
+            (1.0, 1, 0.0, 1.0),
+            (2.0, 2, 0.0, 2.0),
+            (3.0, 3, 3.0, 3.0),
+            (std::f64::MAX, 4, 6.0, 4.0),
+        ].into_iter()
+            .collect();
+        // The gain of the split is 18.0 - 6.0 ^ 2 / 4.
+        let variance = &VarianceReduction;
+        let split = |min_leaf, min_gain| {
+            histogram.best_split(min_leaf, 0.0, min_gain, variance)
+        };
+        assert_eq!(split(1, 0.0), Some((2.0, 9.0)));
+        // Each side must hold at least 3 values.
+        assert_eq!(split(3, 0.0), None);
+        assert_eq!(split(1, 9.0), Some((2.0, 9.0)));
+        assert_eq!(split(1, 9.5), None);
+
+        // 2 * 2 / 4 * (0.0 - 3.0) ^ 2
+        let friedman = &FriedmanMse;
+        let split = histogram.best_split(1, 0.0, 0.0, friedman);
+        assert_eq!(split, Some((2.0, 9.0)));
+    }
+
+    #[test]
+    fn test_best_split_min_hessian() {
+        // As in test_best_split, but the first two values weigh 0.5
+        // each, so the best split leaves 1.0 of the hessians on the
+        // left.
+        let histogram: Histogram = vec![
+            (1.0, 1, 0.0, 0.5),
+            (2.0, 2, 0.0, 1.0),
+            (3.0, 3, 3.0, 2.0),
+            (std::f64::MAX, 4, 6.0, 4.0),
+        ].into_iter()
+            .collect();
+        let variance = &VarianceReduction;
+        let split = |min_hessian| {
+            histogram.best_split(1, min_hessian, 0.0, variance)
+        };
+        assert_eq!(split(1.0), Some((2.0, 9.0)));
+        // The next best split leaves 2.0 on each side.
+        let gain = 9.0 / 3.0 + 9.0 - 9.0;
+        assert_eq!(split(1.5), Some((3.0, gain)));
+        assert_eq!(split(2.5), None);
+
+        let mut rng = Rng::new(0);
+        let split = histogram.random_split(1, 1.5, 0.0, variance, &mut rng);
+        assert_eq!(split, Some((3.0, gain)));
+    }
+
+    #[test]
+    fn test_split_criterion() {
+        for &(sum_left, count_left, sum_right, count_right) in
+            &[(1.0, 1, 1.0, 3), (6.0, 2, -0.5, 5), (1e6, 3, 2e6, 4)]
+        {
+            let variance = VarianceReduction.gain(
+                sum_left,
+                count_left,
+                sum_right,
+                count_right,
+            );
+            let friedman =
+                FriedmanMse.gain(sum_left, count_left, sum_right, count_right);
+            assert!((variance - friedman).abs() <= 1e-9 * variance.abs());
+        }
+        for s in &["variance", "friedman"] {
+            assert_eq!(s.parse::<Criterion>().unwrap().to_string(), *s);
+        }
+        assert!("gini".parse::<Criterion>().is_err());
+    }
+
+    #[test]
+    fn test_best_split_tie() {
+        // Labels [1.0, 0.0, 0.0, 1.0] of values [1.0, 2.0, 3.0, 4.0].
+        // Splitting at 1.0 and at 3.0 have the same s value.
+        let histogram: Histogram = vec![
+            (1.0, 1, 1.0, 1.0),
+            (2.0, 2, 1.0, 2.0),
+            (3.0, 3, 1.0, 3.0),
+            (std::f64::MAX, 4, 2.0, 4.0),
+        ].into_iter()
+            .collect();
+        let gain = 1.0 + 1.0 / 3.0 - 1.0;
+        let split = histogram.best_split(1, 0.0, 0.0, &VarianceReduction);
+        assert_eq!(split, Some((1.0, gain)));
+    }
+
+    #[test]
+    fn test_category_best_split() {
+        // Labels [3.0, 0.0, 3.0, 0.0, 1.0] of categories [7, 2, 7, 2,
+        // 5]. Category 7 against the rest gives 6.0 ^ 2 / 2 + 1.0 ^ 2
+        // / 3, the best of the three.
+        let histogram: CategoryHistogram = vec![
+            (7.0, 3.0, 1.0),
+            (2.0, 0.0, 1.0),
+            (7.0, 3.0, 1.0),
+            (2.0, 0.0, 1.0),
+            (5.0, 1.0, 1.0),
+        ].into_iter()
+            .collect();
+        let criterion = &VarianceReduction;
+        let gain = 18.0 + 1.0 / 3.0 - 7.0 * 7.0 / 5.0;
+        let (category, split_gain) =
+            histogram.best_split(1, 0.0, 0.0, criterion).unwrap();
+        assert_eq!(category, 7.0);
+        assert!((split_gain - gain).abs() < 1e-9);
+        assert_eq!(histogram.best_split(3, 0.0, 0.0, criterion), None);
+        assert_eq!(histogram.best_split(1, 0.0, 9.0, criterion), None);
+        // Category 7 leaves 2.0 of the hessians on its side.
+        assert_eq!(histogram.best_split(1, 2.5, 0.0, criterion), None);
+    }
 
     // #[test]
     // fn test_feature_histogram() {
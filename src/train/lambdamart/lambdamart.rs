@@ -3,41 +3,236 @@ use train::dataset::*;
 use util::*;
 use metric::*;
 use super::training_set::*;
+use super::histogram::Criterion;
+use super::loss::Objective;
 use train::validate_set::*;
+use train::Evaluate;
+use std::collections::HashSet;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+/// Number of consecutive rounds in which the training score must
+/// improve by less than `Config::train_tolerance` to stop training.
+pub const TRAIN_TOLERANCE_ROUNDS: usize = 3;
+
+/// Called after each iteration with the iteration, the score on the
+/// training data and the score on the validation data.
+pub type Callback = Box<FnMut(usize, f64, Option<f64>)>;
+
+/// The scores after an iteration of the training. `validate` is the
+/// score on the primary validation set, if any.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IterationRecord {
+    pub iter: usize,
+    pub train: f64,
+    pub validate: Option<f64>,
+}
 
 /// A instance of LambdaMART algorithm.
 pub struct LambdaMART {
     config: Config,
     ensemble: Ensemble,
+    callback: Option<Callback>,
+    history: Vec<IterationRecord>,
+    /// The iteration with the best validation score, and the score.
+    best: Option<(usize, f64)>,
+    /// The thresholds of the training data, computed by `init`, or
+    /// by the first call to `learn` without `init`.
+    thresholds: Option<Thresholds>,
+    /// The prefix of the files of the training scores, and every how
+    /// many rounds they are written. See `set_dump_scores`.
+    dump_scores: Option<(String, usize)>,
 }
 
 /// Configurable options for LambdaMART.
 pub struct Config {
     pub train: DataSet,
-    pub validate: Option<DataSet>,
+    /// Named validation sets. The first one is the primary set, on
+    /// which early stopping is based. An empty name is displayed as
+    /// "V".
+    pub validate: Vec<(String, DataSet)>,
     pub test: Option<DataSet>,
 
     pub metric: Box<Measure>,
+    /// More metrics reported beside `metric` on the training and the
+    /// validation data each round, and on the testing data at the
+    /// end. Only `metric` drives the training.
+    pub report_metrics: Vec<Box<Measure>>,
+    /// The loss whose gradients the trees fit.
+    pub objective: Objective,
+    /// Whether the queries without relevant documents count in the
+    /// reported metrics.
+    pub zero_queries: ZeroQueries,
+    /// How the instances of equal scores are ranked in the reported
+    /// metrics.
+    pub tie_break: TieBreak,
     pub trees: usize,
     pub max_leaves: usize,
-    pub learning_rate: f64,
+    pub learning_rate: LearningRateSchedule,
     pub thresholds: usize,
+    /// How the thresholds of the features are generated.
+    pub binning: BinningStrategy,
+    /// If given, the thresholds are generated from a random sample of
+    /// at most this many instances. See
+    /// `TrainSet::with_threshold_sample`.
+    pub threshold_sample_size: Option<usize>,
+    /// Nodes with less instances are not split.
     pub min_leaf_samples: usize,
+    /// Minimal count of instances of each child of a split. See
+    /// `RegressionTree::set_min_leaf_support`.
+    pub min_leaf_support: usize,
+    pub min_split_gain: f64,
+    /// Minimal sum of the hessians of each child of a split. See
+    /// `RegressionTree::set_min_sum_hessian`.
+    pub min_sum_hessian: f64,
+    /// If given, the lambda of each instance is clamped into [-clip,
+    /// clip] before fitting each tree. See `TrainSet::set_lambda_clip`.
+    pub lambda_clip: Option<f64>,
+    /// How the gains of the splits are measured. See
+    /// `TrainSet::set_split_criterion`.
+    pub split_criterion: Criterion,
+    /// The features split by category instead of by threshold. See
+    /// `TrainSet::set_categorical_features`.
+    pub categorical_features: HashSet<Id>,
     pub early_stop: usize,
+    /// Minimum increase of the validation score to count as an
+    /// improvement for early stopping.
+    pub min_delta: f64,
+    /// If given, training stops when the training score increases by
+    /// less than this in `TRAIN_TOLERANCE_ROUNDS` consecutive rounds.
+    /// Unlike `early_stop`, this does not need a validation set, and
+    /// the two can be combined.
+    pub train_tolerance: Option<f64>,
+    /// Whether to fit the Platt calibration of the ensemble on the
+    /// primary validation set after training. See
+    /// `Ensemble::calibrate`.
+    pub calibrate: bool,
+    /// If given, the ensemble is saved to this file after training.
+    /// See `Ensemble::save`.
+    pub model_out: Option<String>,
+    /// The format in which `model_out` is written.
+    pub model_format: ModelFormat,
+    /// Seed of the random number generator used for sampling.
+    pub seed: u64,
     pub print_metric: bool,
+    /// Whether to print each tree after it is fitted. For debugging
+    /// on small data.
+    pub print_tree: bool,
+    /// Whether to print the time spent in each phase of the training
+    /// after the last round.
+    pub profile: bool,
+}
+
+/// The learning rate of each iteration.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LearningRateSchedule {
+    /// The same rate for all the iterations.
+    Constant(f64),
+    /// Starts with `initial`, and is multiplied by `factor` every
+    /// `every` iterations.
+    Step {
+        initial: f64,
+        factor: f64,
+        every: usize,
+    },
+    /// Changes linearly from `start` at the first iteration to `end`
+    /// at the last iteration.
+    Linear { start: f64, end: f64 },
+}
+
+impl LearningRateSchedule {
+    /// Parses a schedule specification with the given initial rate.
+    /// The specification is one of "constant", "step:FACTOR:EVERY",
+    /// and "linear:END".
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rforests::train::lambdamart::lambdamart::LearningRateSchedule;
+    ///
+    /// let schedule = LearningRateSchedule::parse("step:0.5:100", 0.1).unwrap();
+    /// assert_eq!(schedule.rate(0, 1000), 0.1);
+    /// assert_eq!(schedule.rate(100, 1000), 0.05);
+    ///
+    /// let schedule = LearningRateSchedule::parse("linear:0.0", 0.1).unwrap();
+    /// assert_eq!(schedule.rate(0, 11), 0.1);
+    /// assert_eq!(schedule.rate(10, 11), 0.0);
+    /// ```
+    pub fn parse(spec: &str, initial: f64) -> Result<LearningRateSchedule> {
+        let fields: Vec<&str> = spec.split(':').collect();
+        let schedule = match (fields[0], fields.len()) {
+            ("constant", 1) => LearningRateSchedule::Constant(initial),
+            ("step", 3) => LearningRateSchedule::Step {
+                initial: initial,
+                factor: fields[1].parse::<f64>()?,
+                every: fields[2].parse::<usize>()?,
+            },
+            ("linear", 2) => LearningRateSchedule::Linear {
+                start: initial,
+                end: fields[1].parse::<f64>()?,
+            },
+            _ => Err(format!("Invalid learning rate schedule: {}", spec))?,
+        };
+
+        if let LearningRateSchedule::Step { every: 0, .. } = schedule {
+            Err(format!("Invalid learning rate schedule: {}", spec))?;
+        }
+        Ok(schedule)
+    }
+
+    /// Returns the learning rate of the iteration, out of `total`
+    /// iterations.
+    pub fn rate(&self, iteration: usize, total: usize) -> f64 {
+        match *self {
+            LearningRateSchedule::Constant(rate) => rate,
+            LearningRateSchedule::Step {
+                initial,
+                factor,
+                every,
+            } => initial * factor.powi((iteration / every) as i32),
+            LearningRateSchedule::Linear { start, end } => {
+                if total <= 1 {
+                    start
+                } else {
+                    start +
+                        (end - start) * iteration as f64 / (total - 1) as f64
+                }
+            }
+        }
+    }
+}
+
+impl ::std::fmt::Display for LearningRateSchedule {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match *self {
+            LearningRateSchedule::Constant(rate) => write!(f, "{}", rate),
+            LearningRateSchedule::Step {
+                initial,
+                factor,
+                every,
+            } => write!(f, "{} x {} every {} trees", initial, factor, every),
+            LearningRateSchedule::Linear { start, end } => {
+                write!(f, "{} to {}", start, end)
+            }
+        }
+    }
 }
 
 struct BestScore {
     name: String,
+    min_delta: f64,
     iter: Option<usize>,
     train: Option<f64>,
     validate: Option<f64>,
 }
 
 impl BestScore {
-    pub fn new(name: &str) -> BestScore {
+    /// Creates a tracker of the best score. A validation score has to
+    /// exceed the best one by more than `min_delta` to replace it.
+    pub fn new(name: &str, min_delta: f64) -> BestScore {
         BestScore {
             name: name.to_string(),
+            min_delta: min_delta,
             iter: None,
             train: None,
             validate: None,
@@ -50,7 +245,7 @@ impl BestScore {
         self.validate = self.validate.or(validate);
 
         if let Some(validate) = validate {
-            if validate > self.validate.unwrap() {
+            if validate > self.validate.unwrap() + self.min_delta {
                 self.iter = Some(iter);
                 self.train = Some(train);
                 self.validate = Some(validate);
@@ -67,6 +262,14 @@ impl BestScore {
     pub fn best_iter(&self) -> Option<usize> {
         self.validate.and(self.iter)
     }
+
+    /// Returns the best iteration and its validate score.
+    pub fn best(&self) -> Option<(usize, f64)> {
+        match (self.iter, self.validate) {
+            (Some(iter), Some(validate)) => Some((iter, validate)),
+            _ => None,
+        }
+    }
 }
 
 impl ::std::fmt::Display for BestScore {
@@ -86,6 +289,44 @@ impl ::std::fmt::Display for BestScore {
     }
 }
 
+/// Wall-clock time spent in each phase of the training rounds, summed
+/// over the rounds.
+#[derive(Debug, Default)]
+struct PhaseTimes {
+    /// Computing the lambdas and the weights.
+    gradients: Duration,
+    /// Building the histograms and fitting the tree.
+    fit: Duration,
+    /// Updating the scores of the training and validation data.
+    update: Duration,
+    /// Measuring the metric on the training and validation data.
+    evaluation: Duration,
+}
+
+/// Returns the duration in seconds.
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 * 1e-9
+}
+
+impl ::std::fmt::Display for PhaseTimes {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        let phases = [
+            ("Gradients", self.gradients),
+            ("Tree fit", self.fit),
+            ("Update", self.update),
+            ("Evaluation", self.evaluation),
+        ];
+        let total: f64 = phases.iter().map(|&(_, d)| seconds(d)).sum();
+        writeln!(f, "{:<10} | {:>9} | {:>5}", "Phase", "Seconds", "%")?;
+        for &(name, duration) in phases.iter() {
+            let secs = seconds(duration);
+            let percent = if total > 0.0 { secs / total * 100.0 } else { 0.0 };
+            writeln!(f, "{:<10} | {:>9.3} | {:>5.1}", name, secs, percent)?;
+        }
+        Ok(())
+    }
+}
+
 impl LambdaMART {
     /// Create a new LambdaMART instance.
     ///
@@ -94,9 +335,14 @@ impl LambdaMART {
     /// ```no_run
     /// # use rforests::util::Result;
     /// # pub fn lambdamart(train_path: &str, valid_path: &str) -> Result<()> {
+    ///     use std::collections::HashSet;
     ///     use std::fs::File;
     ///     use rforests::train::dataset::*;
     ///     use rforests::train::lambdamart::lambdamart::*;
+    ///     use rforests::train::lambdamart::training_set::BinningStrategy;
+    ///     use rforests::train::lambdamart::histogram::Criterion;
+    ///     use rforests::train::lambdamart::loss::Objective;
+    ///     use rforests::train::lambdamart::regression_tree::ModelFormat;
     ///     use rforests::metric;
     ///
     ///     let f = File::open(train_path)?;
@@ -108,15 +354,35 @@ impl LambdaMART {
     ///     let config = Config {
     ///         train: dataset,
     ///         trees: 1000,
-    ///         learning_rate: 0.1,
+    ///         learning_rate: LearningRateSchedule::Constant(0.1),
     ///         max_leaves: 10,
     ///         min_leaf_samples: 1,
+    ///         min_leaf_support: 1,
+    ///         min_split_gain: 0.0,
+    ///         min_sum_hessian: 0.0,
     ///         thresholds: 256,
+    ///         binning: BinningStrategy::Uniform,
+    ///         threshold_sample_size: None,
+    ///         lambda_clip: None,
+    ///         split_criterion: Criterion::Variance,
+    ///         categorical_features: HashSet::new(),
     ///         print_metric: true,
+    ///         print_tree: false,
+    ///         report_metrics: Vec::new(),
+    ///         profile: false,
     ///         metric: metric::new("NDCG", 10).unwrap(),
-    ///         validate: Some(validate),
+    ///         objective: Objective::LambdaRank,
+    ///         zero_queries: metric::ZeroQueries::Include,
+    ///         tie_break: metric::TieBreak::Index,
+    ///         validate: vec![(String::new(), validate)],
     ///         test: None,
     ///         early_stop: 100,
+    ///         min_delta: 0.0,
+    ///         train_tolerance: None,
+    ///         calibrate: false,
+    ///         model_out: None,
+    ///         model_format: ModelFormat::Text,
+    ///         seed: 0,
     ///     };
     ///     let mut lambdamart = LambdaMART::new(config);
     ///     lambdamart.init()?;
@@ -128,74 +394,421 @@ impl LambdaMART {
         LambdaMART {
             config: config,
             ensemble: Ensemble::new(),
+            callback: None,
+            history: Vec::new(),
+            best: None,
+            thresholds: None,
+            dump_scores: None,
+        }
+    }
+
+    /// Sets a callback to observe the training. It replaces printing
+    /// the metric of each iteration.
+    pub fn set_callback(&mut self, f: Callback) {
+        self.callback = Some(f);
+    }
+
+    /// Writes the model scores of the training instances, one per
+    /// line, to "PREFIX.round_N.txt" after the rounds N = 0, every,
+    /// 2 * every, and so on. For debugging.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `every` is 0.
+    pub fn set_dump_scores(&mut self, prefix: &str, every: usize) {
+        assert!(every > 0, "the scores must be dumped every 1 or more rounds");
+        self.dump_scores = Some((prefix.to_string(), every));
+    }
+
+    /// Returns the scores of each iteration of the last training.
+    pub fn history(&self) -> &[IterationRecord] {
+        &self.history
+    }
+
+    /// Returns the iteration with the best score on the primary
+    /// validation set in the last training. It is None without a
+    /// validation set.
+    pub fn best_iteration(&self) -> Option<usize> {
+        self.best.map(|(iter, _)| iter)
+    }
+
+    /// Returns the best score on the primary validation set in the
+    /// last training.
+    pub fn best_validation_score(&self) -> Option<f64> {
+        self.best.map(|(_, score)| score)
+    }
+
+    /// Returns the trained model.
+    pub fn ensemble(&self) -> &Ensemble {
+        &self.ensemble
+    }
+
+    /// Drops the trees after the best iteration, if any.
+    pub fn truncate_to_best(&mut self) {
+        if let Some(iter) = self.best_iteration() {
+            self.ensemble.truncate(iter + 1);
         }
     }
 
-    /// Initializes LambdaMART algorithm.
-    pub fn init(&self) -> Result<()> {
+    /// Writes the history as CSV, with a header row
+    /// "iter,train,validate". The validate field is empty without a
+    /// validation set.
+    pub fn write_history<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writeln!(writer, "iter,train,validate")?;
+        for record in self.history.iter() {
+            let validate = record
+                .validate
+                .map(|score| score.to_string())
+                .unwrap_or(String::new());
+            writeln!(writer, "{},{},{}", record.iter, record.train, validate)?;
+        }
         Ok(())
     }
 
+    /// Initializes LambdaMART algorithm. Computes the thresholds of
+    /// the features of the training data, which is the one-time setup
+    /// of `learn`, and logs the time it takes. Warns about the
+    /// validation and test data whose number of features differs from
+    /// the training data, because the values of the missing features
+    /// are silently taken as 0.0.
+    pub fn init(&mut self) -> Result<()> {
+        let nfeatures = self.config.train.fid_iter().count();
+        for (name, n) in self.mismatched_features() {
+            warn!(
+                "{} data has {} features, but training data has {}",
+                name,
+                n,
+                nfeatures
+            );
+        }
+
+        let start = Instant::now();
+        let thresholds = self.compute_thresholds();
+        let elapsed = start.elapsed();
+        info!(
+            "Computed the thresholds of {} features in {}.{:03}s",
+            thresholds.feature_count(),
+            elapsed.as_secs(),
+            elapsed.subsec_nanos() / 1_000_000
+        );
+        self.thresholds = Some(thresholds);
+        Ok(())
+    }
+
+    /// Computes the thresholds of the training data as configured.
+    fn compute_thresholds(&self) -> Thresholds {
+        Thresholds::new(
+            &self.config.train,
+            self.config.thresholds,
+            self.config.binning,
+            self.config.threshold_sample_size,
+            self.config.seed,
+        )
+    }
+
+    /// Returns the name and the number of features of each validation
+    /// or test data whose number of features differs from the
+    /// training data.
+    fn mismatched_features(&self) -> Vec<(String, usize)> {
+        let nfeatures = self.config.train.fid_iter().count();
+        let validate = self.config.validate.iter().map(|&(ref name, ref v)| {
+            let name = if name.is_empty() { "Validation" } else { name };
+            (name.to_string(), v)
+        });
+        let test = self.config
+            .test
+            .iter()
+            .map(|test| ("Test".to_string(), test));
+        validate
+            .chain(test)
+            .map(|(name, dataset)| (name, dataset.fid_iter().count()))
+            .filter(|&(_, n)| n != nfeatures)
+            .collect()
+    }
+
     /// Learns from the given training data, using the configuration
     /// specified when creating LambdaMART instance.
     pub fn learn(&mut self) -> Result<()> {
+        self.ensemble = Ensemble::new();
+        self.history.clear();
+        self.best = None;
+        self.learn_rounds()
+    }
+
+    /// Trains `additional` more trees on top of the current ensemble.
+    /// The scores of the training and validation data are restored
+    /// from the ensemble, so `learn` followed by `continue_learn(k)`
+    /// results in the same trees as `learn` with `k` more trees. The
+    /// learning rate schedule then spans the new total of trees.
+    pub fn continue_learn(&mut self, additional: usize) -> Result<()> {
+        let trained = self.ensemble.len();
+        self.config.trees = trained + additional;
+        self.history.truncate(trained);
+        self.learn_rounds()
+    }
+
+    /// Trains the trees after the current ensemble, until there are
+    /// `config.trees` of them or training stops early.
+    fn learn_rounds(&mut self) -> Result<()> {
+        let start = self.ensemble.len();
+        let thresholds = match self.thresholds.take() {
+            Some(thresholds) => thresholds,
+            None => self.compute_thresholds(),
+        };
         let mut training =
-            TrainSet::new(&self.config.train, self.config.thresholds);
-        let mut validate =
-            self.config.validate.as_ref().map(|v| ValidateSet::from(v));
-        let mut best_score = BestScore::new(&self.config.metric.name());
+            TrainSet::with_thresholds(&self.config.train, thresholds);
+        training.set_lambda_clip(self.config.lambda_clip);
+        training.set_split_criterion(self.config.split_criterion);
+        training
+            .set_categorical_features(self.config.categorical_features.clone());
+        let mut validates: Vec<ValidateSet> = self.config
+            .validate
+            .iter()
+            .map(|&(_, ref v)| ValidateSet::from(v))
+            .collect();
+        let mut best_score = BestScore::new(
+            &self.config.metric.name(),
+            self.config.min_delta,
+        );
+        if start > 0 {
+            let scores = self.ensemble.evaluate_all(&self.config.train);
+            training.init_model_scores(&scores);
+            for v in validates.iter_mut() {
+                v.update(&self.ensemble);
+            }
+            for record in self.history.iter() {
+                best_score.update(record.iter, record.train, record.validate);
+            }
+        }
+        let loss = self.config
+            .objective
+            .loss(&self.config.metric, &self.config.train);
 
-        self.print_metric_header();
-        for i in 0..self.config.trees {
-            training.update_lambdas_weights(&self.config.metric);
+        if self.callback.is_none() {
+            self.print_metric_header();
+        }
+        let mut stopped = false;
+        // The number of consecutive rounds which improved the
+        // training score by less than the tolerance.
+        let mut flat_rounds = 0;
+        let mut times = PhaseTimes::default();
+        let mut rounds = 0;
+        let training_start = Instant::now();
+        for i in start..self.config.trees {
+            rounds += 1;
+            let timer = Instant::now();
+            training.update_gradients(&*loss);
+            times.gradients += timer.elapsed();
 
+            let timer = Instant::now();
             let mut tree = RegressionTree::new(
-                self.config.learning_rate,
+                self.config.learning_rate.rate(i, self.config.trees),
                 self.config.max_leaves,
                 self.config.min_leaf_samples,
             );
+            tree.set_min_leaf_support(self.config.min_leaf_support);
+            tree.set_min_split_gain(self.config.min_split_gain);
+            tree.set_min_sum_hessian(self.config.min_sum_hessian);
 
             // The scores of the model are updated when the tree node
             // does not split and becomes a leaf.
-            let leaf_output = tree.fit(&training);
+            let leaf_output = tree.fit(&training)?;
+            times.fit += timer.elapsed();
+            if self.config.print_tree {
+                tree.write_tree(&mut ::std::io::stdout())?;
+            }
 
             // Update the scores fitted by the regression tree.
+            let timer = Instant::now();
             training.update_result(&leaf_output);
+            times.update += timer.elapsed();
+
+            if let Some((ref prefix, every)) = self.dump_scores {
+                if i % every == 0 {
+                    let path = format!("{}.round_{}.txt", prefix, i);
+                    write_scores(&path, training.scores()).map_err(|e| {
+                        format!("Failed to write {}: {}", path, e)
+                    })?;
+                }
+            }
 
             // Measure on the training data set.
-            let train_score = training.measure(&self.config.metric);
+            let metric = &self.config.metric;
+            let zero_queries = self.config.zero_queries;
+            let tie_break = self.config.tie_break;
+            let timer = Instant::now();
+            let train_score =
+                training.measure(metric, zero_queries, tie_break);
+            times.evaluation += timer.elapsed();
+
+            // Update scores on validate sets.
+            let timer = Instant::now();
+            for v in validates.iter_mut() {
+                v.update(&tree);
+            }
+            times.update += timer.elapsed();
 
-            // Update scores on validate set.
-            validate.as_mut().map(|v| v.update(&tree));
+            // Measure on validate sets.
+            let timer = Instant::now();
+            let validate_scores: Vec<f64> = validates
+                .iter()
+                .map(|v| v.measure(metric, zero_queries, tie_break))
+                .collect();
+            times.evaluation += timer.elapsed();
+            let validate_score = validate_scores.first().cloned();
 
-            // Measure on validate set.
-            let validate_score =
-                validate.as_ref().map(|v| v.measure(&self.config.metric));
+            // Measure the reported metrics, which are only printed.
+            let timer = Instant::now();
+            let mut report_scores: Vec<(f64, Vec<f64>)> = Vec::new();
+            if self.callback.is_none() && self.config.print_metric {
+                for metric in self.config.report_metrics.iter() {
+                    let train_score =
+                        training.measure(metric, zero_queries, tie_break);
+                    let validate_scores = validates
+                        .iter()
+                        .map(|v| v.measure(metric, zero_queries, tie_break))
+                        .collect();
+                    report_scores.push((train_score, validate_scores));
+                }
+            }
+            times.evaluation += timer.elapsed();
 
             self.ensemble.push(tree);
+            self.history.push(IterationRecord {
+                iter: i,
+                train: train_score,
+                validate: validate_score,
+            });
 
-            self.print_metric(i, train_score, validate_score);
+            match self.callback {
+                Some(ref mut f) => f(i, train_score, validate_score),
+                None => self.print_metric(
+                    i,
+                    train_score,
+                    &validate_scores,
+                    &report_scores,
+                ),
+            }
 
             // Check if the best validation score is `early_stop`
             // round earlier.
             best_score.update(i, train_score, validate_score);
+            self.best = best_score.best();
 
             let stop = best_score
                 .best_iter()
                 .map(|iter| iter + self.config.early_stop < i)
                 .unwrap_or(false);
             if stop {
-                self.ensemble.truncate(best_score.best_iter().unwrap());
+                stopped = true;
                 break;
             }
+
+            if let Some(tolerance) = self.config.train_tolerance {
+                let previous = match i {
+                    0 => None,
+                    _ => self.history.get(i - 1).map(|record| record.train),
+                };
+                match previous {
+                    Some(previous) if train_score - previous < tolerance => {
+                        flat_rounds += 1;
+                    }
+                    _ => flat_rounds = 0,
+                }
+                if flat_rounds >= TRAIN_TOLERANCE_ROUNDS {
+                    break;
+                }
+            }
+        }
+
+        let elapsed = seconds(training_start.elapsed());
+        info!(
+            "Trained {} rounds in {:.3}s, {:.2} rounds/s",
+            rounds,
+            elapsed,
+            if elapsed > 0.0 { rounds as f64 / elapsed } else { 0.0 }
+        );
+        if self.config.profile {
+            print!("{}", times);
+        }
+
+        // Kept for `continue_learn`.
+        self.thresholds = Some(training.into_thresholds());
+        // Releases the borrow of the metric.
+        drop(loss);
+        if stopped {
+            self.truncate_to_best();
+        }
+
+        if self.config.print_metric && self.callback.is_none() {
+            println!("{}", best_score);
+        }
+
+        if self.config.calibrate {
+            match self.config.validate.first() {
+                Some(&(_, ref validate)) => self.ensemble.calibrate(validate),
+                None => Err("Calibration requires a validation set")?,
+            }
+        }
+
+        // Evaluate the final ensemble, after the early stopping, on the
+        // test data.
+        if let Some(ref test) = self.config.test {
+            println!(
+                "{} on testing data: {:.4}",
+                self.config.metric.name(),
+                self.evaluate(test)
+            );
+            for metric in self.config.report_metrics.iter() {
+                let score = test.evaluate(
+                    &self.ensemble,
+                    metric,
+                    self.config.zero_queries,
+                    self.config.tie_break,
+                );
+                println!("{} on testing data: {:.4}", metric.name(), score);
+            }
         }
 
-        println!("{}", best_score);
+        let metadata = self.metadata();
+        self.ensemble.set_metadata(metadata);
+        if let Some(ref path) = self.config.model_out {
+            let ensemble = &self.ensemble;
+            let format = self.config.model_format;
+            open_output(path)
+                .and_then(|writer| ensemble.save(writer, format))
+                .map_err(|e| format!("Failed to save {}: {}", path, e))?;
+        }
         Ok(())
     }
 
+    /// Returns the parameters of the training, saved with the model.
+    /// The number of trees is that of the trained model, which is less
+    /// than configured after early stopping.
+    fn metadata(&self) -> ModelMetadata {
+        let metric = &self.config.metric;
+        let name = metric.name();
+        ModelMetadata {
+            metric: name.split('@').next().map(|name| name.to_string()),
+            metric_k: Some(metric.get_k()),
+            gain_table: metric.gains().map(|gains| gains.to_vec()),
+            discount_base: Some(metric.discount_base()),
+            max_gain: metric.max_gain(),
+            learning_rate: Some(self.config.learning_rate.to_string()),
+            trees: Some(self.ensemble.len()),
+            leaves: Some(self.config.max_leaves),
+            thresholds: Some(self.config.thresholds),
+            seed: Some(self.config.seed),
+        }
+    }
+
     pub fn evaluate(&self, dataset: &DataSet) -> f64 {
-        dataset.evaluate(&self.ensemble, &self.config.metric)
+        dataset.evaluate(
+            &self.ensemble,
+            &self.config.metric,
+            self.config.zero_queries,
+            self.config.tie_break,
+        )
     }
 
     fn print(&self, msg: &str) {
@@ -204,34 +817,94 @@ impl LambdaMART {
         }
     }
 
+    /// Returns the header of the column of each validation set.
+    fn validate_columns(&self) -> Vec<String> {
+        let metric = self.config.metric.name();
+        if self.config.validate.is_empty() {
+            return vec![metric + "-V"];
+        }
+        self.named_validate_columns(metric)
+    }
+
+    /// Returns the header of the column of each validation set for
+    /// the metric, none if there are no validation sets.
+    fn named_validate_columns(&self, metric: String) -> Vec<String> {
+        self.config
+            .validate
+            .iter()
+            .map(|&(ref name, _)| if name.is_empty() {
+                metric.clone() + "-V"
+            } else {
+                metric.clone() + "-" + name
+            })
+            .collect()
+    }
+
     /// Print metric header.
     fn print_metric_header(&self) {
-        self.print(&format!(
-            "{:<7} | {:>9} | {:>9}",
+        let mut s = format!(
+            "{:<7} | {:>9}",
             "#iter",
-            self.config.metric.name() + "-T",
-            self.config.metric.name() + "-V"
-        ));
+            self.config.metric.name() + "-T"
+        );
+        for column in self.validate_columns() {
+            s += &format!(" | {:>9}", column);
+        }
+        for column in self.report_columns() {
+            s += &format!(" | {:>9}", column);
+        }
+        self.print(&s);
+    }
+
+    /// Returns the headers of the columns of the reported metrics:
+    /// for each metric, the training column and a column for each
+    /// validation set.
+    fn report_columns(&self) -> Vec<String> {
+        let mut columns = Vec::new();
+        for metric in self.config.report_metrics.iter() {
+            columns.push(metric.name() + "-T");
+            columns.extend(self.named_validate_columns(metric.name()));
+        }
+        columns
     }
 
-    /// Print metric of each iteration.
+    /// Print metric of each iteration, with a column for each
+    /// validation set, followed by the (training score, validation
+    /// scores) of each reported metric.
     fn print_metric(
         &self,
         iteration: usize,
         train_score: f64,
-        validate_score: Option<f64>,
+        validate_scores: &[f64],
+        report_scores: &[(f64, Vec<f64>)],
     ) {
-        let s = format!(
-            "{:<7} | {:>9.4} | {}",
-            iteration,
-            train_score,
-            validate_score
-                .map(|score| format!("{:>9.4}", score))
-                .unwrap_or("".to_string())
-        );
+        let mut s = format!("{:<7} | {:>9.4}", iteration, train_score);
+        if validate_scores.is_empty() {
+            s += " | ";
+        }
+        for (column, score) in
+            self.validate_columns().iter().zip(validate_scores.iter())
+        {
+            s += &format!(" | {:>1$.4}", score, column.len().max(9));
+        }
+        let scores = report_scores.iter().flat_map(|&(train, ref validate)| {
+            Some(train).into_iter().chain(validate.iter().cloned())
+        });
+        for (column, score) in self.report_columns().iter().zip(scores) {
+            s += &format!(" | {:>1$.4}", score, column.len().max(9));
+        }
         self.print(&s);
+    }
+}
 
+/// Writes the scores to the file, one per line.
+fn write_scores(path: &str, scores: &[f64]) -> Result<()> {
+    let mut writer = open_output(path)?;
+    for score in scores {
+        writeln!(writer, "{}", score)?;
     }
+    writer.flush()?;
+    Ok(())
 }
 
 #[cfg(test)]
@@ -239,27 +912,72 @@ mod test {
     use super::*;
     use std::fs::File;
 
-    #[test]
-    fn test_lambda_mart() {
-        // CWD of cargo test is the root of the project.
-        let path = "./data/train-lite.txt";
-        let f = File::open(path).unwrap();
-        let dataset = DataSet::load(f).unwrap();
-        let validate_set = dataset.clone();
-
-        let config = Config {
-            train: dataset,
+    /// A configuration of ten trees on `train` that each test adjusts
+    /// through struct update syntax.
+    fn test_config(train: DataSet) -> Config {
+        Config {
+            train: train,
             test: None,
             trees: 10,
             early_stop: 100,
-            learning_rate: 0.1,
+            min_delta: 0.0,
+            train_tolerance: None,
+            calibrate: false,
+            model_out: None,
+            model_format: ModelFormat::Text,
+            seed: 0,
+            learning_rate: LearningRateSchedule::Constant(0.1),
             max_leaves: 10,
             min_leaf_samples: 1,
+            min_leaf_support: 1,
+            min_split_gain: 0.0,
+            min_sum_hessian: 0.0,
             thresholds: 256,
+            binning: BinningStrategy::Uniform,
+            threshold_sample_size: None,
+            lambda_clip: None,
+            split_criterion: Criterion::Variance,
+            categorical_features: HashSet::new(),
             print_metric: false,
+            print_tree: false,
+            report_metrics: Vec::new(),
+            profile: false,
             metric: Box::new(NDCGScorer::new(10)),
-            validate: None,
+            objective: Objective::LambdaRank,
+            zero_queries: ZeroQueries::Include,
+            tie_break: TieBreak::Index,
+            validate: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_phase_times() {
+        let times = PhaseTimes {
+            gradients: Duration::from_millis(500),
+            fit: Duration::from_millis(1500),
+            update: Duration::from_millis(0),
+            evaluation: Duration::new(0, 0),
         };
+        assert_eq!(
+            times.to_string(),
+            "Phase      |   Seconds |     %
+Gradients  |     0.500 |  25.0
+Tree fit   |     1.500 |  75.0
+Update     |     0.000 |   0.0
+Evaluation |     0.000 |   0.0
+"
+        );
+    }
+
+    #[test]
+    fn test_lambda_mart() {
+        // CWD of cargo test is the root of the project.
+        let path = "./data/train-lite.txt";
+        let f = File::open(path).unwrap();
+        let dataset = DataSet::load(f).unwrap();
+        let validate_set = dataset.clone();
+
+        let config = test_config(dataset);
         let mut lambdamart = LambdaMART::new(config);
         lambdamart.init().unwrap();
         lambdamart.learn().unwrap();
@@ -267,4 +985,303 @@ mod test {
         // modifications.
         assert_eq!(lambdamart.evaluate(&validate_set), 0.5694960535660895);
     }
+
+    #[test]
+    fn test_mismatched_features() {
+        let train: DataSet =
+            vec![(1.0, 1, vec![1.0, 2.0, 3.0])].into_iter().collect();
+        let narrow: DataSet = vec![(1.0, 1, vec![1.0])].into_iter().collect();
+        let config = Config {
+            test: Some(narrow.clone()),
+            trees: 1,
+            validate: vec![
+                (String::new(), train.clone()),
+                ("fold2".to_string(), narrow),
+            ],
+            ..test_config(train)
+        };
+        let mut lambdamart = LambdaMART::new(config);
+        assert!(lambdamart.init().is_ok());
+        assert_eq!(
+            lambdamart.mismatched_features(),
+            vec![("fold2".to_string(), 1), ("Test".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn test_lambda_mart_continue_learn() {
+        let path = "./data/train-lite.txt";
+        let f = File::open(path).unwrap();
+        let dataset = DataSet::load(f).unwrap();
+
+        let new = |trees: usize| {
+            let config = Config {
+                trees: trees,
+                validate: vec![(String::new(), dataset.clone())],
+                ..test_config(dataset.clone())
+            };
+            LambdaMART::new(config)
+        };
+
+        let mut expected = new(6);
+        expected.learn().unwrap();
+        let mut lambdamart = new(4);
+        lambdamart.learn().unwrap();
+        lambdamart.continue_learn(2).unwrap();
+
+        assert_eq!(lambdamart.ensemble().len(), 6);
+        assert_eq!(
+            lambdamart.ensemble().evaluate_all(&dataset),
+            expected.ensemble().evaluate_all(&dataset)
+        );
+        assert_eq!(lambdamart.history(), expected.history());
+        assert_eq!(lambdamart.best_iteration(), expected.best_iteration());
+    }
+
+    #[test]
+    fn test_lambda_mart_callback() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let path = "./data/train-lite.txt";
+        let f = File::open(path).unwrap();
+        let dataset = DataSet::load(f).unwrap();
+        let validate_set = dataset.clone();
+
+        let config = Config {
+            trees: 5,
+            validate: vec![(String::new(), validate_set)],
+            ..test_config(dataset)
+        };
+        let history = Rc::new(RefCell::new(Vec::new()));
+        let mut lambdamart = LambdaMART::new(config);
+        let h = history.clone();
+        lambdamart.set_callback(Box::new(move |i, train, validate| {
+            h.borrow_mut().push((i, train, validate));
+        }));
+        lambdamart.learn().unwrap();
+
+        let history = history.borrow();
+        assert_eq!(history.len(), 5);
+        for (i, &(iter, train, validate)) in history.iter().enumerate() {
+            assert_eq!(iter, i);
+            assert_eq!(validate, Some(train));
+        }
+    }
+
+    #[test]
+    fn test_lambda_mart_history() {
+        let path = "./data/train-lite.txt";
+        let f = File::open(path).unwrap();
+        let dataset = DataSet::load(f).unwrap();
+
+        let config = Config {
+            trees: 3,
+            ..test_config(dataset)
+        };
+        let mut lambdamart = LambdaMART::new(config);
+        lambdamart.learn().unwrap();
+
+        let history = lambdamart.history().to_vec();
+        assert_eq!(history.len(), 3);
+        for (i, record) in history.iter().enumerate() {
+            assert_eq!(record.iter, i);
+            assert_eq!(record.validate, None);
+        }
+
+        let mut csv = Vec::new();
+        lambdamart.write_history(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], "iter,train,validate");
+        assert_eq!(lines[1], format!("0,{},", history[0].train));
+    }
+
+    #[test]
+    fn test_train_tolerance() {
+        let path = "./data/train-lite.txt";
+        let f = File::open(path).unwrap();
+        let dataset = DataSet::load(f).unwrap();
+
+        let config = Config {
+            trees: 20,
+            // No round can improve NDCG by 1.0.
+            train_tolerance: Some(1.0),
+            ..test_config(dataset)
+        };
+        let mut lambdamart = LambdaMART::new(config);
+        lambdamart.learn().unwrap();
+
+        // The first round has nothing to compare with.
+        assert_eq!(lambdamart.history().len(), TRAIN_TOLERANCE_ROUNDS + 1);
+        assert_eq!(lambdamart.ensemble.len(), TRAIN_TOLERANCE_ROUNDS + 1);
+    }
+
+    #[test]
+    fn test_dump_scores() {
+        let path = "./data/train-lite.txt";
+        let f = File::open(path).unwrap();
+        let dataset = DataSet::load(f).unwrap();
+        let len = dataset.len();
+
+        let config = Config {
+            trees: 3,
+            ..test_config(dataset)
+        };
+        let prefix = ::std::env::temp_dir().join("rforests-test-dump-scores");
+        let prefix = prefix.to_str().unwrap();
+        let mut lambdamart = LambdaMART::new(config);
+        lambdamart.set_dump_scores(prefix, 2);
+        lambdamart.learn().unwrap();
+
+        let read = |round: usize| {
+            let path = format!("{}.round_{}.txt", prefix, round);
+            let content = ::std::fs::read_to_string(&path);
+            let _ = ::std::fs::remove_file(&path);
+            content
+        };
+        assert!(read(1).is_err());
+        for &round in &[0, 2] {
+            let content = read(round).unwrap();
+            assert_eq!(content.lines().count(), len);
+            assert!(content.lines().all(|line| line.parse::<f64>().is_ok()));
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_dump_scores_every_zero() {
+        let train: DataSet = vec![(1.0, 1, vec![1.0])].into_iter().collect();
+        LambdaMART::new(test_config(train)).set_dump_scores("scores", 0);
+    }
+
+    #[test]
+    fn test_metadata() {
+        let path = "./data/train-lite.txt";
+        let f = File::open(path).unwrap();
+        let dataset = DataSet::load(f).unwrap();
+        let gains = Some(vec![0.0, 1.0, 3.0]);
+        let metric = new_with_max_gain("DCG", 5, gains, 10.0, Some(2.0));
+
+        // No validation score improves by 1e9, so the training stops
+        // early and keeps only the first tree.
+        let config = Config {
+            metric: metric.unwrap(),
+            trees: 5,
+            early_stop: 1,
+            min_delta: 1e9,
+            validate: vec![(String::new(), dataset.clone())],
+            ..test_config(dataset)
+        };
+        let mut lambdamart = LambdaMART::new(config);
+        lambdamart.learn().unwrap();
+
+        let metadata = lambdamart.metadata();
+        assert_eq!(lambdamart.ensemble().len(), 1);
+        assert_eq!(metadata.trees, Some(1));
+        assert_eq!(metadata.metric, Some("DCG".to_string()));
+        assert_eq!(metadata.metric_k, Some(5));
+        assert_eq!(metadata.gain_table, Some(vec![0.0, 1.0, 3.0]));
+        assert_eq!(metadata.discount_base, Some(10.0));
+        assert_eq!(metadata.max_gain, Some(2.0));
+    }
+
+    #[test]
+    fn test_model_out() {
+        let path = "./data/train-lite.txt";
+        let f = File::open(path).unwrap();
+        let dataset = DataSet::load(f).unwrap();
+        let validate_set = dataset.clone();
+        let model_path = ::std::env::temp_dir().join("rforests-test-model");
+        let model_path = model_path.to_str().unwrap().to_string();
+
+        let config = |model_out: Option<String>, format: ModelFormat| Config {
+            trees: 5,
+            early_stop: 2,
+            model_out: model_out,
+            model_format: format,
+            validate: vec![(String::new(), validate_set.clone())],
+            ..test_config(dataset.clone())
+        };
+        let mut saved = Vec::new();
+        for &format in &[ModelFormat::Text, ModelFormat::Binary] {
+            let config = config(Some(model_path.clone()), format);
+            let mut lambdamart = LambdaMART::new(config);
+            lambdamart.learn().unwrap();
+
+            let model = ::std::fs::read(&model_path).unwrap();
+            let _ = ::std::fs::remove_file(&model_path);
+            let loaded = Ensemble::load(&model[..]).unwrap();
+            assert_eq!(loaded.len(), lambdamart.ensemble().len());
+            assert_eq!(loaded.metadata().metric, Some("NDCG".to_string()));
+            assert_eq!(loaded.metadata().metric_k, Some(10));
+            assert_eq!(loaded.metadata().trees, Some(loaded.len()));
+            assert_eq!(loaded.metadata().seed, Some(0));
+            assert_eq!(
+                loaded.evaluate_all(&dataset),
+                lambdamart.ensemble().evaluate_all(&dataset)
+            );
+            saved.push(model);
+        }
+        assert_ne!(saved[0], saved[1]);
+
+        // A file which cannot be created is an error.
+        let bad_path = "./data/no-such-dir/model.txt".to_string();
+        let config = config(Some(bad_path), ModelFormat::Text);
+        let mut lambdamart = LambdaMART::new(config);
+        assert!(lambdamart.learn().is_err());
+    }
+
+    #[test]
+    fn test_lambda_mart_best_iteration() {
+        let path = "./data/train-lite.txt";
+        let f = File::open(path).unwrap();
+        let dataset = DataSet::load(f).unwrap();
+        let validate_set = dataset.clone();
+
+        let config = Config {
+            trees: 5,
+            calibrate: true,
+            validate: vec![(String::new(), validate_set)],
+            ..test_config(dataset)
+        };
+        let mut lambdamart = LambdaMART::new(config);
+        assert_eq!(lambdamart.best_iteration(), None);
+        lambdamart.learn().unwrap();
+
+        let best = lambdamart
+            .history()
+            .iter()
+            .fold(None, |best: Option<IterationRecord>, &record| {
+                match best {
+                    Some(best) if best.validate >= record.validate => {
+                        Some(best)
+                    }
+                    _ => Some(record),
+                }
+            })
+            .unwrap();
+        assert_eq!(lambdamart.best_iteration(), Some(best.iter));
+        assert_eq!(lambdamart.best_validation_score(), best.validate);
+
+        lambdamart.truncate_to_best();
+        assert_eq!(lambdamart.ensemble().len(), best.iter + 1);
+        assert!(lambdamart.ensemble().calibration().is_some());
+    }
+
+    #[test]
+    fn test_best_score_min_delta() {
+        let mut best = BestScore::new("NDCG", 0.0);
+        best.update(0, 0.5, Some(0.5));
+        best.update(1, 0.6, Some(0.501));
+        assert_eq!(best.best_iter(), Some(1));
+
+        let mut best = BestScore::new("NDCG", 0.01);
+        best.update(0, 0.5, Some(0.5));
+        best.update(1, 0.6, Some(0.501));
+        assert_eq!(best.best_iter(), Some(0));
+        best.update(2, 0.7, Some(0.52));
+        assert_eq!(best.best_iter(), Some(2));
+    }
 }
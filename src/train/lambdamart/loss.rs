@@ -0,0 +1,281 @@
+use metric::Measure;
+use std;
+use std::cmp::Ordering;
+use std::sync::{Arc, Mutex};
+use train::dataset::DataSet;
+use util::Value;
+
+/// A loss function minimized by gradient boosting.
+pub trait Loss: Sync {
+    /// Accumulates the negative gradients, or lambdas, and the Newton
+    /// step weights of each instance given the current model scores.
+    /// `lambdas` and `weights` are zeros when called.
+    fn gradients(
+        &self,
+        dataset: &DataSet,
+        model_scores: &[Value],
+        lambdas: &mut [Value],
+        weights: &mut [Value],
+    );
+}
+
+/// Squared error loss for pointwise regression. The lambda of each
+/// instance is the residual `label - score`, and the weight is 1.0.
+pub struct SquaredLoss;
+
+impl Loss for SquaredLoss {
+    fn gradients(
+        &self,
+        dataset: &DataSet,
+        model_scores: &[Value],
+        lambdas: &mut [Value],
+        weights: &mut [Value],
+    ) {
+        for (index, label) in dataset.label_iter().enumerate() {
+            lambdas[index] = label - model_scores[index];
+            weights[index] = 1.0;
+        }
+    }
+}
+
+/// The loss optimized by LambdaMART.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Objective {
+    /// The pairwise loss weighted by the change of the metric. See
+    /// `LambdaLoss`.
+    LambdaRank,
+    /// The plain pairwise cross entropy loss, independent of the
+    /// metric. See `RankNetLoss`.
+    RankNet,
+    /// The pairwise hinge loss. See `PairwiseLoss`.
+    Pairwise,
+}
+
+impl Objective {
+    /// Creates the loss of the objective for the data set.
+    pub fn loss<'a>(
+        &self,
+        metric: &'a Box<Measure>,
+        dataset: &DataSet,
+    ) -> Box<Loss + 'a> {
+        match *self {
+            Objective::LambdaRank => Box::new(LambdaLoss::new(metric, dataset)),
+            Objective::RankNet => Box::new(RankNetLoss),
+            Objective::Pairwise => Box::new(PairwiseLoss),
+        }
+    }
+}
+
+impl std::str::FromStr for Objective {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Objective, String> {
+        match s {
+            "lambdarank" => Ok(Objective::LambdaRank),
+            "ranknet" => Ok(Objective::RankNet),
+            "pairwise" => Ok(Objective::Pairwise),
+            _ => Err(format!("Invalid objective: {}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Objective {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Objective::LambdaRank => write!(f, "lambdarank"),
+            Objective::RankNet => write!(f, "ranknet"),
+            Objective::Pairwise => write!(f, "pairwise"),
+        }
+    }
+}
+
+/// Accumulates the lambda and weight of each pair of instances of a
+/// query whose first label is higher, given by `pair` from the
+/// difference of their scores.
+fn accumulate_pairs<F>(
+    dataset: &DataSet,
+    model_scores: &[Value],
+    lambdas: &mut [Value],
+    weights: &mut [Value],
+    pair: F,
+) where
+    F: Fn(f64) -> (f64, f64),
+{
+    for (_qid, query) in dataset.query_iter() {
+        for &index1 in query.iter() {
+            for &index2 in query.iter() {
+                if dataset[index1].label() <= dataset[index2].label() {
+                    continue;
+                }
+
+                let diff = model_scores[index1] - model_scores[index2];
+                let (lambda, weight) = pair(diff);
+                lambdas[index1] += lambda;
+                weights[index1] += weight;
+                lambdas[index2] -= lambda;
+                weights[index2] += weight;
+            }
+        }
+    }
+}
+
+/// RankNet loss, the cross entropy of the probability that the
+/// instance of the higher label ranks above the other of each pair.
+/// Unlike `LambdaLoss`, all the pairs weigh the same regardless of
+/// the metric.
+pub struct RankNetLoss;
+
+impl Loss for RankNetLoss {
+    fn gradients(
+        &self,
+        dataset: &DataSet,
+        model_scores: &[Value],
+        lambdas: &mut [Value],
+        weights: &mut [Value],
+    ) {
+        accumulate_pairs(dataset, model_scores, lambdas, weights, |diff| {
+            let rho = 1.0 / (1.0 + diff.exp());
+            (rho, rho * (1.0 - rho))
+        });
+    }
+}
+
+/// Pairwise hinge loss, as in RankSVM. A pair contributes when the
+/// instance of the higher label does not score above the other by a
+/// margin of 1.0. The loss has no curvature, so the weight of such a
+/// pair is 1.0, and the Newton output of a leaf is the mean lambda.
+pub struct PairwiseLoss;
+
+impl Loss for PairwiseLoss {
+    fn gradients(
+        &self,
+        dataset: &DataSet,
+        model_scores: &[Value],
+        lambdas: &mut [Value],
+        weights: &mut [Value],
+    ) {
+        accumulate_pairs(dataset, model_scores, lambdas, weights, |diff| {
+            if diff < 1.0 {
+                (1.0, 1.0)
+            } else {
+                (0.0, 0.0)
+            }
+        });
+    }
+}
+
+/// LambdaRank loss, which weights the pairwise cross entropy loss by
+/// the change of the metric when swapping the pairs.
+pub struct LambdaLoss<'a> {
+    metric: &'a Box<Measure>,
+    /// Ideal DCG of each query of the data set.
+    ideal_dcgs: Vec<f64>,
+}
+
+impl<'a> LambdaLoss<'a> {
+    /// Creates the loss for the data set. The ideal DCGs of the
+    /// queries are computed once here, and reused by each call of
+    /// `gradients` on the same data set.
+    pub fn new(metric: &'a Box<Measure>, dataset: &DataSet) -> LambdaLoss<'a> {
+        LambdaLoss {
+            metric: metric,
+            ideal_dcgs: dataset.ideal_dcgs(metric),
+        }
+    }
+}
+
+// Input: Vec of (index, label, score)
+// Output: Vec of (higher index, lower index, lambda, weight)
+fn compute_lambda_weight(
+    rank_list: &mut Vec<(usize, f64, f64)>,
+    metric: &Box<Measure>,
+    ideal_dcg: f64,
+) -> Vec<(usize, usize, f64, f64)> {
+    let mut query_values: Vec<(usize, usize, f64, f64)> = Vec::new();
+    // Rank by the scores of our model.
+    rank_list.sort_by(|&(_, _, score1), &(_, _, score2)| {
+        score2.partial_cmp(&score1).unwrap_or(Ordering::Equal)
+    });
+
+    let ranked_labels: Vec<_> =
+        rank_list.iter().map(|&(_, label, _)| label).collect();
+
+    let changes = metric.swap_changes_with_ideal(&ranked_labels, ideal_dcg);
+
+    let k = metric.get_k();
+    for (metric_index1, &(index1, label1, score1)) in
+        rank_list.iter().enumerate()
+    {
+        for (metric_index2, &(index2, label2, score2)) in
+            rank_list.iter().enumerate()
+        {
+            if metric_index1 > k && metric_index2 > k {
+                break;
+            }
+
+            if label1 <= label2 {
+                continue;
+            }
+
+            let change = changes[metric_index1][metric_index2].abs();
+            let rho = 1.0 / (1.0 + (score1 - score2).exp());
+            let lambda = change * rho;
+            let weight = rho * (1.0 - rho) * change;
+
+            query_values.push((index1, index2, lambda, weight));
+        }
+    }
+    query_values
+}
+
+impl<'a> Loss for LambdaLoss<'a> {
+    /// Updates the lambda and weight for each instance.
+    ///
+    /// 1. For each query, rank the instances by the scores of our
+    /// model.
+    ///
+    /// 2. Compute the change of scores by swaping each instance with
+    /// another
+    ///
+    /// 3. Update lambda and weight according to the formulas
+    fn gradients(
+        &self,
+        dataset: &DataSet,
+        model_scores: &[Value],
+        lambdas: &mut [Value],
+        weights: &mut [Value],
+    ) {
+        let metric = self.metric;
+        debug_assert_eq!(self.ideal_dcgs.len(), dataset.query_iter().count());
+
+        // (index, lambda, weight) grouped by queries
+        let values: Arc<Mutex<Vec<Vec<_>>>> = Arc::new(Mutex::new(Vec::new()));
+        let mut pool = ::util::POOL.lock().unwrap();
+        let queries = dataset.query_iter().zip(self.ideal_dcgs.iter());
+        pool.scoped(|scoped| for ((_qid, query), &ideal_dcg) in queries {
+            let values = values.clone();
+            scoped.execute(move || {
+                let mut rank_list: Vec<_> = query
+                    .iter()
+                    .map(|&index| {
+                        (index, dataset[index].label(), model_scores[index])
+                    })
+                    .collect();
+                let query_values =
+                    compute_lambda_weight(&mut rank_list, metric, ideal_dcg);
+                let mut values = values.lock().unwrap();
+                values.push(query_values);
+            })
+        });
+
+        let values = values.lock().unwrap();
+        for query_values in values.iter() {
+            for &(index1, index2, lambda, weight) in query_values.iter() {
+                lambdas[index1] += lambda;
+                weights[index1] += weight;
+                lambdas[index2] -= lambda;
+                weights[index2] += weight;
+            }
+        }
+    }
+}
@@ -1,102 +1,544 @@
 use clap::{App, Arg, ArgMatches, SubCommand};
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
+use std::io::{Read, Write};
 use self::lambdamart::*;
+use self::training_set::BinningStrategy;
+use self::histogram::Criterion;
+use self::loss::Objective;
+use self::regression_tree::ModelFormat;
 use std;
 use std::process::exit;
+use std::str::FromStr;
+use eval;
 use metric;
+use toml;
 use train::dataset::*;
+use util::{open_input, open_output, Id, Result, STDIN_PATH};
 
 pub mod training_set;
+pub mod loss;
 pub mod lambdamart;
 pub mod regression_tree;
 pub mod histogram;
 
 struct LambdaMARTParameter<'a> {
-    train_file_path: &'a str,
-    validate_file_path: Option<&'a str>,
+    /// The training files, concatenated in order.
+    train_file_paths: Vec<&'a str>,
+    /// Whether the qids of each training file are offset past those
+    /// of the previous files. See `DataSet::concat`.
+    rename_qids: bool,
+    /// (name, path) of each validation set. See `validate_file`.
+    validate_files: Vec<(&'a str, &'a str)>,
     test_file_path: Option<&'a str>,
-    metric: &'a str,
+    /// Where the CSV of the scores of each iteration is written.
+    history_out: Option<&'a str>,
+    /// The prefix of the files of the training scores written every
+    /// `dump_scores_every` rounds, for debugging.
+    dump_scores: Option<&'a str>,
+    dump_scores_every: usize,
+    /// Where the CSV of the feature usage of the model is written.
+    feature_usage_out: Option<&'a str>,
+    /// Where the trained model is saved.
+    model_out: Option<&'a str>,
+    model_format: ModelFormat,
+    /// Index of the label field in each line of the data files.
+    label_column: usize,
+    metric: String,
     metric_k: usize,
+    /// More metrics such as "NDCG@5" reported beside the metric.
+    report_metrics: Vec<String>,
+    objective: Objective,
+    gain_table: Option<Vec<f64>>,
+    discount_base: f64,
+    /// The gains are capped to this if given.
+    max_gain: Option<f64>,
+    zero_queries: metric::ZeroQueries,
+    /// Training queries with fewer instances are removed.
+    min_query_docs: usize,
+    /// The labels of all the data are clamped to this if given.
+    max_label: Option<f64>,
+    tie_break: metric::TieBreak,
     trees: usize,
     leaves: usize,
-    shrinkage: f64,
+    shrinkage: LearningRateSchedule,
     thresholds_count: usize,
+    binning: BinningStrategy,
+    threshold_sample_size: Option<usize>,
     min_leaf_samples: usize,
+    min_leaf_support: usize,
+    min_split_gain: f64,
+    min_sum_hessian: f64,
+    split_criterion: Criterion,
+    lambda_clip: Option<f64>,
+    /// The features split by category instead of by threshold.
+    categorical_features: HashSet<Id>,
     early_stop: usize,
+    min_delta: f64,
+    train_tolerance: Option<f64>,
+    calibrate: bool,
+    print_tree: bool,
+    profile: bool,
+    seed: u64,
 }
 
+/// Parameters that can be set in a config file. The keys are the
+/// names of the command line options, with underscores in place of
+/// dashes.
+const FILE_PARAMETERS: &[&str] = &[
+    "metric",
+    "metric-k",
+    "report-metrics",
+    "objective",
+    "gain-table",
+    "discount-base",
+    "cap-gain",
+    "label-column",
+    "eval-compat",
+    "min-query-docs",
+    "max-label",
+    "trees",
+    "leaves",
+    "shrinkage",
+    "shrinkage-schedule",
+    "thresholds",
+    "binning",
+    "max-samples-for-thresholds",
+    "min-leaf-samples",
+    "min-leaf-support",
+    "min-split-gain",
+    "min-sum-hessian",
+    "split-criterion",
+    "lambda-clip",
+    "categorical-features",
+    "early-stop",
+    "min-delta",
+    "train-tolerance",
+    "seed",
+    "model-format",
+    "dump-scores-every",
+];
+
 impl<'a> LambdaMARTParameter<'a> {
     pub fn parse(matches: &'a ArgMatches<'a>) -> LambdaMARTParameter<'a> {
-        // Defaults to 256
-        let train_file_path = matches.value_of("train-file").unwrap();
-        let validate_file_path = matches.value_of("validate-file");
+        let file_values = match matches.value_of("config") {
+            Some(path) => {
+                LambdaMARTParameter::from_toml(path).unwrap_or_else(|e| {
+                    error!("Failed to load {}: {}", path, e);
+                    exit(1)
+                })
+            }
+            None => HashMap::new(),
+        };
+
+        // Options given on the command line take precedence over the
+        // config file, which takes precedence over the defaults.
+        let value_of = |name: &str| -> String {
+            match file_values.get(name) {
+                Some(value) if matches.occurrences_of(name) == 0 => {
+                    value.clone()
+                }
+                _ => matches.value_of(name).unwrap().to_string(),
+            }
+        };
+        fn parse_value<T: FromStr>(name: &str, value: String) -> T {
+            value.parse::<T>().unwrap_or_else(|_e| {
+                error!("Invalid value for {}: {}", name, value);
+                exit(1)
+            })
+        }
+
+        let train_file_paths =
+            matches.values_of("train-file").unwrap().collect();
+        let rename_qids = matches.is_present("rename-qids");
+        let validate_files = matches
+            .values_of("validate-file")
+            .map(|values| values.map(validate_file).collect())
+            .unwrap_or(Vec::new());
         let test_file_path = matches.value_of("test-file");
-        let metric = matches.value_of("metric").unwrap();
-        let metric_k = value_t!(matches.value_of("metric-k"), usize)
-            .unwrap_or_else(|e| e.exit());
-        let trees = value_t!(matches.value_of("trees"), usize).unwrap_or_else(
-            |e| e.exit(),
-        );
-        let leaves = value_t!(matches.value_of("leaves"), usize)
-            .unwrap_or_else(|e| e.exit());
-        let shrinkage = value_t!(matches.value_of("shrinkage"), f64)
-            .unwrap_or_else(|e| e.exit());
-        let thresholds_count = value_t!(matches.value_of("thresholds"), usize)
-            .unwrap_or_else(|e| e.exit());
+        let history_out = matches.value_of("history-out");
+        let feature_usage_out = matches.value_of("feature-usage-out");
+        let model_out = matches.value_of("save-model");
+        let model_format =
+            parse_value("model-format", value_of("model-format"));
+        let dump_scores = matches.value_of("dump-scores");
+        let dump_scores_every =
+            parse_value("dump-scores-every", value_of("dump-scores-every"));
+        let label_column =
+            parse_value("label-column", value_of("label-column"));
+        let metric = value_of("metric");
+        let metric_k = parse_value("metric-k", value_of("metric-k"));
+        let report_metrics = match matches.value_of("report-metrics") {
+            Some(s) => Some(s),
+            None => file_values.get("report-metrics").map(|s| &s[..]),
+        };
+        let report_metrics: Vec<String> = report_metrics
+            .map(|s| s.split(',').map(|name| name.trim().to_string()).collect())
+            .unwrap_or(Vec::new());
+        let objective = parse_value("objective", value_of("objective"));
+        let gain_table = match matches.value_of("gain-table") {
+            Some(s) => Some(s),
+            None => file_values.get("gain-table").map(|s| &s[..]),
+        };
+        let gain_table = gain_table.map(|s| {
+            metric::parse_gains(s).unwrap_or_else(|e| {
+                error!("{}", e);
+                exit(1)
+            })
+        });
+        let discount_base =
+            metric::parse_discount_base(&value_of("discount-base"))
+                .unwrap_or_else(|e| {
+                    error!("{}", e);
+                    exit(1)
+                });
+        let max_gain = match matches.value_of("cap-gain") {
+            Some(s) => Some(s),
+            None => file_values.get("cap-gain").map(|s| &s[..]),
+        };
+        let max_gain = max_gain.map(|s| {
+            metric::parse_max_gain(s).unwrap_or_else(|e| {
+                error!("{}", e);
+                exit(1)
+            })
+        });
+        let zero_queries = if matches.is_present("exclude-zero-queries") {
+            metric::ZeroQueries::Exclude
+        } else {
+            metric::ZeroQueries::Include
+        };
+        let tie_break = parse_value("eval-compat", value_of("eval-compat"));
+        let min_query_docs =
+            parse_value("min-query-docs", value_of("min-query-docs"));
+        let max_label = match matches.value_of("max-label") {
+            Some(s) => Some(s.to_string()),
+            None => file_values.get("max-label").cloned(),
+        };
+        let max_label = max_label.map(|s| parse_value("max-label", s));
+        let trees = parse_value("trees", value_of("trees"));
+        let leaves = parse_value("leaves", value_of("leaves"));
+        let shrinkage = parse_value("shrinkage", value_of("shrinkage"));
+        let shrinkage = LearningRateSchedule::parse(
+            &value_of("shrinkage-schedule"),
+            shrinkage,
+        ).unwrap_or_else(|e| {
+            error!("{}", e);
+            exit(1)
+        });
+        let thresholds_count =
+            parse_value("thresholds", value_of("thresholds"));
+        let binning = parse_value("binning", value_of("binning"));
+        let name = "max-samples-for-thresholds";
+        let threshold_sample_size = match matches.value_of(name) {
+            Some(s) => Some(s.to_string()),
+            None => file_values.get(name).cloned(),
+        };
+        let threshold_sample_size =
+            threshold_sample_size.map(|s| parse_value(name, s));
         let min_leaf_samples =
-            value_t!(matches.value_of("min-leaf-support"), usize)
-                .unwrap_or_else(|e| e.exit());
-        let early_stop = value_t!(matches.value_of("early-stop"), usize)
-            .unwrap_or_else(|e| e.exit());
+            parse_value("min-leaf-samples", value_of("min-leaf-samples"));
+        let min_leaf_support =
+            parse_value("min-leaf-support", value_of("min-leaf-support"));
+        let min_split_gain =
+            parse_value("min-split-gain", value_of("min-split-gain"));
+        let min_sum_hessian =
+            parse_value("min-sum-hessian", value_of("min-sum-hessian"));
+        let split_criterion =
+            parse_value("split-criterion", value_of("split-criterion"));
+        let lambda_clip = match matches.value_of("lambda-clip") {
+            Some(s) => Some(s.to_string()),
+            None => file_values.get("lambda-clip").cloned(),
+        };
+        let lambda_clip = lambda_clip.map(|s| parse_value("lambda-clip", s));
+        let name = "categorical-features";
+        let categorical_features = match matches.value_of(name) {
+            Some(s) => Some(s.to_string()),
+            None => file_values.get(name).cloned(),
+        };
+        let categorical_features = match categorical_features {
+            Some(s) => parse_feature_ids(&s).unwrap_or_else(|e| {
+                error!("{}", e);
+                exit(1)
+            }),
+            None => HashSet::new(),
+        };
+        let early_stop = parse_value("early-stop", value_of("early-stop"));
+        let min_delta = parse_value("min-delta", value_of("min-delta"));
+        let train_tolerance = match matches.value_of("train-tolerance") {
+            Some(s) => Some(s.to_string()),
+            None => file_values.get("train-tolerance").cloned(),
+        };
+        let train_tolerance =
+            train_tolerance.map(|s| parse_value("train-tolerance", s));
+        let calibrate = matches.is_present("calibrate");
+        let print_tree = matches.is_present("print-tree");
+        let profile = matches.is_present("profile");
+        let seed = parse_value("seed", value_of("seed"));
 
         LambdaMARTParameter {
-            train_file_path: train_file_path,
-            validate_file_path: validate_file_path,
+            train_file_paths: train_file_paths,
+            rename_qids: rename_qids,
+            validate_files: validate_files,
             test_file_path: test_file_path,
+            history_out: history_out,
+            feature_usage_out: feature_usage_out,
+            model_out: model_out,
+            model_format: model_format,
+            dump_scores: dump_scores,
+            dump_scores_every: dump_scores_every,
+            label_column: label_column,
             metric: metric,
             metric_k: metric_k,
+            report_metrics: report_metrics,
+            objective: objective,
+            gain_table: gain_table,
+            discount_base: discount_base,
+            max_gain: max_gain,
+            zero_queries: zero_queries,
+            min_query_docs: min_query_docs,
+            max_label: max_label,
+            tie_break: tie_break,
             trees: trees,
             leaves: leaves,
             shrinkage: shrinkage,
             thresholds_count: thresholds_count,
+            binning: binning,
+            threshold_sample_size: threshold_sample_size,
             min_leaf_samples: min_leaf_samples,
+            min_leaf_support: min_leaf_support,
+            min_split_gain: min_split_gain,
+            min_sum_hessian: min_sum_hessian,
+            split_criterion: split_criterion,
+            lambda_clip: lambda_clip,
+            categorical_features: categorical_features,
             early_stop: early_stop,
+            min_delta: min_delta,
+            train_tolerance: train_tolerance,
+            calibrate: calibrate,
+            print_tree: print_tree,
+            profile: profile,
+            seed: seed,
         }
     }
 
-    pub fn config(&self) -> Config {
-        let train_file =
-            File::open(self.train_file_path).unwrap_or_else(|_e| exit(1));
-        let train_set = DataSet::load(train_file).unwrap_or_else(|_e| exit(1));
+    /// Reads parameters from a TOML file. Returns the values keyed by
+    /// the names of the corresponding command line options.
+    ///
+    /// ```toml
+    /// metric = "NDCG"
+    /// metric_k = 10
+    /// trees = 500
+    /// shrinkage = 0.05
+    /// ```
+    pub fn from_toml(path: &str) -> Result<HashMap<String, String>> {
+        let mut content = String::new();
+        File::open(path)?.read_to_string(&mut content)?;
+        LambdaMARTParameter::parse_toml(&content)
+    }
 
-        let validate_set = self.validate_file_path.map(|path| {
-            let file = File::open(path).unwrap_or_else(|_e| exit(1));
-            let dataset = DataSet::load(file).unwrap_or_else(|_e| exit(1));
-            dataset
-        });
+    fn parse_toml(content: &str) -> Result<HashMap<String, String>> {
+        let table = match content.parse::<toml::Value>()? {
+            toml::Value::Table(table) => table,
+            _ => return Err(From::from("Expected a table")),
+        };
+
+        let mut values = HashMap::new();
+        for (key, value) in table {
+            let name = key.replace("_", "-");
+            if !FILE_PARAMETERS.contains(&name.as_str()) {
+                return Err(From::from(format!("Unknown parameter {}", key)));
+            }
+
+            let value = match value {
+                toml::Value::String(s) => s,
+                toml::Value::Integer(i) => i.to_string(),
+                toml::Value::Float(f) => f.to_string(),
+                _ => {
+                    return Err(From::from(
+                        format!("Invalid value for {}: {}", key, value),
+                    ))
+                }
+            };
+            values.insert(name, value);
+        }
+        Ok(values)
+    }
+
+    /// Returns the reported metric "NAME@K" with the gains and the
+    /// discount of the training metric.
+    fn report_metric(&self, s: &str) -> Result<Box<metric::Measure>> {
+        let (name, k) = eval::split_metric(s)?;
+        metric::new_with_max_gain(
+            name,
+            k,
+            self.gain_table.clone(),
+            self.discount_base,
+            self.max_gain,
+        ).ok_or_else(|| From::from(format!("Unknown metric: {}", s)))
+    }
+
+    /// Checks that the parameters make sense before loading the data
+    /// and training.
+    pub fn validate(&self) -> Result<()> {
+        if metric::new(&self.metric, self.metric_k).is_none() {
+            Err(format!("Unknown metric: {}", self.metric))?;
+        }
+        if self.metric_k == 0 {
+            Err("Metric k must be greater than 0")?;
+        }
+        for name in self.report_metrics.iter() {
+            self.report_metric(name)?;
+        }
+        // Checked by the command line validators, but not in the
+        // config file.
+        if self.trees == 0 {
+            Err("Trees must be greater than 0")?;
+        }
+        if self.leaves < 2 {
+            Err(format!("Leaves must be at least 2: {}", self.leaves))?;
+        }
+        if self.thresholds_count == 0 {
+            Err("Thresholds must be greater than 0")?;
+        }
+
+        let paths = self.train_file_paths
+            .iter()
+            .cloned()
+            .chain(self.validate_files.iter().map(|&(_, path)| path))
+            .chain(self.test_file_path);
+        let mut stdin_count = 0;
+        for path in paths {
+            if path == STDIN_PATH {
+                stdin_count += 1;
+                continue;
+            }
+            File::open(path)
+                .map_err(|e| format!("Failed to open {}: {}", path, e))?;
+        }
+        if stdin_count > 1 {
+            Err("Only one file can be read from the standard input")?;
+        }
+
+        if self.dump_scores_every == 0 {
+            Err("Dump scores every must be greater than 0")?;
+        }
+
+        if self.threshold_sample_size == Some(0) {
+            Err("Threshold sample size must be greater than 0")?;
+        }
+
+        if let Some(clip) = self.lambda_clip {
+            if !(clip > 0.0) {
+                Err(format!("Lambda clip must be positive: {}", clip))?;
+            }
+        }
+
+        if !(self.min_sum_hessian >= 0.0) {
+            Err(format!(
+                "Min sum hessian must be non-negative: {}",
+                self.min_sum_hessian
+            ))?;
+        }
+
+        if !(self.min_delta >= 0.0) {
+            Err(format!("Min delta must be non-negative: {}", self.min_delta))?;
+        }
+
+        if let Some(tolerance) = self.train_tolerance {
+            if !(tolerance >= 0.0) {
+                Err(format!(
+                    "Train tolerance must be non-negative: {}",
+                    tolerance
+                ))?;
+            }
+        }
+
+        let last = self.trees.saturating_sub(1);
+        for &iteration in &[0, last] {
+            let rate = self.shrinkage.rate(iteration, self.trees);
+            if !(rate > 0.0 && rate <= 1.0) {
+                Err(format!("Shrinkage must be in (0, 1]: {}", rate))?;
+            }
+        }
+        Ok(())
+    }
 
-        let test_set = self.test_file_path.map(|path| {
-            let file = File::open(path).unwrap_or_else(|_e| exit(1));
-            let dataset = DataSet::load(file).unwrap_or_else(|_e| exit(1));
+    pub fn config(&self) -> Result<Config> {
+        let clamp = |mut dataset: DataSet| -> DataSet {
+            if let Some(max_label) = self.max_label {
+                dataset.clamp_labels(max_label);
+            }
             dataset
-        });
+        };
+        let load = |path: &str| -> Result<DataSet> {
+            let file = open_input(path)?;
+            DataSet::load_with_label_column(file, self.label_column)
+                .map(&clamp)
+                .map_err(|e| {
+                    From::from(format!("Failed to load {}: {}", path, e))
+                })
+        };
+
+        let mut train_set = clamp(super::load_files(
+            &self.train_file_paths,
+            self.label_column,
+            self.rename_qids,
+        )?);
+        train_set.filter_queries(self.min_query_docs, usize::max_value());
+        let mut validate_sets = Vec::new();
+        for &(name, path) in self.validate_files.iter() {
+            validate_sets.push((name.to_string(), load(path)?));
+        }
+        let test_set = match self.test_file_path {
+            Some(path) => Some(load(path)?),
+            None => None,
+        };
 
-        // The param is valid.
-        let metric = metric::new(self.metric, self.metric_k).unwrap();
+        let metric = metric::new_with_max_gain(
+            &self.metric,
+            self.metric_k,
+            self.gain_table.clone(),
+            self.discount_base,
+            self.max_gain,
+        ).ok_or_else(|| format!("Unknown metric: {}", self.metric))?;
+        let report_metrics = self.report_metrics
+            .iter()
+            .map(|name| self.report_metric(name))
+            .collect::<Result<Vec<_>>>()?;
 
-        Config {
+        Ok(Config {
             train: train_set,
             test: test_set,
             trees: self.trees,
             learning_rate: self.shrinkage,
             max_leaves: self.leaves,
             min_leaf_samples: self.min_leaf_samples,
+            min_leaf_support: self.min_leaf_support,
+            min_split_gain: self.min_split_gain,
+            min_sum_hessian: self.min_sum_hessian,
+            split_criterion: self.split_criterion,
+            lambda_clip: self.lambda_clip,
+            categorical_features: self.categorical_features.clone(),
             thresholds: self.thresholds_count,
+            binning: self.binning,
+            threshold_sample_size: self.threshold_sample_size,
             print_metric: true,
+            print_tree: self.print_tree,
+            profile: self.profile,
             metric: metric,
-            validate: validate_set,
+            report_metrics: report_metrics,
+            objective: self.objective,
+            zero_queries: self.zero_queries,
+            tie_break: self.tie_break,
+            validate: validate_sets,
             early_stop: self.early_stop,
-        }
+            min_delta: self.min_delta,
+            train_tolerance: self.train_tolerance,
+            calibrate: self.calibrate,
+            model_out: self.model_out.map(|path| path.to_string()),
+            model_format: self.model_format,
+            seed: self.seed,
+        })
     }
 
     pub fn print(&self) {
@@ -104,14 +546,22 @@ impl<'a> LambdaMARTParameter<'a> {
             println!("{:<20}: {}", name, value);
         }
 
-        print_param("Training file", self.train_file_path);
-        print_param(
-            "Validating file",
-            match self.validate_file_path {
-                Some(path) => path,
-                None => "None",
-            },
-        );
+        for path in self.train_file_paths.iter() {
+            print_param("Training file", path);
+        }
+        if self.rename_qids {
+            print_param("Rename qids", self.rename_qids);
+        }
+        if self.validate_files.is_empty() {
+            print_param("Validating file", "None");
+        }
+        for &(name, path) in self.validate_files.iter() {
+            if name.is_empty() {
+                print_param("Validating file", path);
+            } else {
+                print_param(&format!("Validating file {}", name), path);
+            }
+        }
         print_param(
             "Testing file",
             match self.test_file_path {
@@ -121,24 +571,182 @@ impl<'a> LambdaMARTParameter<'a> {
         );
         print_param(
             "Metric",
-            self.metric.to_owned() + "@" + &self.metric_k.to_string(),
+            self.metric.clone() + "@" + &self.metric_k.to_string(),
+        );
+        if !self.report_metrics.is_empty() {
+            print_param("Report metrics", self.report_metrics.join(","));
+        }
+        print_param("Objective", self.objective);
+        if let Some(ref gains) = self.gain_table {
+            let gains: Vec<String> =
+                gains.iter().map(|gain| gain.to_string()).collect();
+            print_param("Gain table", gains.join(","));
+        }
+        if self.discount_base != 2.0 {
+            print_param("Discount base", self.discount_base);
+        }
+        if let Some(max_gain) = self.max_gain {
+            print_param("Gain cap", max_gain);
+        }
+        print_param(
+            "Zero queries",
+            match self.zero_queries {
+                metric::ZeroQueries::Include => "Included",
+                metric::ZeroQueries::Exclude => "Excluded",
+            },
         );
+        print_param("Eval compat", self.tie_break);
+        print_param("Min query docs", self.min_query_docs);
+        if let Some(max_label) = self.max_label {
+            print_param("Max label", max_label);
+        }
         print_param("Trees", self.trees);
         print_param("Leaves", self.leaves);
         print_param("Shrinkage", self.shrinkage);
         print_param("Thresholds count", self.thresholds_count);
+        print_param("Binning", self.binning);
+        if let Some(size) = self.threshold_sample_size {
+            print_param("Threshold sample", size);
+        }
         print_param("Min leaf samples", self.min_leaf_samples);
+        print_param("Min leaf support", self.min_leaf_support);
+        print_param("Min split gain", self.min_split_gain);
+        if self.min_sum_hessian > 0.0 {
+            print_param("Min sum hessian", self.min_sum_hessian);
+        }
+        print_param("Split criterion", self.split_criterion);
+        if let Some(clip) = self.lambda_clip {
+            print_param("Lambda clip", clip);
+        }
+        if !self.categorical_features.is_empty() {
+            let mut fids: Vec<Id> =
+                self.categorical_features.iter().cloned().collect();
+            fids.sort();
+            let fids: Vec<String> =
+                fids.iter().map(|fid| fid.to_string()).collect();
+            print_param("Categorical features", fids.join(","));
+        }
         print_param("Early stop", self.early_stop);
+        print_param("Min delta", self.min_delta);
+        if let Some(tolerance) = self.train_tolerance {
+            print_param("Train tolerance", tolerance);
+        }
+        print_param("Calibrate", self.calibrate);
+        if self.model_out.is_some() {
+            print_param("Model format", self.model_format);
+        }
+        if self.print_tree {
+            print_param("Print tree", self.print_tree);
+        }
+        print_param("Seed", self.seed);
+    }
+}
+
+/// Parses comma separated feature ids, e.g. "1,5,7".
+fn parse_feature_ids(s: &str) -> Result<HashSet<Id>> {
+    let mut fids = HashSet::new();
+    for field in s.split(',') {
+        let fid = field.trim().parse::<Id>()?;
+        if fid == 0 {
+            Err(format!("Invalid feature id: {}", field))?;
+        }
+        fids.insert(fid);
+    }
+    Ok(fids)
+}
+
+/// Splits a validation file argument "NAME=PATH" into the name and
+/// the path. The name is empty if not given. A path may contain "=",
+/// as in "runs/lr=0.1/vali.txt", so the argument is a plain path if
+/// the part before "=" contains a "/", or if it names an existing
+/// file as a whole.
+fn validate_file(arg: &str) -> (&str, &str) {
+    match arg.find('=') {
+        Some(pos) if !arg[..pos].contains('/') &&
+            !std::path::Path::new(arg).is_file() =>
+        {
+            (&arg[..pos], &arg[pos + 1..])
+        }
+        _ => ("", arg),
     }
 }
 
 pub fn main<'a>(matches: &ArgMatches<'a>) {
     let param = LambdaMARTParameter::parse(matches);
+
+    if matches.is_present("summary") {
+        print_summary(&param);
+        return;
+    }
+
     param.print();
 
-    let mut lambdamart = LambdaMART::new(param.config());
-    lambdamart.init().unwrap();
-    lambdamart.learn().unwrap();
+    let config = param.validate().and_then(|_| param.config());
+    let config = config.unwrap_or_else(|e| {
+        error!("{}", e);
+        exit(1)
+    });
+
+    let mut lambdamart = LambdaMART::new(config);
+    if let Some(prefix) = param.dump_scores {
+        lambdamart.set_dump_scores(prefix, param.dump_scores_every);
+    }
+    lambdamart
+        .init()
+        .and_then(|_| lambdamart.learn())
+        .unwrap_or_else(|e| {
+            error!("{}", e);
+            exit(1)
+        });
+
+    if let Some(path) = param.history_out {
+        let result = open_output(path).and_then(|mut writer| {
+            lambdamart.write_history(&mut writer)?;
+            writer.flush()?;
+            Ok(())
+        });
+        result.unwrap_or_else(|e| {
+            error!("Failed to write {}: {}", path, e);
+            exit(1)
+        });
+    }
+
+    if let Some(path) = param.feature_usage_out {
+        let result = open_output(path).and_then(|mut writer| {
+            lambdamart.ensemble().write_feature_usage(&mut writer)?;
+            writer.flush()?;
+            Ok(())
+        });
+        result.unwrap_or_else(|e| {
+            error!("Failed to write {}: {}", path, e);
+            exit(1)
+        });
+    }
+}
+
+/// Prints the statistics of the training data without training.
+fn print_summary(param: &LambdaMARTParameter) {
+    let dataset = super::load_files(
+        &param.train_file_paths,
+        param.label_column,
+        param.rename_qids,
+    );
+    let dataset = dataset.unwrap_or_else(|e| {
+        error!("{}", e);
+        exit(1)
+    });
+    print!("{}", dataset.summary());
+}
+
+/// Returns a validator of the integer arguments which must be at least
+/// `min`, so that they fail with a clear message before any data is
+/// loaded.
+fn at_least(min: usize) -> impl Fn(String) -> std::result::Result<(), String> {
+    move |s| match s.parse::<usize>() {
+        Ok(n) if n >= min => Ok(()),
+        Ok(_) => Err(format!("must be at least {}", min)),
+        Err(_) => Err(format!("{} is not a non-negative integer", s)),
+    }
 }
 
 pub fn clap_command<'a, 'b>() -> App<'a, 'b> {
@@ -147,11 +755,143 @@ pub fn clap_command<'a, 'b>() -> App<'a, 'b> {
     let lambdamart_command = SubCommand::with_name("lambdamart")
         .about("Train LambdaMART")
         .args(&train_common_args)
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .takes_value(true)
+                .value_name("FILE")
+                .validator(super::non_blank_path)
+                .display_order(100)
+                .help("TOML file of parameters, overridden by command line options"),
+        )
+        .arg(
+            Arg::with_name("summary")
+                .long("summary")
+                .display_order(100)
+                .help("Print the statistics of the training data and exit"),
+        )
+        .arg(
+            Arg::with_name("history-out")
+                .long("history-out")
+                .takes_value(true)
+                .value_name("FILE")
+                .validator(super::non_blank_path)
+                .display_order(100)
+                .help("Write the metrics of each iteration to the file as CSV"),
+        )
+        .arg(
+            Arg::with_name("dump-scores")
+                .long("dump-scores")
+                .takes_value(true)
+                .value_name("PREFIX")
+                .validator(super::non_blank_path)
+                .display_order(100)
+                .help("Write the model scores of the training data to PREFIX.round_N.txt for debugging"),
+        )
+        .arg(
+            Arg::with_name("dump-scores-every")
+                .long("dump-scores-every")
+                .takes_value(true)
+                .value_name("K")
+                .default_value("1")
+                .display_order(100)
+                .help("Write the scores every K rounds"),
+        )
+        .arg(
+            Arg::with_name("feature-usage-out")
+                .long("feature-usage-out")
+                .takes_value(true)
+                .value_name("FILE")
+                .validator(super::non_blank_path)
+                .display_order(100)
+                .help("Write the split counts, gains and mean thresholds of each feature to the file as CSV"),
+        )
+        .arg(
+            Arg::with_name("save-model")
+                .long("save-model")
+                .alias("output-model")
+                .takes_value(true)
+                .value_name("FILE")
+                .validator(super::non_blank_path)
+                .display_order(100)
+                .help("Save the trained model, truncated to the best iteration if early stopped, to the file"),
+        )
+        .arg(
+            Arg::with_name("model-format")
+                .long("model-format")
+                .takes_value(true)
+                .value_name("FORMAT")
+                .possible_values(&["text", "binary"])
+                .default_value("text")
+                .display_order(100)
+                .help("Format of the saved model; binary is smaller than text"),
+        )
+        .arg(
+            Arg::with_name("calibrate")
+                .long("calibrate")
+                .requires("validate-file")
+                .display_order(100)
+                .help("Fit a Platt calibration of the scores on the first validation set"),
+        )
+        .arg(
+            Arg::with_name("report-metrics")
+                .long("report-metrics")
+                .takes_value(true)
+                .value_name("NAME@K,...")
+                .display_order(100)
+                .help("Also report these metrics each round, e.g. NDCG@5,DCG@10; only --metric drives the training"),
+        )
+        .arg(
+            Arg::with_name("print-tree")
+                .long("print-tree")
+                .display_order(100)
+                .help("Print each tree after it is fitted"),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .display_order(100)
+                .help("Print the time spent in each phase of the training"),
+        )
+        .arg(
+            Arg::with_name("exclude-zero-queries")
+                .long("exclude-zero-queries")
+                .display_order(100)
+                .help("Leave the queries whose labels are all zeros out of the reported metrics"),
+        )
+        .arg(
+            Arg::with_name("min-query-docs")
+                .long("min-query-docs")
+                .takes_value(true)
+                .value_name("NUM")
+                .default_value("1")
+                .display_order(100)
+                .help("Remove the training queries with fewer than NUM instances"),
+        )
+        .arg(
+            Arg::with_name("max-label")
+                .long("max-label")
+                .takes_value(true)
+                .value_name("G")
+                .display_order(100)
+                .help("Clamp the labels greater than G to G"),
+        )
+        .arg(
+            Arg::with_name("eval-compat")
+                .long("eval-compat")
+                .takes_value(true)
+                .value_name("MODE")
+                .possible_values(&["default", "trec"])
+                .default_value("default")
+                .display_order(100)
+                .help("How tied scores are ranked in the reported metrics; trec ranks them by descending label, the best case of the ties"),
+        )
         .arg(
             Arg::with_name("trees")
                 .required_if("type", "lambdamart")
                 .long("trees")
                 .takes_value(true)
+                .validator(at_least(1))
                 .value_name("NUM")
                 .default_value("1000")
                 .display_order(101)
@@ -162,6 +902,7 @@ pub fn clap_command<'a, 'b>() -> App<'a, 'b> {
                 .required_if("type", "lambdamart")
                 .long("leaves")
                 .takes_value(true)
+                .validator(at_least(2))
                 .value_name("NUM")
                 .default_value("10")
                 .display_order(102)
@@ -177,16 +918,62 @@ pub fn clap_command<'a, 'b>() -> App<'a, 'b> {
                 .display_order(103)
                 .help("Shrinkage, or learning rate"),
         )
+        .arg(
+            Arg::with_name("shrinkage-schedule")
+                .long("shrinkage-schedule")
+                .value_name("SPEC")
+                .takes_value(true)
+                .default_value("constant")
+                .display_order(103)
+                .help("Shrinkage schedule: constant, step:FACTOR:EVERY, or linear:END"),
+        )
         .arg(
             Arg::with_name("thresholds")
                 .required_if("type", "lambdamart")
                 .long("thresholds")
                 .takes_value(true)
+                .validator(at_least(1))
                 .value_name("NUM")
                 .default_value("256")
                 .display_order(104)
                 .help("Number of threshold candidates for tree spliting"),
         )
+        .arg(
+            Arg::with_name("objective")
+                .long("objective")
+                .takes_value(true)
+                .possible_values(&["lambdarank", "ranknet", "pairwise"])
+                .default_value("lambdarank")
+                .display_order(100)
+                .help("Loss whose gradients the trees fit"),
+        )
+        .arg(
+            Arg::with_name("binning")
+                .long("binning")
+                .takes_value(true)
+                .value_name("STRATEGY")
+                .possible_values(&["uniform", "quantile"])
+                .default_value("uniform")
+                .display_order(104)
+                .help("How the threshold candidates are generated"),
+        )
+        .arg(
+            Arg::with_name("max-samples-for-thresholds")
+                .long("max-samples-for-thresholds")
+                .takes_value(true)
+                .value_name("NUM")
+                .display_order(104)
+                .help("Generate the threshold candidates from a random sample of at most NUM instances"),
+        )
+        .arg(
+            Arg::with_name("min-leaf-samples")
+                .long("min-leaf-samples")
+                .takes_value(true)
+                .value_name("NUM")
+                .default_value("1")
+                .display_order(105)
+                .help("Minimum #samples of a node for it to split"),
+        )
         .arg(
             Arg::with_name("min-leaf-support")
                 .required_if("type", "lambdamart")
@@ -197,6 +984,50 @@ pub fn clap_command<'a, 'b>() -> App<'a, 'b> {
                 .display_order(105)
                 .help("Min leaf support -- minimum #samples each leaf has to contain"),
         )
+        .arg(
+            Arg::with_name("min-split-gain")
+                .long("min-split-gain")
+                .takes_value(true)
+                .value_name("GAIN")
+                .default_value("0.0")
+                .display_order(105)
+                .help("Minimum gain of the split criterion for a node to split"),
+        )
+        .arg(
+            Arg::with_name("min-sum-hessian")
+                .long("min-sum-hessian")
+                .takes_value(true)
+                .value_name("SUM")
+                .default_value("0.0")
+                .display_order(105)
+                .help("Minimum sum of the hessians of each child for a node to split"),
+        )
+        .arg(
+            Arg::with_name("split-criterion")
+                .long("split-criterion")
+                .takes_value(true)
+                .value_name("CRITERION")
+                .possible_values(&["variance", "friedman"])
+                .default_value("variance")
+                .display_order(105)
+                .help("How the gains of the splits are measured: variance reduction or Friedman's MSE improvement"),
+        )
+        .arg(
+            Arg::with_name("lambda-clip")
+                .long("lambda-clip")
+                .takes_value(true)
+                .value_name("CLIP")
+                .display_order(105)
+                .help("Clamp the lambda of each instance into [-CLIP, CLIP] for numerical stability"),
+        )
+        .arg(
+            Arg::with_name("categorical-features")
+                .long("categorical-features")
+                .takes_value(true)
+                .value_name("ID,...")
+                .display_order(105)
+                .help("Split these features by category, one against the rest, instead of by threshold"),
+        )
         .arg(
             Arg::with_name("early-stop")
                 .required_if("type", "lambdamart")
@@ -206,6 +1037,446 @@ pub fn clap_command<'a, 'b>() -> App<'a, 'b> {
                 .default_value("100")
                 .display_order(106)
                 .help("Stop early when no improvement is observed on validaton data in e consecutive rounds"),
+        )
+        .arg(
+            Arg::with_name("min-delta")
+                .long("min-delta")
+                .takes_value(true)
+                .value_name("DELTA")
+                .default_value("0.0")
+                .display_order(106)
+                .help("Minimum increase of the validation metric to count as an improvement"),
+        )
+        .arg(
+            Arg::with_name("train-tolerance")
+                .long("train-tolerance")
+                .takes_value(true)
+                .value_name("DELTA")
+                .display_order(106)
+                .help("Stop when the training metric improves by less than DELTA in 3 consecutive rounds"),
         );
     lambdamart_command
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_toml() {
+        let s = "metric = \"DCG\"
+metric_k = 5
+shrinkage = 0.05
+";
+        let values = LambdaMARTParameter::parse_toml(s).unwrap();
+        assert_eq!(values["metric"], "DCG");
+        assert_eq!(values["metric-k"], "5");
+        assert_eq!(values["shrinkage"], "0.05");
+
+        assert!(LambdaMARTParameter::parse_toml("tree = 5").is_err());
+        assert!(LambdaMARTParameter::parse_toml("trees = [5]").is_err());
+    }
+
+    #[test]
+    fn test_config_file_overridden_by_cli() {
+        let path = std::env::temp_dir().join("rforests-test-lambdamart.toml");
+        File::create(&path)
+            .unwrap()
+            .write_all(b"trees = 50\nleaves = 20\n")
+            .unwrap();
+
+        let matches = clap_command().get_matches_from(vec![
+            "lambdamart",
+            "--train",
+            "train.txt",
+            "--config",
+            path.to_str().unwrap(),
+            "--leaves",
+            "30",
+        ]);
+        let param = LambdaMARTParameter::parse(&matches);
+        assert_eq!(param.trees, 50);
+        assert_eq!(param.leaves, 30);
+        assert_eq!(param.thresholds_count, 256);
+    }
+
+    #[test]
+    fn test_config_uses_parsed_parameters() {
+        let matches = clap_command().get_matches_from(vec![
+            "lambdamart",
+            "--train",
+            "./data/train-lite.txt",
+            "--trees",
+            "50",
+            "--leaves",
+            "20",
+            "--shrinkage",
+            "0.5",
+            "--thresholds",
+            "64",
+            "--min-leaf-samples",
+            "5",
+            "--min-leaf-support",
+            "3",
+            "--early-stop",
+            "7",
+        ]);
+        let config = LambdaMARTParameter::parse(&matches).config().unwrap();
+        assert_eq!(config.trees, 50);
+        assert_eq!(config.max_leaves, 20);
+        assert_eq!(config.learning_rate.rate(0, 50), 0.5);
+        assert_eq!(config.thresholds, 64);
+        assert_eq!(config.min_leaf_samples, 5);
+        assert_eq!(config.min_leaf_support, 3);
+        assert_eq!(config.early_stop, 7);
+    }
+
+    #[test]
+    fn test_eval_compat() {
+        let parse = |args: Vec<&str>| {
+            let mut all = vec!["lambdamart", "--train", "train.txt"];
+            all.extend(args);
+            let matches = clap_command().get_matches_from(all);
+            LambdaMARTParameter::parse(&matches).tie_break
+        };
+        assert_eq!(parse(vec![]), metric::TieBreak::Index);
+        let trec = parse(vec!["--eval-compat", "trec"]);
+        assert_eq!(trec, metric::TieBreak::Trec);
+    }
+
+    #[test]
+    fn test_model_format() {
+        let parse = |args: Vec<&str>| {
+            let mut all = vec!["lambdamart", "--train", "train.txt"];
+            all.extend(args);
+            let matches = clap_command().get_matches_from(all);
+            LambdaMARTParameter::parse(&matches).model_format
+        };
+        assert_eq!(parse(vec![]), ModelFormat::Text);
+        let binary = parse(vec!["--model-format", "binary"]);
+        assert_eq!(binary, ModelFormat::Binary);
+
+        let path = std::env::temp_dir().join("rforests-test-model-format.toml");
+        File::create(&path)
+            .unwrap()
+            .write_all(b"model_format = \"binary\"\n")
+            .unwrap();
+        let binary = parse(vec!["--config", path.to_str().unwrap()]);
+        assert_eq!(binary, ModelFormat::Binary);
+    }
+
+    #[test]
+    fn test_dump_scores_every() {
+        let parse = |args: Vec<&str>| {
+            let mut all = vec!["lambdamart", "--train", "train.txt"];
+            all.extend(args);
+            let matches = clap_command().get_matches_from(all);
+            LambdaMARTParameter::parse(&matches).dump_scores_every
+        };
+        assert_eq!(parse(vec![]), 1);
+        assert_eq!(parse(vec!["--dump-scores-every", "5"]), 5);
+
+        let path = std::env::temp_dir().join("rforests-test-dump-every.toml");
+        File::create(&path)
+            .unwrap()
+            .write_all(b"dump_scores_every = 10\n")
+            .unwrap();
+        assert_eq!(parse(vec!["--config", path.to_str().unwrap()]), 10);
+    }
+
+    #[test]
+    fn test_max_label() {
+        let matches = clap_command().get_matches_from(vec![
+            "lambdamart",
+            "--train",
+            "./data/train-lite.txt",
+            "--validate",
+            "./data/train-lite.txt",
+            "--max-label",
+            "1",
+        ]);
+        let config = LambdaMARTParameter::parse(&matches).config().unwrap();
+        let datasets = Some(&config.train)
+            .into_iter()
+            .chain(config.validate.iter().map(|&(_, ref v)| v));
+        for dataset in datasets {
+            assert!(dataset.label_iter().any(|label| label == 1.0));
+            assert!(dataset.label_iter().all(|label| label <= 1.0));
+        }
+    }
+
+    #[test]
+    fn test_validate_file() {
+        assert_eq!(validate_file("vali.txt"), ("", "vali.txt"));
+        assert_eq!(validate_file("V2=data/vali.txt"), ("V2", "data/vali.txt"));
+        assert_eq!(
+            validate_file("runs/lr=0.1/vali.txt"),
+            ("", "runs/lr=0.1/vali.txt")
+        );
+    }
+
+    #[test]
+    fn test_categorical_features() {
+        assert_eq!(
+            parse_feature_ids("7, 1,7").unwrap(),
+            vec![1, 7].into_iter().collect()
+        );
+        assert!(parse_feature_ids("1,0").is_err());
+        assert!(parse_feature_ids("1,a").is_err());
+
+        let matches = clap_command().get_matches_from(vec![
+            "lambdamart",
+            "--train",
+            "./data/train-lite.txt",
+            "--categorical-features",
+            "2,3",
+        ]);
+        let config = LambdaMARTParameter::parse(&matches).config().unwrap();
+        assert_eq!(
+            config.categorical_features,
+            vec![2, 3].into_iter().collect()
+        );
+    }
+
+    #[test]
+    fn test_gain_table() {
+        let matches = clap_command().get_matches_from(vec![
+            "lambdamart",
+            "--train",
+            "./data/train-lite.txt",
+            "--metric",
+            "DCG",
+            "--gain-table",
+            "0,5",
+        ]);
+        let param = LambdaMARTParameter::parse(&matches);
+        assert_eq!(param.gain_table, Some(vec![0.0, 5.0]));
+
+        let config = param.config().unwrap();
+        assert_eq!(config.metric.measure(&[1.0]), 5.0);
+        assert_eq!(config.metric.measure(&[2.0]), 3.0);
+    }
+
+    #[test]
+    fn test_report_metrics() {
+        let matches = clap_command().get_matches_from(vec![
+            "lambdamart",
+            "--train",
+            "./data/train-lite.txt",
+            "--report-metrics",
+            "NDCG@5, DCG@10",
+        ]);
+        let param = LambdaMARTParameter::parse(&matches);
+        assert!(param.validate().is_ok());
+        let config = param.config().unwrap();
+        let names: Vec<String> = config
+            .report_metrics
+            .iter()
+            .map(|metric| metric.name())
+            .collect();
+        assert_eq!(names, vec!["NDCG@5", "DCG@10"]);
+
+        // The reported metrics share the gains of the training metric.
+        let matches = clap_command().get_matches_from(vec![
+            "lambdamart",
+            "--train",
+            "./data/train-lite.txt",
+            "--report-metrics",
+            "DCG@1",
+            "--cap-gain",
+            "1",
+        ]);
+        let config = LambdaMARTParameter::parse(&matches).config().unwrap();
+        assert_eq!(config.report_metrics[0].measure(&[4.0]), 1.0);
+
+        let matches = clap_command().get_matches_from(vec![
+            "lambdamart",
+            "--train",
+            "./data/train-lite.txt",
+            "--report-metrics",
+            "NDCG@5,MAP",
+        ]);
+        assert!(LambdaMARTParameter::parse(&matches).validate().is_err());
+    }
+
+    #[test]
+    fn test_cap_gain() {
+        let matches = clap_command().get_matches_from(vec![
+            "lambdamart",
+            "--train",
+            "./data/train-lite.txt",
+            "--metric",
+            "DCG",
+            "--cap-gain",
+            "5",
+        ]);
+        let param = LambdaMARTParameter::parse(&matches);
+        assert_eq!(param.max_gain, Some(5.0));
+
+        let config = param.config().unwrap();
+        assert_eq!(config.metric.measure(&[2.0]), 3.0);
+        assert_eq!(config.metric.measure(&[31.0]), 5.0);
+    }
+
+    #[test]
+    fn test_split_criterion() {
+        let matches = clap_command().get_matches_from(vec![
+            "lambdamart",
+            "--train",
+            "./data/train-lite.txt",
+            "--split-criterion",
+            "friedman",
+        ]);
+        let config = LambdaMARTParameter::parse(&matches).config().unwrap();
+        assert_eq!(config.split_criterion, Criterion::Friedman);
+
+        let matches = clap_command().get_matches_from(vec![
+            "lambdamart",
+            "--train",
+            "./data/train-lite.txt",
+        ]);
+        let config = LambdaMARTParameter::parse(&matches).config().unwrap();
+        assert_eq!(config.split_criterion, Criterion::Variance);
+    }
+
+    #[test]
+    fn test_discount_base() {
+        let matches = clap_command().get_matches_from(vec![
+            "lambdamart",
+            "--train",
+            "./data/train-lite.txt",
+            "--metric",
+            "DCG",
+            "--discount-base",
+            "3",
+        ]);
+        let param = LambdaMARTParameter::parse(&matches);
+        assert_eq!(param.discount_base, 3.0);
+
+        let config = param.config().unwrap();
+        let score = config.metric.measure(&[0.0, 1.0]);
+        assert!((score - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_validate() {
+        let parse = |args: &[&str]| {
+            let mut argv =
+                vec!["lambdamart", "--train", "./data/train-lite.txt"];
+            argv.extend_from_slice(args);
+            let matches = clap_command().get_matches_from(argv);
+            LambdaMARTParameter::parse(&matches).validate()
+        };
+
+        assert!(parse(&[]).is_ok());
+        assert!(parse(&["--metric-k", "0"]).is_err());
+        assert!(parse(&["--shrinkage", "0"]).is_err());
+        assert!(parse(&["--shrinkage", "1.5"]).is_err());
+        assert!(parse(&["--min-delta=-0.1"]).is_err());
+        assert!(parse(&["--max-samples-for-thresholds", "0"]).is_err());
+        assert!(parse(&["--max-samples-for-thresholds", "100"]).is_ok());
+        assert!(parse(&["--lambda-clip", "0"]).is_err());
+        assert!(parse(&["--lambda-clip", "10"]).is_ok());
+        assert!(parse(&["--train-tolerance=-0.1"]).is_err());
+        assert!(parse(&["--train-tolerance", "0.001"]).is_ok());
+        assert!(parse(&["--dump-scores-every", "0"]).is_err());
+        assert!(parse(&["--validate", "./data/nonexistent.txt"]).is_err());
+    }
+
+    #[test]
+    fn test_numeric_validators() {
+        let parse = |args: &[&str]| {
+            let mut argv = vec!["lambdamart", "--train", "train.txt"];
+            argv.extend_from_slice(args);
+            clap_command().get_matches_from_safe(argv)
+        };
+
+        assert!(parse(&[]).is_ok());
+        assert!(parse(&["--trees", "0"]).is_err());
+        assert!(parse(&["--trees", "1"]).is_ok());
+        assert!(parse(&["--leaves", "1"]).is_err());
+        assert!(parse(&["--leaves", "2"]).is_ok());
+        assert!(parse(&["--thresholds", "0"]).is_err());
+        assert!(parse(&["--thresholds", "x"]).is_err());
+        let e = parse(&["--leaves", "1"]).unwrap_err();
+        assert!(e.message.contains("must be at least 2"));
+    }
+
+    #[test]
+    fn test_path_validators() {
+        let parse = |args: &[&str]| {
+            let mut argv = vec!["lambdamart"];
+            argv.extend_from_slice(args);
+            clap_command().get_matches_from_safe(argv)
+        };
+
+        assert!(parse(&["--train", "train.txt"]).is_ok());
+        assert!(parse(&["--train", " "]).is_err());
+        assert!(parse(&["--train", "train.txt", "--validate", "\t"]).is_err());
+        assert!(parse(&["--train", "train.txt", "--test", " "]).is_err());
+        assert!(parse(&["--train", "train.txt", "--save-model", ""]).is_err());
+        for name in &["--history-out", "--dump-scores", "--feature-usage-out"] {
+            assert!(parse(&["--train", "train.txt", name, " "]).is_err());
+        }
+        let e = parse(&["--train", " "]).unwrap_err();
+        assert!(e.message.contains("the path must not be empty"));
+    }
+
+    #[test]
+    fn test_multiple_validate_files() {
+        let matches = clap_command().get_matches_from(vec![
+            "lambdamart",
+            "--train",
+            "./data/train-lite.txt",
+            "--validate",
+            "web=./data/train-lite.txt",
+            "--validate",
+            "./data/train-lite.txt",
+        ]);
+        let param = LambdaMARTParameter::parse(&matches);
+        assert_eq!(
+            param.validate_files,
+            vec![
+                ("web", "./data/train-lite.txt"),
+                ("", "./data/train-lite.txt"),
+            ]
+        );
+
+        let config = param.config().unwrap();
+        let names: Vec<&str> =
+            config.validate.iter().map(|&(ref name, _)| &name[..]).collect();
+        assert_eq!(names, vec!["web", ""]);
+    }
+
+    #[test]
+    fn test_multiple_train_files() {
+        let parse = |args: &[&str]| {
+            let mut argv = vec![
+                "lambdamart",
+                "--train",
+                "./data/train-lite.txt",
+                "./data/train-lite.txt",
+            ];
+            argv.extend_from_slice(args);
+            let matches = clap_command().get_matches_from(argv);
+            LambdaMARTParameter::parse(&matches).config().unwrap()
+        };
+        let path = "./data/train-lite.txt";
+        let single = DataSet::load(File::open(path).unwrap()).unwrap();
+        let nqueries = single.query_iter().count();
+
+        let config = parse(&[]);
+        assert_eq!(config.train.len(), 2 * single.len());
+        // The files share the qids, yet their queries stay apart since
+        // the last qid of the file differs from the first.
+        assert_eq!(config.train.query_iter().count(), 2 * nqueries);
+
+        let config = parse(&["--rename-qids"]);
+        assert_eq!(config.train.len(), 2 * single.len());
+        let mut qids: Vec<_> =
+            config.train.query_iter().map(|(qid, _)| qid).collect();
+        qids.sort();
+        qids.dedup();
+        assert_eq!(qids.len(), 2 * nqueries);
+    }
+}
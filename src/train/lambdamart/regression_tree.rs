@@ -1,15 +1,30 @@
 use std;
+use metric;
 use train::dataset::*;
 use util::*;
 use std::collections::BinaryHeap;
+use format::prediction::{PredictionFormat, PredictionWriter};
 use std::cmp::Ordering;
+use std::io::{BufWriter, Read, Write};
+use train::lambdamart::histogram::SplitStrategy;
 use train::lambdamart::training_set::*;
+use train::Evaluate;
 
 /// A node in the regression tree.
 struct Node {
     fid: Option<Id>,
     threshold: Option<Value>,
+    /// The categories of the instances that go to the left child, if
+    /// the node splits a categorical feature. The threshold is None
+    /// then.
+    categories: Option<Vec<Value>>,
     output: Option<f64>,
+    /// Output the node would have as a leaf, i.e. the mean output of
+    /// its subtree. Used for feature attribution.
+    mean: f64,
+    /// The reduction of the variance by the split of the node during
+    /// training. It is 0.0 for leaves and for loaded models.
+    gain: f64,
     parent: Option<usize>,
     left: Option<usize>,
     right: Option<usize>,
@@ -21,10 +36,13 @@ impl Node {
         Node {
             fid: None,
             threshold: None,
+            categories: None,
             parent: parent,
             left: None,
             right: None,
             output: None,
+            mean: 0.0,
+            gain: 0.0,
         }
     }
 
@@ -41,8 +59,38 @@ impl Node {
         self.right = Some(right);
     }
 
+    /// Splits the feature into the instances of the categories, which
+    /// go left, and the rest.
+    pub fn set_categorical(
+        &mut self,
+        fid: Id,
+        categories: Vec<Value>,
+        left: usize,
+        right: usize,
+    ) {
+        self.fid = Some(fid);
+        self.categories = Some(categories);
+        self.left = Some(left);
+        self.right = Some(right);
+    }
+
     pub fn set_leaf(&mut self, output: f64) {
         self.output = Some(output);
+        self.mean = output;
+    }
+
+    /// Returns the index of the child the instance goes to.
+    fn child(&self, instance: &Instance) -> usize {
+        let value = instance.value(self.fid.unwrap());
+        let left = match self.categories {
+            Some(ref categories) => categories.contains(&value),
+            None => value <= self.threshold.unwrap(),
+        };
+        if left {
+            self.left.unwrap()
+        } else {
+            self.right.unwrap()
+        }
     }
 }
 
@@ -50,9 +98,10 @@ impl std::fmt::Debug for Node {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
             f,
-            "Node {{ fid: {:?}, threshold: {:?}, output: {:?}, left: {:?}, right: {:?} }}",
+            "Node {{ fid: {:?}, threshold: {:?}, categories: {:?}, output: {:?}, left: {:?}, right: {:?} }}",
             self.fid,
             self.threshold,
+            self.categories,
             self.output,
             self.left,
             self.right
@@ -67,13 +116,32 @@ fn option_to_string<T: ToString>(option: &Option<T>) -> String {
     }
 }
 
+/// How the output value of a leaf is computed from its samples.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LeafOutput {
+    /// The Newton step of the lambdas and weights. Used by boosting.
+    Newton,
+    /// The mean of the target values. Used by random forest.
+    Mean,
+}
+
 /// A regression tree.
 #[derive(Debug)]
 pub struct RegressionTree {
     learning_rate: f64,
-    // Minimal count of samples per leaf.
+    // Minimal count of samples of a node to split.
     min_leaf_samples: usize,
+    // Minimal count of samples of each child of a split.
+    min_leaf_support: usize,
+    // Minimal variance reduction of a split.
+    min_split_gain: f64,
+    // Minimal sum of the hessians of each child of a split.
+    min_sum_hessian: f64,
     max_leaves: usize,
+    leaf_output: LeafOutput,
+    split_strategy: SplitStrategy,
+    // Generates the seed of each random split.
+    rng: Rng,
     nodes: Vec<Node>,
 }
 
@@ -116,7 +184,8 @@ impl<'a> Ord for NodeData<'a> {
 
 impl RegressionTree {
     /// Create a new regression tree, with at least min_leaf_samples
-    /// training instances on the leaves.
+    /// training instances on the leaves, unless a lower support is
+    /// set by `set_min_leaf_support`.
     pub fn new(
         learning_rate: f64,
         max_leaves: usize,
@@ -125,16 +194,71 @@ impl RegressionTree {
         RegressionTree {
             learning_rate: learning_rate,
             min_leaf_samples: min_leaf_samples,
+            min_leaf_support: min_leaf_samples,
+            min_split_gain: 0.0,
+            min_sum_hessian: 0.0,
             max_leaves: max_leaves,
+            leaf_output: LeafOutput::Newton,
+            split_strategy: SplitStrategy::Best,
+            rng: Rng::new(0),
             nodes: Vec::new(),
         }
     }
 
+    /// Sets how the output value of leaves are computed. Defaults to
+    /// `LeafOutput::Newton`.
+    pub fn set_leaf_output(&mut self, leaf_output: LeafOutput) {
+        self.leaf_output = leaf_output;
+    }
+
+    /// Sets how the thresholds of the splits are chosen. Defaults to
+    /// `SplitStrategy::Best`. The seed is used by
+    /// `SplitStrategy::Random`.
+    pub fn set_split_strategy(&mut self, strategy: SplitStrategy, seed: u64) {
+        self.split_strategy = strategy;
+        self.rng = Rng::new(seed);
+    }
+
+    /// Sets the minimal count of training instances of each child of
+    /// a split. A split leaving less in either child is not
+    /// considered, while `min_leaf_samples` only stops the split of
+    /// smaller nodes. Defaults to `min_leaf_samples`.
+    pub fn set_min_leaf_support(&mut self, min_leaf_support: usize) {
+        self.min_leaf_support = min_leaf_support;
+    }
+
+    /// Sets the minimal variance reduction for a node to split.
+    /// Defaults to 0.0.
+    pub fn set_min_split_gain(&mut self, min_split_gain: f64) {
+        self.min_split_gain = min_split_gain;
+    }
+
+    /// Sets the minimal sum of the hessians, the Newton step weights,
+    /// of each child of a split. Splits leaving less in either child
+    /// are not considered, and a node with no other split becomes a
+    /// leaf, so that no leaf is fitted on too little signal. Defaults
+    /// to 0.0, which never stops a split.
+    pub fn set_min_sum_hessian(&mut self, min_sum_hessian: f64) {
+        self.min_sum_hessian = min_sum_hessian;
+    }
+
+    /// Computes the output value of a leaf.
+    fn leaf_value(&self, sample: &TrainSample) -> f64 {
+        match self.leaf_output {
+            LeafOutput::Newton => sample.newton_output(),
+            LeafOutput::Mean => sample.mean_output(),
+        }
+    }
+
+    /// Splits the node at the threshold, or by the category if
+    /// `categorical`. See `SampleSplit`.
     fn split_node(
         &mut self,
         index: usize,
         fid: usize,
         threshold: f64,
+        categorical: bool,
+        gain: f64,
     ) -> (usize, usize) {
         let left_index = self.nodes.len();
         let mut left = Node::new(Some(index));
@@ -146,7 +270,12 @@ impl RegressionTree {
         self.nodes.push(right);
 
         let node = &mut self.nodes[index];
-        node.set_non_leaf(fid, threshold, left_index, right_index);
+        if categorical {
+            node.set_categorical(fid, vec![threshold], left_index, right_index);
+        } else {
+            node.set_non_leaf(fid, threshold, left_index, right_index);
+        }
+        node.gain = gain;
 
         (left_index, right_index)
     }
@@ -156,10 +285,23 @@ impl RegressionTree {
     }
 
     /// Fit to a training.
-    pub fn fit(&mut self, training: &TrainSet) -> Vec<Value> {
+    pub fn fit(&mut self, training: &TrainSet) -> Result<Vec<Value>> {
         let sample = TrainSample::from(training);
+        self.fit_sample(sample)
+    }
+
+    /// Fit to a sample of a training. Returns the output of each
+    /// instance of the training, which is 0.0 for instances not in the
+    /// sample. A sample that cannot be split results in a tree of a
+    /// single leaf. An empty sample is an error.
+    pub fn fit_sample(&mut self, sample: TrainSample) -> Result<Vec<Value>> {
+        if sample.len() == 0 {
+            Err("Cannot fit a regression tree to an empty sample")?;
+        }
+
         let mut leaves = 0;
-        let mut leaf_output: Vec<Value> = vec![0.0; training.len()];
+        let mut leaf_output: Vec<Value> =
+            vec![0.0; sample.training().len()];
 
         let root = Node::new(None);
         self.nodes.push(root);
@@ -170,9 +312,11 @@ impl RegressionTree {
 
         while !queue.is_empty() {
             let NodeData { index, sample } = queue.pop().unwrap();
+            let value = self.leaf_value(&sample);
+            self.nodes[index].mean = value;
+
             // We have reached leaves count limitation.
             if 1 + leaves + queue.len() >= self.max_leaves {
-                let value = sample.newton_output();
                 let output = value * self.learning_rate;
                 self.set_leaf_node(index, value);
                 sample.update_output(&mut leaf_output, output);
@@ -180,9 +324,19 @@ impl RegressionTree {
                 continue;
             }
 
-            let split_result = sample.split(self.min_leaf_samples);
+            let seed = match self.split_strategy {
+                SplitStrategy::Best => 0,
+                SplitStrategy::Random => self.rng.next_u64(),
+            };
+            let split_result = sample.split_with(
+                self.min_leaf_samples,
+                self.min_leaf_support,
+                self.min_sum_hessian,
+                self.min_split_gain,
+                self.split_strategy,
+                seed,
+            );
             if split_result.is_none() {
-                let value = sample.newton_output();
                 let output = value * self.learning_rate;
                 self.set_leaf_node(index, value);
                 sample.update_output(&mut leaf_output, output);
@@ -195,28 +349,35 @@ impl RegressionTree {
             let right_len = split.right.len();
 
             // Split node at `index`.
-            let (left, right) =
-                self.split_node(index, split.fid, split.threshold);
+            let (left, right) = self.split_node(
+                index,
+                split.fid,
+                split.threshold,
+                split.categorical,
+                split.gain,
+            );
 
             queue.push(NodeData::new(left, split.left));
             queue.push(NodeData::new(right, split.right));
 
             debug!(
-                "Split: fid:{} threshold:{} s:{}",
+                "Split: fid:{} threshold:{} gain:{}",
                 split.fid,
                 split.threshold,
-                split.s
+                split.gain
             );
             debug!("Split: {} => {} + {}", sample.len(), left_len, right_len);
         }
 
-        leaf_output
+        Ok(leaf_output)
     }
 
-    pub fn print(&self) {
+    /// Writes the nodes of the tree in depth-first order, indenting
+    /// each child two spaces deeper than its parent.
+    pub fn write_tree<W: Write>(&self, writer: &mut W) -> Result<()> {
         if self.nodes.is_empty() {
-            println!("Empty tree");
-            return;
+            writeln!(writer, "Empty tree")?;
+            return Ok(());
         }
 
         // (index, indent)
@@ -224,19 +385,67 @@ impl RegressionTree {
         while !queue.is_empty() {
             let (index, indent) = queue.pop().unwrap();
             let node = &self.nodes[index];
-            print!("{:width$}", "", width = indent);
+            write!(writer, "{:width$}", "", width = indent)?;
             if let Some(output) = node.output {
-                println!("{{ output: {:?} }}", output);
+                writeln!(writer, "{{ output: {:?} }}", output)?;
+            } else if let Some(ref categories) = node.categories {
+                writeln!(
+                    writer,
+                    "{{ fid: {:?}, categories: {:?} }}",
+                    option_to_string(&node.fid),
+                    categories
+                )?;
+                queue.push((node.left.unwrap(), indent + 2));
+                queue.push((node.right.unwrap(), indent + 2));
             } else {
-                println!(
+                writeln!(
+                    writer,
                     "{{ fid: {:?}, threshold: {:?} }}",
                     option_to_string(&node.fid),
                     option_to_string(&node.threshold)
-                );
+                )?;
                 queue.push((node.left.unwrap(), indent + 2));
                 queue.push((node.right.unwrap(), indent + 2));
             }
         }
+        Ok(())
+    }
+}
+
+impl RegressionTree {
+    /// Returns (feature id, threshold, gain) of each split node. The
+    /// threshold is None for a categorical split.
+    fn splits<'a>(
+        &'a self,
+    ) -> impl Iterator<Item = (Id, Option<Value>, f64)> + 'a {
+        self.nodes
+            .iter()
+            .filter(|node| node.output.is_none())
+            .map(|node| (node.fid.unwrap(), node.threshold, node.gain))
+    }
+
+    /// Returns whether any node splits a categorical feature.
+    fn has_categorical_splits(&self) -> bool {
+        self.nodes.iter().any(|node| node.categories.is_some())
+    }
+
+    /// Attributes the output for the instance to the features on the
+    /// decision path, by the "Saabas" method: each split adds the
+    /// change of the mean output from the node to the child taken to
+    /// the feature of the split. Returns the mean output of the root,
+    /// which is the bias, and the (feature id, contribution) pairs
+    /// in the order of the path. All are scaled by the learning rate.
+    fn path_contributions(&self, instance: &Instance) -> (f64, Vec<(Id, f64)>) {
+        let mut contributions = Vec::new();
+        let mut node = &self.nodes[0];
+        while node.output.is_none() {
+            let fid = node.fid.unwrap();
+            let child = &self.nodes[node.child(instance)];
+            let change = child.mean - node.mean;
+            contributions.push((fid, change * self.learning_rate));
+            node = child;
+        }
+        (self.nodes[0].mean * self.learning_rate, contributions)
     }
 }
 
@@ -245,11 +454,7 @@ impl ::train::Evaluate for RegressionTree {
     fn evaluate(&self, instance: &Instance) -> f64 {
         let mut node = &self.nodes[0];
         while node.output.is_none() {
-            if instance.value(node.fid.unwrap()) <= node.threshold.unwrap() {
-                node = &self.nodes[node.left.unwrap()];
-            } else {
-                node = &self.nodes[node.right.unwrap()];
-            }
+            node = &self.nodes[node.child(instance)];
         }
 
         assert!(node.output.is_some());
@@ -257,24 +462,409 @@ impl ::train::Evaluate for RegressionTree {
     }
 }
 
+/// How the outputs of the trees in an ensemble are combined.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EnsembleMode {
+    /// Sum of the outputs. Used by boosting.
+    Sum,
+    /// Average of the outputs. Used by random forest.
+    Average,
+}
+
 pub struct Ensemble {
+    mode: EnsembleMode,
     trees: Vec<RegressionTree>,
+    /// Original feature ids of the compacted ids used by the trees. See
+    /// `DataSet::compact_features`. Empty if the ids are not compacted.
+    feature_map: Vec<Id>,
+    /// The Platt parameters (A, B) of `evaluate_calibrated`.
+    calibration: Option<(f64, f64)>,
+    /// How the ensemble was trained, saved with the model.
+    metadata: ModelMetadata,
 }
 
 impl Ensemble {
     pub fn new() -> Ensemble {
-        Ensemble { trees: Vec::new() }
+        Ensemble::with_mode(EnsembleMode::Sum)
+    }
+
+    pub fn with_mode(mode: EnsembleMode) -> Ensemble {
+        Ensemble {
+            mode: mode,
+            trees: Vec::new(),
+            feature_map: Vec::new(),
+            calibration: None,
+            metadata: ModelMetadata::default(),
+        }
+    }
+
+    /// Records the feature id mapping of the training data, to be
+    /// saved with the model.
+    pub fn set_feature_map(&mut self, feature_map: Vec<Id>) {
+        self.feature_map = feature_map;
+    }
+
+    /// The feature id mapping to apply to data before evaluating,
+    /// with `DataSet::remap_features`. Empty if the ids are not
+    /// compacted.
+    pub fn feature_map(&self) -> &[Id] {
+        &self.feature_map
+    }
+
+    /// Records how the ensemble was trained, to be saved with the
+    /// model.
+    pub fn set_metadata(&mut self, metadata: ModelMetadata) {
+        self.metadata = metadata;
+    }
+
+    /// How the ensemble was trained. The fields are None if unknown.
+    pub fn metadata(&self) -> &ModelMetadata {
+        &self.metadata
+    }
+
+    /// Fits the Platt parameters of `evaluate_calibrated` to the data
+    /// set. The instances with positive labels are the positive
+    /// examples.
+    pub fn calibrate(&mut self, dataset: &DataSet) {
+        let scores = self.evaluate_all(dataset);
+        let targets: Vec<bool> =
+            dataset.label_iter().map(|label| label > 0.0).collect();
+        self.calibration = Some(fit_platt(&scores, &targets));
+    }
+
+    /// Returns the Platt parameters (A, B), if the ensemble is
+    /// calibrated.
+    pub fn calibration(&self) -> Option<(f64, f64)> {
+        self.calibration
+    }
+
+    /// Sets the Platt parameters (A, B).
+    pub fn set_calibration(&mut self, calibration: Option<(f64, f64)>) {
+        self.calibration = calibration;
+    }
+
+    /// Evaluates the instance on the first `n_trees` trees only, as if
+    /// the training had stopped there. All the trees are used if there
+    /// are fewer than `n_trees`.
+    pub fn evaluate_prefix(&self, instance: &Instance, n_trees: usize) -> f64 {
+        let trees = &self.trees[..n_trees.min(self.trees.len())];
+        let mut result = 0.0;
+        for tree in trees {
+            result += tree.evaluate(instance);
+        }
+
+        match self.mode {
+            EnsembleMode::Sum => result,
+            EnsembleMode::Average if trees.is_empty() => 0.0,
+            EnsembleMode::Average => result / trees.len() as f64,
+        }
+    }
+
+    /// Returns the output for the instance mapped to [0, 1] by
+    /// 1 / (1 + exp(A * output + B)). Without calibration, A is -1 and
+    /// B is 0, which is the logistic function of the output.
+    pub fn evaluate_calibrated(&self, instance: &Instance) -> f64 {
+        let (a, b) = self.calibration.unwrap_or((-1.0, 0.0));
+        platt_probability(self.evaluate(instance), a, b)
+    }
+
+    /// Scores the instances read from the svmlight data one query at a
+    /// time, without loading the whole data. Writes a line of
+    /// "qid\tindex\tscore" for each instance, where the index is the
+    /// position of the instance in its query, as in the score files of
+    /// RankLib. The original feature ids of the data are mapped by the
+    /// feature map of the ensemble.
+    pub fn predict_stream<R: Read, W: Write>(
+        &self,
+        reader: R,
+        writer: W,
+    ) -> Result<()> {
+        self.predict_stream_as(reader, writer, PredictionFormat::Tsv)
+    }
+
+    /// Same as `predict_stream`, writing the scores in the `format`.
+    pub fn predict_stream_as<R: Read, W: Write>(
+        &self,
+        reader: R,
+        writer: W,
+        format: PredictionFormat,
+    ) -> Result<()> {
+        use format::svmlight::SvmLightFile;
+
+        let mut writer =
+            PredictionWriter::new(BufWriter::new(writer), format);
+        // (qid, scores) of the current query.
+        let mut query: Option<(String, Vec<f64>)> = None;
+        for instance in SvmLightFile::sparse_instances(reader) {
+            let instance = instance?;
+            let qid = match instance.qid_label() {
+                Some(label) => label.to_string(),
+                None => instance.qid().to_string(),
+            };
+            let score = self.evaluate(&self.map_features(instance));
+
+            match query {
+                Some((ref current, ref mut scores)) if *current == qid => {
+                    scores.push(score);
+                    continue;
+                }
+                _ => (),
+            }
+            if let Some((qid, scores)) = query.take() {
+                writer.write_query(&qid, &scores)?;
+            }
+            query = Some((qid, vec![score]));
+        }
+        if let Some((qid, scores)) = query {
+            writer.write_query(&qid, &scores)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Maps the original feature ids of the instance to the compacted
+    /// ids of the trees. See `feature_map`.
+    fn map_features(&self, instance: Instance) -> Instance {
+        if self.feature_map.is_empty() {
+            return instance;
+        }
+
+        let pairs = self.feature_map
+            .iter()
+            .enumerate()
+            .map(|(index, &id)| (index + 1, instance.value(id)))
+            .filter(|&(_, value)| value != 0.0)
+            .collect();
+        Instance::new_sparse(instance.label(), instance.qid(), pairs)
+    }
+
+    /// Returns the contribution of each tree to the output for the
+    /// instance, in the order of the trees. The contributions are
+    /// scaled by the learning rates, and divided by the number of
+    /// trees in the average mode, so they sum to the output.
+    pub fn evaluate_contributions(&self, instance: &Instance) -> Vec<f64> {
+        let scale = match self.mode {
+            EnsembleMode::Sum => 1.0,
+            EnsembleMode::Average => 1.0 / self.trees.len() as f64,
+        };
+        self.trees
+            .iter()
+            .map(|tree| tree.evaluate(instance) * scale)
+            .collect()
+    }
+
+    /// Returns the contribution of each feature to the output for the
+    /// instance, summed across the trees and sorted by feature id. See
+    /// `RegressionTree::path_contributions`. The sum of the mean
+    /// outputs of the roots is reported as the feature id 0, so the
+    /// contributions sum to the output. Features not on any decision
+    /// path are omitted.
+    pub fn feature_contributions(&self, instance: &Instance) -> Vec<(Id, f64)> {
+        let scale = match self.mode {
+            EnsembleMode::Sum => 1.0,
+            EnsembleMode::Average => 1.0 / self.trees.len() as f64,
+        };
+
+        let mut contributions = ::std::collections::BTreeMap::new();
+        for tree in self.trees.iter() {
+            let (bias, path) = tree.path_contributions(instance);
+            *contributions.entry(0).or_insert(0.0) += bias * scale;
+            for (fid, contribution) in path {
+                let sum = contributions.entry(fid).or_insert(0.0);
+                *sum += contribution * scale;
+            }
+        }
+        contributions.into_iter().collect()
+    }
+
+    /// Returns how the trees use each feature: the number of split
+    /// nodes on it, the sum of their gains and the mean of their
+    /// thresholds, sorted by feature id. Features that are never
+    /// split on are omitted. The gains are only known for trees
+    /// trained in this process, and are 0.0 for loaded models.
+    /// Categorical splits have no thresholds, so the mean threshold of
+    /// a feature of only those is 0.0.
+    pub fn feature_usage(&self) -> Vec<FeatureUsage> {
+        // (usage, number of the splits at thresholds)
+        let mut usages = ::std::collections::BTreeMap::new();
+        for tree in self.trees.iter() {
+            for (fid, threshold, gain) in tree.splits() {
+                let entry = usages.entry(fid).or_insert((
+                    FeatureUsage {
+                        id: fid,
+                        splits: 0,
+                        total_gain: 0.0,
+                        mean_threshold: 0.0,
+                    },
+                    0,
+                ));
+                entry.0.splits += 1;
+                entry.0.total_gain += gain;
+                if let Some(threshold) = threshold {
+                    // Running sum of the thresholds, divided below.
+                    entry.0.mean_threshold += threshold;
+                    entry.1 += 1;
+                }
+            }
+        }
+
+        usages
+            .into_iter()
+            .map(|(_, (mut usage, thresholds))| {
+                if thresholds > 0 {
+                    usage.mean_threshold /= thresholds as f64;
+                }
+                usage
+            })
+            .collect()
+    }
+
+    /// Writes the feature usage as CSV, with a header row
+    /// "id,splits,total_gain,mean_threshold".
+    pub fn write_feature_usage<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writeln!(writer, "id,splits,total_gain,mean_threshold")?;
+        for usage in self.feature_usage() {
+            writeln!(
+                writer,
+                "{},{},{},{}",
+                usage.id,
+                usage.splits,
+                usage.total_gain,
+                usage.mean_threshold
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// How an ensemble uses a feature. See `Ensemble::feature_usage`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeatureUsage {
+    /// The feature id used by the trees. See `Ensemble::feature_map`.
+    pub id: Id,
+    /// Number of split nodes on the feature.
+    pub splits: usize,
+    /// Sum of the variance reductions of the splits.
+    pub total_gain: f64,
+    /// Mean of the thresholds of the splits.
+    pub mean_threshold: f64,
+}
+
+/// Returns 1 / (1 + exp(A * score + B)), computed without overflow.
+fn platt_probability(score: f64, a: f64, b: f64) -> f64 {
+    let f = score * a + b;
+    if f >= 0.0 {
+        (-f).exp() / (1.0 + (-f).exp())
+    } else {
+        1.0 / (1.0 + f.exp())
     }
 }
 
+/// Fits the Platt parameters (A, B) to the scores and the targets by
+/// Newton's method with backtracking, as in "A Note on Platt's
+/// Probabilistic Outputs for Support Vector Machines" by Lin et al.
+fn fit_platt(scores: &[f64], targets: &[bool]) -> (f64, f64) {
+    const MAX_ITER: usize = 100;
+    const MIN_STEP: f64 = 1e-10;
+    const SIGMA: f64 = 1e-12;
+    const EPS: f64 = 1e-5;
+
+    let prior1 = targets.iter().filter(|&&t| t).count() as f64;
+    let prior0 = targets.len() as f64 - prior1;
+
+    // Regularized targets, which avoid overfitting to a separable
+    // data set.
+    let hi_target = (prior1 + 1.0) / (prior1 + 2.0);
+    let lo_target = 1.0 / (prior0 + 2.0);
+    let t: Vec<f64> = targets
+        .iter()
+        .map(|&t| if t { hi_target } else { lo_target })
+        .collect();
+
+    // The negative log likelihood.
+    let objective = |a: f64, b: f64| -> f64 {
+        scores
+            .iter()
+            .zip(t.iter())
+            .map(|(&score, &t)| {
+                let f = score * a + b;
+                if f >= 0.0 {
+                    t * f + (1.0 + (-f).exp()).ln()
+                } else {
+                    (t - 1.0) * f + (1.0 + f.exp()).ln()
+                }
+            })
+            .sum()
+    };
+
+    let mut a = 0.0;
+    let mut b = ((prior0 + 1.0) / (prior1 + 1.0)).ln();
+    let mut fval = objective(a, b);
+    for _ in 0..MAX_ITER {
+        // Gradient and Hessian, with H' = H + sigma * I.
+        let (mut h11, mut h22, mut h21) = (SIGMA, SIGMA, 0.0);
+        let (mut g1, mut g2) = (0.0, 0.0);
+        for (&score, &t) in scores.iter().zip(t.iter()) {
+            let p = platt_probability(score, a, b);
+            let d2 = p * (1.0 - p);
+            h11 += score * score * d2;
+            h22 += d2;
+            h21 += score * d2;
+            let d1 = t - p;
+            g1 += score * d1;
+            g2 += d1;
+        }
+        if g1.abs() < EPS && g2.abs() < EPS {
+            break;
+        }
+
+        let det = h11 * h22 - h21 * h21;
+        let da = -(h22 * g1 - h21 * g2) / det;
+        let db = -(-h21 * g1 + h11 * g2) / det;
+        let gd = g1 * da + g2 * db;
+
+        let mut step = 1.0;
+        while step >= MIN_STEP {
+            let (new_a, new_b) = (a + step * da, b + step * db);
+            let new_fval = objective(new_a, new_b);
+            if new_fval < fval + 0.0001 * step * gd {
+                a = new_a;
+                b = new_b;
+                fval = new_fval;
+                break;
+            }
+            step /= 2.0;
+        }
+        if step < MIN_STEP {
+            break;
+        }
+    }
+    (a, b)
+}
+
 impl ::train::Evaluate for Ensemble {
     fn evaluate(&self, instance: &Instance) -> f64 {
-        let mut result = 0.0;
+        self.evaluate_prefix(instance, self.trees.len())
+    }
+
+    /// Evaluate tree by tree rather than instance by instance, so
+    /// the nodes of a tree stay in cache while all the instances are
+    /// traversed.
+    fn evaluate_all(&self, dataset: &DataSet) -> Vec<f64> {
+        let mut scores = vec![0.0; dataset.len()];
         for tree in &self.trees {
-            result += tree.evaluate(instance);
+            for (instance, score) in dataset.iter().zip(scores.iter_mut()) {
+                *score += tree.evaluate(instance);
+            }
         }
 
-        result
+        if self.mode == EnsembleMode::Average && !self.trees.is_empty() {
+            let ntrees = self.trees.len() as f64;
+            for score in scores.iter_mut() {
+                *score /= ntrees;
+            }
+        }
+        scores
     }
 }
 
@@ -292,10 +882,811 @@ impl std::ops::DerefMut for Ensemble {
     }
 }
 
+/// Format of a saved model.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ModelFormat {
+    /// Human readable text, one node per line.
+    Text,
+    /// Compact binary dump of the nodes.
+    Binary,
+}
+
+impl std::str::FromStr for ModelFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<ModelFormat, String> {
+        match s {
+            "text" => Ok(ModelFormat::Text),
+            "binary" => Ok(ModelFormat::Binary),
+            _ => Err(format!("Invalid model format: {}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for ModelFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            ModelFormat::Text => write!(f, "text"),
+            ModelFormat::Binary => write!(f, "binary"),
+        }
+    }
+}
+
+/// The parameters a saved model was trained with, for reproducing it
+/// and for evaluating it with the same metric. Each field is None if
+/// unknown, e.g. in the models saved before the metadata.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ModelMetadata {
+    /// The name of the metric, e.g. "NDCG".
+    pub metric: Option<String>,
+    pub metric_k: Option<usize>,
+    /// The gain of each integer label, if the metric has a table of
+    /// gains.
+    pub gain_table: Option<Vec<f64>>,
+    /// The base of the logarithm of the discount of the metric.
+    pub discount_base: Option<f64>,
+    /// The cap of the gains of the metric, if any.
+    pub max_gain: Option<f64>,
+    /// The learning rate schedule as displayed, e.g. "0.1".
+    pub learning_rate: Option<String>,
+    /// The number of trees of the model, after early stopping.
+    pub trees: Option<usize>,
+    pub leaves: Option<usize>,
+    pub thresholds: Option<usize>,
+    pub seed: Option<u64>,
+}
+
+impl ModelMetadata {
+    /// Returns the known fields as (key, value) pairs, in the order
+    /// they are saved.
+    fn pairs(&self) -> Vec<(&'static str, String)> {
+        fn pair<T: ToString>(
+            key: &'static str,
+            value: &Option<T>,
+        ) -> Option<(&'static str, String)> {
+            value.as_ref().map(|value| (key, value.to_string()))
+        }
+
+        let gain_table = self.gain_table.as_ref().map(|gains| {
+            let gains: Vec<String> =
+                gains.iter().map(|gain| gain.to_string()).collect();
+            gains.join(",")
+        });
+        vec![
+            pair("metric", &self.metric),
+            pair("metric-k", &self.metric_k),
+            pair("gain-table", &gain_table),
+            pair("discount-base", &self.discount_base),
+            pair("cap-gain", &self.max_gain),
+            pair("learning-rate", &self.learning_rate),
+            pair("trees", &self.trees),
+            pair("leaves", &self.leaves),
+            pair("thresholds", &self.thresholds),
+            pair("seed", &self.seed),
+        ].into_iter()
+            .filter_map(|pair| pair)
+            .collect()
+    }
+
+    /// Sets the field of a saved (key, value) pair. Unknown keys are
+    /// ignored, so that newer models can be loaded.
+    fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        match key {
+            "metric" => self.metric = Some(value.to_string()),
+            "metric-k" => self.metric_k = Some(value.parse()?),
+            "gain-table" => {
+                self.gain_table = Some(metric::parse_gains(value)?)
+            }
+            "discount-base" => self.discount_base = Some(value.parse()?),
+            "cap-gain" => self.max_gain = Some(value.parse()?),
+            "learning-rate" => self.learning_rate = Some(value.to_string()),
+            "trees" => self.trees = Some(value.parse()?),
+            "leaves" => self.leaves = Some(value.parse()?),
+            "thresholds" => self.thresholds = Some(value.parse()?),
+            "seed" => self.seed = Some(value.parse()?),
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+/// The first line of a model in the text format.
+const TEXT_MAGIC: &str = "rforests-ensemble";
+
+/// The leading bytes of a model in the binary format.
+const BINARY_MAGIC: &[u8] = b"RFBIN\x02";
+
+/// The leading bytes of a model in the binary format before the
+/// metadata was added. Such models are still loaded.
+const BINARY_MAGIC_V1: &[u8] = b"RFBIN\x01";
+
+fn invalid_model<T>(msg: &str) -> Result<T> {
+    Err(From::from(format!("Invalid model: {}", msg)))
+}
+
+/// Reads little-endian values from a byte slice.
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.pos + n > self.bytes.len() {
+            return invalid_model("unexpected end of data");
+        }
+        let bytes = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(bytes.iter().rev().fold(0, |acc, &b| (acc << 8) | b as u32))
+    }
+
+    fn f64(&mut self) -> Result<f64> {
+        let bytes = self.take(8)?;
+        let bits = bytes.iter().rev().fold(0, |acc, &b| (acc << 8) | b as u64);
+        Ok(f64::from_bits(bits))
+    }
+
+    /// Reads a string prefixed by its length in bytes.
+    fn string(&mut self) -> Result<String> {
+        let len = self.u32()? as usize;
+        Ok(String::from_utf8(self.take(len)?.to_vec())?)
+    }
+}
+
+fn write_u32<W: Write>(writer: &mut W, value: u32) -> Result<()> {
+    let bytes: Vec<u8> = (0..4).map(|i| (value >> (i * 8)) as u8).collect();
+    writer.write_all(&bytes)?;
+    Ok(())
+}
+
+fn write_f64<W: Write>(writer: &mut W, value: f64) -> Result<()> {
+    let bits = value.to_bits();
+    let bytes: Vec<u8> = (0..8).map(|i| (bits >> (i * 8)) as u8).collect();
+    writer.write_all(&bytes)?;
+    Ok(())
+}
+
+fn write_string<W: Write>(writer: &mut W, value: &str) -> Result<()> {
+    write_u32(writer, value.len() as u32)?;
+    writer.write_all(value.as_bytes())?;
+    Ok(())
+}
+
+impl RegressionTree {
+    /// Creates a tree from nodes read from a saved model. The children
+    /// of a node must come after it.
+    fn from_nodes(learning_rate: f64, mut nodes: Vec<Node>) -> Result<Self> {
+        if nodes.is_empty() {
+            return invalid_model("empty tree");
+        }
+
+        for index in 0..nodes.len() {
+            if nodes[index].output.is_some() {
+                continue;
+            }
+            let children = [nodes[index].left, nodes[index].right];
+            for &child in children.iter() {
+                let child = child.unwrap();
+                if child <= index || child >= nodes.len() {
+                    return invalid_model("bad child index");
+                }
+                nodes[child].parent = Some(index);
+            }
+        }
+
+        let leaves = nodes.iter().filter(|node| node.output.is_some()).count();
+        Ok(RegressionTree {
+            learning_rate: learning_rate,
+            min_leaf_samples: 1,
+            min_leaf_support: 1,
+            min_split_gain: 0.0,
+            min_sum_hessian: 0.0,
+            max_leaves: leaves,
+            leaf_output: LeafOutput::Newton,
+            split_strategy: SplitStrategy::Best,
+            rng: Rng::new(0),
+            nodes: nodes,
+        })
+    }
+}
+
+impl Ensemble {
+    /// Saves the ensemble in the given format. See `Ensemble::load`.
+    pub fn save<W: Write>(&self, writer: W, format: ModelFormat) -> Result<()> {
+        let mut writer = BufWriter::new(writer);
+        match format {
+            ModelFormat::Text => self.save_text(&mut writer)?,
+            ModelFormat::Binary => self.save_binary(&mut writer)?,
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn save_text<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writeln!(writer, "{}", TEXT_MAGIC)?;
+        for (key, value) in self.metadata.pairs() {
+            writeln!(writer, "meta {} {}", key, value)?;
+        }
+        let mode = match self.mode {
+            EnsembleMode::Sum => "sum",
+            EnsembleMode::Average => "average",
+        };
+        writeln!(writer, "mode {}", mode)?;
+        write!(writer, "features {}", self.feature_map.len())?;
+        for id in self.feature_map.iter() {
+            write!(writer, " {}", id)?;
+        }
+        writeln!(writer)?;
+        match self.calibration {
+            Some((a, b)) => writeln!(writer, "calibration {} {}", a, b)?,
+            None => writeln!(writer, "calibration none")?,
+        }
+        writeln!(writer, "trees {}", self.trees.len())?;
+        for tree in self.trees.iter() {
+            let nnodes = tree.nodes.len();
+            writeln!(writer, "tree {} {}", tree.learning_rate, nnodes)?;
+            for node in tree.nodes.iter() {
+                match (node.output, &node.categories) {
+                    (Some(output), _) => writeln!(writer, "leaf {}", output)?,
+                    (None, &Some(ref categories)) => {
+                        write!(
+                            writer,
+                            "category {} {} {} {}",
+                            node.fid.unwrap(),
+                            node.left.unwrap(),
+                            node.right.unwrap(),
+                            node.mean
+                        )?;
+                        for category in categories.iter() {
+                            write!(writer, " {}", category)?;
+                        }
+                        writeln!(writer)?;
+                    }
+                    (None, &None) => writeln!(
+                        writer,
+                        "split {} {} {} {} {}",
+                        node.fid.unwrap(),
+                        node.threshold.unwrap(),
+                        node.left.unwrap(),
+                        node.right.unwrap(),
+                        node.mean
+                    )?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn save_binary<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(BINARY_MAGIC)?;
+        let pairs = self.metadata.pairs();
+        write_u32(writer, pairs.len() as u32)?;
+        for (key, value) in pairs {
+            write_string(writer, key)?;
+            write_string(writer, &value)?;
+        }
+        let mode = match self.mode {
+            EnsembleMode::Sum => 0,
+            EnsembleMode::Average => 1,
+        };
+        writer.write_all(&[mode])?;
+        write_u32(writer, self.feature_map.len() as u32)?;
+        for &id in self.feature_map.iter() {
+            write_u32(writer, id as u32)?;
+        }
+        match self.calibration {
+            Some((a, b)) => {
+                writer.write_all(&[1])?;
+                write_f64(writer, a)?;
+                write_f64(writer, b)?;
+            }
+            None => writer.write_all(&[0])?,
+        }
+        write_u32(writer, self.trees.len() as u32)?;
+        for tree in self.trees.iter() {
+            write_f64(writer, tree.learning_rate)?;
+            write_u32(writer, tree.nodes.len() as u32)?;
+            for node in tree.nodes.iter() {
+                match (node.output, &node.categories) {
+                    (Some(output), _) => {
+                        writer.write_all(&[0])?;
+                        write_f64(writer, output)?;
+                    }
+                    (None, &Some(ref categories)) => {
+                        writer.write_all(&[2])?;
+                        write_u32(writer, node.fid.unwrap() as u32)?;
+                        write_u32(writer, node.left.unwrap() as u32)?;
+                        write_u32(writer, node.right.unwrap() as u32)?;
+                        write_f64(writer, node.mean)?;
+                        write_u32(writer, categories.len() as u32)?;
+                        for &category in categories.iter() {
+                            write_f64(writer, category)?;
+                        }
+                    }
+                    (None, &None) => {
+                        writer.write_all(&[1])?;
+                        write_u32(writer, node.fid.unwrap() as u32)?;
+                        write_f64(writer, node.threshold.unwrap())?;
+                        write_u32(writer, node.left.unwrap() as u32)?;
+                        write_u32(writer, node.right.unwrap() as u32)?;
+                        write_f64(writer, node.mean)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Loads an ensemble saved by `Ensemble::save`. The format is
+    /// detected from the header of the data.
+    pub fn load<R: Read>(mut reader: R) -> Result<Ensemble> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+
+        if bytes.starts_with(BINARY_MAGIC) {
+            Ensemble::load_binary(&bytes[BINARY_MAGIC.len()..], true)
+        } else if bytes.starts_with(BINARY_MAGIC_V1) {
+            Ensemble::load_binary(&bytes[BINARY_MAGIC_V1.len()..], false)
+        } else if bytes.starts_with(TEXT_MAGIC.as_bytes()) {
+            Ensemble::load_text(&String::from_utf8(bytes)?)
+        } else {
+            invalid_model("unknown format")
+        }
+    }
+
+    fn load_text(s: &str) -> Result<Ensemble> {
+        let mut lines = s.lines().skip(1).peekable();
+        let mut metadata = ModelMetadata::default();
+        while let Some(line) = lines.peek().cloned() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.split_first() {
+                Some((&"meta", fields)) if fields.len() >= 2 => {
+                    metadata.set(fields[0], &fields[1..].join(" "))?
+                }
+                Some((&"meta", _)) => return invalid_model("bad metadata"),
+                _ => break,
+            }
+            lines.next();
+        }
+        let mut next_fields = || -> Result<Vec<&str>> {
+            match lines.next() {
+                Some(line) => Ok(line.split_whitespace().collect()),
+                None => invalid_model("unexpected end of data"),
+            }
+        };
+
+        let mode = match &next_fields()?[..] {
+            &["mode", "sum"] => EnsembleMode::Sum,
+            &["mode", "average"] => EnsembleMode::Average,
+            _ => return invalid_model("bad mode"),
+        };
+        let feature_map = match next_fields()?.split_first() {
+            Some((&"features", fields)) if !fields.is_empty() => {
+                let ids = fields[1..]
+                    .iter()
+                    .map(|id| id.parse::<Id>())
+                    .collect::<::std::result::Result<Vec<_>, _>>()?;
+                if ids.len() != fields[0].parse::<usize>()? {
+                    return invalid_model("bad features count");
+                }
+                ids
+            }
+            _ => return invalid_model("bad features"),
+        };
+        let calibration = match &next_fields()?[..] {
+            &["calibration", "none"] => None,
+            &["calibration", a, b] => Some((a.parse()?, b.parse()?)),
+            _ => return invalid_model("bad calibration"),
+        };
+        let ntrees = match &next_fields()?[..] {
+            &["trees", ntrees] => ntrees.parse::<usize>()?,
+            _ => return invalid_model("bad trees count"),
+        };
+
+        let mut ensemble = Ensemble::with_mode(mode);
+        ensemble.set_feature_map(feature_map);
+        ensemble.set_calibration(calibration);
+        ensemble.set_metadata(metadata);
+        for _ in 0..ntrees {
+            let (learning_rate, nnodes) = match &next_fields()?[..] {
+                &["tree", learning_rate, nnodes] => {
+                    (learning_rate.parse::<f64>()?, nnodes.parse::<usize>()?)
+                }
+                _ => return invalid_model("bad tree"),
+            };
+
+            let mut nodes = Vec::with_capacity(nnodes);
+            for _ in 0..nnodes {
+                let mut node = Node::new(None);
+                let fields = next_fields()?;
+                match &fields[..] {
+                    &["leaf", output] => node.set_leaf(output.parse()?),
+                    &["category", fid, left, right, mean, _, ..] => {
+                        let categories = fields[5..]
+                            .iter()
+                            .map(|category| category.parse::<Value>())
+                            .collect::<::std::result::Result<Vec<_>, _>>()?;
+                        node.set_categorical(
+                            fid.parse()?,
+                            categories,
+                            left.parse()?,
+                            right.parse()?,
+                        );
+                        node.mean = mean.parse()?;
+                    }
+                    &["split", fid, threshold, left, right, mean] => {
+                        node.set_non_leaf(
+                            fid.parse()?,
+                            threshold.parse()?,
+                            left.parse()?,
+                            right.parse()?,
+                        );
+                        node.mean = mean.parse()?;
+                    }
+                    _ => return invalid_model("bad node"),
+                }
+                nodes.push(node);
+            }
+            ensemble.push(RegressionTree::from_nodes(learning_rate, nodes)?);
+        }
+        Ok(ensemble)
+    }
+
+    /// Loads the binary format after the magic. The models of
+    /// `BINARY_MAGIC_V1` have no metadata.
+    fn load_binary(bytes: &[u8], has_metadata: bool) -> Result<Ensemble> {
+        let mut reader = ByteReader {
+            bytes: bytes,
+            pos: 0,
+        };
+
+        let mut metadata = ModelMetadata::default();
+        if has_metadata {
+            for _ in 0..reader.u32()? {
+                let key = reader.string()?;
+                metadata.set(&key, &reader.string()?)?;
+            }
+        }
+
+        let mode = match reader.u8()? {
+            0 => EnsembleMode::Sum,
+            1 => EnsembleMode::Average,
+            _ => return invalid_model("bad mode"),
+        };
+        let nfeatures = reader.u32()?;
+        let mut feature_map = Vec::new();
+        for _ in 0..nfeatures {
+            feature_map.push(reader.u32()? as Id);
+        }
+        let calibration = match reader.u8()? {
+            0 => None,
+            1 => Some((reader.f64()?, reader.f64()?)),
+            _ => return invalid_model("bad calibration"),
+        };
+        let ntrees = reader.u32()?;
+
+        let mut ensemble = Ensemble::with_mode(mode);
+        ensemble.set_feature_map(feature_map);
+        ensemble.set_calibration(calibration);
+        ensemble.set_metadata(metadata);
+        for _ in 0..ntrees {
+            let learning_rate = reader.f64()?;
+            let nnodes = reader.u32()? as usize;
+
+            let mut nodes = Vec::with_capacity(nnodes);
+            for _ in 0..nnodes {
+                let mut node = Node::new(None);
+                match reader.u8()? {
+                    0 => node.set_leaf(reader.f64()?),
+                    1 => {
+                        let fid = reader.u32()? as Id;
+                        let threshold = reader.f64()?;
+                        let left = reader.u32()? as usize;
+                        let right = reader.u32()? as usize;
+                        node.set_non_leaf(fid, threshold, left, right);
+                        node.mean = reader.f64()?;
+                    }
+                    2 => {
+                        let fid = reader.u32()? as Id;
+                        let left = reader.u32()? as usize;
+                        let right = reader.u32()? as usize;
+                        node.mean = reader.f64()?;
+                        let mut categories = Vec::new();
+                        for _ in 0..reader.u32()? {
+                            categories.push(reader.f64()?);
+                        }
+                        node.set_categorical(fid, categories, left, right);
+                    }
+                    _ => return invalid_model("bad node"),
+                }
+                nodes.push(node);
+            }
+            ensemble.push(RegressionTree::from_nodes(learning_rate, nodes)?);
+        }
+        Ok(ensemble)
+    }
+}
+
+/// A token of the XML of a RankLib model.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum XmlToken<'a> {
+    /// The name and the attributes of a start tag.
+    Start(&'a str, &'a str),
+    End(&'a str),
+    Text(&'a str),
+}
+
+/// Splits the XML into tags and trimmed text. Comments, declarations
+/// and the "##" header lines of RankLib are skipped.
+fn xml_tokens(s: &str) -> Result<Vec<XmlToken>> {
+    let mut tokens = Vec::new();
+    for line in s.lines().filter(|line| !line.trim().starts_with("##")) {
+        let mut rest = line;
+        while let Some(start) = rest.find('<') {
+            let text = rest[..start].trim();
+            if !text.is_empty() {
+                tokens.push(XmlToken::Text(text));
+            }
+            let end = match rest[start..].find('>') {
+                Some(end) => start + end,
+                None => return invalid_model("unclosed tag"),
+            };
+            let tag = rest[start + 1..end].trim();
+            if tag.starts_with('/') {
+                tokens.push(XmlToken::End(tag[1..].trim()));
+            } else if !tag.starts_with('?') && !tag.starts_with('!') {
+                let split = tag.find(char::is_whitespace).unwrap_or(tag.len());
+                let (name, attributes) = tag.split_at(split);
+                tokens.push(XmlToken::Start(name, attributes));
+            }
+            rest = &rest[end + 1..];
+        }
+        let text = rest.trim();
+        if !text.is_empty() {
+            tokens.push(XmlToken::Text(text));
+        }
+    }
+    Ok(tokens)
+}
+
+/// Returns the value of the attribute, e.g. "0.1" of weight="0.1".
+fn xml_attribute<'a>(attributes: &'a str, name: &str) -> Option<&'a str> {
+    let key = format!("{}=\"", name);
+    attributes
+        .split_whitespace()
+        .find(|attribute| attribute.starts_with(&key))
+        .map(|attribute| attribute[key.len()..].trim_matches('"'))
+}
+
+/// Walks the tokens of a RankLib model.
+struct RanklibParser<'a> {
+    tokens: Vec<XmlToken<'a>>,
+    pos: usize,
+}
+
+impl<'a> RanklibParser<'a> {
+    fn peek(&self) -> Option<XmlToken<'a>> {
+        self.tokens.get(self.pos).cloned()
+    }
+
+    fn next(&mut self) -> Result<XmlToken<'a>> {
+        match self.peek() {
+            Some(token) => {
+                self.pos += 1;
+                Ok(token)
+            }
+            None => invalid_model("unexpected end of data"),
+        }
+    }
+
+    /// Consumes a start tag of the name. Returns its attributes.
+    fn start(&mut self, name: &str) -> Result<&'a str> {
+        match self.next()? {
+            XmlToken::Start(tag, attributes) if tag == name => Ok(attributes),
+            token => {
+                invalid_model(&format!("expected <{}>: {:?}", name, token))
+            }
+        }
+    }
+
+    fn end(&mut self, name: &str) -> Result<()> {
+        match self.next()? {
+            XmlToken::End(tag) if tag == name => Ok(()),
+            token => {
+                invalid_model(&format!("expected </{}>: {:?}", name, token))
+            }
+        }
+    }
+
+    /// Parses an element of text, e.g. "<feature> 3 </feature>".
+    fn element<T>(&mut self, name: &str) -> Result<T>
+    where
+        T: std::str::FromStr,
+        T::Err: std::error::Error + 'static,
+    {
+        self.start(name)?;
+        let value = match self.next()? {
+            XmlToken::Text(text) => text.parse::<T>()?,
+            token => {
+                return invalid_model(&format!("bad {}: {:?}", name, token))
+            }
+        };
+        self.end(name)?;
+        Ok(value)
+    }
+
+    /// Parses a split and its children into nodes, in the preorder.
+    /// Returns the index of the node of the split.
+    fn split(&mut self, nodes: &mut Vec<Node>) -> Result<usize> {
+        self.start("split")?;
+        let index = nodes.len();
+        nodes.push(Node::new(None));
+
+        if let Some(XmlToken::Start("output", _)) = self.peek() {
+            let output = self.element("output")?;
+            nodes[index].set_leaf(output);
+        } else {
+            let fid = self.element("feature")?;
+            let threshold = self.element("threshold")?;
+            let left = self.split(nodes)?;
+            let right = self.split(nodes)?;
+            nodes[index].set_non_leaf(fid, threshold, left, right);
+        }
+        self.end("split")?;
+        Ok(index)
+    }
+}
+
+impl Ensemble {
+    /// Loads a LambdaMART or MART model saved by RankLib, which is XML
+    /// of the form:
+    ///
+    /// ```text
+    /// <ensemble>
+    ///   <tree id="1" weight="0.1">
+    ///     <split>
+    ///       <feature> 3 </feature>
+    ///       <threshold> 0.5 </threshold>
+    ///       <split pos="left"> <output> -1.0 </output> </split>
+    ///       <split pos="right"> <output> 1.0 </output> </split>
+    ///     </split>
+    ///   </tree>
+    /// </ensemble>
+    /// ```
+    ///
+    /// The weight of a tree is its learning rate. The left child of a
+    /// split is taken when the value is at most the threshold, as in
+    /// RankLib.
+    pub fn load_ranklib<R: Read>(mut reader: R) -> Result<Ensemble> {
+        let mut s = String::new();
+        reader.read_to_string(&mut s)?;
+        let mut parser = RanklibParser {
+            tokens: xml_tokens(&s)?,
+            pos: 0,
+        };
+
+        let mut ensemble = Ensemble::new();
+        parser.start("ensemble")?;
+        while let Some(XmlToken::Start("tree", _)) = parser.peek() {
+            let attributes = parser.start("tree")?;
+            let weight = match xml_attribute(attributes, "weight") {
+                Some(weight) => weight.parse::<f64>()?,
+                None => return invalid_model("missing tree weight"),
+            };
+
+            let mut nodes = Vec::new();
+            parser.split(&mut nodes)?;
+            parser.end("tree")?;
+            ensemble.push(RegressionTree::from_nodes(weight, nodes)?);
+        }
+        parser.end("ensemble")?;
+        Ok(ensemble)
+    }
+
+    /// Saves the ensemble as a RankLib LambdaMART model. See
+    /// `Ensemble::load_ranklib` for the format. Compacted feature ids
+    /// are written as the original ids, and in the average mode the
+    /// weights of the trees are divided by the number of trees, so
+    /// RankLib computes the same scores. The calibration is not
+    /// saved. RankLib has no categorical splits, so an ensemble with
+    /// them cannot be saved.
+    pub fn save_ranklib<W: Write>(&self, writer: W) -> Result<()> {
+        if self.trees.iter().any(|tree| tree.has_categorical_splits()) {
+            Err("RankLib models cannot have categorical splits")?;
+        }
+        let mut writer = BufWriter::new(writer);
+        let scale = match self.mode {
+            EnsembleMode::Sum => 1.0,
+            EnsembleMode::Average => 1.0 / self.trees.len() as f64,
+        };
+
+        // RankLib detects the type of the model by the first line.
+        writeln!(writer, "## LambdaMART")?;
+        writeln!(writer, "## No. of trees = {}", self.trees.len())?;
+        writeln!(writer, "<ensemble>")?;
+        for (i, tree) in self.trees.iter().enumerate() {
+            writeln!(
+                writer,
+                "\t<tree id=\"{}\" weight=\"{}\">",
+                i + 1,
+                tree.learning_rate * scale
+            )?;
+            self.save_ranklib_split(&mut writer, tree, 0, None, 2)?;
+            writeln!(writer, "\t</tree>")?;
+        }
+        writeln!(writer, "</ensemble>")?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Writes the node and its children as a RankLib split.
+    fn save_ranklib_split<W: Write>(
+        &self,
+        writer: &mut W,
+        tree: &RegressionTree,
+        index: usize,
+        pos: Option<&str>,
+        depth: usize,
+    ) -> Result<()> {
+        let indent = "\t".repeat(depth);
+        match pos {
+            Some(pos) => writeln!(writer, "{}<split pos=\"{}\">", indent, pos)?,
+            None => writeln!(writer, "{}<split>", indent)?,
+        }
+
+        let node = &tree.nodes[index];
+        match node.output {
+            Some(output) => {
+                writeln!(writer, "{}\t<output> {} </output>", indent, output)?
+            }
+            None => {
+                let fid = node.fid.unwrap();
+                let fid = match self.feature_map.get(fid - 1) {
+                    Some(&id) => id,
+                    None => fid,
+                };
+                writeln!(writer, "{}\t<feature> {} </feature>", indent, fid)?;
+                writeln!(
+                    writer,
+                    "{}\t<threshold> {} </threshold>",
+                    indent,
+                    node.threshold.unwrap()
+                )?;
+                let children = [
+                    (node.left.unwrap(), "left"),
+                    (node.right.unwrap(), "right"),
+                ];
+                for &(child, pos) in children.iter() {
+                    self.save_ranklib_split(
+                        writer,
+                        tree,
+                        child,
+                        Some(pos),
+                        depth + 1,
+                    )?;
+                }
+            }
+        }
+        writeln!(writer, "{}</split>", indent)?;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use metric;
+    use train::Evaluate;
+    use test::Bencher;
 
     #[test]
     fn test_tree_fitting() {
@@ -328,11 +1719,627 @@ mod test {
                 max_leaves,
                 min_leaf_samples,
             );
-            tree.fit(&training);
+            tree.fit(&training).unwrap();
 
             // println!("{:?}", tree);
             // println!("{:?}", training.model_scores);
             // println!("-----------------------------------");
         }
     }
+
+    #[test]
+    fn test_tree_min_sum_hessian() {
+        // (label, qid, feature_values)
+        let data = vec![
+            (3.0, 1, vec![3.0]),
+            (2.0, 1, vec![2.0]),
+            (1.0, 1, vec![1.0]),
+            (0.0, 1, vec![0.0]),
+        ];
+        let dataset: DataSet = data.into_iter().collect();
+
+        let mut training = TrainSet::new(&dataset, 3);
+        training.update_lambdas_weights(&metric::new("NDCG", 10).unwrap());
+        let total = TrainSample::from(&training).weight_sum();
+        assert!(total > 0.0);
+
+        let mut tree = RegressionTree::new(0.1, 10, 1);
+        tree.fit(&training).unwrap();
+        assert!(tree.nodes.len() > 1);
+
+        // Neither child of any split can have all the weights.
+        let mut tree = RegressionTree::new(0.1, 10, 1);
+        tree.set_min_sum_hessian(total);
+        let leaf_output = tree.fit(&training).unwrap();
+        assert_eq!(tree.nodes.len(), 1);
+        let output = TrainSample::from(&training).newton_output() * 0.1;
+        assert_eq!(leaf_output, vec![output; 4]);
+    }
+
+    #[test]
+    fn test_tree_min_leaf_support() {
+        // (label, qid, feature_values)
+        let data = vec![
+            (3.0, 1, vec![3.0]),
+            (2.0, 1, vec![2.0]),
+            (1.0, 1, vec![1.0]),
+            (0.0, 1, vec![0.0]),
+        ];
+        let dataset: DataSet = data.into_iter().collect();
+
+        let mut training = TrainSet::new(&dataset, 3);
+        training.update_lambdas_weights(&metric::new("NDCG", 10).unwrap());
+
+        // Each child of the first split has two instances.
+        let mut tree = RegressionTree::new(0.1, 10, 1);
+        tree.set_min_leaf_support(2);
+        tree.fit(&training).unwrap();
+        assert_eq!(tree.nodes.len(), 3);
+
+        // No split leaves three instances in both children.
+        let mut tree = RegressionTree::new(0.1, 10, 1);
+        tree.set_min_leaf_support(3);
+        let leaf_output = tree.fit(&training).unwrap();
+        assert_eq!(tree.nodes.len(), 1);
+        let output = TrainSample::from(&training).newton_output() * 0.1;
+        assert_eq!(leaf_output, vec![output; 4]);
+
+        // The node itself is large enough to split.
+        let mut tree = RegressionTree::new(0.1, 10, 4);
+        tree.set_min_leaf_support(1);
+        tree.fit(&training).unwrap();
+        assert_eq!(tree.nodes.len(), 3);
+    }
+
+    #[test]
+    fn test_ensemble_evaluate_all() {
+        let data = vec![
+            (3.0, 1, vec![3.0, 0.0]),
+            (2.0, 1, vec![2.0, 1.0]),
+            (1.0, 1, vec![1.0, 0.0]),
+            (1.0, 2, vec![1.0, 1.0]),
+            (3.0, 2, vec![3.0, 0.0]),
+            (2.0, 2, vec![2.0, 1.0]),
+        ];
+        let dataset: DataSet = data.into_iter().collect();
+
+        let mut training = TrainSet::new(&dataset, 3);
+        for &mode in &[EnsembleMode::Sum, EnsembleMode::Average] {
+            let mut ensemble = Ensemble::with_mode(mode);
+            for _ in 0..3 {
+                training
+                    .update_lambdas_weights(&metric::new("NDCG", 10).unwrap());
+                let mut tree = RegressionTree::new(0.1, 3, 1);
+                tree.fit(&training).unwrap();
+                ensemble.push(tree);
+            }
+
+            let expected: Vec<f64> = dataset
+                .iter()
+                .map(|instance| ensemble.evaluate(instance))
+                .collect();
+            assert_eq!(ensemble.evaluate_all(&dataset), expected);
+        }
+    }
+
+    #[test]
+    fn test_ensemble_evaluate_prefix() {
+        let data = vec![
+            (3.0, 1, vec![3.0, 0.0]),
+            (2.0, 1, vec![2.0, 1.0]),
+            (1.0, 1, vec![1.0, 0.0]),
+            (1.0, 2, vec![1.0, 1.0]),
+        ];
+        let dataset: DataSet = data.into_iter().collect();
+
+        let mut training = TrainSet::new(&dataset, 3);
+        let mut ensemble = Ensemble::new();
+        for _ in 0..3 {
+            training.update_lambdas_weights(&metric::new("NDCG", 10).unwrap());
+            let mut tree = RegressionTree::new(0.1, 3, 1);
+            let output = tree.fit(&training).unwrap();
+            training.update_result(&output);
+            ensemble.push(tree);
+        }
+
+        for instance in dataset.iter() {
+            let outputs: Vec<f64> =
+                ensemble.iter().map(|tree| tree.evaluate(instance)).collect();
+            assert_eq!(ensemble.evaluate_prefix(instance, 0), 0.0);
+            assert_eq!(
+                ensemble.evaluate_prefix(instance, 2),
+                outputs[0] + outputs[1]
+            );
+            assert_eq!(
+                ensemble.evaluate_prefix(instance, 10),
+                ensemble.evaluate(instance)
+            );
+        }
+
+        ensemble.mode = EnsembleMode::Average;
+        let instance = &dataset[0];
+        assert_eq!(
+            ensemble.evaluate_prefix(instance, 2),
+            (ensemble[0].evaluate(instance) + ensemble[1].evaluate(instance))
+                / 2.0
+        );
+    }
+
+    #[test]
+    fn test_ensemble_evaluate_contributions() {
+        let data = vec![
+            (3.0, 1, vec![3.0, 0.0]),
+            (2.0, 1, vec![2.0, 1.0]),
+            (1.0, 1, vec![1.0, 0.0]),
+        ];
+        let dataset: DataSet = data.into_iter().collect();
+
+        let mut training = TrainSet::new(&dataset, 3);
+        for &mode in &[EnsembleMode::Sum, EnsembleMode::Average] {
+            let mut ensemble = Ensemble::with_mode(mode);
+            for _ in 0..3 {
+                training
+                    .update_lambdas_weights(&metric::new("NDCG", 10).unwrap());
+                let mut tree = RegressionTree::new(0.1, 3, 1);
+                let leaf_output = tree.fit(&training).unwrap();
+                training.update_result(&leaf_output);
+                ensemble.push(tree);
+            }
+
+            for instance in dataset.iter() {
+                let contributions = ensemble.evaluate_contributions(instance);
+                assert_eq!(contributions.len(), 3);
+                let sum: f64 = contributions.iter().sum();
+                assert!((sum - ensemble.evaluate(instance)).abs() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn test_ensemble_feature_contributions() {
+        let path = "./data/train-lite.txt";
+        let f = ::std::fs::File::open(path).unwrap();
+        let dataset = DataSet::load(f).unwrap();
+
+        let mut training = TrainSet::new(&dataset, 256);
+        let mut ensemble = Ensemble::new();
+        for _ in 0..5 {
+            training.update_lambdas_weights(&metric::new("NDCG", 10).unwrap());
+            let mut tree = RegressionTree::new(0.1, 10, 1);
+            let leaf_output = tree.fit(&training).unwrap();
+            training.update_result(&leaf_output);
+            ensemble.push(tree);
+        }
+
+        let mut bytes = Vec::new();
+        ensemble.save(&mut bytes, ModelFormat::Text).unwrap();
+        let loaded = Ensemble::load(&bytes[..]).unwrap();
+
+        for instance in dataset.iter().take(10) {
+            let contributions = ensemble.feature_contributions(instance);
+            assert_eq!(contributions[0].0, 0);
+            assert!(contributions.windows(2).all(|w| w[0].0 < w[1].0));
+            let sum: f64 = contributions.iter().map(|&(_, c)| c).sum();
+            assert!((sum - ensemble.evaluate(instance)).abs() < 1e-9);
+            assert_eq!(loaded.feature_contributions(instance), contributions);
+        }
+    }
+
+    #[test]
+    fn test_ensemble_save_load() {
+        let path = "./data/train-lite.txt";
+        let f = ::std::fs::File::open(path).unwrap();
+        let dataset = DataSet::load(f).unwrap();
+
+        let mut training = TrainSet::new(&dataset, 256);
+        let mut ensemble = Ensemble::new();
+        for _ in 0..5 {
+            training.update_lambdas_weights(&metric::new("NDCG", 10).unwrap());
+            let mut tree = RegressionTree::new(0.1, 10, 1);
+            let leaf_output = tree.fit(&training).unwrap();
+            training.update_result(&leaf_output);
+            ensemble.push(tree);
+        }
+        let expected = ensemble.evaluate_all(&dataset);
+
+        let mut text = Vec::new();
+        ensemble.save(&mut text, ModelFormat::Text).unwrap();
+        let mut binary = Vec::new();
+        ensemble.save(&mut binary, ModelFormat::Binary).unwrap();
+        assert!(binary.len() < text.len());
+
+        for bytes in vec![text, binary] {
+            let loaded = Ensemble::load(&bytes[..]).unwrap();
+            assert_eq!(loaded.len(), 5);
+            assert_eq!(loaded.evaluate_all(&dataset), expected);
+        }
+
+        assert!(Ensemble::load(&b"model"[..]).is_err());
+        assert!(Ensemble::load(&BINARY_MAGIC[..]).is_err());
+    }
+
+    #[test]
+    fn test_ensemble_save_load_metadata() {
+        // A model saved before the metadata.
+        let text = "rforests-ensemble
+mode sum
+features 0
+calibration none
+trees 1
+tree 0.1 1
+leaf 1
+";
+        let mut ensemble = Ensemble::load(text.as_bytes()).unwrap();
+        assert_eq!(ensemble.metadata(), &ModelMetadata::default());
+
+        let metadata = ModelMetadata {
+            metric: Some("NDCG".to_string()),
+            metric_k: Some(10),
+            gain_table: Some(vec![0.0, 1.0, 3.5]),
+            discount_base: Some(10.0),
+            max_gain: None,
+            learning_rate: Some("0.1 x 0.5 every 100 trees".to_string()),
+            trees: Some(1000),
+            leaves: Some(10),
+            thresholds: Some(256),
+            seed: None,
+        };
+        ensemble.set_metadata(metadata.clone());
+
+        for &format in &[ModelFormat::Text, ModelFormat::Binary] {
+            let mut bytes = Vec::new();
+            ensemble.save(&mut bytes, format).unwrap();
+            let loaded = Ensemble::load(&bytes[..]).unwrap();
+            assert_eq!(loaded.metadata(), &metadata);
+        }
+
+        // A binary model saved before the metadata.
+        let mut binary = Vec::new();
+        ensemble.save(&mut binary, ModelFormat::Binary).unwrap();
+        let metadata_len = 4 + metadata
+            .pairs()
+            .iter()
+            .map(|&(key, ref value)| 8 + key.len() + value.len())
+            .sum::<usize>();
+        let mut v1 = BINARY_MAGIC_V1.to_vec();
+        v1.extend_from_slice(&binary[BINARY_MAGIC.len() + metadata_len..]);
+        let loaded = Ensemble::load(&v1[..]).unwrap();
+        assert_eq!(loaded.metadata(), &ModelMetadata::default());
+        assert_eq!(loaded.len(), 1);
+    }
+
+    #[test]
+    fn test_ensemble_save_load_feature_map() {
+        let s = "3.0 qid:1 10:5.0 700:1.0
+2.0 qid:1 10:7.0
+1.0 qid:1 10:2.0 700:3.0";
+        let mut dataset = DataSet::load_sparse(::std::io::Cursor::new(s))
+            .unwrap();
+        let mapping = dataset.compact_features();
+
+        let mut training = TrainSet::new(&dataset, 256);
+        training.update_lambdas_weights(&metric::new("NDCG", 10).unwrap());
+        let mut tree = RegressionTree::new(0.1, 10, 1);
+        tree.fit(&training).unwrap();
+        let mut ensemble = Ensemble::new();
+        ensemble.push(tree);
+        ensemble.set_feature_map(mapping);
+
+        for &format in &[ModelFormat::Text, ModelFormat::Binary] {
+            let mut bytes = Vec::new();
+            ensemble.save(&mut bytes, format).unwrap();
+            let loaded = Ensemble::load(&bytes[..]).unwrap();
+            assert_eq!(loaded.feature_map(), &[10, 700]);
+            assert_eq!(
+                loaded.evaluate_all(&dataset),
+                ensemble.evaluate_all(&dataset)
+            );
+        }
+    }
+
+    #[test]
+    fn test_ensemble_calibration() {
+        let path = "./data/train-lite.txt";
+        let f = ::std::fs::File::open(path).unwrap();
+        let dataset = DataSet::load(f).unwrap();
+
+        let mut training = TrainSet::new(&dataset, 256);
+        let mut ensemble = Ensemble::new();
+        for _ in 0..5 {
+            training.update_lambdas_weights(&metric::new("NDCG", 10).unwrap());
+            let mut tree = RegressionTree::new(0.1, 10, 1);
+            let leaf_output = tree.fit(&training).unwrap();
+            training.update_result(&leaf_output);
+            ensemble.push(tree);
+        }
+        assert_eq!(ensemble.calibration(), None);
+        ensemble.calibrate(&dataset);
+        let (a, _b) = ensemble.calibration().unwrap();
+        // Higher scores are more likely relevant.
+        assert!(a < 0.0);
+
+        // The mean probability is close to the rate of the positives.
+        let mean = dataset
+            .iter()
+            .map(|instance| ensemble.evaluate_calibrated(instance))
+            .sum::<f64>() / dataset.len() as f64;
+        let rate = dataset.label_iter().filter(|&l| l > 0.0).count() as f64 /
+            dataset.len() as f64;
+        assert!((mean - rate).abs() < 0.01);
+
+        for &format in &[ModelFormat::Text, ModelFormat::Binary] {
+            let mut bytes = Vec::new();
+            ensemble.save(&mut bytes, format).unwrap();
+            let loaded = Ensemble::load(&bytes[..]).unwrap();
+            assert_eq!(loaded.calibration(), ensemble.calibration());
+        }
+    }
+
+    #[test]
+    fn test_load_ranklib() {
+        let s = r#"## LambdaMART
+## No. of trees = 2
+<ensemble>
+	<tree id="1" weight="0.1">
+		<split>
+			<feature> 1 </feature>
+			<threshold> 0.5 </threshold>
+			<split pos="left">
+				<feature> 2 </feature>
+				<threshold> 3.0 </threshold>
+				<split pos="left">
+					<output> -2.0 </output>
+				</split>
+				<split pos="right">
+					<output> -1.0 </output>
+				</split>
+			</split>
+			<split pos="right">
+				<output> 4.0 </output>
+			</split>
+		</split>
+	</tree>
+	<tree id="2" weight="0.5">
+		<split>
+			<output> 1.0 </output>
+		</split>
+	</tree>
+</ensemble>
+"#;
+        let ensemble = Ensemble::load_ranklib(s.as_bytes()).unwrap();
+        assert_eq!(ensemble.len(), 2);
+
+        let score = |values: Vec<f64>| {
+            ensemble.evaluate(&Instance::new(0.0, 1, values))
+        };
+        assert_eq!(score(vec![0.5, 3.0]), 0.1 * -2.0 + 0.5);
+        assert_eq!(score(vec![0.0, 4.0]), 0.1 * -1.0 + 0.5);
+        assert_eq!(score(vec![1.0, 0.0]), 0.1 * 4.0 + 0.5);
+
+        assert!(Ensemble::load_ranklib(&b"<ensemble>"[..]).is_err());
+        let s = "<ensemble><tree id=\"1\"><split><output> 1 </output>\
+                 </split></tree></ensemble>";
+        assert!(Ensemble::load_ranklib(s.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_ensemble_feature_usage() {
+        // (label, qid, feature_values)
+        let data = vec![
+            (0.0, 1, vec![1.0, 5.0]),
+            (0.0, 1, vec![2.0, 5.0]),
+            (3.0, 1, vec![3.0, 5.0]),
+            (3.0, 1, vec![4.0, 6.0]),
+        ];
+        let dataset: DataSet = data.into_iter().collect();
+
+        let mut training = TrainSet::new(&dataset, 256);
+        training.fit_labels();
+        let mut ensemble = Ensemble::new();
+        for _ in 0..2 {
+            let mut tree = RegressionTree::new(1.0, 2, 1);
+            tree.set_leaf_output(LeafOutput::Mean);
+            tree.fit(&training).unwrap();
+            ensemble.push(tree);
+        }
+
+        // Splitting at 2.0 reduces the variance by
+        // 6.0 ^ 2 / 2 - 6.0 ^ 2 / 4.
+        let usage = FeatureUsage {
+            id: 1,
+            splits: 2,
+            total_gain: 18.0,
+            mean_threshold: 2.0,
+        };
+        assert_eq!(ensemble.feature_usage(), vec![usage]);
+
+        let mut csv = Vec::new();
+        ensemble.write_feature_usage(&mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "id,splits,total_gain,mean_threshold\n1,2,18,2\n"
+        );
+    }
+
+    #[test]
+    fn test_write_tree() {
+        let mut bytes = Vec::new();
+        RegressionTree::new(1.0, 2, 1).write_tree(&mut bytes).unwrap();
+        assert_eq!(String::from_utf8(bytes).unwrap(), "Empty tree\n");
+
+        let data = vec![
+            (0.0, 1, vec![1.0, 5.0]),
+            (0.0, 1, vec![2.0, 5.0]),
+            (3.0, 1, vec![3.0, 5.0]),
+            (3.0, 1, vec![4.0, 6.0]),
+        ];
+        let dataset: DataSet = data.into_iter().collect();
+        let mut training = TrainSet::new(&dataset, 256);
+        training.fit_labels();
+        let mut tree = RegressionTree::new(1.0, 2, 1);
+        tree.set_leaf_output(LeafOutput::Mean);
+        tree.fit(&training).unwrap();
+
+        let mut bytes = Vec::new();
+        tree.write_tree(&mut bytes).unwrap();
+        assert_eq!(
+            String::from_utf8(bytes).unwrap(),
+            "{ fid: \"1\", threshold: \"2\" }
+  { output: 3.0 }
+  { output: 0.0 }
+"
+        );
+    }
+
+    #[test]
+    fn test_save_ranklib() {
+        let path = "./data/train-lite.txt";
+        let f = ::std::fs::File::open(path).unwrap();
+        let dataset = DataSet::load(f).unwrap();
+
+        let mut training = TrainSet::new(&dataset, 256);
+        training.fit_labels();
+        let mut ensemble = Ensemble::with_mode(EnsembleMode::Average);
+        for _ in 0..3 {
+            let mut tree = RegressionTree::new(1.0, 10, 1);
+            tree.set_leaf_output(LeafOutput::Mean);
+            tree.fit(&training).unwrap();
+            ensemble.push(tree);
+        }
+
+        let mut bytes = Vec::new();
+        ensemble.save_ranklib(&mut bytes).unwrap();
+        assert!(bytes.starts_with(b"## LambdaMART\n"));
+        let loaded = Ensemble::load_ranklib(&bytes[..]).unwrap();
+        assert_eq!(loaded.len(), 3);
+        for (a, b) in loaded
+            .evaluate_all(&dataset)
+            .iter()
+            .zip(ensemble.evaluate_all(&dataset))
+        {
+            assert!((a - b).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_categorical_tree() {
+        let data = vec![
+            (0.0, 1, vec![1.0]),
+            (5.0, 1, vec![2.0]),
+            (0.0, 1, vec![3.0]),
+            (0.0, 1, vec![1.0]),
+            (5.0, 1, vec![2.0]),
+            (0.0, 1, vec![3.0]),
+        ];
+        let dataset: DataSet = data.into_iter().collect();
+
+        let mut training = TrainSet::new(&dataset, 256);
+        training.fit_labels();
+        training.set_categorical_features(vec![1].into_iter().collect());
+        let mut tree = RegressionTree::new(1.0, 2, 1);
+        tree.set_leaf_output(LeafOutput::Mean);
+        tree.fit(&training).unwrap();
+        assert_eq!(tree.nodes[0].categories, Some(vec![2.0]));
+        assert_eq!(tree.nodes[0].threshold, None);
+        let mut ensemble = Ensemble::new();
+        ensemble.push(tree);
+        let expected = vec![0.0, 5.0, 0.0, 0.0, 5.0, 0.0];
+        assert_eq!(ensemble.evaluate_all(&dataset), expected);
+
+        let usage = ensemble.feature_usage();
+        assert_eq!(usage[0].splits, 1);
+        assert_eq!(usage[0].mean_threshold, 0.0);
+
+        for &format in &[ModelFormat::Text, ModelFormat::Binary] {
+            let mut bytes = Vec::new();
+            ensemble.save(&mut bytes, format).unwrap();
+            let loaded = Ensemble::load(&bytes[..]).unwrap();
+            assert_eq!(loaded.evaluate_all(&dataset), expected);
+        }
+        assert!(ensemble.save_ranklib(Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_predict_stream() {
+        let s = "3.0 qid:1 10:5.0 700:1.0
+2.0 qid:1 10:7.0
+1.0 qid:A 10:2.0 700:3.0";
+        let mut dataset = DataSet::load_sparse(::std::io::Cursor::new(s))
+            .unwrap();
+        let mapping = dataset.compact_features();
+
+        let mut training = TrainSet::new(&dataset, 256);
+        training.fit_labels();
+        let mut tree = RegressionTree::new(1.0, 10, 1);
+        tree.set_leaf_output(LeafOutput::Mean);
+        tree.fit(&training).unwrap();
+        let mut ensemble = Ensemble::new();
+        ensemble.push(tree);
+        ensemble.set_feature_map(mapping);
+
+        let mut output = Vec::new();
+        ensemble.predict_stream(s.as_bytes(), &mut output).unwrap();
+        let scores = ensemble.evaluate_all(&dataset);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!(
+                "1\t0\t{}\n1\t1\t{}\nA\t0\t{}\n",
+                scores[0],
+                scores[1],
+                scores[2]
+            )
+        );
+
+        let mut output = Vec::new();
+        ensemble
+            .predict_stream_as(
+                s.as_bytes(),
+                &mut output,
+                PredictionFormat::Scores,
+            )
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!("{}\n{}\n{}\n", scores[0], scores[1], scores[2])
+        );
+    }
+
+    #[test]
+    fn test_platt_probability() {
+        assert_eq!(platt_probability(0.0, -1.0, 0.0), 0.5);
+        assert!(platt_probability(1000.0, -1.0, 0.0) > 0.99);
+        assert!(platt_probability(-1000.0, -1.0, 0.0) < 0.01);
+    }
+
+    #[test]
+    fn test_tree_fitting_single_instance_query() {
+        // (label, qid, feature_values)
+        let data = vec![(2.0, 1, vec![3.0, 1.0])];
+        let dataset: DataSet = data.into_iter().collect();
+
+        let mut training = TrainSet::new(&dataset, 3);
+        training.update_lambdas_weights(&metric::new("NDCG", 10).unwrap());
+
+        let mut tree = RegressionTree::new(0.1, 10, 1);
+        let leaf_output = tree.fit(&training).unwrap();
+        assert_eq!(tree.nodes.len(), 1);
+        assert_eq!(leaf_output, vec![0.0]);
+        assert_eq!(tree.evaluate(&dataset[0]), 0.0);
+
+        let sample = TrainSample::new(&training, Vec::new(), vec![1, 2]);
+        let mut tree = RegressionTree::new(0.1, 10, 1);
+        assert!(tree.fit_sample(sample).is_err());
+    }
+
+    #[bench]
+    fn bench_fit(b: &mut Bencher) {
+        let path = "./data/train-lite.txt";
+        let f = ::std::fs::File::open(path).unwrap();
+        let dataset = DataSet::load(f).unwrap();
+
+        let mut training = TrainSet::new(&dataset, 256);
+        training.update_lambdas_weights(&metric::new("NDCG", 10).unwrap());
+
+        b.iter(|| RegressionTree::new(0.1, 10, 1).fit(&training).unwrap());
+    }
 }
@@ -1,12 +1,48 @@
-use metric::Measure;
+use metric::{Measure, TieBreak, ZeroQueries};
 use super::histogram::*;
-use util::{Id, Value};
+use util::{Id, Rng, Value};
 use std;
 use std::cmp::Ordering::*;
 use train::dataset::*;
-use std::collections::{BinaryHeap, HashMap};
+use std::collections::{BinaryHeap, HashMap, HashSet};
 use std::cmp::Ordering;
 use std::sync::{Arc, Mutex};
+use super::loss::*;
+
+/// How the thresholds of a feature are generated when it has more
+/// distinct values than the thresholds count.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinningStrategy {
+    /// Thresholds evenly spaced between the min and max values.
+    Uniform,
+    /// Thresholds at the quantiles of the values, so that each bin
+    /// holds roughly the same number of instances. A value repeated in
+    /// more than the share of a bin fills a bin by itself, and the
+    /// other values share the remaining bins. Better for skewed
+    /// distributions.
+    Quantile,
+}
+
+impl std::str::FromStr for BinningStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<BinningStrategy, String> {
+        match s {
+            "uniform" => Ok(BinningStrategy::Uniform),
+            "quantile" => Ok(BinningStrategy::Quantile),
+            _ => Err(format!("Invalid binning strategy: {}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for BinningStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            BinningStrategy::Uniform => write!(f, "uniform"),
+            BinningStrategy::Quantile => write!(f, "quantile"),
+        }
+    }
+}
 
 /// A Mapping from the index of a Instance in the DataSet into a
 /// threshold interval.
@@ -27,36 +63,77 @@ struct ThresholdMap {
     /// assert!(map.iter().all(|&i| i <= 256));
     /// ```
     map: Vec<usize>,
+
+    /// Whether all the values are equal.
+    constant: bool,
 }
 
 impl ThresholdMap {
     /// Generate thresholds according to the given values and max
     /// bins. If the count of values exceeds max bins, thresholds are
-    /// generated by averaging the difference of max and min of the
-    /// values by max bins.
+    /// generated by the binning strategy. See `BinningStrategy`.
     fn thresholds(
         sorted_values: Vec<Value>,
         thresholds_count: usize,
+        binning: BinningStrategy,
     ) -> Vec<Value> {
-        let mut thresholds = sorted_values;
+        let mut thresholds = sorted_values.clone();
 
         thresholds.dedup();
 
         // If too many values, generate at most thresholds_count thresholds.
         if thresholds.len() > thresholds_count {
-            let max = *thresholds.last().unwrap();
-            let min = *thresholds.first().unwrap();
-            let step = (max - min) / thresholds_count as Value;
-            thresholds = (0..thresholds_count)
-                .map(|n| min + n as Value * step)
-                .collect();
+            match binning {
+                BinningStrategy::Uniform => {
+                    let max = *thresholds.last().unwrap();
+                    let min = *thresholds.first().unwrap();
+                    let step = (max - min) / thresholds_count as Value;
+                    thresholds = (0..thresholds_count)
+                        .map(|n| min + n as Value * step)
+                        .collect();
+                }
+                BinningStrategy::Quantile => {
+                    thresholds = ThresholdMap::quantiles(
+                        &sorted_values,
+                        thresholds_count,
+                    );
+                }
+            }
         }
         thresholds.push(std::f64::MAX);
         thresholds
     }
 
+    /// Returns at most `count` thresholds splitting the sorted values
+    /// into bins of equal numbers of values. Each bin takes an equal
+    /// share of the values left for the bins left, and the repeats of
+    /// its last value, which is its threshold. A heavily repeated value
+    /// thus fills a bin by itself, and the bins after it still split
+    /// the rest of the values evenly.
+    fn quantiles(sorted_values: &[Value], count: usize) -> Vec<Value> {
+        let nvalues = sorted_values.len();
+        let mut thresholds = Vec::with_capacity(count);
+        let mut start = 0;
+        while start < nvalues && thresholds.len() < count {
+            let bins_left = count - thresholds.len();
+            let share = (nvalues - start + bins_left - 1) / bins_left;
+            let threshold = sorted_values[start + share - 1];
+            let mut end = start + share;
+            while end < nvalues && sorted_values[end] == threshold {
+                end += 1;
+            }
+            thresholds.push(threshold);
+            start = end;
+        }
+        thresholds
+    }
+
     /// Create a map according to the given values and max bins.
-    pub fn new(values: Vec<Value>, thresholds_count: usize) -> ThresholdMap {
+    pub fn new(
+        values: Vec<Value>,
+        thresholds_count: usize,
+        binning: BinningStrategy,
+    ) -> ThresholdMap {
         let nvalues = values.len();
 
         let mut indexed_values: Vec<(usize, Value)> =
@@ -69,8 +146,12 @@ impl ThresholdMap {
             .iter()
             .map(|&(_, value)| value)
             .collect::<Vec<Value>>();
-        let thresholds =
-            ThresholdMap::thresholds(sorted_values, thresholds_count);
+        let constant = sorted_values.first() == sorted_values.last();
+        let thresholds = ThresholdMap::thresholds(
+            sorted_values,
+            thresholds_count,
+            binning,
+        );
         let mut map: Vec<usize> = Vec::new();
         map.resize(nvalues, 0);
 
@@ -87,9 +168,58 @@ impl ThresholdMap {
         ThresholdMap {
             thresholds: thresholds,
             map: map,
+            constant: constant,
+        }
+    }
+
+    /// Create a map with the thresholds generated from a sample of the
+    /// values, given by their indices. All the values are mapped, and
+    /// those above the largest threshold of the sample fall into the
+    /// last threshold, which is Value::MAX.
+    pub fn with_sample(
+        values: Vec<Value>,
+        sample: &[usize],
+        thresholds_count: usize,
+        binning: BinningStrategy,
+    ) -> ThresholdMap {
+        // Decided by all the values, which may vary outside the sample.
+        let constant = values.windows(2).all(|pair| pair[0] == pair[1]);
+        let mut sorted_values: Vec<Value> =
+            sample.iter().map(|&index| values[index]).collect();
+        sorted_values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Less));
+        let thresholds = ThresholdMap::thresholds(
+            sorted_values,
+            thresholds_count,
+            binning,
+        );
+
+        // The index of the first threshold not less than the value.
+        let map = values
+            .iter()
+            .map(|value| {
+                let pos = thresholds.binary_search_by(|threshold| {
+                    threshold.partial_cmp(value).unwrap_or(Less)
+                });
+                match pos {
+                    Ok(index) | Err(index) => index,
+                }
+            })
+            .collect();
+        ThresholdMap {
+            thresholds: thresholds,
+            map: map,
+            constant: constant,
         }
     }
 
+    /// Returns true if all the values are equal, i.e. the feature is
+    /// constant and can never be split on. This is decided by the
+    /// values rather than by the thresholds, which are few for any
+    /// feature when the thresholds count is small.
+    fn is_constant(&self) -> bool {
+        self.constant
+    }
+
     /// Generate a histogram for a series of values.
     ///
     /// The input is an iterator over (instance id, feature value,
@@ -117,30 +247,36 @@ impl ThresholdMap {
     ///     (0.0, 6.0),
     /// ];
     ///
-    /// let map = ThresholdMap::new(data.iter().map(|&(_, value)| value), 3);
+    /// let map = ThresholdMap::new(
+    ///     data.iter().map(|&(_, value)| value),
+    ///     3,
+    ///     BinningStrategy::Uniform,
+    /// );
     /// let histogram = map.histogram(data.iter().map(|&(target, _)| target));
     ///
     /// assert_eq!(histogram.variance(), 15.555555555555557);
-    pub fn histogram<I: Iterator<Item = (Id, Value)>>(
+    pub fn histogram<I: Iterator<Item = (Id, Value, Value)>>(
         &self,
         iter: I,
     ) -> Histogram {
-        // (threshold value, count, sum, squared_sum)
-        let mut hist: Vec<(Value, usize, Value)> = self.thresholds
+        // (threshold value, count, sum, weight)
+        let mut hist: Vec<(Value, usize, Value, Value)> = self.thresholds
             .iter()
-            .map(|&threshold| (threshold, 0, 0.0))
+            .map(|&threshold| (threshold, 0, 0.0, 0.0))
             .collect();
 
-        for (id, label) in iter {
+        for (id, label, weight) in iter {
             let threshold_index = self.map[id];
 
             hist[threshold_index].1 += 1;
             hist[threshold_index].2 += label;
+            hist[threshold_index].3 += weight;
         }
 
         for i in 1..hist.len() {
             hist[i].1 += hist[i - 1].1;
             hist[i].2 += hist[i - 1].2;
+            hist[i].3 += hist[i - 1].3;
         }
         let feature_histogram = hist.into_iter().collect();
         feature_histogram
@@ -167,47 +303,91 @@ impl std::fmt::Debug for ThresholdMap {
     }
 }
 
-// Input: Vec of (index, label, score)
-// Output: Vec of (higher index, lower index, lambda, weight)
-fn compute_lambda_weight(
-    rank_list: &mut Vec<(usize, f64, f64)>,
-    metric: &Box<Measure>,
-) -> Vec<(usize, usize, f64, f64)> {
-    let mut query_values: Vec<(usize, usize, f64, f64)> = Vec::new();
-    // Rank by the scores of our model.
-    rank_list.sort_by(|&(_, _, score1), &(_, _, score2)| {
-        score2.partial_cmp(&score1).unwrap_or(Ordering::Equal)
-    });
-
-    let ranked_labels: Vec<_> =
-        rank_list.iter().map(|&(_, label, _)| label).collect();
-
-    let changes = metric.swap_changes(&ranked_labels);
-
-    let k = metric.get_k();
-    for (metric_index1, &(index1, label1, score1)) in
-        rank_list.iter().enumerate()
-    {
-        for (metric_index2, &(index2, label2, score2)) in
-            rank_list.iter().enumerate()
-        {
-            if metric_index1 > k && metric_index2 > k {
-                break;
-            }
+/// Generates the threshold map of each feature which is not constant
+/// over the data, reading the values column by column. The thresholds
+/// are generated from the sample of the instances if given.
+fn threshold_maps<C: FeatureColumns>(
+    columns: &C,
+    thresholds_count: usize,
+    binning: BinningStrategy,
+    sample: Option<&[usize]>,
+) -> HashMap<Id, ThresholdMap> {
+    let mut threshold_maps = HashMap::new();
+    for fid in columns.feature_ids() {
+        let values = columns.feature_column(fid).into_owned();
+        let map = match sample {
+            Some(sample) => ThresholdMap::with_sample(
+                values,
+                sample,
+                thresholds_count,
+                binning,
+            ),
+            None => ThresholdMap::new(values, thresholds_count, binning),
+        };
+        if map.is_constant() {
+            continue;
+        }
 
-            if label1 <= label2 {
-                continue;
-            }
+        threshold_maps.insert(fid, map);
+    }
+    threshold_maps
+}
+
+/// The thresholds of the features of a DataSet, and the mapping of
+/// the feature values into them. Computing them is the one-time setup
+/// of the training, so they can be computed ahead and reused by
+/// `TrainSet::with_thresholds`.
+pub struct Thresholds {
+    maps: HashMap<Id, ThresholdMap>,
+    // Ids of the features that are not constant over the data set.
+    fids: Vec<Id>,
+}
 
-            let change = changes[metric_index1][metric_index2].abs();
-            let rho = 1.0 / (1.0 + (score1 - score2).exp());
-            let lambda = change * rho;
-            let weight = rho * (1.0 - rho) * change;
+impl Thresholds {
+    /// Computes the thresholds of each feature which is not constant,
+    /// as in `TrainSet::with_threshold_sample`.
+    pub fn new(
+        dataset: &DataSet,
+        thresholds_count: usize,
+        binning: BinningStrategy,
+        sample_size: Option<usize>,
+        seed: u64,
+    ) -> Thresholds {
+        let len = dataset.instance_count();
+        let sample = match sample_size {
+            Some(size) if size < len => {
+                let mut indices: Vec<usize> = (0..len).collect();
+                Rng::new(seed).shuffle(&mut indices);
+                indices.truncate(size);
+                indices.sort();
+                Some(indices)
+            }
+            _ => None,
+        };
+
+        let maps = threshold_maps(
+            dataset,
+            thresholds_count,
+            binning,
+            sample.as_ref().map(|indices| &indices[..]),
+        );
+        let mut fids: Vec<Id> = maps.keys().cloned().collect();
+        fids.sort();
+        let nconstant = dataset.feature_ids().len() - fids.len();
+        if nconstant > 0 {
+            info!("Skipped {} constant features", nconstant);
+        }
 
-            query_values.push((index1, index2, lambda, weight));
+        Thresholds {
+            maps: maps,
+            fids: fids,
         }
     }
-    query_values
+
+    /// Returns the number of features which are not constant.
+    pub fn feature_count(&self) -> usize {
+        self.fids.len()
+    }
 }
 
 /// A collection type containing a data set. The difference with
@@ -226,32 +406,80 @@ pub struct TrainSet<'d> {
     // Do not make assumptions on feature id values, so use a hash
     // map.
     threshold_maps: HashMap<usize, ThresholdMap>,
+
+    // Ids of the features that are not constant over the data set.
+    fids: Vec<Id>,
+
+    // If set, the lambdas are clamped into [-clip, clip].
+    lambda_clip: Option<Value>,
+
+    // Ids of the features split by category instead of by threshold.
+    categorical: HashSet<Id>,
+
+    // How the gains of the splits are measured.
+    split_criterion: Criterion,
 }
 
 impl<'d> TrainSet<'d> {
     /// Creates a new TrainSet from DataSet. Thresholds will be
-    /// generated.
+    /// generated uniformly. Features that are constant over the whole
+    /// data set can never be split on, so they are skipped.
     pub fn new(
         dataset: &'d DataSet,
         thresholds_count: usize,
     ) -> TrainSet<'d> {
-        fn generate_thresholds(
-            dataset: &DataSet,
-            thresholds_count: usize,
-        ) -> HashMap<usize, ThresholdMap> {
-            let mut threshold_maps = HashMap::new();
-            for fid in dataset.fid_iter() {
-                let values: Vec<Value> =
-                    dataset.feature_value_iter(fid).collect();
-                let map = ThresholdMap::new(values, thresholds_count);
-
-                threshold_maps.insert(fid, map);
-            }
-            threshold_maps
-        }
+        TrainSet::with_binning(
+            dataset,
+            thresholds_count,
+            BinningStrategy::Uniform,
+        )
+    }
+
+    /// Creates a new TrainSet from DataSet, with the thresholds
+    /// generated by the binning strategy.
+    pub fn with_binning(
+        dataset: &'d DataSet,
+        thresholds_count: usize,
+        binning: BinningStrategy,
+    ) -> TrainSet<'d> {
+        TrainSet::with_threshold_sample(
+            dataset,
+            thresholds_count,
+            binning,
+            None,
+            0,
+        )
+    }
 
-        let len = dataset.len();
+    /// Creates a new TrainSet from DataSet, with the thresholds
+    /// generated from a random sample of at most `sample_size`
+    /// instances, drawn with the seed. The whole data set is still
+    /// mapped into the thresholds and trained on. A feature which is
+    /// constant over the sample is skipped.
+    pub fn with_threshold_sample(
+        dataset: &'d DataSet,
+        thresholds_count: usize,
+        binning: BinningStrategy,
+        sample_size: Option<usize>,
+        seed: u64,
+    ) -> TrainSet<'d> {
+        let thresholds = Thresholds::new(
+            dataset,
+            thresholds_count,
+            binning,
+            sample_size,
+            seed,
+        );
+        TrainSet::with_thresholds(dataset, thresholds)
+    }
 
+    /// Creates a new TrainSet from DataSet, with the thresholds
+    /// computed beforehand from the same DataSet.
+    pub fn with_thresholds(
+        dataset: &'d DataSet,
+        thresholds: Thresholds,
+    ) -> TrainSet<'d> {
+        let len = dataset.instance_count();
         let model_scores = vec![0.0; len];
         let lambdas = vec![0.0; len];
         let weights = vec![0.0; len];
@@ -261,10 +489,53 @@ impl<'d> TrainSet<'d> {
             model_scores: model_scores,
             lambdas: lambdas,
             weights: weights,
-            threshold_maps: generate_thresholds(dataset, thresholds_count),
+            threshold_maps: thresholds.maps,
+            fids: thresholds.fids,
+            lambda_clip: None,
+            categorical: HashSet::new(),
+            split_criterion: Criterion::Variance,
         }
     }
 
+    /// Consumes the TrainSet, returning its thresholds for reuse in
+    /// another TrainSet of the same DataSet.
+    pub fn into_thresholds(self) -> Thresholds {
+        Thresholds {
+            maps: self.threshold_maps,
+            fids: self.fids,
+        }
+    }
+
+    /// Sets the bound of the absolute value of each lambda computed
+    /// by `update_gradients`. A query with extreme swap changes can
+    /// produce huge lambdas, and hence unstable Newton steps. `None`,
+    /// the default, leaves the lambdas as they are. NaN lambdas are
+    /// kept rather than clamped, with a warning.
+    pub fn set_lambda_clip(&mut self, clip: Option<Value>) {
+        self.lambda_clip = clip;
+    }
+
+    /// Sets the features whose values are categories rather than
+    /// ordered numbers. A node splits such a feature into the
+    /// instances of one category and the rest, choosing the category
+    /// that reduces the variance the most, whatever the
+    /// `SplitStrategy`. The default is none.
+    pub fn set_categorical_features(&mut self, fids: HashSet<Id>) {
+        self.categorical = fids;
+    }
+
+    /// Sets the criterion by which the splits of the nodes are chosen
+    /// and their gains measured. The default is
+    /// `Criterion::Variance`.
+    pub fn set_split_criterion(&mut self, criterion: Criterion) {
+        self.split_criterion = criterion;
+    }
+
+    /// Returns whether the feature is split by category.
+    pub fn is_categorical(&self, fid: Id) -> bool {
+        self.categorical.contains(&fid)
+    }
+
     /// Returns the number of instances in the training set, also
     /// referred to as its 'length'.
     pub fn len(&self) -> usize {
@@ -286,9 +557,25 @@ impl<'d> TrainSet<'d> {
         (self.lambdas[index], self.weights[index])
     }
 
-    /// Returns an iterator over the feature ids in the training set.
-    pub fn fid_iter(&self) -> impl Iterator<Item = Id> {
-        self.dataset.fid_iter()
+    /// Returns an iterator over the ids of the features that can be
+    /// split on, i.e. that are not constant over the training set.
+    pub fn fid_iter<'a>(&'a self) -> impl Iterator<Item = Id> + 'a {
+        self.fids.iter().cloned()
+    }
+
+    /// Sets the labels as the target values to fit, with unit
+    /// weights. This is used by the algorithms that fit the labels
+    /// directly instead of lambdas, e.g. random forest.
+    pub fn fit_labels(&mut self) {
+        let labels = self.dataset.label_column();
+        for ((lambda, weight), &label) in self.lambdas
+            .iter_mut()
+            .zip(self.weights.iter_mut())
+            .zip(labels.iter())
+        {
+            *lambda = label;
+            *weight = 1.0;
+        }
     }
 
     pub fn init_model_scores(&mut self, values: &[Value]) {
@@ -308,6 +595,12 @@ impl<'d> TrainSet<'d> {
         self.model_scores.iter().cloned()
     }
 
+    /// Returns the model score of each instance, in the order of the
+    /// instances.
+    pub fn scores(&self) -> &[Value] {
+        &self.model_scores
+    }
+
     /// Returns the label value at given index.
     pub fn model_score(&self, index: usize) -> f64 {
         self.model_scores[index]
@@ -332,120 +625,168 @@ impl<'d> TrainSet<'d> {
         iter: I,
     ) -> Histogram {
         // Get the map by feature id.
-        let iter = iter.map(|id| (id, self.lambdas[id]));
+        let iter = iter.map(|id| (id, self.lambdas[id], self.weights[id]));
 
         // Get the map by feature id.
         let threshold_map = &self.threshold_maps[&fid];
         threshold_map.histogram(iter)
     }
 
-    /// Updates the lambda and weight for each instance.
-    ///
-    /// 1. For each query, rank the instances by the scores of our
-    /// model.
-    ///
-    /// 2. Compute the change of scores by swaping each instance with
-    /// another
-    ///
-    /// 3. Update lambda and weight according to the formulas
-    pub fn update_lambdas_weights<'a, 'b>(&'a mut self, metric: &Box<Measure>) {
+    /// Updates the lambda and weight for each instance by the loss
+    /// function. Both are scaled by the weight of the instance, so
+    /// that the Newton output of a leaf is a weighted average.
+    pub fn update_gradients<L: Loss + ?Sized>(&mut self, loss: &L) {
         for (l, w) in self.lambdas.iter_mut().zip(self.weights.iter_mut()) {
             *l = 0.0;
             *w = 0.0;
         }
 
-        // (index, lambda, weight) grouped by queries
-        let values: Arc<Mutex<Vec<Vec<_>>>> = Arc::new(Mutex::new(Vec::new()));
-        let mut pool = ::util::POOL.lock().unwrap();
-        pool.scoped(|scoped| for (_qid, query) in self.dataset.query_iter() {
-            let values = values.clone();
-            let training = &self;
-            scoped.execute(move || {
-                let mut rank_list: Vec<_> = query
-                    .iter()
-                    .map(|&index| {
-                        (
-                            index,
-                            training.dataset[index].label(),
-                            training.model_scores[index],
-                        )
-                    })
-                    .collect();
-                let query_values =
-                    compute_lambda_weight(&mut rank_list, metric);
-                let mut values = values.lock().unwrap();
-                values.push(query_values);
-            })
-        });
+        loss.gradients(
+            self.dataset,
+            &self.model_scores,
+            &mut self.lambdas,
+            &mut self.weights,
+        );
+
+        let instance_weights = self.dataset.iter().map(|i| i.weight());
+        for ((l, w), instance_weight) in self.lambdas
+            .iter_mut()
+            .zip(self.weights.iter_mut())
+            .zip(instance_weights)
+        {
+            *l *= instance_weight;
+            *w *= instance_weight;
+        }
 
-        let values = values.lock().unwrap();
-        for query_values in values.iter() {
-            for &(index1, index2, lambda, weight) in query_values.iter() {
-                self.lambdas[index1] += lambda;
-                self.weights[index1] += weight;
-                self.lambdas[index2] -= lambda;
-                self.weights[index2] += weight;
+        if let Some(clip) = self.lambda_clip {
+            // NaN is kept rather than clamped, since it is no bound of
+            // a lambda.
+            let mut nans = 0;
+            for l in self.lambdas.iter_mut() {
+                if l.is_nan() {
+                    nans += 1;
+                } else {
+                    *l = l.max(-clip).min(clip);
+                }
+            }
+            if nans > 0 {
+                warn!("{} lambdas are NaN and cannot be clipped", nans);
             }
         }
     }
 
-    pub fn measure(&self, metric: &Box<Measure>) -> f64 {
+    /// Updates the lambda and weight for each instance by the
+    /// LambdaRank loss of the metric. See `LambdaLoss`. The ideal
+    /// DCGs are computed on each call; build a `LambdaLoss` once and
+    /// use `update_gradients` when training repeatedly.
+    pub fn update_lambdas_weights(&mut self, metric: &Box<Measure>) {
+        self.update_gradients(&LambdaLoss::new(metric, self.dataset));
+    }
+
+    /// Returns the root mean squared error of the model scores against
+    /// the labels.
+    pub fn rmse(&self) -> f64 {
+        let sum: f64 = self.model_scores
+            .iter()
+            .zip(self.dataset.label_iter())
+            .map(|(score, label)| (label - score) * (label - score))
+            .sum();
+        (sum / self.len() as f64).sqrt()
+    }
+
+    /// Returns the average of the metric over the queries. See
+    /// `ZeroQueries` for which queries count, and `TieBreak` for how
+    /// the ties are ranked.
+    pub fn measure(
+        &self,
+        metric: &Box<Measure>,
+        zero_queries: ZeroQueries,
+        tie_break: TieBreak,
+    ) -> f64 {
         let mut score = 0.0;
         let mut count = 0;
         for (_qid, mut indices) in self.dataset.query_iter() {
             // Sort the indices by the score of the model, rank the
             // query based on the scores, then measure the output.
 
-            indices.sort_by(|&index1, &index2| {
-                self.model_score(index2)
-                    .partial_cmp(&self.model_score(index1))
-                    .unwrap()
-            });
+            tie_break.rank(
+                &mut indices,
+                |index| self.model_score(index),
+                |index| self.dataset[index].label(),
+            );
 
             let labels: Vec<Value> = indices
                 .iter()
                 .map(|&index| self.dataset[index].label())
                 .collect();
+            if !zero_queries.counts(&labels) {
+                continue;
+            }
 
             count += 1;
             score += metric.measure(&labels);
         }
 
+        if count == 0 {
+            return 0.0;
+        }
         score / count as f64
     }
 }
 
-/// Representing a split position with its s value.
+/// Representing a split position with its gain.
+#[derive(Debug, Clone)]
 struct SplitPos {
     pub fid: usize,
     pub threshold: f64,
-    pub s: f64,
+    pub gain: f64,
+    /// Whether `threshold` is a category. See `SampleSplit`.
+    pub categorical: bool,
 }
 
 impl PartialEq for SplitPos {
     fn eq(&self, other: &SplitPos) -> bool {
-        self.s == other.s
+        self.cmp(other) == Ordering::Equal
     }
 }
 
 impl PartialOrd for SplitPos {
     fn partial_cmp(&self, other: &SplitPos) -> Option<Ordering> {
-        self.s.partial_cmp(&other.s)
+        Some(self.cmp(other))
     }
 }
 
 impl Eq for SplitPos {}
 
+/// Splits are ordered by the gains. Of the splits of equal gains, the
+/// one of the lower feature id, then of the lower
+/// threshold, is greater, so that the best split does not depend on
+/// the order in which the features are searched.
 impl Ord for SplitPos {
     fn cmp(&self, other: &SplitPos) -> Ordering {
-        self.partial_cmp(other).unwrap_or(Ordering::Equal)
+        self.gain
+            .partial_cmp(&other.gain)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| other.fid.cmp(&self.fid))
+            .then_with(|| {
+                other
+                    .threshold
+                    .partial_cmp(&self.threshold)
+                    .unwrap_or(Ordering::Equal)
+            })
     }
 }
 
 pub struct SampleSplit<'a> {
     pub fid: usize,
+    /// The instances whose values are at most the threshold go left.
+    /// If `categorical`, the threshold is a category instead, and the
+    /// instances of that value go left.
     pub threshold: f64,
-    pub s: f64,
+    pub categorical: bool,
+    /// The gain of the split by the `SplitCriterion` of the training
+    /// set, by default the reduction of the variance.
+    pub gain: f64,
     pub left: TrainSample<'a>,
     pub right: TrainSample<'a>,
 }
@@ -457,9 +798,32 @@ pub struct TrainSample<'a> {
 
     /// Indices into training
     indices: Vec<usize>,
+
+    /// Feature ids that are considered when splitting
+    fids: Vec<Id>,
 }
 
 impl<'a> TrainSample<'a> {
+    /// Creates a sample of the instances at `indices` of the training
+    /// set. Indices may repeat, e.g. in a bootstrap sample. Only the
+    /// features in `fids` are considered when splitting.
+    pub fn new(
+        training: &'a TrainSet<'a>,
+        indices: Vec<usize>,
+        fids: Vec<Id>,
+    ) -> TrainSample<'a> {
+        TrainSample {
+            training: training,
+            indices: indices,
+            fids: fids,
+        }
+    }
+
+    /// Returns the training set that the sample is drawn from.
+    pub fn training(&self) -> &'a TrainSet<'a> {
+        self.training
+    }
+
     /// Returns the number of instances in the data set sample, also
     /// referred to as its 'length'.
     pub fn len(&self) -> usize {
@@ -484,7 +848,7 @@ impl<'a> TrainSample<'a> {
     /// Returns an iterator over the feature ids in the data set
     /// sample.
     pub fn fid_iter(&'a self) -> impl Iterator<Item = Id> + 'a {
-        self.training.fid_iter()
+        self.fids.iter().cloned()
     }
 
     /// Returns an iterator over the labels in the data set sample.
@@ -501,9 +865,10 @@ impl<'a> TrainSample<'a> {
         self.iter().map(move |(_index, _label, ins)| ins.value(fid))
     }
 
-    /// Returns the Newton step value.
-    pub fn newton_output(&self) -> f64 {
-        let (lambda_sum, weight_sum) = self.indices.iter().fold(
+    /// Returns the sums of the lambdas and of the Newton step weights
+    /// of the instances.
+    fn lambda_weight_sums(&self) -> (f64, f64) {
+        self.indices.iter().fold(
             (0.0, 0.0),
             |(lambda_sum,
               weight_sum),
@@ -511,7 +876,12 @@ impl<'a> TrainSample<'a> {
                 let (lambda, weight) = self.training.get_lambda_weight(index);
                 (lambda_sum + lambda, weight_sum + weight)
             },
-        );
+        )
+    }
+
+    /// Returns the Newton step value.
+    pub fn newton_output(&self) -> f64 {
+        let (lambda_sum, weight_sum) = self.lambda_weight_sums();
 
         if weight_sum == 0.0 {
             0.0
@@ -520,6 +890,25 @@ impl<'a> TrainSample<'a> {
         }
     }
 
+    /// Returns the sum of the Newton step weights, i.e. the hessians,
+    /// of the instances.
+    pub fn weight_sum(&self) -> f64 {
+        self.lambda_weight_sums().1
+    }
+
+    /// Returns the mean of the target values.
+    pub fn mean_output(&self) -> f64 {
+        if self.indices.is_empty() {
+            return 0.0;
+        }
+
+        let sum: f64 = self.indices
+            .iter()
+            .map(|&index| self.training.lambda(index))
+            .sum();
+        sum / self.indices.len() as f64
+    }
+
     pub fn update_output(&self, leaf_output: &mut Vec<Value>, delta: Value) {
         for &i in self.indices.iter() {
             leaf_output[i] = delta;
@@ -534,6 +923,18 @@ impl<'a> TrainSample<'a> {
         )
     }
 
+    /// Returns a histogram of the categories of the feature of the
+    /// data set sample. The raw values are the categories, so they are
+    /// not mapped into the thresholds.
+    fn category_histogram(&self, fid: Id) -> CategoryHistogram {
+        self.iter()
+            .map(|(index, _label, instance)| {
+                let (lambda, weight) = self.training.get_lambda_weight(index);
+                (instance.value(fid), lambda, weight)
+            })
+            .collect()
+    }
+
     /// To facilitate computing the variance. We made a little
     /// transformation.
     ///
@@ -560,18 +961,73 @@ impl<'a> TrainSample<'a> {
     /// Find the best split of this sample. For each feature, find the
     /// best split point that gets the best squared error. And find
     /// the best among all the features.
-    fn best_split(&self, min_leaf_samples: usize) -> Option<SplitPos> {
-        // (fid, threshold, s)
+    ///
+    /// With `SplitStrategy::Random`, a random threshold is chosen for
+    /// each feature instead of the best one. The random numbers of
+    /// each feature are generated from `seed` and the feature id, so
+    /// they do not depend on the scheduling of the threads.
+    fn best_split(
+        &self,
+        min_leaf_support: usize,
+        min_sum_hessian: f64,
+        min_split_gain: f64,
+        strategy: SplitStrategy,
+        seed: u64,
+    ) -> Option<SplitPos> {
+        // (fid, threshold, gain)
         let splits: Arc<Mutex<BinaryHeap<SplitPos>>> =
             Arc::new(Mutex::new(BinaryHeap::new()));
+        let criterion = self.training.split_criterion.split_criterion();
         let mut pool = ::util::POOL.lock().unwrap();
         pool.scoped(|scoped| for fid in self.fid_iter() {
             let splits = splits.clone();
             scoped.execute(move || {
+                if self.training.is_categorical(fid) {
+                    let split = self.category_histogram(fid).best_split(
+                        min_leaf_support,
+                        min_sum_hessian,
+                        min_split_gain,
+                        criterion,
+                    );
+                    if let Some((threshold, gain)) = split {
+                        splits.lock().unwrap().push(SplitPos {
+                            fid,
+                            threshold,
+                            gain,
+                            categorical: true,
+                        })
+                    }
+                    return;
+                }
+
                 let feature_histogram = self.feature_histogram(fid);
-                let split = feature_histogram.best_split(min_leaf_samples);
-                if let Some((threshold, s)) = split {
-                    splits.lock().unwrap().push(SplitPos { fid, threshold, s })
+                let split = match strategy {
+                    SplitStrategy::Best => feature_histogram.best_split(
+                        min_leaf_support,
+                        min_sum_hessian,
+                        min_split_gain,
+                        criterion,
+                    ),
+                    SplitStrategy::Random => {
+                        let seed = seed ^ (fid as u64).wrapping_mul(
+                            0x9e37_79b9_7f4a_7c15,
+                        );
+                        feature_histogram.random_split(
+                            min_leaf_support,
+                            min_sum_hessian,
+                            min_split_gain,
+                            criterion,
+                            &mut Rng::new(seed),
+                        )
+                    }
+                };
+                if let Some((threshold, gain)) = split {
+                    splits.lock().unwrap().push(SplitPos {
+                        fid,
+                        threshold,
+                        gain,
+                        categorical: false,
+                    })
                 }
             })
         });
@@ -580,25 +1036,74 @@ impl<'a> TrainSample<'a> {
         guard.pop()
     }
 
-    /// Split self. Returns (split feature, threshold, s value, left
+    /// Split self. Returns (split feature, threshold, gain, left
     /// child, right child). For each split, if its variance is zero,
     /// it's non-splitable.
-    pub fn split(&self, min_leaf_samples: usize) -> Option<SampleSplit<'a>> {
+    ///
+    /// A split is accepted only if each child contains at least
+    /// `min_leaf_samples` instances, and the reduction of the variance
+    /// is at least `min_split_gain`.
+    pub fn split(
+        &self,
+        min_leaf_samples: usize,
+        min_split_gain: f64,
+    ) -> Option<SampleSplit<'a>> {
+        self.split_with(
+            min_leaf_samples,
+            min_leaf_samples,
+            0.0,
+            min_split_gain,
+            SplitStrategy::Best,
+            0,
+        )
+    }
+
+    /// Same as `split`, with the given strategy of choosing the
+    /// thresholds. `seed` is used by `SplitStrategy::Random`. A node
+    /// with less than `min_leaf_samples` instances is not split, and
+    /// a split is accepted only if each child has at least
+    /// `min_leaf_support` instances and `min_sum_hessian` of the sum
+    /// of the Newton step weights.
+    pub fn split_with(
+        &self,
+        min_leaf_samples: usize,
+        min_leaf_support: usize,
+        min_sum_hessian: f64,
+        min_split_gain: f64,
+        strategy: SplitStrategy,
+        seed: u64,
+    ) -> Option<SampleSplit<'a>> {
         assert!(min_leaf_samples > 0);
+        assert!(min_leaf_support > 0);
         if self.indices.len() < min_leaf_samples ||
             self.variance().abs() <= 0.000001
         {
             return None;
         }
 
-        // Find the split with the best s value;
-        if let Some(SplitPos { fid, threshold, s }) =
-            self.best_split(min_leaf_samples)
-        {
+        // Find the split with the best gain;
+        if let Some(SplitPos {
+            fid,
+            threshold,
+            gain,
+            categorical,
+        }) = self.best_split(
+            min_leaf_support,
+            min_sum_hessian,
+            min_split_gain,
+            strategy,
+            seed,
+        ) {
             let mut left_indices = Vec::new();
             let mut right_indices = Vec::new();
             for (index, _label, instance) in self.iter() {
-                if instance.value(fid) <= threshold {
+                let value = instance.value(fid);
+                let goes_left = if categorical {
+                    value == threshold
+                } else {
+                    value <= threshold
+                };
+                if goes_left {
                     left_indices.push(index);
                 } else {
                     right_indices.push(index);
@@ -608,15 +1113,18 @@ impl<'a> TrainSample<'a> {
             let left = TrainSample {
                 training: self.training,
                 indices: left_indices,
+                fids: self.fids.clone(),
             };
             let right = TrainSample {
                 training: self.training,
                 indices: right_indices,
+                fids: self.fids.clone(),
             };
             Some(SampleSplit {
                 fid,
                 threshold,
-                s,
+                categorical,
+                gain,
                 left,
                 right,
             })
@@ -633,6 +1141,7 @@ impl<'a> From<&'a TrainSet<'a>> for TrainSample<'a> {
         TrainSample {
             training: training,
             indices: indices,
+            fids: training.fid_iter().collect(),
         }
     }
 }
@@ -660,6 +1169,7 @@ mod tests {
     use super::*;
     use test::Bencher;
     use metric;
+    use train::lambdamart::regression_tree::RegressionTree;
 
     #[test]
     fn test_instance_interface() {
@@ -695,7 +1205,7 @@ mod tests {
     fn test_threshold_map() {
         let values = vec![5.0, 7.0, 3.0, 2.0, 1.0, 8.0, 9.0, 4.0, 6.0];
 
-        let map = ThresholdMap::new(values, 3);
+        let map = ThresholdMap::new(values, 3, BinningStrategy::Uniform);
 
         assert_eq!(
             map.thresholds,
@@ -710,6 +1220,186 @@ mod tests {
         assert_eq!(map.map, vec![2, 3, 1, 1, 0, 3, 3, 2, 2]);
     }
 
+    #[test]
+    fn test_threshold_map_constant() {
+        let values = vec![5.0, 7.0, 3.0, 2.0];
+        for &binning in &[BinningStrategy::Uniform, BinningStrategy::Quantile] {
+            let map = ThresholdMap::new(values.clone(), 1, binning);
+            assert!(!map.is_constant());
+            let map =
+                ThresholdMap::with_sample(values.clone(), &[0], 1, binning);
+            assert!(!map.is_constant());
+        }
+        let map = ThresholdMap::new(vec![2.0; 4], 1, BinningStrategy::Uniform);
+        assert!(map.is_constant());
+
+        // A single threshold still trains on the non-constant feature.
+        let data = vec![
+            (1.0, 1, vec![5.0, 1.0]),
+            (0.0, 1, vec![3.0, 1.0]),
+            (2.0, 1, vec![7.0, 1.0]),
+        ];
+        let dataset: DataSet = data.into_iter().collect();
+        let training = TrainSet::new(&dataset, 1);
+        assert_eq!(training.fid_iter().collect::<Vec<Id>>(), vec![1]);
+    }
+
+    #[test]
+    fn test_threshold_map_quantile() {
+        let values = vec![5.0, 7.0, 3.0, 2.0, 1.0, 8.0, 1000.0, 4.0, 6.0];
+
+        let uniform =
+            ThresholdMap::new(values.clone(), 3, BinningStrategy::Uniform);
+        // Most of the values fall into the same bin.
+        assert_eq!(uniform.map, vec![1, 1, 1, 1, 0, 1, 3, 1, 1]);
+
+        let quantile = ThresholdMap::new(values, 3, BinningStrategy::Quantile);
+        assert_eq!(
+            quantile.thresholds,
+            vec![3.0, 6.0, 1000.0, std::f64::MAX]
+        );
+        assert_eq!(quantile.map, vec![1, 2, 0, 0, 0, 2, 2, 1, 1]);
+    }
+
+    #[test]
+    fn test_threshold_map_quantile_skewed() {
+        // 95% zeros, which fill the first bin by themselves.
+        let mut values = vec![0.0; 95];
+        values.extend_from_slice(&[1.0, 2.0, 3.0, 4.0, 5.0]);
+
+        let quantile = ThresholdMap::new(values, 3, BinningStrategy::Quantile);
+        assert_eq!(quantile.thresholds, vec![0.0, 3.0, 5.0, std::f64::MAX]);
+        assert_eq!(quantile.map[0], 0);
+        assert_eq!(&quantile.map[95..], &[1, 1, 1, 2, 2]);
+    }
+
+    #[test]
+    fn test_threshold_map_quantile_counts() {
+        // The values 1.0 to 4.0 repeated 1, 1, 2 and 4 times.
+        let values = vec![4.0, 1.0, 3.0, 4.0, 2.0, 4.0, 3.0, 4.0];
+
+        // Equal counts of the instances, not of the distinct values,
+        // which would put 1.0 and 2.0 in the first bin.
+        let quantile = ThresholdMap::new(values, 2, BinningStrategy::Quantile);
+        assert_eq!(quantile.thresholds, vec![3.0, 4.0, std::f64::MAX]);
+        let count = |bin| quantile.map.iter().filter(|&&b| b == bin).count();
+        assert_eq!((count(0), count(1)), (4, 4));
+    }
+
+    #[test]
+    fn test_threshold_maps_from_columns() {
+        use std::borrow::Cow;
+
+        /// Feature values stored by columns.
+        struct Columns(Vec<Vec<Value>>);
+
+        impl FeatureColumns for Columns {
+            fn instance_count(&self) -> usize {
+                self.0[0].len()
+            }
+
+            fn feature_ids(&self) -> Vec<Id> {
+                (1..self.0.len() + 1).collect()
+            }
+
+            fn feature_column(&self, fid: Id) -> Cow<[Value]> {
+                Cow::Borrowed(&self.0[fid - 1])
+            }
+
+            fn label_column(&self) -> Cow<[Value]> {
+                Cow::Owned(vec![0.0; self.instance_count()])
+            }
+        }
+
+        let data = vec![
+            (3.0, 1, vec![5.0, 1.0, 2.0]),
+            (2.0, 1, vec![7.0, 1.0, 0.0]),
+            (1.0, 2, vec![3.0, 1.0, 4.0]),
+        ];
+        let dataset: DataSet = data.into_iter().collect();
+        let columns = Columns(vec![
+            vec![5.0, 7.0, 3.0],
+            vec![1.0, 1.0, 1.0],
+            vec![2.0, 0.0, 4.0],
+        ]);
+
+        let uniform = BinningStrategy::Uniform;
+        let rows = threshold_maps(&dataset, 2, uniform, None);
+        let cols = threshold_maps(&columns, 2, uniform, None);
+        // The constant feature 2 is skipped.
+        let mut fids: Vec<Id> = cols.keys().cloned().collect();
+        fids.sort();
+        assert_eq!(fids, vec![1, 3]);
+        for fid in fids {
+            assert_eq!(rows[&fid].thresholds, cols[&fid].thresholds);
+            assert_eq!(rows[&fid].map, cols[&fid].map);
+        }
+    }
+
+    #[test]
+    fn test_threshold_map_with_sample() {
+        let values = vec![5.0, 7.0, 3.0, 2.0, 1.0, 8.0, 9.0, 4.0, 6.0];
+
+        // The full sample gives the same map.
+        let all: Vec<usize> = (0..values.len()).collect();
+        let full =
+            ThresholdMap::new(values.clone(), 3, BinningStrategy::Uniform);
+        let sampled = ThresholdMap::with_sample(
+            values.clone(),
+            &all,
+            3,
+            BinningStrategy::Uniform,
+        );
+        assert_eq!(sampled.thresholds, full.thresholds);
+        assert_eq!(sampled.map, full.map);
+
+        // Values out of the range of the sample fall into the first
+        // and the last bins.
+        let sampled = ThresholdMap::with_sample(
+            values,
+            &[2, 7, 8],
+            3,
+            BinningStrategy::Uniform,
+        );
+        assert_eq!(sampled.thresholds, vec![3.0, 4.0, 6.0, std::f64::MAX]);
+        assert_eq!(sampled.map, vec![2, 3, 0, 0, 0, 3, 3, 1, 2]);
+    }
+
+    #[test]
+    fn test_threshold_sample_deterministic() {
+        let path = "./data/train-lite.txt";
+        let f = std::fs::File::open(path).unwrap();
+        let dataset = DataSet::load(f).unwrap();
+
+        let sampled = |seed: u64| {
+            let training = TrainSet::with_threshold_sample(
+                &dataset,
+                16,
+                BinningStrategy::Quantile,
+                Some(100),
+                seed,
+            );
+            let mut maps: Vec<(Id, Vec<Value>)> = training
+                .threshold_maps
+                .iter()
+                .map(|(&fid, map)| (fid, map.thresholds.clone()))
+                .collect();
+            maps.sort_by_key(|&(fid, _)| fid);
+            maps
+        };
+        assert_eq!(sampled(1), sampled(1));
+        assert!(sampled(1) != sampled(2));
+
+        let training = TrainSet::with_threshold_sample(
+            &dataset,
+            16,
+            BinningStrategy::Quantile,
+            Some(100),
+            1,
+        );
+        assert_eq!(training.len(), dataset.len());
+    }
+
     #[test]
     fn test_data_set_lambda_weight() {
         // (label, qid, feature_values)
@@ -736,32 +1426,159 @@ mod tests {
             training.lambdas,
             &[
                 0.2959880583703105,
-                -0.05406635038708441,
-                0.06664831928002701,
-                -0.10688704271796713,
-                -0.1309783051272036,
+                -0.054066350387084405,
+                0.06664831928002699,
+                -0.10688704271796712,
+                -0.13097830512720357,
                 -0.056352467003334426,
                 0.2573545140200802,
-                -0.11687432957979353,
-                -0.15483239685503464,
+                -0.11687432957979352,
+                -0.1548323968550346,
             ]
         );
         assert_eq!(
             training.weights,
             &[
-                0.2503273430028968,
-                0.07986338018045583,
-                0.05890748809444887,
-                0.056771982359676655,
-                0.0654891525636018,
-                0.037537655576830996,
+                0.2503273430028967,
+                0.07986338018045581,
+                0.05890748809444886,
+                0.05677198235967665,
+                0.06548915256360179,
+                0.03753765557683099,
                 0.1286772570100401,
-                0.06008388967286634,
-                0.07741619842751732,
+                0.060083889672866334,
+                0.0774161984275173,
             ]
         );
     }
 
+    #[test]
+    fn test_data_set_instance_weights() {
+        let s = "3.0 qid:1 1:5.0
+2.0 qid:1 cost:2.0 1:7.0
+0.0 qid:1 cost:0.5 1:1.0";
+        let weighted = DataSet::load(::std::io::Cursor::new(s)).unwrap();
+        let s = "3.0 qid:1 1:5.0
+2.0 qid:1 1:7.0
+0.0 qid:1 1:1.0";
+        let unweighted = DataSet::load(::std::io::Cursor::new(s)).unwrap();
+
+        let metric = metric::new("NDCG", 10).unwrap();
+        let mut expected = TrainSet::new(&unweighted, 3);
+        expected.update_lambdas_weights(&metric);
+        let mut training = TrainSet::new(&weighted, 3);
+        training.update_lambdas_weights(&metric);
+
+        for (index, &weight) in [1.0, 2.0, 0.5].iter().enumerate() {
+            let lambda = expected.lambdas[index] * weight;
+            assert_eq!(training.lambdas[index], lambda);
+            let newton_weight = expected.weights[index] * weight;
+            assert_eq!(training.weights[index], newton_weight);
+        }
+    }
+
+    #[test]
+    fn test_lambda_clip() {
+        // The DCG changes of the top instance overflow.
+        let mut data = vec![(1023.0, 1, vec![1.0])];
+        data.extend((0..10).map(|i| (0.0, 1, vec![i as f64 + 2.0])));
+        let dataset: DataSet = data.into_iter().collect();
+        let metric = metric::new("DCG", 10).unwrap();
+
+        let mut training = TrainSet::new(&dataset, 3);
+        training.update_lambdas_weights(&metric);
+        assert!(training.lambdas.iter().any(|lambda| !lambda.is_finite()));
+
+        training.set_lambda_clip(Some(1.0));
+        training.update_lambdas_weights(&metric);
+        assert!(training.lambdas.iter().all(|lambda| lambda.abs() <= 1.0));
+
+        let mut tree = RegressionTree::new(0.1, 2, 1);
+        let output = tree.fit(&training).unwrap();
+        assert!(output.iter().all(|value| value.is_finite()));
+
+        // NaN is not clamped to a bound.
+        struct NanLoss;
+        impl Loss for NanLoss {
+            fn gradients(
+                &self,
+                _dataset: &DataSet,
+                _model_scores: &[Value],
+                lambdas: &mut [Value],
+                _weights: &mut [Value],
+            ) {
+                lambdas[0] = ::std::f64::NAN;
+                lambdas[1] = 5.0;
+            }
+        }
+        training.update_gradients(&NanLoss);
+        assert!(training.lambdas[0].is_nan());
+        assert_eq!(training.lambdas[1], 1.0);
+    }
+
+    #[test]
+    fn test_objectives() {
+        let data = vec![
+            (2.0, 1, vec![1.0]),
+            (1.0, 1, vec![2.0]),
+            (1.0, 1, vec![3.0]),
+            (0.0, 2, vec![4.0]),
+        ];
+        let dataset: DataSet = data.into_iter().collect();
+        let metric = metric::new("NDCG", 10).unwrap();
+
+        let mut training = TrainSet::new(&dataset, 3);
+        training.init_model_scores(&[0.0, 0.0, 2.0, 0.0]);
+
+        // Instance 0 is below instance 2, and ties with instance 1.
+        let rho = 1.0 / (1.0 + (-2.0f64).exp());
+        training.update_gradients(&*Objective::RankNet.loss(&metric, &dataset));
+        assert_eq!(training.lambdas, vec![0.5 + rho, -0.5, -rho, 0.0]);
+        assert_eq!(
+            training.weights,
+            vec![0.25 + rho * (1.0 - rho), 0.25, rho * (1.0 - rho), 0.0]
+        );
+
+        let loss = Objective::Pairwise.loss(&metric, &dataset);
+        training.update_gradients(&*loss);
+        assert_eq!(training.lambdas, vec![2.0, -1.0, -1.0, 0.0]);
+        assert_eq!(training.weights, vec![2.0, 1.0, 1.0, 0.0]);
+
+        training.init_model_scores(&[3.0, 0.0, 2.0, 0.0]);
+        training.update_gradients(&*loss);
+        assert_eq!(training.lambdas, vec![0.0, 0.0, 0.0, 0.0]);
+
+        // The gradients of LambdaRank stay those of the metric.
+        let loss = Objective::LambdaRank.loss(&metric, &dataset);
+        training.update_gradients(&*loss);
+        let lambdas = training.lambdas.clone();
+        training.update_lambdas_weights(&metric);
+        assert_eq!(training.lambdas, lambdas);
+    }
+
+    #[test]
+    fn test_split_pos_tie() {
+        let split = |fid, threshold, gain| SplitPos {
+            fid: fid,
+            threshold: threshold,
+            gain: gain,
+            categorical: false,
+        };
+        let splits = vec![
+            split(3, 1.0, 2.0),
+            split(2, 5.0, 2.0),
+            split(2, 4.0, 2.0),
+            split(1, 0.0, 1.0),
+        ];
+        for n in 0..splits.len() {
+            let mut rotated = splits.clone();
+            rotated.rotate_left(n);
+            let mut heap: BinaryHeap<SplitPos> = rotated.into_iter().collect();
+            let best = heap.pop().unwrap();
+            assert_eq!((best.fid, best.threshold), (2, 4.0));
+        }
+    }
+
     #[test]
     fn test_data_set_sample_split() {
         // (label, qid, feature_values)
@@ -783,11 +1600,40 @@ mod tests {
         training.update_lambdas_weights(&metric::new("NDCG", 10).unwrap());
 
         let sample = TrainSample::from(&training);
-        let split = sample.split(1).unwrap();
+        let split = sample.split(1, 0.0).unwrap();
         assert_eq!(split.fid, 1);
         assert_eq!(split.threshold, 1.0);
     }
 
+    #[test]
+    fn test_data_set_sample_categorical_split() {
+        // Category 2 has the high labels. No threshold separates it
+        // from both 1 and 3.
+        let data = vec![
+            (0.0, 1, vec![1.0]),
+            (5.0, 1, vec![2.0]),
+            (0.0, 1, vec![3.0]),
+            (0.0, 1, vec![1.0]),
+            (5.0, 1, vec![2.0]),
+            (0.0, 1, vec![3.0]),
+        ];
+        let dataset: DataSet = data.into_iter().collect();
+
+        let mut training = TrainSet::new(&dataset, 256);
+        training.fit_labels();
+        let split = TrainSample::from(&training).split(1, 0.0).unwrap();
+        assert!(!split.categorical);
+        let numeric_gain = split.gain;
+
+        training.set_categorical_features(vec![1].into_iter().collect());
+        let split = TrainSample::from(&training).split(1, 0.0).unwrap();
+        assert!(split.categorical);
+        assert_eq!(split.threshold, 2.0);
+        assert!(split.gain > numeric_gain);
+        let left: Vec<Id> = split.left.iter().map(|(i, _, _)| i).collect();
+        assert_eq!(left, vec![1, 4]);
+    }
+
     #[test]
     fn test_data_set_sample_non_split() {
         // (label, qid, feature_values)
@@ -813,13 +1659,93 @@ mod tests {
         training.update_lambdas_weights(&metric::new("NDCG", 10).unwrap());
 
         let sample = TrainSample::from(&training);
-        assert!(sample.split(9).is_none());
-        assert!(sample.split(4).is_none());
-        let split = sample.split(3).unwrap();
+        assert!(sample.split(9, 0.0).is_none());
+        assert!(sample.split(4, 0.0).is_none());
+        let split = sample.split(3, 0.0).unwrap();
         assert_eq!(split.fid, 1);
         assert_eq!(split.threshold, 3.0 + 2.0 / 3.0);
 
-        assert!(split.left.split(2).is_none());
+        assert!(split.left.split(2, 0.0).is_none());
+    }
+
+    #[test]
+    fn test_data_set_sample_min_split_gain() {
+        // (label, qid, feature_values)
+        let data = vec![
+            (3.0, 1, vec![5.0]),
+            (2.0, 1, vec![7.0]),
+            (3.0, 1, vec![3.0]),
+            (1.0, 1, vec![2.0]),
+            (0.0, 1, vec![1.0]),
+            (2.0, 1, vec![8.0]),
+            (4.0, 1, vec![9.0]),
+            (1.0, 1, vec![4.0]),
+            (0.0, 1, vec![6.0]),
+        ];
+
+        let dataset: DataSet = data.into_iter().collect();
+
+        let mut training = TrainSet::new(&dataset, 3);
+        training.update_lambdas_weights(&metric::new("NDCG", 10).unwrap());
+
+        let sample = TrainSample::from(&training);
+        let variance = sample.variance();
+        assert!(sample.split(1, 0.0).is_some());
+        // No split reduces more than the total variance.
+        assert!(sample.split(1, variance + 1.0).is_none());
+    }
+
+    #[test]
+    fn test_constant_features_skipped() {
+        // (label, qid, feature_values)
+        let data = vec![
+            (3.0, 1, vec![1.0, 5.0, 2.0]),
+            (2.0, 1, vec![1.0, 7.0, 2.0]),
+            (0.0, 1, vec![1.0, 3.0, 4.0]),
+            (1.0, 1, vec![1.0, 2.0, 4.0]),
+        ];
+
+        let dataset: DataSet = data.into_iter().collect();
+
+        let mut training = TrainSet::new(&dataset, 3);
+        assert_eq!(training.fid_iter().collect::<Vec<Id>>(), vec![2, 3]);
+
+        // Feature 3 is constant in the sample of the first two
+        // instances, but is still considered.
+        training.fit_labels();
+        let sample = TrainSample::new(&training, vec![0, 1], vec![2, 3]);
+        assert_eq!(sample.fid_iter().collect::<Vec<Id>>(), vec![2, 3]);
+        assert_eq!(sample.split(1, 0.0).map(|split| split.fid), Some(2));
+    }
+
+    #[test]
+    fn test_reuse_thresholds() {
+        let data = vec![
+            (3.0, 1, vec![1.0, 5.0, 2.0]),
+            (2.0, 1, vec![1.0, 7.0, 2.0]),
+            (0.0, 1, vec![1.0, 3.0, 4.0]),
+        ];
+        let dataset: DataSet = data.into_iter().collect();
+
+        let thresholds =
+            Thresholds::new(&dataset, 3, BinningStrategy::Uniform, None, 0);
+        assert_eq!(thresholds.feature_count(), 2);
+
+        let mut training = TrainSet::with_thresholds(&dataset, thresholds);
+        training.fit_labels();
+        let split = TrainSample::from(&training)
+            .split(1, 0.0)
+            .map(|split| (split.fid, split.threshold));
+
+        let thresholds = training.into_thresholds();
+        let mut training = TrainSet::with_thresholds(&dataset, thresholds);
+        assert_eq!(training.fid_iter().collect::<Vec<Id>>(), vec![2, 3]);
+        training.fit_labels();
+        let reused = TrainSample::from(&training)
+            .split(1, 0.0)
+            .map(|split| (split.fid, split.threshold));
+        assert!(split.is_some());
+        assert_eq!(reused, split);
     }
 
     #[bench]
@@ -832,6 +1758,40 @@ mod tests {
         training.update_lambdas_weights(&metric::new("NDCG", 10).unwrap());
 
         let sample = TrainSample::from(&training);
-        b.iter(|| sample.split(1).unwrap());
+        b.iter(|| sample.split(1, 0.0).unwrap());
+    }
+
+    #[bench]
+    fn bench_thresholds(b: &mut Bencher) {
+        let path = "./data/train-lite.txt";
+        let f = std::fs::File::open(path).unwrap();
+        let dataset = DataSet::load(f).unwrap();
+
+        b.iter(|| {
+            Thresholds::new(&dataset, 256, BinningStrategy::Uniform, None, 0)
+        });
+    }
+
+    #[bench]
+    fn bench_thresholds_columnar(b: &mut Bencher) {
+        let path = "./data/train-lite.txt";
+        let f = std::fs::File::open(path).unwrap();
+        let mut dataset = DataSet::load(f).unwrap();
+        dataset.columnarize();
+
+        b.iter(|| {
+            Thresholds::new(&dataset, 256, BinningStrategy::Uniform, None, 0)
+        });
+    }
+
+    #[bench]
+    fn bench_update_lambdas_weights(b: &mut Bencher) {
+        let path = "./data/train-lite.txt";
+        let f = std::fs::File::open(path).unwrap();
+        let dataset = DataSet::load(f).unwrap();
+        let metric = metric::new("NDCG", 10).unwrap();
+
+        let mut training = TrainSet::new(&dataset, 256);
+        b.iter(|| training.update_lambdas_weights(&metric));
     }
 }
@@ -1,15 +1,34 @@
 pub mod dataset;
 pub mod validate_set;
 pub mod lambdamart;
+pub mod ranknet;
+pub mod randomforest;
+pub mod gbrt;
+pub mod coordinate_ascent;
 
 use clap::{App, Arg, ArgMatches, SubCommand};
-use train::dataset::Instance;
+use metric;
+use std;
+use train::dataset::{DataSet, Instance};
+use util::{open_input, Result};
 
 pub fn main<'a>(matches: &ArgMatches<'a>) {
     match matches.subcommand_name() {
         Some("lambdamart") => lambdamart::main(
             matches.subcommand_matches("lambdamart").unwrap(),
         ),
+        Some("ranknet") => ranknet::main(
+            matches.subcommand_matches("ranknet").unwrap(),
+        ),
+        Some("randomforest") => randomforest::main(
+            matches.subcommand_matches("randomforest").unwrap(),
+        ),
+        Some("gbrt") => {
+            gbrt::main(matches.subcommand_matches("gbrt").unwrap())
+        }
+        Some("coordinate-ascent") => coordinate_ascent::main(
+            matches.subcommand_matches("coordinate-ascent").unwrap(),
+        ),
         _ => (),
     }
 }
@@ -18,11 +37,26 @@ pub fn main<'a>(matches: &ArgMatches<'a>) {
 pub fn clap_command<'a, 'b>() -> App<'a, 'b> {
     let train_command = SubCommand::with_name("train")
         .about("Train an learning algorithm")
-        .subcommand(lambdamart::clap_command());
+        .subcommand(lambdamart::clap_command())
+        .subcommand(ranknet::clap_command())
+        .subcommand(randomforest::clap_command())
+        .subcommand(gbrt::clap_command())
+        .subcommand(coordinate_ascent::clap_command());
 
     train_command
 }
 
+/// Validates a file path argument, which must not be empty or only
+/// whitespace, so that it fails with a clear message before any file
+/// is opened.
+fn non_blank_path(s: String) -> std::result::Result<(), String> {
+    if s.trim().is_empty() {
+        Err(String::from("the path must not be empty"))
+    } else {
+        Ok(())
+    }
+}
+
 /// Returns the common arguments for a learning algorithm. The display
 /// order of this type of arguments ranges from 1 to 100.
 fn common_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
@@ -33,23 +67,33 @@ fn common_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
             .value_name("FILE")
             .takes_value(true)
             .empty_values(false)
+            .validator(non_blank_path)
             .required(true)
+            .multiple(true)
             .display_order(1)
-            .help("Training file"),
+            .help("Training files, concatenated in order, or - for the standard input"),
+        Arg::with_name("rename-qids")
+            .long("rename-qids")
+            .display_order(1)
+            .help("Offset the qids of each training file past those of the previous files"),
         Arg::with_name("validate-file")
             .short("v")
             .long("validate")
             .value_name("FILE")
             .takes_value(true)
             .empty_values(false)
+            .validator(non_blank_path)
             .display_order(2)
-            .help("Validating file"),
+            .multiple(true)
+            .number_of_values(1)
+            .help("Validating file. LambdaMART accepts several as NAME=FILE"),
         Arg::with_name("test-file")
             .short("T")
             .long("test")
             .value_name("FILE")
             .takes_value(true)
             .empty_values(false)
+            .validator(non_blank_path)
             .display_order(3)
             .help("Testing file"),
         Arg::with_name("metric")
@@ -67,12 +111,177 @@ fn common_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
             .default_value("10")
             .display_order(5)
             .help("K value for metrics"),
+        Arg::with_name("gain-table")
+            .long("gain-table")
+            .value_name("GAIN,...")
+            .display_order(5)
+            .help("Gains of the labels from 0, e.g. 0,1,3,7,15 [default: 2^label - 1]"),
+        Arg::with_name("cap-gain")
+            .long("cap-gain")
+            .value_name("MAX")
+            .display_order(5)
+            .help("Cap the gain of each label to MAX, e.g. for absurdly high grades whose gains 2^label - 1 dominate"),
+        Arg::with_name("discount-base")
+            .long("discount-base")
+            .value_name("BASE")
+            .default_value("2")
+            .display_order(5)
+            .help("Base of the logarithm of the DCG discount, e.g. 2.718281828 for the natural logarithm"),
+        Arg::with_name("seed")
+            .long("seed")
+            .value_name("NUM")
+            .default_value("0")
+            .display_order(6)
+            .help("Seed of the random number generator"),
+        Arg::with_name("label-column")
+            .long("label-column")
+            .value_name("NUM")
+            .default_value("0")
+            .display_order(7)
+            .help("Index of the label field in each line; the fields before it, e.g. line ids, are ignored"),
     ];
 
     common_args
 }
 
+/// Returns the gain table given by the option of `common_args`.
+/// Exits on an invalid table.
+fn gain_table<'a>(matches: &ArgMatches<'a>) -> Option<Vec<f64>> {
+    matches.value_of("gain-table").map(|s| {
+        metric::parse_gains(s).unwrap_or_else(|e| {
+            error!("{}", e);
+            std::process::exit(1)
+        })
+    })
+}
+
+/// Returns the discount base given by the option of `common_args`.
+/// Exits on an invalid base.
+fn discount_base<'a>(matches: &ArgMatches<'a>) -> f64 {
+    let s = matches.value_of("discount-base").unwrap();
+    metric::parse_discount_base(s).unwrap_or_else(|e| {
+        error!("{}", e);
+        std::process::exit(1)
+    })
+}
+
+/// Returns the gain cap given by the option of `common_args`, if any.
+/// Exits on an invalid cap.
+fn max_gain<'a>(matches: &ArgMatches<'a>) -> Option<f64> {
+    matches.value_of("cap-gain").map(|s| {
+        metric::parse_max_gain(s).unwrap_or_else(|e| {
+            error!("{}", e);
+            std::process::exit(1)
+        })
+    })
+}
+
+/// Loads the data files and concatenates them in order. See
+/// `DataSet::concat`.
+pub fn load_files(
+    paths: &[&str],
+    label_column: usize,
+    rename_qids: bool,
+) -> Result<DataSet> {
+    let mut datasets = Vec::with_capacity(paths.len());
+    for &path in paths {
+        let dataset = open_input(path).and_then(|file| {
+            DataSet::load_with_label_column(file, label_column)
+        });
+        let dataset = dataset
+            .map_err(|e| format!("Failed to load {}: {}", path, e))?;
+        datasets.push(dataset);
+    }
+    match datasets.len() {
+        1 => Ok(datasets.pop().unwrap()),
+        _ => Ok(DataSet::concat(datasets, rename_qids)),
+    }
+}
+
+/// Loads the training files given by the options of `common_args`.
+/// Exits on failure.
+fn load_train_files<'a>(matches: &ArgMatches<'a>) -> DataSet {
+    let paths: Vec<&str> = matches.values_of("train-file").unwrap().collect();
+    let label_column = value_t!(matches.value_of("label-column"), usize)
+        .unwrap_or_else(|e| e.exit());
+    load_files(&paths, label_column, matches.is_present("rename-qids"))
+        .unwrap_or_else(|e| {
+            error!("{}", e);
+            std::process::exit(1)
+        })
+}
+
 /// Evaluate on an instance.
 pub trait Evaluate {
     fn evaluate(&self, instance: &Instance) -> f64;
+
+    /// Evaluate on all the instances of a data set. The scores are in
+    /// the same order as the instances.
+    fn evaluate_all(&self, dataset: &DataSet) -> Vec<f64> {
+        dataset
+            .iter()
+            .map(|instance| self.evaluate(instance))
+            .collect()
+    }
+}
+
+/// A reference evaluates as the model it refers to, so a model can be
+/// lent to `DataSet::evaluate` without being moved.
+impl<'a, E: Evaluate + ?Sized> Evaluate for &'a E {
+    fn evaluate(&self, instance: &Instance) -> f64 {
+        (**self).evaluate(instance)
+    }
+
+    fn evaluate_all(&self, dataset: &DataSet) -> Vec<f64> {
+        (**self).evaluate_all(dataset)
+    }
+}
+
+/// A boxed model evaluates as the model in the box. With
+/// `Box<Evaluate>`, models of different learners can be stored and
+/// passed alike.
+impl<E: Evaluate + ?Sized> Evaluate for Box<E> {
+    fn evaluate(&self, instance: &Instance) -> f64 {
+        (**self).evaluate(instance)
+    }
+
+    fn evaluate_all(&self, dataset: &DataSet) -> Vec<f64> {
+        (**self).evaluate_all(dataset)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use metric::{TieBreak, ZeroQueries};
+    use train::coordinate_ascent::LinearModel;
+
+    #[test]
+    fn test_evaluate_boxed_and_borrowed() {
+        let s = "1 qid:1 1:2.0 2:1.0
+0 qid:1 1:1.0 2:3.0";
+        let dataset = DataSet::load(::std::io::Cursor::new(s)).unwrap();
+        let first = LinearModel { weights: vec![1.0, 0.0] };
+        let second = LinearModel { weights: vec![0.0, 1.0] };
+        let expected = vec![first.evaluate_all(&dataset), vec![1.0, 3.0]];
+
+        let models: Vec<Box<Evaluate>> =
+            vec![Box::new(first), Box::new(second)];
+        for (model, expected) in models.iter().zip(expected.iter()) {
+            assert_eq!(model.evaluate_all(&dataset), *expected);
+            assert_eq!((&model).evaluate(&dataset[1]), expected[1]);
+        }
+
+        let metric = metric::new("NDCG", 10).unwrap();
+        let score = |model: &Box<Evaluate>| {
+            dataset.evaluate(
+                model,
+                &metric,
+                ZeroQueries::Include,
+                TieBreak::Index,
+            )
+        };
+        assert_eq!(score(&models[0]), 1.0);
+        assert!(score(&models[1]) < 1.0);
+    }
 }
@@ -0,0 +1,449 @@
+use clap::{App, Arg, ArgMatches, SubCommand};
+use std;
+use metric;
+use metric::{Measure, TieBreak, ZeroQueries};
+use train::dataset::*;
+use train::lambdamart::histogram::SplitStrategy;
+use train::lambdamart::regression_tree::*;
+use train::lambdamart::training_set::*;
+use train::validate_set::*;
+use train::Evaluate;
+use util::{open_input, Id, Result, Rng};
+
+/// Configurable options for random forest.
+pub struct Config {
+    pub train: DataSet,
+    pub validate: Option<DataSet>,
+
+    pub metric: Box<Measure>,
+    pub trees: usize,
+    pub max_leaves: usize,
+    pub thresholds: usize,
+    pub min_leaf_samples: usize,
+    /// Ratio of features considered by each tree.
+    pub feature_ratio: f64,
+    /// How the thresholds are chosen. With `SplitStrategy::Random`,
+    /// the forest is Extremely Randomized Trees.
+    pub split_strategy: SplitStrategy,
+    /// Seed of the random number generator used for sampling.
+    pub seed: u64,
+    pub print_metric: bool,
+}
+
+/// A random forest of regression trees. Each tree is fitted to the
+/// labels of a bootstrap sample of the queries, on a random subset of
+/// the features. The output of the forest is the average of the
+/// trees.
+pub struct RandomForest {
+    config: Config,
+    ensemble: Ensemble,
+    /// Whether each query is in the bootstrap sample of each tree,
+    /// indexed by the tree and then the query.
+    in_bag: Vec<Vec<bool>>,
+}
+
+impl RandomForest {
+    /// Create a new RandomForest instance.
+    pub fn new(config: Config) -> RandomForest {
+        RandomForest {
+            config: config,
+            ensemble: Ensemble::with_mode(EnsembleMode::Average),
+            in_bag: Vec::new(),
+        }
+    }
+
+    /// Grows the trees.
+    pub fn learn(&mut self) -> Result<()> {
+        let mut training =
+            TrainSet::new(&self.config.train, self.config.thresholds);
+        training.fit_labels();
+        let mut train_scores = ValidateSet::from(&self.config.train);
+        let mut validate =
+            self.config.validate.as_ref().map(|v| ValidateSet::from(v));
+
+        let queries: Vec<Vec<Id>> = self.config
+            .train
+            .query_iter()
+            .map(|(_qid, indices)| indices)
+            .collect();
+        let fids: Vec<Id> = training.fid_iter().collect();
+        let nfids = ((fids.len() as f64 * self.config.feature_ratio).ceil() as
+                         usize)
+            .max(1)
+            .min(fids.len());
+
+        let mut rng = Rng::new(self.config.seed);
+
+        self.print_metric_header();
+        for i in 0..self.config.trees {
+            // Bootstrap sample of the queries.
+            let sampled: Vec<usize> = (0..queries.len())
+                .map(|_| rng.gen_index(queries.len()))
+                .collect();
+            let indices: Vec<usize> = sampled
+                .iter()
+                .flat_map(|&query| queries[query].iter().cloned())
+                .collect();
+            let mut in_bag = vec![false; queries.len()];
+            for &query in sampled.iter() {
+                in_bag[query] = true;
+            }
+
+            // Random subset of the features.
+            let mut tree_fids = fids.clone();
+            rng.shuffle(&mut tree_fids);
+            tree_fids.truncate(nfids);
+            tree_fids.sort();
+
+            let sample = TrainSample::new(&training, indices, tree_fids);
+            let mut tree = RegressionTree::new(
+                1.0,
+                self.config.max_leaves,
+                self.config.min_leaf_samples,
+            );
+            tree.set_leaf_output(LeafOutput::Mean);
+            if self.config.split_strategy == SplitStrategy::Random {
+                tree.set_split_strategy(SplitStrategy::Random, rng.next_u64());
+            }
+            tree.fit_sample(sample)?;
+
+            // Ranking by the sum of the outputs is the same as by the
+            // average.
+            train_scores.update(&tree);
+            validate.as_mut().map(|v| v.update(&tree));
+
+            let metric = &self.config.metric;
+            let include = ZeroQueries::Include;
+            let index = TieBreak::Index;
+            let train_score = train_scores.measure(metric, include, index);
+            let validate_score = validate
+                .as_ref()
+                .map(|v| v.measure(metric, include, index));
+
+            self.ensemble.push(tree);
+            self.in_bag.push(in_bag);
+
+            self.print_metric(i, train_score, validate_score);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the out-of-bag estimate of the metric. Each instance
+    /// is scored by the average of the trees whose bootstrap samples
+    /// leave its query out, and the metric is averaged over the
+    /// queries left out by at least one tree.
+    pub fn oob_score(&self) -> f64 {
+        let train = &self.config.train;
+        let mut sums = vec![0.0; train.len()];
+        let mut counts = vec![0; train.len()];
+        for (tree, in_bag) in self.ensemble.iter().zip(self.in_bag.iter()) {
+            for (query, (_qid, indices)) in train.query_iter().enumerate() {
+                if in_bag[query] {
+                    continue;
+                }
+                for index in indices {
+                    sums[index] += tree.evaluate(&train[index]);
+                    counts[index] += 1;
+                }
+            }
+        }
+
+        let mut score = 0.0;
+        let mut count = 0;
+        for (_qid, mut indices) in train.query_iter() {
+            // The trees leave out all the instances of a query.
+            if counts[indices[0]] == 0 {
+                continue;
+            }
+            TieBreak::Index.rank(
+                &mut indices,
+                |index| sums[index] / counts[index] as f64,
+                |index| train[index].label(),
+            );
+            let labels: Vec<f64> =
+                indices.iter().map(|&index| train[index].label()).collect();
+            score += self.config.metric.measure(&labels);
+            count += 1;
+        }
+
+        if count == 0 {
+            0.0
+        } else {
+            score / count as f64
+        }
+    }
+
+    pub fn evaluate(&self, dataset: &DataSet) -> f64 {
+        dataset.evaluate(
+            &self.ensemble,
+            &self.config.metric,
+            ZeroQueries::Include,
+            TieBreak::Index,
+        )
+    }
+
+    fn print(&self, msg: &str) {
+        if self.config.print_metric {
+            println!("{}", msg);
+        }
+    }
+
+    /// Print metric header.
+    fn print_metric_header(&self) {
+        self.print(&format!(
+            "{:<7} | {:>9} | {:>9}",
+            "#trees",
+            self.config.metric.name() + "-T",
+            self.config.metric.name() + "-V"
+        ));
+    }
+
+    /// Print metric after each tree.
+    fn print_metric(
+        &self,
+        iteration: usize,
+        train_score: f64,
+        validate_score: Option<f64>,
+    ) {
+        let s = format!(
+            "{:<7} | {:>9.4} | {}",
+            iteration + 1,
+            train_score,
+            validate_score
+                .map(|score| format!("{:>9.4}", score))
+                .unwrap_or("".to_string())
+        );
+        self.print(&s);
+    }
+}
+
+fn load_dataset(path: &str, label_column: usize) -> Result<DataSet> {
+    let file = open_input(path)?;
+    DataSet::load_with_label_column(file, label_column)
+}
+
+pub fn main<'a>(matches: &ArgMatches<'a>) {
+    let validate_file_path = matches.value_of("validate-file");
+    let metric_name = matches.value_of("metric").unwrap();
+    let metric_k = value_t!(matches.value_of("metric-k"), usize)
+        .unwrap_or_else(|e| e.exit());
+    let trees = value_t!(matches.value_of("trees"), usize).unwrap_or_else(
+        |e| e.exit(),
+    );
+    let leaves = value_t!(matches.value_of("leaves"), usize)
+        .unwrap_or_else(|e| e.exit());
+    let thresholds = value_t!(matches.value_of("thresholds"), usize)
+        .unwrap_or_else(|e| e.exit());
+    let min_leaf_samples =
+        value_t!(matches.value_of("min-leaf-support"), usize)
+            .unwrap_or_else(|e| e.exit());
+    let feature_ratio = value_t!(matches.value_of("feature-ratio"), f64)
+        .unwrap_or_else(|e| e.exit());
+    let seed = value_t!(matches.value_of("seed"), u64)
+        .unwrap_or_else(|e| e.exit());
+    let split_strategy = value_t!(matches.value_of("split"), SplitStrategy)
+        .unwrap_or_else(|e| e.exit());
+    let label_column = value_t!(matches.value_of("label-column"), usize)
+        .unwrap_or_else(|e| e.exit());
+
+    let load = |path: &str| {
+        load_dataset(path, label_column).unwrap_or_else(|e| {
+            error!("Failed to load {}: {}", path, e);
+            std::process::exit(1)
+        })
+    };
+
+    let config = Config {
+        train: super::load_train_files(matches),
+        validate: validate_file_path.map(&load),
+        // The param is valid.
+        metric: metric::new_with_max_gain(
+            metric_name,
+            metric_k,
+            super::gain_table(matches),
+            super::discount_base(matches),
+            super::max_gain(matches),
+        ).unwrap(),
+        trees: trees,
+        max_leaves: leaves,
+        thresholds: thresholds,
+        min_leaf_samples: min_leaf_samples,
+        feature_ratio: feature_ratio,
+        split_strategy: split_strategy,
+        seed: seed,
+        print_metric: true,
+    };
+
+    let mut forest = RandomForest::new(config);
+    forest.learn().unwrap();
+    println!(
+        "{} out of bag: {:.4}",
+        forest.config.metric.name(),
+        forest.oob_score()
+    );
+}
+
+pub fn clap_command<'a, 'b>() -> App<'a, 'b> {
+    let train_common_args = super::common_args();
+    // Random forest args
+    let randomforest_command = SubCommand::with_name("randomforest")
+        .about("Train random forest")
+        .args(&train_common_args)
+        .arg(
+            Arg::with_name("trees")
+                .long("trees")
+                .takes_value(true)
+                .value_name("NUM")
+                .default_value("300")
+                .display_order(101)
+                .help("Number of trees"),
+        )
+        .arg(
+            Arg::with_name("leaves")
+                .long("leaves")
+                .takes_value(true)
+                .value_name("NUM")
+                .default_value("100")
+                .display_order(102)
+                .help("Number of leaves for each tree"),
+        )
+        .arg(
+            Arg::with_name("thresholds")
+                .long("thresholds")
+                .takes_value(true)
+                .value_name("NUM")
+                .default_value("256")
+                .display_order(103)
+                .help("Number of threshold candidates for tree spliting"),
+        )
+        .arg(
+            Arg::with_name("min-leaf-support")
+                .long("min-leaf-support")
+                .takes_value(true)
+                .value_name("NUM")
+                .default_value("1")
+                .display_order(104)
+                .help("Min leaf support -- minimum #samples each leaf has to contain"),
+        )
+        .arg(
+            Arg::with_name("feature-ratio")
+                .long("feature-ratio")
+                .takes_value(true)
+                .value_name("RATIO")
+                .default_value("0.3")
+                .display_order(105)
+                .help("Ratio of features considered by each tree"),
+        )
+        .arg(
+            Arg::with_name("split")
+                .long("split")
+                .takes_value(true)
+                .value_name("STRATEGY")
+                .possible_values(&["best", "random"])
+                .default_value("best")
+                .display_order(106)
+                .help("How the thresholds are chosen; random gives Extremely Randomized Trees"),
+        );
+    randomforest_command
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::File;
+
+    #[test]
+    fn test_random_forest() {
+        let path = "./data/train-lite.txt";
+        let f = File::open(path).unwrap();
+        let dataset = DataSet::load(f).unwrap();
+        let validate_set = dataset.clone();
+
+        let config = Config {
+            train: dataset,
+            validate: None,
+            metric: metric::new("NDCG", 10).unwrap(),
+            trees: 10,
+            max_leaves: 10,
+            thresholds: 256,
+            min_leaf_samples: 1,
+            feature_ratio: 0.3,
+            split_strategy: SplitStrategy::Best,
+            seed: 0,
+            print_metric: false,
+        };
+        let mut forest = RandomForest::new(config);
+        forest.learn().unwrap();
+
+        assert_eq!(forest.ensemble.len(), 10);
+        // The output of the forest is the average of the trees.
+        let instance = &validate_set[0];
+        let sum: f64 = forest
+            .ensemble
+            .iter()
+            .map(|tree| tree.evaluate(instance))
+            .sum();
+        assert_eq!(forest.ensemble.evaluate(instance), sum / 10.0);
+        assert!(forest.evaluate(&validate_set) > 0.0);
+
+        // About a third of the queries are out of each bag.
+        assert_eq!(forest.in_bag.len(), 10);
+        assert!(forest.in_bag.iter().all(|bag| bag.contains(&false)));
+        let oob = forest.oob_score();
+        assert!(oob > 0.0 && oob <= 1.0);
+    }
+
+    #[test]
+    fn test_oob_score_untrained() {
+        let path = "./data/train-lite.txt";
+        let f = File::open(path).unwrap();
+        let config = Config {
+            train: DataSet::load(f).unwrap(),
+            validate: None,
+            metric: metric::new("NDCG", 10).unwrap(),
+            trees: 0,
+            max_leaves: 10,
+            thresholds: 256,
+            min_leaf_samples: 1,
+            feature_ratio: 0.3,
+            split_strategy: SplitStrategy::Best,
+            seed: 0,
+            print_metric: false,
+        };
+        let forest = RandomForest::new(config);
+        assert_eq!(forest.oob_score(), 0.0);
+    }
+
+    #[test]
+    fn test_extra_trees() {
+        let path = "./data/train-lite.txt";
+        let f = File::open(path).unwrap();
+        let dataset = DataSet::load(f).unwrap();
+        let validate_set = dataset.clone();
+
+        let learn = |seed: u64| {
+            let config = Config {
+                train: dataset.clone(),
+                validate: None,
+                metric: metric::new("NDCG", 10).unwrap(),
+                trees: 5,
+                max_leaves: 10,
+                thresholds: 256,
+                min_leaf_samples: 1,
+                feature_ratio: 0.3,
+                split_strategy: SplitStrategy::Random,
+                seed: seed,
+                print_metric: false,
+            };
+            let mut forest = RandomForest::new(config);
+            forest.learn().unwrap();
+            forest.ensemble.evaluate_all(&validate_set)
+        };
+
+        // Random splits are reproducible by the seed.
+        let scores = learn(1);
+        assert_eq!(learn(1), scores);
+        assert!(learn(2) != scores);
+    }
+}
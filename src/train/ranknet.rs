@@ -1,8 +1,13 @@
+use std;
 use std::iter::FromIterator;
-use std::fs::File;
 use std::cmp::Ordering;
+use clap::{App, Arg, ArgMatches, SubCommand};
 use format::svmlight::*;
-use util::Result;
+use metric;
+use metric::{Measure, TieBreak, ZeroQueries};
+use train::dataset::*;
+use train::Evaluate;
+use util::{open_input, Id, Result, Rng, Value};
 
 pub struct RankList {
     list: Vec<Instance>,
@@ -13,9 +18,19 @@ impl RankList {
         RankList { list: Vec::new() }
     }
 
+    /// Returns the number of instances in the rank list.
+    pub fn len(&self) -> usize {
+        self.list.len()
+    }
+
+    /// Returns the qid of the rank list.
+    pub fn qid(&self) -> Option<Id> {
+        self.list.first().map(|instance| instance.qid())
+    }
+
     pub fn sort_by_target(&mut self) {
         self.list.sort_by(|instance1, instance2| {
-            let (target1, target2) = (instance1.target(), instance2.target());
+            let (target1, target2) = (instance1.label(), instance2.label());
             target1.partial_cmp(&target2).unwrap_or(Ordering::Less)
         });
     }
@@ -27,60 +42,655 @@ impl FromIterator<Instance> for RankList {
     }
 }
 
-/// A layer in neural network
+impl std::ops::Deref for RankList {
+    type Target = Vec<Instance>;
+
+    fn deref(&self) -> &Vec<Instance> {
+        &self.list
+    }
+}
+
+/// A weighted connection from a neuron, or an input, of the previous
+/// layer.
+pub struct Synapse {
+    weight: f64,
+
+    /// Accumulated gradient of the cost over the weight.
+    gradient: f64,
+}
+
+impl Synapse {
+    fn new(weight: f64) -> Synapse {
+        Synapse {
+            weight: weight,
+            gradient: 0.0,
+        }
+    }
+
+    /// Moves the weight against the accumulated gradient and resets
+    /// the gradient.
+    fn update(&mut self, learning_rate: f64) {
+        self.weight -= learning_rate * self.gradient;
+        self.gradient = 0.0;
+    }
+}
+
+/// A neuron in neural network
 pub struct Neuron {
+    /// Synapses from the previous layer. The last one is the bias.
+    synapses: Vec<Synapse>,
+
+    /// Linear neurons output the weighted sum directly, others apply
+    /// the sigmoid function.
+    linear: bool,
+
+    /// Output of the last propagation.
     output: f64,
 
     /// Outputs for each propagation
     outputs: Vec<f64>,
+
+    /// Derivative of the cost over the weighted sum.
+    delta: f64,
 }
 
-pub struct Synapse {}
+impl Neuron {
+    fn new(
+        ninputs: usize,
+        linear: bool,
+        rng: &mut Rng,
+    ) -> Neuron {
+        // Initial weights are uniformly distributed in [-bound, bound].
+        let bound = 1.0 / (ninputs as f64 + 1.0).sqrt();
+        let synapses = (0..(ninputs + 1))
+            .map(|_| Synapse::new((rng.next_f64() * 2.0 - 1.0) * bound))
+            .collect();
+        Neuron {
+            synapses: synapses,
+            linear: linear,
+            output: 0.0,
+            outputs: Vec::new(),
+            delta: 0.0,
+        }
+    }
+
+    /// Computes the output of the neuron on the outputs of the
+    /// previous layer.
+    pub fn output(&self, inputs: &[f64]) -> f64 {
+        let bias = self.synapses.last().unwrap().weight;
+        let sum = self.synapses
+            .iter()
+            .zip(inputs.iter())
+            .fold(bias, |sum, (synapse, &input)| sum + synapse.weight * input);
 
+        if self.linear {
+            sum
+        } else {
+            1.0 / (1.0 + (-sum).exp())
+        }
+    }
+
+    /// Derivative of the output over the weighted sum, given the
+    /// output.
+    fn derivative(&self, output: f64) -> f64 {
+        if self.linear {
+            1.0
+        } else {
+            output * (1.0 - output)
+        }
+    }
+}
+
+/// A layer in neural network
 pub struct Layer {
-    
+    neurons: Vec<Neuron>,
 }
 
+impl Layer {
+    fn new(
+        ninputs: usize,
+        nneurons: usize,
+        linear: bool,
+        rng: &mut Rng,
+    ) -> Layer {
+        let neurons = (0..nneurons)
+            .map(|_| Neuron::new(ninputs, linear, rng))
+            .collect();
+        Layer { neurons: neurons }
+    }
+
+    /// Computes the outputs of all the neurons in the layer.
+    fn output(&self, inputs: &[f64]) -> Vec<f64> {
+        self.neurons
+            .iter()
+            .map(|neuron| neuron.output(inputs))
+            .collect()
+    }
+
+    /// Computes and records the outputs for the `index`-th
+    /// propagation.
+    fn propagate(&mut self, index: usize, inputs: &[f64]) -> Vec<f64> {
+        self.neurons
+            .iter_mut()
+            .map(|neuron| {
+                let output = neuron.output(inputs);
+                neuron.output = output;
+                neuron.outputs[index] = output;
+                output
+            })
+            .collect()
+    }
+
+    /// Outputs of the `index`-th propagation.
+    fn outputs(&self, index: usize) -> Vec<f64> {
+        self.neurons
+            .iter()
+            .map(|neuron| neuron.outputs[index])
+            .collect()
+    }
+}
+
+/// Configurable options for RankNet.
+pub struct Config {
+    pub train: Vec<RankList>,
+    pub validate: Option<DataSet>,
+
+    pub metric: Box<Measure>,
+    /// Number of neurons of each hidden layer.
+    pub hidden_layers: Vec<usize>,
+    pub epochs: usize,
+    pub learning_rate: f64,
+    /// Seed of the random number generator for the initial weights.
+    pub seed: u64,
+    pub print_metric: bool,
+}
+
+/// A neural network trained by pairwise cross entropy, as described
+/// in "Learning to Rank using Gradient Descent" by Burges et al.
 pub struct RankNet {
-    
+    config: Config,
+    nfeatures: usize,
+
+    /// Hidden layers followed by the output layer of one linear
+    /// neuron.
+    layers: Vec<Layer>,
 }
 
 impl RankNet {
-    pub fn new() -> RankNet {
-        RankNet {}
+    /// Create a new RankNet instance.
+    pub fn new(config: Config) -> RankNet {
+        let nfeatures = config
+            .train
+            .iter()
+            .flat_map(|list| list.iter())
+            .map(|instance| instance.max_feature_id())
+            .max()
+            .unwrap_or(0);
+
+        RankNet {
+            config: config,
+            nfeatures: nfeatures,
+            layers: Vec::new(),
+        }
     }
 
-    pub fn read_file(&self, filename: &str) -> Result<Vec<RankList>> {
-        let filename = "";
-        let file = File::open(&filename)?;
+    /// Read rank lists from the file, whose label is the field at
+    /// `label_column` of each line. Consecutive instances of the same
+    /// qid form a rank list.
+    pub fn read_file(
+        filename: &str,
+        label_column: usize,
+    ) -> Result<Vec<RankList>> {
+        let file = open_input(filename)?;
+        RankNet::read_with_label_column(file, label_column)
+    }
+
+    /// Read rank lists from the reader. Consecutive instances of the
+    /// same qid form a rank list.
+    pub fn read<R: ::std::io::Read>(reader: R) -> Result<Vec<RankList>> {
+        RankNet::read_with_label_column(reader, 0)
+    }
+
+    /// Like `read`, but the label is the field at `label_column` of
+    /// each line.
+    pub fn read_with_label_column<R: ::std::io::Read>(
+        reader: R,
+        label_column: usize,
+    ) -> Result<Vec<RankList>> {
         let mut prev_qid = None;
 
-        let lists = Vec::new();
+        let mut lists = Vec::new();
 
         let mut data_points = Vec::new();
-        for instance in SvmLightFile::instances(file) {
-            let instance = instance?;
+        for line in SvmLightFile::lines(reader) {
+            let instance =
+                SvmLightFile::parse_instance_at(&line?, label_column)?;
 
-            if Some(instance.qid()) == prev_qid {
-                data_points.push(instance);
-            } else {
-                lists.push(data_points.into_iter().collect::<RankList>());
+            if prev_qid.is_some() && Some(instance.qid()) != prev_qid {
+                // Flush the previous group and reset the buffer.
+                let group = std::mem::replace(&mut data_points, Vec::new());
+                lists.push(group.into_iter().collect::<RankList>());
             }
+            prev_qid = Some(instance.qid());
+            data_points.push(instance);
+        }
+
+        // The last group
+        if !data_points.is_empty() {
+            lists.push(data_points.into_iter().collect::<RankList>());
         }
         Ok(lists)
     }
 
-    pub fn train(&self) -> Result<()> {
-        let rank_lists = self.read_file("");
+    /// Builds the layers according to the topology in the config.
+    pub fn init(&mut self) {
+        debug!("Init ranknet");
+        let mut rng = Rng::new(self.config.seed);
+        let mut ninputs = self.nfeatures;
+        self.layers.clear();
+        for &nneurons in self.config.hidden_layers.iter() {
+            self.layers.push(
+                Layer::new(ninputs, nneurons, false, &mut rng),
+            );
+            ninputs = nneurons;
+        }
+        self.layers.push(Layer::new(ninputs, 1, true, &mut rng));
+    }
+
+    /// Learns from the training rank lists for the configured number
+    /// of epochs.
+    pub fn learn(&mut self) -> Result<()> {
+        if self.layers.is_empty() {
+            self.init();
+        }
+
+        let train = std::mem::replace(&mut self.config.train, Vec::new());
+        self.print_metric_header();
+        for epoch in 0..self.config.epochs {
+            for list in train.iter() {
+                self.train_list(list);
+            }
+
+            let train_score = self.measure(&train);
+            let validate_score = self.config
+                .validate
+                .as_ref()
+                .map(|v| {
+                    let include = ZeroQueries::Include;
+                    let index = TieBreak::Index;
+                    v.evaluate(&*self, &self.config.metric, include, index)
+                });
+            self.print_metric(epoch, train_score, validate_score);
+        }
+        self.config.train = train;
+
         Ok(())
     }
 
-    pub fn init(&self) {
-        debug!("Init ranknet");
-        // self.layers.push();
+    /// Returns the feature values of an instance as the inputs of the
+    /// network.
+    fn inputs(&self, instance: &Instance) -> Vec<f64> {
+        (1..(self.nfeatures + 1))
+            .map(|id| instance.value(id))
+            .collect()
+    }
+
+    /// Propagates each instance of the rank list through the network
+    /// and records the outputs of the neurons.
+    fn propagate(&mut self, inputs: &[Vec<f64>]) -> Vec<f64> {
+        for layer in self.layers.iter_mut() {
+            for neuron in layer.neurons.iter_mut() {
+                neuron.outputs.resize(inputs.len(), 0.0);
+            }
+        }
+
+        inputs
+            .iter()
+            .enumerate()
+            .map(|(index, input)| {
+                let mut values = input.clone();
+                for layer in self.layers.iter_mut() {
+                    values = layer.propagate(index, &values);
+                }
+                values[0]
+            })
+            .collect()
+    }
+
+    /// Back propagates the derivative of the cost over the score of
+    /// the `index`-th instance, and accumulates the gradients of the
+    /// synapses.
+    fn back_propagate(&mut self, index: usize, input: &[f64], lambda: f64) {
+        let nlayers = self.layers.len();
+        for l in (0..nlayers).rev() {
+            // Compute deltas.
+            for n in 0..self.layers[l].neurons.len() {
+                let output = self.layers[l].neurons[n].outputs[index];
+                let error = if l + 1 == nlayers {
+                    lambda
+                } else {
+                    self.layers[l + 1]
+                        .neurons
+                        .iter()
+                        .map(|next| next.synapses[n].weight * next.delta)
+                        .sum()
+                };
+                let neuron = &mut self.layers[l].neurons[n];
+                neuron.delta = error * neuron.derivative(output);
+            }
+
+            // Accumulate gradients.
+            let layer_inputs = if l == 0 {
+                input.to_vec()
+            } else {
+                self.layers[l - 1].outputs(index)
+            };
+            for neuron in self.layers[l].neurons.iter_mut() {
+                let delta = neuron.delta;
+                let (bias, synapses) =
+                    neuron.synapses.split_last_mut().unwrap();
+                for (synapse, &value) in
+                    synapses.iter_mut().zip(layer_inputs.iter())
+                {
+                    synapse.gradient += delta * value;
+                }
+                bias.gradient += delta;
+            }
+        }
+    }
+
+    /// Trains the network on a rank list.
+    ///
+    /// For each pair (i, j) where the label of i is higher, the cost
+    /// is C = log(1 + exp(-(s_i - s_j))). The derivatives of the cost
+    /// over the scores of each instance are summed up before back
+    /// propagation.
+    fn train_list(&mut self, list: &RankList) {
+        let inputs: Vec<Vec<f64>> =
+            list.iter().map(|instance| self.inputs(instance)).collect();
+        let scores = self.propagate(&inputs);
+
+        let mut lambdas = vec![0.0; list.len()];
+        for (i, instance1) in list.iter().enumerate() {
+            for (j, instance2) in list.iter().enumerate() {
+                if instance1.label() <= instance2.label() {
+                    continue;
+                }
+
+                // -dC/d(s_i - s_j)
+                let rho = 1.0 / (1.0 + (scores[i] - scores[j]).exp());
+                lambdas[i] -= rho;
+                lambdas[j] += rho;
+            }
+        }
+
+        for (index, &lambda) in lambdas.iter().enumerate() {
+            if lambda != 0.0 {
+                self.back_propagate(index, &inputs[index], lambda);
+            }
+        }
+
+        let learning_rate = self.config.learning_rate;
+        for layer in self.layers.iter_mut() {
+            for neuron in layer.neurons.iter_mut() {
+                for synapse in neuron.synapses.iter_mut() {
+                    synapse.update(learning_rate);
+                }
+            }
+        }
     }
 
-    pub fn learn(&self) {
-        
+    /// Measures the rank lists with the configured metric. No lists
+    /// score 0.0.
+    pub fn measure(&self, lists: &[RankList]) -> f64 {
+        if lists.is_empty() {
+            return 0.0;
+        }
+
+        let mut score = 0.0;
+        for list in lists {
+            let mut model_scores: Vec<(Value, Value)> = list.iter()
+                .map(|instance| (self.evaluate(instance), instance.label()))
+                .collect();
+            model_scores.sort_by(|&(score1, _), &(score2, _)| {
+                score2.partial_cmp(&score1).unwrap_or(Ordering::Equal)
+            });
+
+            let labels: Vec<Value> =
+                model_scores.iter().map(|&(_, label)| label).collect();
+            score += self.config.metric.measure(&labels);
+        }
+
+        score / lists.len() as f64
+    }
+
+    fn print(&self, msg: &str) {
+        if self.config.print_metric {
+            println!("{}", msg);
+        }
+    }
+
+    /// Print metric header.
+    fn print_metric_header(&self) {
+        self.print(&format!(
+            "{:<7} | {:>9} | {:>9}",
+            "#epoch",
+            self.config.metric.name() + "-T",
+            self.config.metric.name() + "-V"
+        ));
+    }
+
+    /// Print metric of each epoch.
+    fn print_metric(
+        &self,
+        epoch: usize,
+        train_score: f64,
+        validate_score: Option<f64>,
+    ) {
+        let s = format!(
+            "{:<7} | {:>9.4} | {}",
+            epoch,
+            train_score,
+            validate_score
+                .map(|score| format!("{:>9.4}", score))
+                .unwrap_or("".to_string())
+        );
+        self.print(&s);
+    }
+}
+
+impl Evaluate for RankNet {
+    fn evaluate(&self, instance: &Instance) -> f64 {
+        let mut values = self.inputs(instance);
+        for layer in self.layers.iter() {
+            values = layer.output(&values);
+        }
+        values[0]
+    }
+}
+
+fn load_dataset(path: &str, label_column: usize) -> Result<DataSet> {
+    let file = open_input(path)?;
+    DataSet::load_with_label_column(file, label_column)
+}
+
+pub fn main<'a>(matches: &ArgMatches<'a>) {
+    let validate_file_path = matches.value_of("validate-file");
+    let metric_name = matches.value_of("metric").unwrap();
+    let metric_k = value_t!(matches.value_of("metric-k"), usize)
+        .unwrap_or_else(|e| e.exit());
+    let epochs = value_t!(matches.value_of("epochs"), usize)
+        .unwrap_or_else(|e| e.exit());
+    let hidden_layers = values_t!(matches.values_of("hidden-layers"), usize)
+        .unwrap_or_else(|e| e.exit());
+    let learning_rate = value_t!(matches.value_of("learning-rate"), f64)
+        .unwrap_or_else(|e| e.exit());
+    let seed = value_t!(matches.value_of("seed"), u64)
+        .unwrap_or_else(|e| e.exit());
+    let label_column = value_t!(matches.value_of("label-column"), usize)
+        .unwrap_or_else(|e| e.exit());
+
+    // The rank lists of the files are independent, so they need no
+    // renaming of the qids.
+    let mut train = Vec::new();
+    for path in matches.values_of("train-file").unwrap() {
+        let lists = RankNet::read_file(path, label_column)
+            .unwrap_or_else(|e| {
+                error!("Failed to load {}: {}", path, e);
+                std::process::exit(1)
+            });
+        train.extend(lists);
+    }
+    let validate = validate_file_path.map(|path| {
+        load_dataset(path, label_column).unwrap_or_else(|e| {
+            error!("Failed to load {}: {}", path, e);
+            std::process::exit(1)
+        })
+    });
+
+    let config = Config {
+        train: train,
+        validate: validate,
+        // The param is valid.
+        metric: metric::new_with_max_gain(
+            metric_name,
+            metric_k,
+            super::gain_table(matches),
+            super::discount_base(matches),
+            super::max_gain(matches),
+        ).unwrap(),
+        hidden_layers: hidden_layers,
+        epochs: epochs,
+        learning_rate: learning_rate,
+        seed: seed,
+        print_metric: true,
+    };
+
+    let mut ranknet = RankNet::new(config);
+    ranknet.init();
+    ranknet.learn().unwrap();
+}
+
+pub fn clap_command<'a, 'b>() -> App<'a, 'b> {
+    let train_common_args = super::common_args();
+    // RankNet args
+    let ranknet_command = SubCommand::with_name("ranknet")
+        .about("Train RankNet")
+        .args(&train_common_args)
+        .arg(
+            Arg::with_name("epochs")
+                .long("epochs")
+                .takes_value(true)
+                .value_name("NUM")
+                .default_value("100")
+                .display_order(101)
+                .help("Number of epochs"),
+        )
+        .arg(
+            Arg::with_name("hidden-layers")
+                .long("hidden-layers")
+                .takes_value(true)
+                .value_name("NUM,...")
+                .use_delimiter(true)
+                .default_value("10")
+                .display_order(102)
+                .help("Number of neurons of each hidden layer"),
+        )
+        .arg(
+            Arg::with_name("learning-rate")
+                .long("learning-rate")
+                .takes_value(true)
+                .value_name("FACTOR")
+                .default_value("0.001")
+                .display_order(103)
+                .help("Learning rate"),
+        );
+    ranknet_command
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_read_rank_lists() {
+        let s = "0 qid:1 1:1.0
+2 qid:1 1:2.0
+1 qid:1 1:3.0
+0 qid:2 1:4.0
+1 qid:3 1:5.0
+2 qid:3 1:6.0";
+        let lists = RankNet::read(::std::io::Cursor::new(s)).unwrap();
+
+        assert_eq!(lists.len(), 3);
+        assert_eq!(
+            lists.iter().map(|list| list.len()).collect::<Vec<_>>(),
+            vec![3, 1, 2]
+        );
+        assert_eq!(
+            lists.iter().map(|list| list.qid()).collect::<Vec<_>>(),
+            vec![Some(1), Some(2), Some(3)]
+        );
+    }
+
+    #[test]
+    fn test_measure_empty() {
+        let config = Config {
+            train: Vec::new(),
+            validate: None,
+            metric: metric::new("NDCG", 10).unwrap(),
+            hidden_layers: vec![5],
+            epochs: 1,
+            learning_rate: 0.1,
+            seed: 0,
+            print_metric: false,
+        };
+        let ranknet = RankNet::new(config);
+        assert_eq!(ranknet.measure(&[]), 0.0);
+    }
+
+    #[test]
+    fn test_ranknet() {
+        // The label is increasing with feature 1, and unrelated with
+        // feature 2.
+        let data = vec![
+            (2.0, 1, vec![0.9, 0.5]),
+            (0.0, 1, vec![0.1, 0.4]),
+            (1.0, 1, vec![0.5, 0.7]),
+            (0.0, 2, vec![0.2, 0.9]),
+            (1.0, 2, vec![0.6, 0.1]),
+            (2.0, 3, vec![0.8, 0.2]),
+            (1.0, 3, vec![0.4, 0.3]),
+        ];
+        let dataset: DataSet = data.into_iter().collect();
+        let lists: Vec<RankList> = dataset
+            .query_iter()
+            .map(|(_qid, indices)| {
+                indices.iter().map(|&index| dataset[index].clone()).collect()
+            })
+            .collect();
+
+        let config = Config {
+            train: lists,
+            validate: Some(dataset.clone()),
+            metric: metric::new("NDCG", 10).unwrap(),
+            hidden_layers: vec![5],
+            epochs: 300,
+            learning_rate: 0.1,
+            seed: 0,
+            print_metric: false,
+        };
+        let mut ranknet = RankNet::new(config);
+        ranknet.init();
+        ranknet.learn().unwrap();
+
+        let metric = &ranknet.config.metric;
+        let score = dataset.evaluate(
+            &ranknet,
+            metric,
+            ZeroQueries::Include,
+            TieBreak::Index,
+        );
+        assert_eq!(score, 1.0);
     }
 }
@@ -1,12 +1,17 @@
 use train::dataset::DataSet;
 use train::Evaluate;
-use metric::Measure;
-use util::Value;
-use std::cmp::Ordering;
+use metric::{Measure, ScoreNorm, TieBreak, ZeroQueries};
+use scoped_threadpool::Pool;
+
+/// The minimum number of instances scored by a thread in `update`.
+const MIN_CHUNK_LEN: usize = 1000;
 
 pub struct ValidateSet<'d> {
     dataset: &'d DataSet,
     scores: Vec<f64>,
+    /// How the scores of each query are rescaled before `measure`
+    /// ranks them. See `set_score_normalize`.
+    score_normalize: Option<ScoreNorm>,
 }
 
 impl<'a> From<&'a DataSet> for ValidateSet<'a> {
@@ -16,42 +21,180 @@ impl<'a> From<&'a DataSet> for ValidateSet<'a> {
         ValidateSet {
             dataset: dataset,
             scores: scores,
+            score_normalize: None,
         }
     }
 }
 
 impl<'a> ValidateSet<'a> {
-    pub fn measure(&self, metric: &Box<Measure>) -> f64 {
-        let mut score = 0.0;
-        let mut count: usize = 0;
-        for (_, query) in self.dataset.query_iter() {
+    /// Sets how the scores of each query are rescaled before they are
+    /// ranked by `measure`. `None`, the default, ranks them as they
+    /// are.
+    pub fn set_score_normalize(&mut self, norm: Option<ScoreNorm>) {
+        self.score_normalize = norm;
+    }
 
-            let mut model_scores: Vec<(Value, Value)> = query
-                .iter()
-                .map(|&id| (self.scores[id], self.dataset[id].label()))
-                .collect();
+    /// Returns the average of the metric over the queries. See
+    /// `ZeroQueries` for which queries count, and `TieBreak` for how
+    /// the ties are ranked.
+    pub fn measure(
+        &self,
+        metric: &Box<Measure>,
+        zero_queries: ZeroQueries,
+        tie_break: TieBreak,
+    ) -> f64 {
+        let normalized = self.score_normalize.map(|norm| {
+            let mut scores = self.scores.clone();
+            self.dataset.normalize_scores(&mut scores, norm);
+            scores
+        });
+        let scores = normalized.as_ref().unwrap_or(&self.scores);
 
-            model_scores.sort_by(|&(score1, _), &(score2, _)| {
-                score2.partial_cmp(&score1).unwrap_or(Ordering::Equal)
-            });
+        let mut score = 0.0;
+        let mut count: usize = 0;
+        for (_, mut ranked) in self.dataset.query_iter() {
+            tie_break.rank(
+                &mut ranked,
+                |id| scores[id],
+                |id| self.dataset[id].label(),
+            );
 
             let labels: Vec<f64> =
-                model_scores.iter().map(|&(_, label)| label).collect();
+                ranked.iter().map(|&id| self.dataset[id].label()).collect();
+            if !zero_queries.counts(&labels) {
+                continue;
+            }
             let query_score = metric.measure(&labels);
 
             count += 1;
             score += query_score;
         }
 
+        if count == 0 {
+            return 0.0;
+        }
         let result = score / count as f64;
         result
     }
 
-    pub fn update<E: Evaluate>(&mut self, evaluator: &E) {
-        for (instance, score) in
-            self.dataset.iter().zip(self.scores.iter_mut())
-        {
-            *score += evaluator.evaluate(instance);
+    /// Returns the root mean squared error of the scores against the
+    /// labels.
+    pub fn rmse(&self) -> f64 {
+        let sum: f64 = self.scores
+            .iter()
+            .zip(self.dataset.label_iter())
+            .map(|(score, label)| (label - score) * (label - score))
+            .sum();
+        (sum / self.scores.len() as f64).sqrt()
+    }
+
+    /// Adds the output of the evaluator to the scores. The instances
+    /// are split into a chunk for each thread of `util::POOL`, and each
+    /// thread updates the scores of its own chunk. Small data sets are
+    /// updated on the current thread, where the threads cost more than
+    /// they save.
+    pub fn update<E: Evaluate + Sync>(&mut self, evaluator: &E) {
+        let mut pool = ::util::POOL.lock().unwrap();
+        self.update_in(&mut pool, evaluator);
+    }
+
+    fn update_in<E: Evaluate + Sync>(
+        &mut self,
+        pool: &mut Pool,
+        evaluator: &E,
+    ) {
+        let dataset = self.dataset;
+        let threads = pool.thread_count() as usize;
+        let chunk_size = ((self.scores.len() + threads - 1) / threads)
+            .max(MIN_CHUNK_LEN);
+        if chunk_size >= self.scores.len() {
+            for (score, instance) in self.scores.iter_mut().zip(dataset.iter())
+            {
+                *score += evaluator.evaluate(instance);
+            }
+            return;
         }
+
+        pool.scoped(|scoped| {
+            let chunks = self.scores.chunks_mut(chunk_size).enumerate();
+            for (i, chunk) in chunks {
+                scoped.execute(move || {
+                    let start = i * chunk_size;
+                    for (offset, score) in chunk.iter_mut().enumerate() {
+                        *score += evaluator.evaluate(&dataset[start + offset]);
+                    }
+                })
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::File;
+    use std::io::Read;
+    use test::Bencher;
+    use train::lambdamart::regression_tree::RegressionTree;
+    use train::lambdamart::training_set::TrainSet;
+
+    /// Loads the lite data set repeated `times` times.
+    fn load_repeated(times: usize) -> DataSet {
+        let mut s = String::new();
+        File::open("./data/train-lite.txt")
+            .unwrap()
+            .read_to_string(&mut s)
+            .unwrap();
+        let s = vec![s; times].join("\n");
+        DataSet::load(s.as_bytes()).unwrap()
+    }
+
+    fn fit_tree(dataset: &DataSet) -> RegressionTree {
+        let mut training = TrainSet::new(dataset, 256);
+        training.fit_labels();
+        let mut tree = RegressionTree::new(1.0, 10, 1);
+        tree.fit(&training).unwrap();
+        tree
+    }
+
+    /// The serial version of `update`, for comparison.
+    fn update_serial<E: Evaluate>(validate: &mut ValidateSet, evaluator: &E) {
+        let delta = evaluator.evaluate_all(validate.dataset);
+        for (score, delta) in validate.scores.iter_mut().zip(delta) {
+            *score += delta;
+        }
+    }
+
+    #[test]
+    fn test_update() {
+        let dataset = load_repeated(5);
+        let tree = fit_tree(&dataset);
+
+        let mut pool = Pool::new(4);
+        let mut parallel = ValidateSet::from(&dataset);
+        let mut serial = ValidateSet::from(&dataset);
+        for _ in 0..2 {
+            parallel.update_in(&mut pool, &tree);
+            update_serial(&mut serial, &tree);
+        }
+        assert_eq!(parallel.scores, serial.scores);
+    }
+
+    #[bench]
+    fn bench_update(b: &mut Bencher) {
+        let dataset = load_repeated(20);
+        let tree = fit_tree(&dataset);
+
+        let mut validate = ValidateSet::from(&dataset);
+        b.iter(|| validate.update(&tree));
+    }
+
+    #[bench]
+    fn bench_update_serial(b: &mut Bencher) {
+        let dataset = load_repeated(20);
+        let tree = fit_tree(&dataset);
+
+        let mut validate = ValidateSet::from(&dataset);
+        b.iter(|| update_serial(&mut validate, &tree));
     }
 }
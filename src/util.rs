@@ -12,3 +12,192 @@ use num_cpus;
 lazy_static! {
     pub static ref POOL: Mutex<Pool> = Mutex::new(Pool::new(num_cpus::get() as u32));
 }
+
+/// Replaces `POOL` with a pool of the given number of threads. It is
+/// meant to be called once at startup, before any parallel work.
+pub fn set_threads(threads: usize) {
+    assert!(threads > 0);
+    *POOL.lock().unwrap() = Pool::new(threads as u32);
+}
+
+/// Path that stands for the standard input.
+pub const STDIN_PATH: &str = "-";
+
+/// Path that stands for the standard output.
+pub const STDOUT_PATH: &str = "-";
+
+/// The standard input, which can be opened only once since it can not
+/// be read again.
+enum Stdin {
+    Unread,
+    /// A reader read in place of the standard input. See `set_stdin`.
+    Substituted(Box<::std::io::Read + Send>),
+    Taken,
+}
+
+lazy_static! {
+    static ref STDIN: Mutex<Stdin> = Mutex::new(Stdin::Unread);
+}
+
+/// Substitutes the reader for the standard input opened by the next
+/// `open_input(STDIN_PATH)`, e.g. in tests.
+pub fn set_stdin(reader: Box<::std::io::Read + Send>) {
+    *STDIN.lock().unwrap() = Stdin::Substituted(reader);
+}
+
+/// Opens a file for reading. A dash means the standard input, which
+/// can be opened only once: a second dash would read an empty stream,
+/// so it is an error.
+pub fn open_input(path: &str) -> Result<Box<::std::io::Read>> {
+    if path == STDIN_PATH {
+        let mut stdin = STDIN.lock().unwrap();
+        match ::std::mem::replace(&mut *stdin, Stdin::Taken) {
+            Stdin::Unread => Ok(Box::new(::std::io::stdin())),
+            Stdin::Substituted(reader) => Ok(reader),
+            Stdin::Taken => {
+                Err("Only one file can be read from the standard input")?
+            }
+        }
+    } else {
+        Ok(Box::new(::std::fs::File::open(path)?))
+    }
+}
+
+/// Opens a file for buffered writing. A dash means the standard
+/// output.
+pub fn open_output(path: &str) -> Result<Box<::std::io::Write>> {
+    use std::io::BufWriter;
+
+    if path == STDOUT_PATH {
+        Ok(Box::new(BufWriter::new(::std::io::stdout())))
+    } else {
+        Ok(Box::new(BufWriter::new(::std::fs::File::create(path)?)))
+    }
+}
+
+/// A xorshift pseudo random number generator. It is fast and good
+/// enough for sampling, but not cryptographically secure.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    /// Creates a generator from the seed. The same seed always
+    /// generates the same sequence. The seed is mixed by a splitmix64
+    /// step, which maps distinct seeds to distinct states, so that
+    /// small or similar seeds do not start similar sequences.
+    pub fn new(seed: u64) -> Rng {
+        let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        // Xorshift gets stuck on zero state, which one seed mixes to.
+        let state = if z == 0 { 0x2545_f491_4f6c_dd1d } else { z };
+        Rng { state: state }
+    }
+
+    /// Returns the next random u64.
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    /// Returns a random f64 uniformly distributed in [0, 1).
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Returns a random index uniformly distributed in [0, n).
+    pub fn gen_index(&mut self, n: usize) -> usize {
+        assert!(n > 0);
+        (self.next_u64() % n as u64) as usize
+    }
+
+    /// Shuffles the slice in place.
+    pub fn shuffle<T>(&mut self, values: &mut [T]) {
+        for i in (1..values.len()).rev() {
+            let j = self.gen_index(i + 1);
+            values.swap(i, j);
+        }
+    }
+}
+
+/// A score with a total order, for sorting deterministically. NaN is
+/// less than any other score, and equal to itself. Sort by
+/// `(Reverse(OrderedScore(score)), index)` to rank in descending
+/// order of the scores with ties broken by the index.
+///
+/// # Examples
+///
+/// ```
+/// use std::cmp::Reverse;
+/// use rforests::util::OrderedScore;
+///
+/// let scores = vec![1.0, ::std::f64::NAN, 2.0, 1.0];
+/// let mut indices: Vec<usize> = (0..scores.len()).collect();
+/// indices.sort_by_key(|&i| (Reverse(OrderedScore(scores[i])), i));
+/// assert_eq!(indices, vec![2, 0, 3, 1]);
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct OrderedScore(pub f64);
+
+impl Ord for OrderedScore {
+    fn cmp(&self, other: &OrderedScore) -> ::std::cmp::Ordering {
+        use std::cmp::Ordering;
+
+        match (self.0.is_nan(), other.0.is_nan()) {
+            (true, true) => Ordering::Equal,
+            (true, false) => Ordering::Less,
+            (false, true) => Ordering::Greater,
+            (false, false) => self.0.partial_cmp(&other.0).unwrap(),
+        }
+    }
+}
+
+impl PartialOrd for OrderedScore {
+    fn partial_cmp(
+        &self,
+        other: &OrderedScore,
+    ) -> Option<::std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for OrderedScore {
+    fn eq(&self, other: &OrderedScore) -> bool {
+        self.cmp(other) == ::std::cmp::Ordering::Equal
+    }
+}
+
+impl Eq for OrderedScore {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+    use train::dataset::DataSet;
+
+    #[test]
+    fn test_rng_seeds() {
+        let first = |seed| Rng::new(seed).next_u64();
+        assert_eq!(first(7), first(7));
+        assert_ne!(first(0), first(0x2545_f491_4f6c_dd1d));
+        assert_ne!(first(0), first(1));
+    }
+
+    #[test]
+    fn test_open_input_stdin() {
+        let data = "1 qid:1 1:2.0\n0 qid:1 1:1.0\n";
+        set_stdin(Box::new(Cursor::new(data.as_bytes().to_vec())));
+
+        let dataset = DataSet::load(open_input(STDIN_PATH).unwrap()).unwrap();
+        assert_eq!(dataset.len(), 2);
+        assert_eq!(dataset[1].value(1), 1.0);
+
+        // The standard input is already consumed.
+        assert!(open_input(STDIN_PATH).is_err());
+    }
+}